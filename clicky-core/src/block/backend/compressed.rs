@@ -0,0 +1,538 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Debug;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use futures::io::{AsyncRead, AsyncSeek, AsyncWrite};
+use futures::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::block::{self, BlockDev};
+
+/// Compressed images start with a header:
+///
+/// - magic number (8 bytes)
+/// - length of the uncompressed image (u64)
+/// - chunk size (u32)
+/// - reserved (4 bytes)
+/// - offset of the chunk index (u64)
+///
+/// followed by each chunk's (raw deflate) compressed data. All values are
+/// little-endian.
+const MAGIC: &[u8; 8] = b"CLKYCMP1";
+const HEADER_LEN: u64 = 32;
+
+/// Each index entry consists of the chunk's offset in the file (u64), and its
+/// compressed length (u32).
+///
+/// Chunks which only contain zeros aren't stored at all (i.e: have a length of
+/// 0), and chunks which don't compress well are stored as-is (i.e: have a
+/// length equal to the chunk size).
+const INDEX_ENTRY_LEN: usize = 12;
+
+/// Number of decompressed chunks to keep around.
+const CACHE_CHUNKS: usize = 32;
+
+/// Something a compressed image can be read from (e.g: a `File`).
+trait Source: Read + Seek + Send + Sync + Debug {}
+impl<T: Read + Seek + Send + Sync + Debug> Source for T {}
+
+#[derive(Debug, Copy, Clone)]
+struct Chunk {
+    offset: u64,
+    len: u32,
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "wasm32")] {
+        // no threads to offload to, so chunks are decompressed synchronously
+        // (images are typically in-memory on wasm anyway).
+        type Inner = futures::io::AllowStdIo<Image>;
+    } else {
+        // reading + decompressing chunks is blocking I/O, which is offloaded
+        // to a separate thread.
+        type Inner = blocking::Unblock<Image>;
+    }
+}
+
+/// Chunk-compressed block device, supporting random access to images which
+/// would otherwise be too big to ship around (e.g: multi-GB images which are
+/// mostly empty).
+///
+/// The image is never modified. Instead, written chunks are kept in memory,
+/// and are discarded once the block device is dropped.
+///
+/// Use `Compressed::compress` to convert raw images into the compressed
+/// format.
+#[derive(Debug)]
+pub struct Compressed {
+    len: u64,
+    inner: Inner,
+}
+
+/// An open compressed image.
+struct Image {
+    len: u64,
+    pos: u64,
+    chunk_size: u32,
+    source: Box<dyn Source>,
+    index: Vec<Chunk>,
+    /// Recently accessed chunks, with the most recent at the front.
+    cache: VecDeque<(usize, Box<[u8]>)>,
+    /// Chunks which have been written to.
+    dirty: HashMap<usize, Box<[u8]>>,
+}
+
+impl std::fmt::Debug for Image {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Image")
+            .field("len", &self.len)
+            .field("pos", &self.pos)
+            .field("chunk_size", &self.chunk_size)
+            .field("source", &self.source)
+            .field("index", &"[...]")
+            .field("cache", &"[...]")
+            .field("dirty", &"[...]")
+            .finish()
+    }
+}
+
+fn invalid_data(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Compressed {
+    /// Default size of each compressed chunk (in bytes).
+    pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+
+    /// Check if `data` starts with a compressed image's magic number.
+    pub fn is_compressed(data: &[u8]) -> bool {
+        data.starts_with(MAGIC)
+    }
+
+    /// Open a compressed image.
+    pub fn new(source: impl Read + Seek + Send + Sync + Debug + 'static) -> io::Result<Compressed> {
+        let image = Image::new(source)?;
+        Ok(Compressed {
+            len: image.len,
+            inner: Inner::new(image),
+        })
+    }
+
+    /// Convert a raw image of length `len` into a compressed image.
+    pub fn compress(
+        raw: &mut impl Read,
+        len: u64,
+        out: &mut (impl Write + Seek),
+        chunk_size: u32,
+    ) -> io::Result<()> {
+        if chunk_size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid chunk size",
+            ));
+        }
+
+        out.seek(SeekFrom::Start(0))?;
+        out.write_all(&[0; HEADER_LEN as usize])?;
+
+        let mut index = Vec::new();
+        let mut offset = HEADER_LEN;
+        let mut chunk = vec![0; chunk_size as usize];
+        let mut remaining = len;
+        while remaining != 0 {
+            let chunk = &mut chunk[..remaining.min(chunk_size as u64) as usize];
+            raw.read_exact(chunk)?;
+            remaining -= chunk.len() as u64;
+
+            let data = if chunk.iter().all(|b| *b == 0) {
+                Vec::new()
+            } else {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(chunk)?;
+                let compressed = encoder.finish()?;
+                if compressed.len() < chunk.len() {
+                    compressed
+                } else {
+                    chunk.to_vec()
+                }
+            };
+
+            out.write_all(&data)?;
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            offset += data.len() as u64;
+        }
+        out.write_all(&index)?;
+
+        out.seek(SeekFrom::Start(0))?;
+        out.write_all(MAGIC)?;
+        out.write_all(&len.to_le_bytes())?;
+        out.write_all(&chunk_size.to_le_bytes())?;
+        out.write_all(&[0; 4])?;
+        out.write_all(&offset.to_le_bytes())?;
+        out.flush()
+    }
+}
+
+impl Image {
+    fn new(mut source: impl Read + Seek + Send + Sync + Debug + 'static) -> io::Result<Image> {
+        let source_len = source.seek(SeekFrom::End(0))?;
+
+        let mut header = [0; HEADER_LEN as usize];
+        source.seek(SeekFrom::Start(0))?;
+        source.read_exact(&mut header)?;
+        if !Compressed::is_compressed(&header) {
+            return Err(invalid_data("not a compressed image"));
+        }
+
+        let u64_at = |i: usize| {
+            let mut buf = [0; 8];
+            buf.copy_from_slice(&header[i..i + 8]);
+            u64::from_le_bytes(buf)
+        };
+        let len = u64_at(8);
+        // the upper half is reserved
+        let chunk_size = u64_at(16) as u32;
+        let index_offset = u64_at(24);
+        if chunk_size == 0 {
+            return Err(invalid_data("invalid chunk size"));
+        }
+
+        // validate the header against the size of the file before allocating
+        // anything based on it
+        let num_chunks = len / chunk_size as u64 + (len % chunk_size as u64 != 0) as u64;
+        let index_end = num_chunks
+            .checked_mul(INDEX_ENTRY_LEN as u64)
+            .and_then(|index_len| index_len.checked_add(index_offset));
+        match index_end {
+            Some(end) if end <= source_len => {}
+            _ => return Err(invalid_data("chunk index extends past the end of the file")),
+        }
+
+        let mut raw_index = vec![0; num_chunks as usize * INDEX_ENTRY_LEN];
+        source.seek(SeekFrom::Start(index_offset))?;
+        source.read_exact(&mut raw_index)?;
+
+        let index = raw_index
+            .chunks_exact(INDEX_ENTRY_LEN)
+            .map(|entry| {
+                let mut offset = [0; 8];
+                let mut len = [0; 4];
+                offset.copy_from_slice(&entry[..8]);
+                len.copy_from_slice(&entry[8..]);
+                Chunk {
+                    offset: u64::from_le_bytes(offset),
+                    len: u32::from_le_bytes(len),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for (idx, chunk) in index.iter().enumerate() {
+            let start = idx as u64 * chunk_size as u64;
+            let chunk_len = (len - start).min(chunk_size as u64);
+            // chunks which don't compress are stored as-is, so a chunk's data
+            // is never longer than the chunk itself
+            let in_bounds = match chunk.offset.checked_add(chunk.len as u64) {
+                Some(end) => end <= source_len,
+                None => false,
+            };
+            if chunk.len as u64 > chunk_len || !in_bounds {
+                return Err(invalid_data("invalid chunk index entry"));
+            }
+        }
+
+        Ok(Image {
+            len,
+            pos: 0,
+            chunk_size,
+            source: Box::new(source),
+            index,
+            cache: VecDeque::new(),
+            dirty: HashMap::new(),
+        })
+    }
+
+    fn chunk_len(&self, idx: usize) -> usize {
+        let start = idx as u64 * self.chunk_size as u64;
+        (self.len - start).min(self.chunk_size as u64) as usize
+    }
+
+    fn decompress_chunk(&mut self, idx: usize) -> io::Result<Box<[u8]>> {
+        let chunk = self.index[idx];
+        let chunk_len = self.chunk_len(idx);
+        let mut data = vec![0; chunk_len].into_boxed_slice();
+
+        if chunk.len == 0 {
+            return Ok(data);
+        }
+
+        let mut compressed = vec![0; chunk.len as usize];
+        self.source.seek(SeekFrom::Start(chunk.offset))?;
+        self.source.read_exact(&mut compressed)?;
+
+        if chunk.len as usize == chunk_len {
+            data.copy_from_slice(&compressed);
+        } else {
+            DeflateDecoder::new(&compressed[..]).read_exact(&mut data)?;
+        }
+        Ok(data)
+    }
+
+    /// Return the contents of a chunk, decompressing it if it isn't already
+    /// cached.
+    fn chunk(&mut self, idx: usize) -> io::Result<&[u8]> {
+        if self.dirty.contains_key(&idx) {
+            return Ok(&self.dirty[&idx]);
+        }
+
+        match self.cache.iter().position(|(i, _)| *i == idx) {
+            Some(pos) => {
+                let entry = self.cache.remove(pos).unwrap();
+                self.cache.push_front(entry);
+            }
+            None => {
+                let data = self.decompress_chunk(idx)?;
+                self.cache.push_front((idx, data));
+                self.cache.truncate(CACHE_CHUNKS);
+            }
+        }
+
+        Ok(&self.cache[0].1)
+    }
+}
+
+impl BlockDev for Compressed {
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Read for Image {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+
+        let mut done = 0;
+        while done < n {
+            let idx = (self.pos / self.chunk_size as u64) as usize;
+            let offset = (self.pos % self.chunk_size as u64) as usize;
+            let data = &self.chunk(idx)?[offset..];
+            let chunk = data.len().min(n - done);
+
+            buf[done..done + chunk].copy_from_slice(&data[..chunk]);
+            done += chunk;
+            self.pos += chunk as u64;
+        }
+
+        Ok(n)
+    }
+}
+
+impl Write for Image {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+
+        let mut done = 0;
+        while done < n {
+            let idx = (self.pos / self.chunk_size as u64) as usize;
+            let offset = (self.pos % self.chunk_size as u64) as usize;
+            if !self.dirty.contains_key(&idx) {
+                let data = self.chunk(idx)?.into();
+                self.dirty.insert(idx, data);
+            }
+
+            let data = &mut self.dirty.get_mut(&idx).unwrap()[offset..];
+            let chunk = data.len().min(n - done);
+
+            data[..chunk].copy_from_slice(&buf[done..done + chunk]);
+            done += chunk;
+            self.pos += chunk as u64;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Image {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = block::seek_pos(self.pos, self.len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl Read for Compressed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        futures_executor::block_on(async { AsyncReadExt::read(self, buf).await })
+    }
+}
+
+impl Write for Compressed {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        futures_executor::block_on(async { AsyncWriteExt::write(self, buf).await })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        futures_executor::block_on(async { AsyncWriteExt::flush(self).await })
+    }
+}
+
+impl Seek for Compressed {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        futures_executor::block_on(async { AsyncSeekExt::seek(self, pos).await })
+    }
+}
+
+impl AsyncRead for Compressed {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncRead::poll_read(Pin::new(&mut self.inner), cx, buf)
+    }
+}
+
+impl AsyncWrite for Compressed {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.inner), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.inner), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_close(Pin::new(&mut self.inner), cx)
+    }
+}
+
+impl AsyncSeek for Compressed {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        AsyncSeek::poll_seek(Pin::new(&mut self.inner), cx, pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compressed, HEADER_LEN, INDEX_ENTRY_LEN};
+
+    use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+
+    use crate::block::BlockDev;
+
+    const CHUNK_SIZE: u32 = 4096;
+
+    /// 4.5 chunks: one empty, one compressible, one incompressible, one
+    /// compressible, and a partial final chunk.
+    fn raw_image() -> Vec<u8> {
+        let mut data = vec![0; CHUNK_SIZE as usize * 4 + CHUNK_SIZE as usize / 2];
+        let (_empty, rest) = data.split_at_mut(CHUNK_SIZE as usize);
+        for (i, b) in rest.iter_mut().enumerate() {
+            *b = (i / 64) as u8;
+        }
+
+        // xorshift noise, which deflate can't do anything with
+        let mut x = 0x1234_5678_u32;
+        for b in &mut data[2 * CHUNK_SIZE as usize..3 * CHUNK_SIZE as usize] {
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            *b = x as u8;
+        }
+        data
+    }
+
+    fn compress(raw: &[u8]) -> Vec<u8> {
+        let mut out = Cursor::new(Vec::new());
+        Compressed::compress(&mut &raw[..], raw.len() as u64, &mut out, CHUNK_SIZE).unwrap();
+        out.into_inner()
+    }
+
+    fn read_all(img: &mut Compressed) -> Vec<u8> {
+        let mut buf = Vec::new();
+        img.seek(SeekFrom::Start(0)).unwrap();
+        img.read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn round_trip() {
+        let raw = raw_image();
+        let compressed = compress(&raw);
+        assert!(Compressed::is_compressed(&compressed));
+        assert!(compressed.len() < raw.len());
+
+        let mut img = Compressed::new(Cursor::new(compressed)).unwrap();
+        assert_eq!(img.len(), raw.len() as u64);
+        assert_eq!(read_all(&mut img), raw);
+
+        // unaligned reads which straddle chunks
+        let mut buf = vec![0; 3 * CHUNK_SIZE as usize];
+        img.seek(SeekFrom::Start(CHUNK_SIZE as u64 - 7)).unwrap();
+        img.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, raw[CHUNK_SIZE as usize - 7..][..buf.len()]);
+    }
+
+    #[test]
+    fn writes_stay_in_memory() {
+        let raw = raw_image();
+        let compressed = compress(&raw);
+
+        let mut img = Compressed::new(Cursor::new(compressed.clone())).unwrap();
+        img.seek(SeekFrom::Start(CHUNK_SIZE as u64 - 8)).unwrap();
+        img.write_all(&[0xaa; 16]).unwrap();
+        img.seek(SeekFrom::End(-1)).unwrap();
+        img.write_all(&[0xbb]).unwrap();
+
+        let mut expected = raw.clone();
+        expected[CHUNK_SIZE as usize - 8..][..16].fill(0xaa);
+        *expected.last_mut().unwrap() = 0xbb;
+        assert_eq!(read_all(&mut img), expected);
+
+        // re-opening the image discards the writes
+        let mut img = Compressed::new(Cursor::new(compressed)).unwrap();
+        assert_eq!(read_all(&mut img), raw);
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let compressed = compress(&raw_image());
+        let index_offset = compressed.len() - 5 * INDEX_ENTRY_LEN;
+
+        // index extends past the end of the file
+        let truncated = compressed[..compressed.len() - 1].to_vec();
+        assert!(Compressed::new(Cursor::new(truncated)).is_err());
+
+        // absurd image length (which would otherwise allocate a huge index)
+        let mut huge = compressed.clone();
+        huge[8..16].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(Compressed::new(Cursor::new(huge)).is_err());
+
+        // chunk data extends past the end of the file
+        let mut bad_chunk = compressed.clone();
+        let entry = index_offset + INDEX_ENTRY_LEN;
+        bad_chunk[entry..entry + 8].copy_from_slice(&(index_offset as u64).to_le_bytes());
+        assert!(Compressed::new(Cursor::new(bad_chunk)).is_err());
+
+        // chunk data is longer than the chunk itself
+        let mut bad_len = compressed;
+        let entry = index_offset + INDEX_ENTRY_LEN;
+        bad_len[entry..entry + 8].copy_from_slice(&HEADER_LEN.to_le_bytes());
+        bad_len[entry + 8..entry + 12].copy_from_slice(&(CHUNK_SIZE + 1).to_le_bytes());
+        assert!(Compressed::new(Cursor::new(bad_len)).is_err());
+    }
+}
@@ -0,0 +1,475 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use blocking::Unblock;
+use futures::io::{AsyncRead, AsyncSeek, AsyncWrite};
+use futures::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::block::{self, BlockDev};
+
+const SECTOR_SIZE: u64 = 512;
+
+/// Delta files start with a magic number, followed by the length of the image
+/// they apply to (as a little-endian u64).
+const MAGIC: &[u8; 8] = b"CLKYCOW1";
+const HEADER_LEN: u64 = 16;
+
+/// Each record in a delta file consists of a sector number (as a
+/// little-endian u64), followed by the sector's contents.
+const RECORD_LEN: u64 = 8 + SECTOR_SIZE;
+
+/// Something an overlay can be stacked on top of.
+trait Layer: Read + Write + Seek + Send + Sync + Debug {}
+impl<T: Read + Write + Seek + Send + Sync + Debug> Layer for T {}
+
+/// A single copy-on-write layer: a base layer, plus a delta file recording
+/// which sectors have been changed.
+#[derive(Debug)]
+struct Overlay {
+    len: u64,
+    pos: u64,
+    base: Box<dyn Layer>,
+    delta: File,
+    /// Maps sector numbers to the offset of their data in the delta file.
+    index: HashMap<u64, u64>,
+}
+
+impl Overlay {
+    fn new(base: Box<dyn Layer>, len: u64, mut delta: File) -> io::Result<Overlay> {
+        let delta_len = delta.metadata()?.len();
+        let mut index = HashMap::new();
+
+        if delta_len == 0 {
+            delta.write_all(MAGIC)?;
+            delta.write_all(&len.to_le_bytes())?;
+        } else {
+            let mut header = [0; HEADER_LEN as usize];
+            delta.seek(SeekFrom::Start(0))?;
+            delta.read_exact(&mut header)?;
+            if &header[..8] != MAGIC {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not a delta file",
+                ));
+            }
+            let mut base_len = [0; 8];
+            base_len.copy_from_slice(&header[8..]);
+            if u64::from_le_bytes(base_len) != len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "delta file was created for a different image",
+                ));
+            }
+
+            // a partially-written record (e.g: if clicky was killed mid-write)
+            // is dropped, as the guest never saw that write complete
+            let records_end = delta_len - (delta_len - HEADER_LEN) % RECORD_LEN;
+            if records_end != delta_len {
+                warn!("discarding truncated record at the end of the delta file");
+                delta.set_len(records_end)?;
+            }
+
+            // rebuild the index
+            let mut sector = [0; 8];
+            let mut offset = HEADER_LEN;
+            while offset < records_end {
+                delta.seek(SeekFrom::Start(offset))?;
+                delta.read_exact(&mut sector)?;
+                index.insert(u64::from_le_bytes(sector), offset + 8);
+                offset += RECORD_LEN;
+            }
+        }
+
+        Ok(Overlay {
+            len,
+            pos: 0,
+            base,
+            delta,
+            index,
+        })
+    }
+
+    /// Read an entire sector from the base layer, zero-padding the final
+    /// sector if the image isn't a multiple of the sector size.
+    fn read_base_sector(&mut self, sector: u64) -> io::Result<[u8; SECTOR_SIZE as usize]> {
+        let mut data = [0; SECTOR_SIZE as usize];
+        let start = sector * SECTOR_SIZE;
+        let n = (self.len - start).min(SECTOR_SIZE) as usize;
+        self.base.seek(SeekFrom::Start(start))?;
+        self.base.read_exact(&mut data[..n])?;
+        Ok(data)
+    }
+
+    /// Merge the delta into the base layer, and reset the delta.
+    fn commit(&mut self) -> io::Result<()> {
+        let mut sectors = self.index.iter().map(|(&s, &o)| (s, o)).collect::<Vec<_>>();
+        sectors.sort_unstable();
+
+        let mut data = [0; SECTOR_SIZE as usize];
+        for (sector, offset) in sectors {
+            let start = sector * SECTOR_SIZE;
+            let n = (self.len - start).min(SECTOR_SIZE) as usize;
+            self.delta.seek(SeekFrom::Start(offset))?;
+            self.delta.read_exact(&mut data)?;
+            self.base.seek(SeekFrom::Start(start))?;
+            self.base.write_all(&data[..n])?;
+        }
+        self.base.flush()?;
+
+        self.discard()
+    }
+
+    /// Throw away all changes recorded in the delta.
+    fn discard(&mut self) -> io::Result<()> {
+        self.delta.set_len(HEADER_LEN)?;
+        self.delta.sync_all()?;
+        self.index.clear();
+        Ok(())
+    }
+}
+
+impl Read for Overlay {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+
+        let mut done = 0;
+        while done < n {
+            let sector = self.pos / SECTOR_SIZE;
+            let offset = self.pos % SECTOR_SIZE;
+            let chunk = ((SECTOR_SIZE - offset) as usize).min(n - done);
+            let buf = &mut buf[done..done + chunk];
+
+            match self.index.get(&sector) {
+                Some(&data) => {
+                    self.delta.seek(SeekFrom::Start(data + offset))?;
+                    self.delta.read_exact(buf)?;
+                }
+                None => {
+                    self.base.seek(SeekFrom::Start(self.pos))?;
+                    self.base.read_exact(buf)?;
+                }
+            }
+
+            done += chunk;
+            self.pos += chunk as u64;
+        }
+
+        Ok(n)
+    }
+}
+
+impl Write for Overlay {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+
+        let mut done = 0;
+        while done < n {
+            let sector = self.pos / SECTOR_SIZE;
+            let offset = self.pos % SECTOR_SIZE;
+            let chunk = ((SECTOR_SIZE - offset) as usize).min(n - done);
+            let buf = &buf[done..done + chunk];
+
+            match self.index.get(&sector) {
+                Some(&data) => {
+                    self.delta.seek(SeekFrom::Start(data + offset))?;
+                    self.delta.write_all(buf)?;
+                }
+                None => {
+                    // copy the sector into the delta before modifying it
+                    let mut data = self.read_base_sector(sector)?;
+                    data[offset as usize..][..chunk].copy_from_slice(buf);
+
+                    let record = self.delta.seek(SeekFrom::End(0))?;
+                    self.delta.write_all(&sector.to_le_bytes())?;
+                    self.delta.write_all(&data)?;
+                    self.index.insert(sector, record + 8);
+                }
+            }
+
+            done += chunk;
+            self.pos += chunk as u64;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.delta.flush()
+    }
+}
+
+impl Seek for Overlay {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = block::seek_pos(self.pos, self.len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Copy-on-write block device. Reads come from a read-only base image, while
+/// writes are recorded in a sparse delta file (which only contains the
+/// sectors that were changed).
+///
+/// Overlays can be stacked on top of one another, with lower overlays' delta
+/// files becoming read-only.
+#[derive(Debug)]
+pub struct Cow {
+    len: u64,
+    inner: Unblock<Overlay>,
+}
+
+impl Cow {
+    /// Create a copy-on-write overlay on top of `base`. The delta file is
+    /// initialized if it's empty.
+    ///
+    /// `base` only needs to be writable in order to `commit` changes into it.
+    pub fn new(base: File, delta: File) -> io::Result<Cow> {
+        let len = base.metadata()?.len();
+        let overlay = Overlay::new(Box::new(base), len, delta)?;
+        Ok(Cow {
+            len,
+            inner: Unblock::new(overlay),
+        })
+    }
+
+    /// Stack another overlay on top of this one. The current overlay's delta
+    /// file is left untouched until the new overlay is committed.
+    pub fn stack(self, delta: File) -> io::Result<Cow> {
+        let lower = futures_executor::block_on(self.inner.into_inner());
+        let overlay = Overlay::new(Box::new(lower), self.len, delta)?;
+        Ok(Cow {
+            len: self.len,
+            inner: Unblock::new(overlay),
+        })
+    }
+
+    /// Merge the top-most overlay's changes into the layer beneath it, leaving
+    /// the overlay empty.
+    pub fn commit(&mut self) -> io::Result<()> {
+        futures_executor::block_on(self.inner.get_mut()).commit()
+    }
+
+    /// Throw away the top-most overlay's changes.
+    pub fn discard(&mut self) -> io::Result<()> {
+        futures_executor::block_on(self.inner.get_mut()).discard()
+    }
+}
+
+impl BlockDev for Cow {
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Read for Cow {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        futures_executor::block_on(async { AsyncReadExt::read(self, buf).await })
+    }
+}
+
+impl Write for Cow {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        futures_executor::block_on(async { AsyncWriteExt::write(self, buf).await })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        futures_executor::block_on(async { AsyncWriteExt::flush(self).await })
+    }
+}
+
+impl Seek for Cow {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        futures_executor::block_on(async { AsyncSeekExt::seek(self, pos).await })
+    }
+}
+
+impl AsyncRead for Cow {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncRead::poll_read(Pin::new(&mut self.inner), cx, buf)
+    }
+}
+
+impl AsyncWrite for Cow {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.inner), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.inner), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_close(Pin::new(&mut self.inner), cx)
+    }
+}
+
+impl AsyncSeek for Cow {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        AsyncSeek::poll_seek(Pin::new(&mut self.inner), cx, pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cow, HEADER_LEN, RECORD_LEN, SECTOR_SIZE};
+
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    use crate::testutil::TempPath;
+
+    /// 3.5 sectors, to exercise the partial final sector.
+    const IMAGE_LEN: usize = 3 * SECTOR_SIZE as usize + 256;
+
+    fn base_image() -> Vec<u8> {
+        (0..IMAGE_LEN).map(|i| i as u8).collect()
+    }
+
+    fn read_all(cow: &mut Cow) -> Vec<u8> {
+        let mut buf = Vec::new();
+        cow.seek(SeekFrom::Start(0)).unwrap();
+        cow.read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn records_writes_in_delta() {
+        let base = TempPath::file("cow-record-base", &base_image());
+        let delta = TempPath::file("cow-record-delta", &[]);
+
+        let mut cow = Cow::new(base.open(), delta.open()).unwrap();
+        // straddles a sector boundary
+        cow.seek(SeekFrom::Start(500)).unwrap();
+        cow.write_all(&[0xaa; 24]).unwrap();
+        // lands in the partial final sector
+        cow.seek(SeekFrom::End(-4)).unwrap();
+        cow.write_all(&[0xbb; 4]).unwrap();
+        cow.flush().unwrap();
+
+        let mut expected = base_image();
+        expected[500..524].fill(0xaa);
+        expected[IMAGE_LEN - 4..].fill(0xbb);
+        assert_eq!(read_all(&mut cow), expected);
+        drop(cow);
+
+        // the base is untouched, and only the 3 modified sectors are recorded
+        assert_eq!(base.contents(), base_image());
+        assert_eq!(delta.contents().len() as u64, HEADER_LEN + 3 * RECORD_LEN);
+
+        // the changes persist across re-opening the delta
+        let mut cow = Cow::new(base.open(), delta.open()).unwrap();
+        assert_eq!(read_all(&mut cow), expected);
+    }
+
+    #[test]
+    fn truncated_record_is_discarded() {
+        let base = TempPath::file("cow-truncated-base", &base_image());
+        let delta = TempPath::file("cow-truncated-delta", &[]);
+
+        let mut cow = Cow::new(base.open(), delta.open()).unwrap();
+        cow.write_all(&[0xaa; 16]).unwrap();
+        cow.flush().unwrap();
+        drop(cow);
+
+        // simulate a record which was only partially written
+        let mut file = delta.open();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(&1u64.to_le_bytes()).unwrap();
+        file.write_all(&[0xcc; 100]).unwrap();
+        drop(file);
+
+        let mut cow = Cow::new(base.open(), delta.open()).unwrap();
+        let mut expected = base_image();
+        expected[..16].fill(0xaa);
+        assert_eq!(read_all(&mut cow), expected);
+        assert_eq!(delta.contents().len() as u64, HEADER_LEN + RECORD_LEN);
+    }
+
+    #[test]
+    fn rejects_mismatched_delta() {
+        let base = TempPath::file("cow-mismatch-base", &base_image());
+        let delta = TempPath::file("cow-mismatch-delta", &[]);
+        drop(Cow::new(base.open(), delta.open()).unwrap());
+
+        let other = TempPath::file("cow-mismatch-other", &[0; 512]);
+        assert!(Cow::new(other.open(), delta.open()).is_err());
+
+        let garbage = TempPath::file("cow-mismatch-garbage", &[0; 64]);
+        assert!(Cow::new(base.open(), garbage.open()).is_err());
+    }
+
+    #[test]
+    fn commit_and_discard() {
+        let base = TempPath::file("cow-commit-base", &base_image());
+        let delta = TempPath::file("cow-commit-delta", &[]);
+
+        let mut cow = Cow::new(base.open(), delta.open()).unwrap();
+        cow.seek(SeekFrom::Start(1000)).unwrap();
+        cow.write_all(&[0xaa; 100]).unwrap();
+        cow.seek(SeekFrom::End(-1)).unwrap();
+        cow.write_all(&[0xbb]).unwrap();
+        cow.commit().unwrap();
+
+        let mut expected = base_image();
+        expected[1000..1100].fill(0xaa);
+        expected[IMAGE_LEN - 1] = 0xbb;
+        assert_eq!(base.contents(), expected);
+        assert_eq!(delta.contents().len() as u64, HEADER_LEN);
+        assert_eq!(read_all(&mut cow), expected);
+
+        cow.seek(SeekFrom::Start(0)).unwrap();
+        cow.write_all(&[0xcc; 10]).unwrap();
+        cow.discard().unwrap();
+        assert_eq!(read_all(&mut cow), expected);
+        assert_eq!(base.contents(), expected);
+        assert_eq!(delta.contents().len() as u64, HEADER_LEN);
+    }
+
+    #[test]
+    fn stacked_overlays() {
+        let base = TempPath::file("cow-stack-base", &base_image());
+        let lower = TempPath::file("cow-stack-lower", &[]);
+        let upper = TempPath::file("cow-stack-upper", &[]);
+
+        let mut cow = Cow::new(base.open(), lower.open()).unwrap();
+        cow.write_all(&[0xaa; 600]).unwrap();
+        cow.flush().unwrap();
+        let lower_delta = lower.contents();
+
+        let mut cow = cow.stack(upper.open()).unwrap();
+        cow.seek(SeekFrom::Start(500)).unwrap();
+        cow.write_all(&[0xbb; 200]).unwrap();
+
+        let mut expected = base_image();
+        expected[..600].fill(0xaa);
+        expected[500..700].fill(0xbb);
+        assert_eq!(read_all(&mut cow), expected);
+        // the lower overlay is read-only until the upper one is committed
+        assert_eq!(lower.contents(), lower_delta);
+
+        // committing merges into the lower overlay, not the base image
+        cow.commit().unwrap();
+        cow.flush().unwrap();
+        assert_eq!(read_all(&mut cow), expected);
+        assert_eq!(base.contents(), base_image());
+        assert_eq!(upper.contents().len() as u64, HEADER_LEN);
+
+        drop(cow);
+        let mut cow = Cow::new(base.open(), lower.open()).unwrap();
+        assert_eq!(read_all(&mut cow), expected);
+    }
+}
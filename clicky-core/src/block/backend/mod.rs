@@ -1,9 +1,15 @@
 //! Block device backends.
 
+mod compressed;
+mod cow;
 mod mem;
 mod null;
 mod raw;
+mod vvfat;
 
+pub use compressed::Compressed;
+pub use cow::Cow;
 pub use mem::Mem;
 pub use null::Null;
 pub use raw::Raw;
+pub use vvfat::Vvfat;
@@ -0,0 +1,1642 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::SystemTime;
+
+use blocking::Unblock;
+use chrono::{DateTime, Datelike, Local, Timelike};
+use futures::io::{AsyncRead, AsyncSeek, AsyncWrite};
+use futures::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::block::{self, BlockDev};
+
+const SECTOR_SIZE: u64 = 512;
+
+// WinPod (i.e: MBR formatted) layout, with the FAT32 partition following the
+// firmware partition
+const DISK_ID: u32 = 0x0420_6969;
+const FW_PART_START: u64 = 2048;
+const FW_PART_MIN_LEN: u64 = 10240;
+/// Partitions are aligned to 1MiB boundaries.
+const PART_ALIGN: u64 = 2048;
+
+const RESERVED_SECTORS: u64 = 32;
+const NUM_FATS: u64 = 2;
+const FSINFO_SECTOR: u64 = 1;
+const BACKUP_BOOT_SECTOR: u64 = 6;
+const ROOT_CLUSTER: u32 = 2;
+const END_OF_CHAIN: u32 = 0x0fff_ffff;
+/// FAT32 volumes must contain at least this many clusters.
+const MIN_CLUSTERS: u64 = 65525;
+
+const VOLUME_LABEL: &[u8; 11] = b"IPOD       ";
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// Number of UTF-16 characters stored in each long file name entry.
+const LFN_CHARS: usize = 13;
+
+/// `a / b`, rounded up.
+fn div_ceil(a: u64, b: u64) -> u64 {
+    (a + b - 1) / b
+}
+
+#[derive(Debug)]
+enum NodeKind {
+    File { len: u32 },
+    Dir { children: Vec<Node> },
+}
+
+/// A file / directory in the host directory tree.
+#[derive(Debug)]
+struct Node {
+    path: PathBuf,
+    short_name: [u8; 11],
+    /// Only present if the name doesn't fit into a 8.3 short name.
+    long_name: Option<Vec<u16>>,
+    mtime: SystemTime,
+    kind: NodeKind,
+    /// First cluster (or 0 for empty files).
+    cluster: u32,
+}
+
+impl Node {
+    /// Number of directory entries required to describe the node.
+    fn num_entries(&self) -> usize {
+        1 + self
+            .long_name
+            .as_ref()
+            .map(|name| (name.len() + LFN_CHARS - 1) / LFN_CHARS)
+            .unwrap_or(0)
+    }
+}
+
+fn short_name_char(c: char) -> Option<u8> {
+    match c {
+        'A'..='Z' | '0'..='9' => Some(c as u8),
+        '!' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' | '-' | '@' | '^' | '_' | '`' | '{'
+        | '}' | '~' => Some(c as u8),
+        _ => None,
+    }
+}
+
+/// Pick a unique 8.3 short name for `name`, along with a long name (if one is
+/// required).
+fn fat_names(name: &str, taken: &mut HashSet<[u8; 11]>) -> ([u8; 11], Option<Vec<u16>>) {
+    let (base, ext) = match name.rfind('.') {
+        Some(i) if i > 0 => (&name[..i], &name[i + 1..]),
+        _ => (name, ""),
+    };
+
+    let make_short = |base: &[u8], ext: &[u8]| {
+        let mut short = [b' '; 11];
+        short[..base.len()].copy_from_slice(base);
+        short[8..8 + ext.len()].copy_from_slice(ext);
+        short
+    };
+
+    // names which are already valid short names don't need a long name
+    let is_short = |s: &str, max| s.len() <= max && s.chars().all(|c| short_name_char(c).is_some());
+    if !base.is_empty() && is_short(base, 8) && is_short(ext, 3) {
+        let short = make_short(base.as_bytes(), ext.as_bytes());
+        if taken.insert(short) {
+            return (short, None);
+        }
+    }
+
+    let convert = |s: &str| -> Vec<u8> {
+        s.chars()
+            .filter(|c| *c != ' ' && *c != '.')
+            .map(|c| short_name_char(c.to_ascii_uppercase()).unwrap_or(b'_'))
+            .collect()
+    };
+    let base_chars = convert(base);
+    let ext_chars = convert(ext);
+    let ext_chars = &ext_chars[..ext_chars.len().min(3)];
+    let long_name = Some(name.encode_utf16().collect());
+
+    // try the upper-cased name as-is before falling back to a numeric tail
+    // (e.g: `LONGFI~1.TXT`)
+    if !base_chars.is_empty() && base_chars.len() <= 8 {
+        let short = make_short(&base_chars, ext_chars);
+        if taken.insert(short) {
+            return (short, long_name);
+        }
+    }
+
+    for n in 1.. {
+        let tail = format!("~{}", n);
+        let mut base = base_chars[..base_chars.len().min(8 - tail.len())].to_vec();
+        base.extend_from_slice(tail.as_bytes());
+        let short = make_short(&base, ext_chars);
+        if taken.insert(short) {
+            return (short, long_name);
+        }
+    }
+    unreachable!()
+}
+
+fn scan_dir(path: &Path) -> io::Result<Vec<Node>> {
+    let mut entries = fs::read_dir(path)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut taken = HashSet::new();
+    let mut nodes = Vec::new();
+    for entry in entries {
+        let path = entry.path();
+        let meta = fs::metadata(&path)?;
+
+        let kind = if meta.is_dir() {
+            // avoid symlink loops
+            if entry.file_type()?.is_symlink() {
+                warn!("vvfat: skipping symlinked directory {}", path.display());
+                continue;
+            }
+            NodeKind::Dir {
+                children: scan_dir(&path)?,
+            }
+        } else if meta.is_file() {
+            if meta.len() > u32::MAX as u64 {
+                warn!("vvfat: skipping {} (too big for FAT32)", path.display());
+                continue;
+            }
+            NodeKind::File {
+                len: meta.len() as u32,
+            }
+        } else {
+            continue;
+        };
+
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let (short_name, long_name) = fat_names(&name, &mut taken);
+        nodes.push(Node {
+            path,
+            short_name,
+            long_name,
+            mtime: meta.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+            kind,
+            cluster: 0,
+        })
+    }
+
+    Ok(nodes)
+}
+
+/// Convert a timestamp into a FAT (date, time) pair.
+fn fat_timestamp(time: SystemTime) -> (u16, u16) {
+    let time = DateTime::<Local>::from(time);
+    if time.year() < 1980 {
+        // 1980-01-01 00:00:00
+        return ((1 << 5) | 1, 0);
+    }
+
+    let date = ((time.year() - 1980).min(127) as u16) << 9
+        | (time.month() as u16) << 5
+        | (time.day() as u16);
+    let time =
+        (time.hour() as u16) << 11 | (time.minute() as u16) << 5 | (time.second() as u16 / 2);
+    (date, time)
+}
+
+fn dir_entry(name: &[u8; 11], attr: u8, cluster: u32, size: u32, mtime: SystemTime) -> [u8; 32] {
+    let (date, time) = fat_timestamp(mtime);
+
+    let mut entry = [0; 32];
+    entry[..11].copy_from_slice(name);
+    entry[11] = attr;
+    entry[14..16].copy_from_slice(&time.to_le_bytes());
+    entry[16..18].copy_from_slice(&date.to_le_bytes());
+    entry[18..20].copy_from_slice(&date.to_le_bytes());
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[22..24].copy_from_slice(&time.to_le_bytes());
+    entry[24..26].copy_from_slice(&date.to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// Checksum of a short name, which ties long file name entries to their
+/// short name entry.
+fn lfn_checksum(short_name: &[u8; 11]) -> u8 {
+    (short_name.iter()).fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c))
+}
+
+/// Long file name entries for `name`, in on-disk order (i.e: last chunk of the
+/// name first).
+fn lfn_entries(name: &[u16], short_name: &[u8; 11]) -> Vec<[u8; 32]> {
+    let checksum = lfn_checksum(short_name);
+
+    let chunks = name.chunks(LFN_CHARS).collect::<Vec<_>>();
+    let mut entries = Vec::new();
+    for (i, chunk) in chunks.iter().enumerate().rev() {
+        // names are null terminated (unless they fill the entire entry), with
+        // any remaining space padded with 0xffff
+        let mut chars = [0xffff; LFN_CHARS];
+        chars[..chunk.len()].copy_from_slice(chunk);
+        if chunk.len() < LFN_CHARS {
+            chars[chunk.len()] = 0;
+        }
+
+        let mut entry = [0; 32];
+        entry[0] = (i + 1) as u8 | if i == chunks.len() - 1 { 0x40 } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+        let offsets = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (offset, c) in offsets.zip(chars.iter()) {
+            entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+        }
+        entries.push(entry);
+    }
+    entries
+}
+
+/// Contents of a contiguous run of clusters.
+#[derive(Debug)]
+enum Extent {
+    Dir(Box<[u8]>),
+    File(PathBuf),
+}
+
+/// Lays out the host directory tree in the FAT32 partition.
+struct Builder {
+    cluster_bytes: u64,
+    next_cluster: u32,
+    fat: Vec<u32>,
+    /// Maps each extent's first cluster to its length (in clusters) and
+    /// contents.
+    extents: BTreeMap<u32, (u32, Extent)>,
+}
+
+impl Builder {
+    /// Allocate a chain of clusters large enough to hold `len` bytes,
+    /// returning the first cluster (or 0 if `len` is 0).
+    fn alloc(&mut self, len: u64) -> io::Result<u32> {
+        let clusters = div_ceil(len, self.cluster_bytes) as u32;
+        if clusters == 0 {
+            return Ok(0);
+        }
+
+        let start = self.next_cluster;
+        let end = start as u64 + clusters as u64;
+        if end > self.fat.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "directory doesn't fit on the disk",
+            ));
+        }
+
+        for cluster in start..end as u32 - 1 {
+            self.fat[cluster as usize] = cluster + 1;
+        }
+        self.fat[end as usize - 1] = END_OF_CHAIN;
+        self.next_cluster = end as u32;
+        Ok(start)
+    }
+
+    fn build_dir(
+        &mut self,
+        children: &mut [Node],
+        cluster: u32,
+        parent: Option<u32>,
+    ) -> io::Result<()> {
+        for child in children.iter_mut() {
+            match &mut child.kind {
+                NodeKind::File { len } => {
+                    child.cluster = self.alloc(*len as u64)?;
+                    if child.cluster != 0 {
+                        let clusters = div_ceil(*len as u64, self.cluster_bytes) as u32;
+                        let extent = Extent::File(child.path.clone());
+                        self.extents.insert(child.cluster, (clusters, extent));
+                    }
+                }
+                NodeKind::Dir { children } => {
+                    let entries = 2 + children.iter().map(Node::num_entries).sum::<usize>();
+                    child.cluster = self.alloc(entries as u64 * 32)?;
+                    self.build_dir(children, child.cluster, Some(cluster))?;
+                }
+            }
+        }
+
+        let mut data = Vec::new();
+        match parent {
+            None => {
+                let now = SystemTime::now();
+                data.extend_from_slice(&dir_entry(VOLUME_LABEL, ATTR_VOLUME_ID, 0, 0, now));
+            }
+            Some(parent) => {
+                // the root directory is always referred to as cluster 0
+                let parent = if parent == ROOT_CLUSTER { 0 } else { parent };
+                let now = SystemTime::now();
+                data.extend_from_slice(&dir_entry(b".          ", ATTR_DIRECTORY, cluster, 0, now));
+                data.extend_from_slice(&dir_entry(b"..         ", ATTR_DIRECTORY, parent, 0, now));
+            }
+        }
+
+        for child in children.iter() {
+            if let Some(long_name) = &child.long_name {
+                for entry in lfn_entries(long_name, &child.short_name) {
+                    data.extend_from_slice(&entry);
+                }
+            }
+            let (attr, size) = match child.kind {
+                NodeKind::File { len } => (ATTR_ARCHIVE, len),
+                NodeKind::Dir { .. } => (ATTR_DIRECTORY, 0),
+            };
+            let entry = dir_entry(&child.short_name, attr, child.cluster, size, child.mtime);
+            data.extend_from_slice(&entry);
+        }
+
+        // unused entries are zeroed (which also marks the end of the directory)
+        let clusters = div_ceil(data.len() as u64, self.cluster_bytes) as u32;
+        data.resize((clusters as u64 * self.cluster_bytes) as usize, 0);
+        (self.extents).insert(cluster, (clusters, Extent::Dir(data.into_boxed_slice())));
+        Ok(())
+    }
+}
+
+/// The synthesized disk.
+struct Disk {
+    len: u64,
+    pos: u64,
+
+    firmware: Box<[u8]>,
+    /// First sector of the FAT32 partition.
+    part_start: u64,
+    part_sectors: u64,
+    sectors_per_cluster: u64,
+    fat_sectors: u64,
+    free_clusters: u32,
+
+    fat: Vec<u32>,
+    extents: BTreeMap<u32, (u32, Extent)>,
+    /// Most recently accessed host file.
+    open_file: Option<(PathBuf, File)>,
+
+    /// Sectors which have been written to.
+    overlay: HashMap<u64, Box<[u8; SECTOR_SIZE as usize]>>,
+}
+
+impl std::fmt::Debug for Disk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Disk")
+            .field("len", &self.len)
+            .field("pos", &self.pos)
+            .field("part_start", &self.part_start)
+            .field("part_sectors", &self.part_sectors)
+            .field("sectors_per_cluster", &self.sectors_per_cluster)
+            .field("fat_sectors", &self.fat_sectors)
+            .field("open_file", &self.open_file)
+            .field("overlay", &self.overlay.len())
+            .finish()
+    }
+}
+
+impl Disk {
+    fn new(dir: Option<&Path>, firmware: Box<[u8]>, len: u64) -> io::Result<Disk> {
+        let total_sectors = len / SECTOR_SIZE;
+        let fw_sectors = FW_PART_MIN_LEN.max(div_ceil(firmware.len() as u64, SECTOR_SIZE));
+        let part_start = div_ceil(FW_PART_START + fw_sectors, PART_ALIGN) * PART_ALIGN;
+        let part_sectors = total_sectors.saturating_sub(part_start);
+
+        // cluster sizes recommended by Microsoft's FAT32 spec
+        let sectors_per_cluster = match part_sectors {
+            0..=532_480 => 1,
+            532_481..=16_777_216 => 8,
+            16_777_217..=33_554_432 => 16,
+            33_554_433..=67_108_864 => 32,
+            _ => 64,
+        };
+        let fat_sectors = div_ceil(
+            part_sectors.saturating_sub(RESERVED_SECTORS),
+            (256 * sectors_per_cluster + NUM_FATS) / 2,
+        );
+        let data_sectors = part_sectors.saturating_sub(RESERVED_SECTORS + NUM_FATS * fat_sectors);
+        let num_clusters = data_sectors / sectors_per_cluster;
+        if num_clusters < MIN_CLUSTERS {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "disk is too small to fit a FAT32 partition",
+            ));
+        }
+
+        let mut fat = vec![0; num_clusters as usize + 2];
+        fat[0] = 0x0fff_fff8;
+        fat[1] = END_OF_CHAIN;
+
+        let mut children = match dir {
+            Some(dir) => scan_dir(dir)?,
+            None => Vec::new(),
+        };
+        let mut builder = Builder {
+            cluster_bytes: sectors_per_cluster * SECTOR_SIZE,
+            next_cluster: ROOT_CLUSTER,
+            fat,
+            extents: BTreeMap::new(),
+        };
+        let root_entries = 1 + children.iter().map(Node::num_entries).sum::<usize>();
+        let root = builder.alloc(root_entries as u64 * 32)?;
+        builder.build_dir(&mut children, root, None)?;
+
+        Ok(Disk {
+            len,
+            pos: 0,
+
+            firmware,
+            part_start,
+            part_sectors,
+            sectors_per_cluster,
+            fat_sectors,
+            free_clusters: (builder.fat.len() as u32) - builder.next_cluster,
+
+            fat: builder.fat,
+            extents: builder.extents,
+            open_file: None,
+
+            overlay: HashMap::new(),
+        })
+    }
+
+    fn mbr(&self) -> [u8; SECTOR_SIZE as usize] {
+        let mut mbr = [0; SECTOR_SIZE as usize];
+        mbr[440..444].copy_from_slice(&DISK_ID.to_le_bytes());
+
+        let partitions = [
+            // the firmware partition has a type of 0, and is marked as bootable
+            (0x80, 0x00, FW_PART_START, self.part_start - FW_PART_START),
+            (0x00, 0x0b, self.part_start, self.part_sectors),
+        ];
+        for (i, &(status, kind, start, len)) in partitions.iter().enumerate() {
+            let entry = &mut mbr[446 + i * 16..][..16];
+            entry[0] = status;
+            // CHS addressing isn't supported
+            entry[1..4].copy_from_slice(&[0xfe, 0xff, 0xff]);
+            entry[4] = kind;
+            entry[5..8].copy_from_slice(&[0xfe, 0xff, 0xff]);
+            entry[8..12].copy_from_slice(&(start as u32).to_le_bytes());
+            entry[12..16].copy_from_slice(&(len as u32).to_le_bytes());
+        }
+
+        mbr[510] = 0x55;
+        mbr[511] = 0xaa;
+        mbr
+    }
+
+    fn boot_sector(&self) -> [u8; SECTOR_SIZE as usize] {
+        let mut bs = [0; SECTOR_SIZE as usize];
+        bs[0..3].copy_from_slice(&[0xeb, 0x58, 0x90]);
+        bs[3..11].copy_from_slice(b"CLICKY  ");
+        bs[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+        bs[13] = self.sectors_per_cluster as u8;
+        bs[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        bs[16] = NUM_FATS as u8;
+        bs[21] = 0xf8; // media descriptor: fixed disk
+        bs[24..26].copy_from_slice(&63u16.to_le_bytes()); // sectors per track
+        bs[26..28].copy_from_slice(&255u16.to_le_bytes()); // heads
+        bs[28..32].copy_from_slice(&(self.part_start as u32).to_le_bytes());
+        bs[32..36].copy_from_slice(&(self.part_sectors as u32).to_le_bytes());
+        bs[36..40].copy_from_slice(&(self.fat_sectors as u32).to_le_bytes());
+        bs[44..48].copy_from_slice(&ROOT_CLUSTER.to_le_bytes());
+        bs[48..50].copy_from_slice(&(FSINFO_SECTOR as u16).to_le_bytes());
+        bs[50..52].copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+        bs[64] = 0x80; // drive number
+        bs[66] = 0x29; // extended boot signature
+        bs[67..71].copy_from_slice(&DISK_ID.to_le_bytes());
+        bs[71..82].copy_from_slice(VOLUME_LABEL);
+        bs[82..90].copy_from_slice(b"FAT32   ");
+        bs[510] = 0x55;
+        bs[511] = 0xaa;
+        bs
+    }
+
+    fn fsinfo(&self) -> [u8; SECTOR_SIZE as usize] {
+        let mut fsinfo = [0; SECTOR_SIZE as usize];
+        fsinfo[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+        fsinfo[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+        fsinfo[488..492].copy_from_slice(&self.free_clusters.to_le_bytes());
+        let next_free = self.fat.len() as u32 - self.free_clusters;
+        fsinfo[492..496].copy_from_slice(&next_free.to_le_bytes());
+        fsinfo[508..512].copy_from_slice(&0xaa55_0000u32.to_le_bytes());
+        fsinfo
+    }
+
+    /// Read part of a data cluster.
+    fn read_cluster(&mut self, cluster: u32, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let (start, extent) = match self.extents.range(..=cluster).next_back() {
+            Some((&start, (len, extent))) if cluster < start + len => (start, extent),
+            // unallocated
+            _ => return Ok(()),
+        };
+        let offset = (cluster - start) as u64 * self.sectors_per_cluster * SECTOR_SIZE + offset;
+
+        match extent {
+            Extent::Dir(data) => {
+                buf.copy_from_slice(&data[offset as usize..][..buf.len()]);
+            }
+            Extent::File(path) => {
+                let file = match &mut self.open_file {
+                    Some((open_path, file)) if open_path == path => file,
+                    open_file => &mut open_file.insert((path.clone(), File::open(path)?)).1,
+                };
+
+                // the file may have been truncated since the directory was
+                // scanned, in which case the remaining data reads as zeros
+                file.seek(SeekFrom::Start(offset))?;
+                let mut done = 0;
+                while done < buf.len() {
+                    match file.read(&mut buf[done..])? {
+                        0 => break,
+                        n => done += n,
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_fat_part_sector(&mut self, sector: u64) -> io::Result<[u8; SECTOR_SIZE as usize]> {
+        let fat_start = RESERVED_SECTORS;
+        let data_start = fat_start + NUM_FATS * self.fat_sectors;
+
+        let mut buf = [0; SECTOR_SIZE as usize];
+        match sector {
+            0 | BACKUP_BOOT_SECTOR => buf = self.boot_sector(),
+            FSINFO_SECTOR => buf = self.fsinfo(),
+            _ if sector < fat_start => {}
+            _ if sector < data_start => {
+                let entries_per_sector = SECTOR_SIZE as usize / 4;
+                let first = ((sector - fat_start) % self.fat_sectors) as usize * entries_per_sector;
+                for (i, chunk) in buf.chunks_exact_mut(4).enumerate() {
+                    let entry = self.fat.get(first + i).copied().unwrap_or(0);
+                    chunk.copy_from_slice(&entry.to_le_bytes());
+                }
+            }
+            _ => {
+                let sector = sector - data_start;
+                let cluster = ROOT_CLUSTER + (sector / self.sectors_per_cluster) as u32;
+                let offset = (sector % self.sectors_per_cluster) * SECTOR_SIZE;
+                self.read_cluster(cluster, offset, &mut buf)?;
+            }
+        }
+        Ok(buf)
+    }
+
+    fn read_sector(&mut self, sector: u64) -> io::Result<[u8; SECTOR_SIZE as usize]> {
+        if let Some(data) = self.overlay.get(&sector) {
+            return Ok(**data);
+        }
+
+        let mut buf = [0; SECTOR_SIZE as usize];
+        if sector == 0 {
+            buf = self.mbr();
+        } else if (FW_PART_START..self.part_start).contains(&sector) {
+            let offset = ((sector - FW_PART_START) * SECTOR_SIZE) as usize;
+            if offset < self.firmware.len() {
+                let data = &self.firmware[offset..];
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+            }
+        } else if sector >= self.part_start {
+            buf = self.read_fat_part_sector(sector - self.part_start)?;
+        }
+        Ok(buf)
+    }
+}
+
+impl Read for Disk {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+
+        let mut done = 0;
+        while done < n {
+            let sector = self.pos / SECTOR_SIZE;
+            let offset = (self.pos % SECTOR_SIZE) as usize;
+            let chunk = (SECTOR_SIZE as usize - offset).min(n - done);
+
+            let data = self.read_sector(sector)?;
+            buf[done..done + chunk].copy_from_slice(&data[offset..offset + chunk]);
+
+            done += chunk;
+            self.pos += chunk as u64;
+        }
+
+        Ok(n)
+    }
+}
+
+impl Write for Disk {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+
+        let mut done = 0;
+        while done < n {
+            let sector = self.pos / SECTOR_SIZE;
+            let offset = (self.pos % SECTOR_SIZE) as usize;
+            let chunk = (SECTOR_SIZE as usize - offset).min(n - done);
+
+            let mut data = self.read_sector(sector)?;
+            data[offset..offset + chunk].copy_from_slice(&buf[done..done + chunk]);
+            self.overlay.insert(sector, Box::new(data));
+
+            done += chunk;
+            self.pos += chunk as u64;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for Disk {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = block::seek_pos(self.pos, self.len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Find the first sector of the FAT32 partition in an existing disk image
+/// (using either an MBR or an Apple partition map).
+fn find_fat32_partition(img: &mut (impl Read + Seek)) -> io::Result<u64> {
+    let mut block = [0; SECTOR_SIZE as usize];
+    img.seek(SeekFrom::Start(0))?;
+    img.read_exact(&mut block)?;
+
+    if &block[0..2] == b"ER" {
+        if u16::from_be_bytes([block[2], block[3]]) as u64 != SECTOR_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported Apple partition map block size",
+            ));
+        }
+
+        let mut i = 1;
+        let mut num_entries = 1;
+        while i <= num_entries {
+            img.seek(SeekFrom::Start(i * SECTOR_SIZE))?;
+            img.read_exact(&mut block)?;
+            if &block[0..2] != b"PM" {
+                break;
+            }
+            let be_u32 =
+                |i: usize| u32::from_be_bytes([block[i], block[i + 1], block[i + 2], block[i + 3]]);
+            num_entries = be_u32(4) as u64;
+            if block[48..].starts_with(b"DOS_FAT_32\0") {
+                return Ok(be_u32(8) as u64);
+            }
+            i += 1;
+        }
+    } else if block[510..512] == [0x55, 0xaa] {
+        for entry in block[446..510].chunks_exact(16) {
+            if let 0x0b | 0x0c = entry[4] {
+                return Ok(u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64);
+            }
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "could not find a FAT32 partition",
+    ))
+}
+
+/// A directory entry in an existing FAT32 partition.
+struct ExistingEntry {
+    /// Long name (if present), otherwise the short name in `NAME.EXT` form.
+    name: String,
+    /// Offset of the short name entry within the directory.
+    offset: usize,
+    is_dir: bool,
+    cluster: u32,
+}
+
+/// Parse the directory entries in `data`, returning the entries along with
+/// the offset at which new entries can be appended.
+fn parse_dir(data: &[u8]) -> (Vec<ExistingEntry>, usize) {
+    let mut entries = Vec::new();
+    let mut long_name: Vec<u16> = Vec::new();
+    let mut long_checksum = None;
+
+    for (i, raw) in data.chunks_exact(32).enumerate() {
+        if raw[0] == 0 {
+            return (entries, i * 32);
+        }
+        if raw[0] == 0xe5 {
+            // deleted
+            long_name.clear();
+            continue;
+        }
+        if raw[11] == ATTR_LONG_NAME {
+            if raw[0] & 0x40 != 0 {
+                long_name.clear();
+            }
+            let chars = [&raw[1..11], &raw[14..26], &raw[28..32]].concat();
+            let chars = chars
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]));
+            // entries are stored last chunk first
+            long_name.splice(0..0, chars);
+            long_checksum = Some(raw[13]);
+            continue;
+        }
+
+        let mut short_name = [0; 11];
+        short_name.copy_from_slice(&raw[..11]);
+        let name = match long_checksum.take() {
+            Some(checksum) if !long_name.is_empty() && checksum == lfn_checksum(&short_name) => {
+                let len = (long_name.iter().position(|&c| c == 0)).unwrap_or(long_name.len());
+                String::from_utf16_lossy(&long_name[..len])
+            }
+            _ => {
+                let base = String::from_utf8_lossy(&short_name[..8]);
+                let ext = String::from_utf8_lossy(&short_name[8..]);
+                match ext.trim_end() {
+                    "" => base.trim_end().to_string(),
+                    ext => format!("{}.{}", base.trim_end(), ext),
+                }
+            }
+        };
+        long_name.clear();
+
+        if raw[11] & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+        entries.push(ExistingEntry {
+            name,
+            offset: i * 32,
+            is_dir: raw[11] & ATTR_DIRECTORY != 0,
+            cluster: (u16::from_le_bytes([raw[20], raw[21]]) as u32) << 16
+                | u16::from_le_bytes([raw[26], raw[27]]) as u32,
+        });
+    }
+
+    (entries, data.len())
+}
+
+/// An existing FAT32 partition, which host files are copied into.
+struct Volume<'a, F> {
+    img: &'a mut F,
+    part_start: u64,
+    reserved_sectors: u64,
+    num_fats: u64,
+    fat_sectors: u64,
+    sectors_per_cluster: u64,
+    fsinfo_sector: u64,
+    root_cluster: u32,
+    /// Number of data clusters (i.e: excluding the two reserved FAT entries).
+    num_clusters: u32,
+    fat: Vec<u32>,
+    /// Where to start searching for free clusters.
+    next_free: u32,
+}
+
+impl<'a, F: Read + Write + Seek> Volume<'a, F> {
+    fn open(img: &'a mut F) -> io::Result<Volume<'a, F>> {
+        let part_start = find_fat32_partition(img)?;
+
+        let mut bs = [0; SECTOR_SIZE as usize];
+        img.seek(SeekFrom::Start(part_start * SECTOR_SIZE))?;
+        img.read_exact(&mut bs)?;
+        let le_u16 = |i: usize| u16::from_le_bytes([bs[i], bs[i + 1]]) as u64;
+        let le_u32 = |i: usize| u32::from_le_bytes([bs[i], bs[i + 1], bs[i + 2], bs[i + 3]]);
+
+        if bs[510..512] != [0x55, 0xaa] || &bs[82..90] != b"FAT32   " {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data partition isn't formatted as FAT32",
+            ));
+        }
+        if le_u16(11) != SECTOR_SIZE || bs[13] == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unsupported FAT32 sector / cluster size",
+            ));
+        }
+
+        let sectors_per_cluster = bs[13] as u64;
+        let reserved_sectors = le_u16(14);
+        let num_fats = bs[16] as u64;
+        let fat_sectors = le_u32(36) as u64;
+        let data_sectors =
+            (le_u32(32) as u64).saturating_sub(reserved_sectors + num_fats * fat_sectors);
+        let num_clusters = (data_sectors / sectors_per_cluster).min(fat_sectors * 128 - 2);
+
+        let mut raw_fat = vec![0; (fat_sectors * SECTOR_SIZE) as usize];
+        img.seek(SeekFrom::Start(
+            (part_start + reserved_sectors) * SECTOR_SIZE,
+        ))?;
+        img.read_exact(&mut raw_fat)?;
+        let fat = (raw_fat.chunks_exact(4))
+            .map(|e| u32::from_le_bytes([e[0], e[1], e[2], e[3]]) & 0x0fff_ffff)
+            .collect();
+
+        Ok(Volume {
+            img,
+            part_start,
+            reserved_sectors,
+            num_fats,
+            fat_sectors,
+            sectors_per_cluster,
+            fsinfo_sector: le_u16(48),
+            root_cluster: le_u32(44),
+            num_clusters: num_clusters as u32,
+            fat,
+            next_free: ROOT_CLUSTER,
+        })
+    }
+
+    fn cluster_bytes(&self) -> u64 {
+        self.sectors_per_cluster * SECTOR_SIZE
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        let data_start = self.part_start + self.reserved_sectors + self.num_fats * self.fat_sectors;
+        (data_start + (cluster - ROOT_CLUSTER) as u64 * self.sectors_per_cluster) * SECTOR_SIZE
+    }
+
+    fn is_data_cluster(&self, cluster: u32) -> bool {
+        (ROOT_CLUSTER..self.num_clusters + 2).contains(&cluster)
+    }
+
+    fn chain(&self, mut cluster: u32) -> io::Result<Vec<u32>> {
+        let mut chain = Vec::new();
+        while cluster < 0x0fff_fff8 {
+            if !self.is_data_cluster(cluster) || chain.len() > self.num_clusters as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "corrupt FAT cluster chain",
+                ));
+            }
+            chain.push(cluster);
+            cluster = self.fat[cluster as usize];
+        }
+        Ok(chain)
+    }
+
+    /// Allocate (and link together) `count` free clusters.
+    fn alloc(&mut self, count: u64) -> io::Result<Vec<u32>> {
+        let mut clusters = Vec::new();
+        let mut cluster = self.next_free;
+        for _ in 0..self.num_clusters {
+            if clusters.len() as u64 == count {
+                break;
+            }
+            if !self.is_data_cluster(cluster) {
+                cluster = ROOT_CLUSTER;
+            }
+            if self.fat[cluster as usize] == 0 {
+                clusters.push(cluster);
+            }
+            cluster += 1;
+        }
+        if (clusters.len() as u64) < count {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "not enough free space on the disk",
+            ));
+        }
+
+        for pair in clusters.windows(2) {
+            self.fat[pair[0] as usize] = pair[1];
+        }
+        if let Some(&last) = clusters.last() {
+            self.fat[last as usize] = END_OF_CHAIN;
+        }
+        self.next_free = cluster;
+        Ok(clusters)
+    }
+
+    fn free(&mut self, cluster: u32) -> io::Result<()> {
+        for cluster in self.chain(cluster)? {
+            self.fat[cluster as usize] = 0;
+        }
+        Ok(())
+    }
+
+    fn read_chain(&mut self, chain: &[u32]) -> io::Result<Vec<u8>> {
+        let mut data = vec![0; chain.len() * self.cluster_bytes() as usize];
+        for (&cluster, buf) in chain
+            .iter()
+            .zip(data.chunks_exact_mut(self.cluster_bytes() as usize))
+        {
+            self.img
+                .seek(SeekFrom::Start(self.cluster_offset(cluster)))?;
+            self.img.read_exact(buf)?;
+        }
+        Ok(data)
+    }
+
+    fn write_chain(&mut self, chain: &[u32], data: &[u8]) -> io::Result<()> {
+        for (&cluster, buf) in chain.iter().zip(data.chunks(self.cluster_bytes() as usize)) {
+            self.img
+                .seek(SeekFrom::Start(self.cluster_offset(cluster)))?;
+            self.img.write_all(buf)?;
+        }
+        Ok(())
+    }
+
+    /// Copy a host file into newly allocated clusters, returning the first
+    /// cluster (or 0 if the file is empty).
+    fn write_file(&mut self, path: &Path, len: u32) -> io::Result<u32> {
+        let chain = self.alloc(div_ceil(len as u64, self.cluster_bytes()))?;
+        let mut file = File::open(path)?;
+        let mut buf = vec![0; self.cluster_bytes() as usize];
+        for &cluster in &chain {
+            // the file may have been truncated since it was scanned, in which
+            // case the remaining data is zero-filled
+            buf.iter_mut().for_each(|b| *b = 0);
+            let mut done = 0;
+            while done < buf.len() {
+                match file.read(&mut buf[done..])? {
+                    0 => break,
+                    n => done += n,
+                }
+            }
+            self.img
+                .seek(SeekFrom::Start(self.cluster_offset(cluster)))?;
+            self.img.write_all(&buf)?;
+        }
+        Ok(chain.first().copied().unwrap_or(0))
+    }
+
+    /// Copy `nodes` into the directory starting at `cluster`, replacing any
+    /// existing files with the same name.
+    fn add_nodes(&mut self, cluster: u32, nodes: &[Node]) -> io::Result<()> {
+        let mut chain = self.chain(cluster)?;
+        let mut data = self.read_chain(&chain)?;
+        let (existing, end) = parse_dir(&data);
+
+        let mut taken = (existing.iter())
+            .map(|e| {
+                let mut short_name = [0; 11];
+                short_name.copy_from_slice(&data[e.offset..e.offset + 11]);
+                short_name
+            })
+            .collect::<HashSet<_>>();
+
+        let mut new_entries = Vec::new();
+        for node in nodes {
+            let name = node.path.file_name().unwrap().to_string_lossy();
+            let existing = (existing.iter()).find(|e| e.name.eq_ignore_ascii_case(&name));
+
+            match (&node.kind, existing) {
+                (NodeKind::Dir { children }, Some(e)) if e.is_dir => {
+                    self.add_nodes(e.cluster, children)?;
+                }
+                (NodeKind::File { len }, Some(e)) if !e.is_dir => {
+                    if e.cluster != 0 {
+                        self.free(e.cluster)?;
+                    }
+                    let file_cluster = self.write_file(&node.path, *len)?;
+                    let mut short_name = [0; 11];
+                    short_name.copy_from_slice(&data[e.offset..e.offset + 11]);
+                    let entry =
+                        dir_entry(&short_name, ATTR_ARCHIVE, file_cluster, *len, node.mtime);
+                    data[e.offset..e.offset + 32].copy_from_slice(&entry);
+                }
+                (_, Some(_)) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!(
+                            "{} conflicts with an existing file / directory",
+                            node.path.display()
+                        ),
+                    ))
+                }
+                (kind, None) => {
+                    let (short_name, long_name) = fat_names(&name, &mut taken);
+                    let (attr, child_cluster, size) = match kind {
+                        NodeKind::File { len } => {
+                            (ATTR_ARCHIVE, self.write_file(&node.path, *len)?, *len)
+                        }
+                        NodeKind::Dir { children } => {
+                            let child_cluster = self.alloc(1)?[0];
+                            // the root directory is always referred to as cluster 0
+                            let parent = if cluster == self.root_cluster {
+                                0
+                            } else {
+                                cluster
+                            };
+                            let now = SystemTime::now();
+                            let mut dir = vec![0; self.cluster_bytes() as usize];
+                            dir[..32].copy_from_slice(&dir_entry(
+                                b".          ",
+                                ATTR_DIRECTORY,
+                                child_cluster,
+                                0,
+                                now,
+                            ));
+                            dir[32..64].copy_from_slice(&dir_entry(
+                                b"..         ",
+                                ATTR_DIRECTORY,
+                                parent,
+                                0,
+                                now,
+                            ));
+                            self.write_chain(&[child_cluster], &dir)?;
+                            self.add_nodes(child_cluster, children)?;
+                            (ATTR_DIRECTORY, child_cluster, 0)
+                        }
+                    };
+
+                    if let Some(long_name) = &long_name {
+                        for entry in lfn_entries(long_name, &short_name) {
+                            new_entries.extend_from_slice(&entry);
+                        }
+                    }
+                    let entry = dir_entry(&short_name, attr, child_cluster, size, node.mtime);
+                    new_entries.extend_from_slice(&entry);
+                }
+            }
+        }
+
+        // new entries are appended after the last entry, growing the directory
+        // if required (with the remaining space zeroed)
+        data.truncate(end);
+        data.extend_from_slice(&new_entries);
+        let clusters = div_ceil(data.len() as u64, self.cluster_bytes()).max(1);
+        if clusters > chain.len() as u64 {
+            let extra = self.alloc(clusters - chain.len() as u64)?;
+            self.fat[*chain.last().unwrap() as usize] = extra[0];
+            chain.extend(extra);
+        }
+        data.resize(chain.len() * self.cluster_bytes() as usize, 0);
+        self.write_chain(&chain, &data)
+    }
+
+    /// Write back the FATs and FSInfo sector.
+    fn flush(&mut self) -> io::Result<()> {
+        let raw_fat = (self.fat.iter())
+            .flat_map(|e| e.to_le_bytes())
+            .collect::<Vec<_>>();
+        for i in 0..self.num_fats {
+            let sector = self.part_start + self.reserved_sectors + i * self.fat_sectors;
+            self.img.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+            self.img.write_all(&raw_fat)?;
+        }
+
+        let fsinfo_offset = (self.part_start + self.fsinfo_sector) * SECTOR_SIZE;
+        let mut fsinfo = [0; SECTOR_SIZE as usize];
+        self.img.seek(SeekFrom::Start(fsinfo_offset))?;
+        self.img.read_exact(&mut fsinfo)?;
+        if fsinfo[0..4] == 0x4161_5252u32.to_le_bytes() {
+            let free = (self.fat[2..self.num_clusters as usize + 2].iter())
+                .filter(|e| **e == 0)
+                .count() as u32;
+            fsinfo[488..492].copy_from_slice(&free.to_le_bytes());
+            fsinfo[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+            self.img.seek(SeekFrom::Start(fsinfo_offset))?;
+            self.img.write_all(&fsinfo)?;
+        }
+
+        self.img.flush()
+    }
+}
+
+/// Block device which presents a directory on the host as an iPod-formatted
+/// disk (a la QEMU's `vvfat`): a firmware partition, followed by a FAT32 data
+/// partition which is synthesized from the directory's contents.
+///
+/// The directory is scanned up-front, after which file contents are read from
+/// the host on-demand. Writes are kept in memory, and are _not_ synced back
+/// to the host directory.
+#[derive(Debug)]
+pub struct Vvfat {
+    len: u64,
+    inner: Unblock<Disk>,
+}
+
+impl Vvfat {
+    /// Synthesize a `len` byte disk from the contents of `dir`, with the
+    /// provided firmware image placed in the firmware partition.
+    pub fn new(dir: impl AsRef<Path>, firmware: Option<Box<[u8]>>, len: u64) -> io::Result<Vvfat> {
+        Vvfat::new_inner(Some(dir.as_ref()), firmware, len)
+    }
+
+    /// Synthesize a `len` byte disk with an empty data partition.
+    pub fn empty(firmware: Option<Box<[u8]>>, len: u64) -> io::Result<Vvfat> {
+        Vvfat::new_inner(None, firmware, len)
+    }
+
+    /// Copy the contents of `dir` into the FAT32 data partition of an
+    /// existing iPod disk image (e.g: one built from a `Vvfat` disk). Existing
+    /// files with the same name are replaced.
+    pub fn add_files(
+        img: &mut (impl Read + Write + Seek),
+        dir: impl AsRef<Path>,
+    ) -> io::Result<()> {
+        let nodes = scan_dir(dir.as_ref())?;
+        let mut volume = Volume::open(img)?;
+        let root = volume.root_cluster;
+        // write back the FAT even if the copy fails part-way through, so that
+        // any clusters which were written to are accounted for
+        let res = volume.add_nodes(root, &nodes);
+        volume.flush()?;
+        res
+    }
+
+    fn new_inner(dir: Option<&Path>, firmware: Option<Box<[u8]>>, len: u64) -> io::Result<Vvfat> {
+        let firmware = firmware.unwrap_or_default();
+        let disk = Disk::new(dir, firmware, len)?;
+        Ok(Vvfat {
+            len,
+            inner: Unblock::new(disk),
+        })
+    }
+}
+
+impl BlockDev for Vvfat {
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+
+impl Read for Vvfat {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        futures_executor::block_on(async { AsyncReadExt::read(self, buf).await })
+    }
+}
+
+impl Write for Vvfat {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        futures_executor::block_on(async { AsyncWriteExt::write(self, buf).await })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        futures_executor::block_on(async { AsyncWriteExt::flush(self).await })
+    }
+}
+
+impl Seek for Vvfat {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        futures_executor::block_on(async { AsyncSeekExt::seek(self, pos).await })
+    }
+}
+
+impl AsyncRead for Vvfat {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncRead::poll_read(Pin::new(&mut self.inner), cx, buf)
+    }
+}
+
+impl AsyncWrite for Vvfat {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncWrite::poll_write(Pin::new(&mut self.inner), cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_flush(Pin::new(&mut self.inner), cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        AsyncWrite::poll_close(Pin::new(&mut self.inner), cx)
+    }
+}
+
+impl AsyncSeek for Vvfat {
+    fn poll_seek(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        pos: SeekFrom,
+    ) -> Poll<io::Result<u64>> {
+        AsyncSeek::poll_seek(Pin::new(&mut self.inner), cx, pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        fat_names, lfn_entries, Disk, Vvfat, ATTR_ARCHIVE, ATTR_DIRECTORY, ATTR_LONG_NAME,
+    };
+    use super::{ATTR_VOLUME_ID, END_OF_CHAIN, FW_PART_START, SECTOR_SIZE, VOLUME_LABEL};
+
+    use std::collections::HashSet;
+    use std::fs;
+    use std::io::{Read, Seek, SeekFrom};
+
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use crate::testutil::TempPath;
+
+    const DISK_LEN: u64 = 64 * 1024 * 1024;
+
+    fn utf16(s: &str) -> Vec<u16> {
+        s.encode_utf16().collect()
+    }
+
+    #[test]
+    fn short_names() {
+        let mut taken = HashSet::new();
+
+        // valid 8.3 names are used as-is
+        assert_eq!(fat_names("README.TXT", &mut taken), (*b"README  TXT", None));
+        assert_eq!(fat_names("NOEXT", &mut taken), (*b"NOEXT      ", None));
+
+        // anything else gets a long name
+        assert_eq!(
+            fat_names("readme.md", &mut taken),
+            (*b"README  MD ", Some(utf16("readme.md")))
+        );
+        assert_eq!(
+            fat_names("a long name.jpeg", &mut taken),
+            (*b"ALONGN~1JPE", Some(utf16("a long name.jpeg")))
+        );
+        assert_eq!(
+            fat_names("a+b.txt", &mut taken),
+            (*b"A_B     TXT", Some(utf16("a+b.txt")))
+        );
+
+        // collisions fall back to a numeric tail
+        assert_eq!(fat_names("readme.txt", &mut taken).0, *b"README~1TXT");
+        assert_eq!(fat_names("Readme.txt", &mut taken).0, *b"README~2TXT");
+        // (only the first 3 chars of the extension are kept)
+        assert_eq!(fat_names("a long name.jpe", &mut taken).0, *b"ALONGN~2JPE");
+    }
+
+    #[test]
+    fn lfn_layout() {
+        let short = *b"ALONGN~1JPE";
+        let checksum = short
+            .iter()
+            .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c));
+
+        // 16 chars: one full entry, plus one with 3 chars + terminator + padding
+        let entries = lfn_entries(&utf16("a long name.jpeg"), &short);
+        assert_eq!(entries.len(), 2);
+
+        // the last chunk of the name comes first, and is flagged as such
+        assert_eq!(entries[0][0], 0x42);
+        assert_eq!(entries[1][0], 0x01);
+        for entry in &entries {
+            assert_eq!(entry[11], ATTR_LONG_NAME);
+            assert_eq!(entry[12], 0);
+            assert_eq!(entry[13], checksum);
+            assert_eq!(&entry[26..28], &[0, 0]);
+        }
+
+        let chars = |entry: &[u8; 32]| {
+            [&entry[1..11], &entry[14..26], &entry[28..32]]
+                .concat()
+                .chunks_exact(2)
+                .map(LittleEndian::read_u16)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(chars(&entries[1]), utf16("a long name.j"));
+        let mut tail = utf16("peg");
+        tail.push(0);
+        tail.resize(13, 0xffff);
+        assert_eq!(chars(&entries[0]), tail);
+
+        // names which exactly fill their entries aren't null terminated
+        let entries = lfn_entries(&utf16("thirteen.char"), b"THIRTE~1CHA");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0][0], 0x41);
+        assert_eq!(chars(&entries[0]), utf16("thirteen.char"));
+    }
+
+    /// A directory entry, along with its (reassembled) long name.
+    #[derive(Debug)]
+    struct Entry {
+        long_name: Option<String>,
+        short_name: [u8; 11],
+        attr: u8,
+        cluster: u32,
+        size: u32,
+    }
+
+    /// Minimal FAT32 reader, used to check the synthesized partition's
+    /// layout.
+    struct Fat32 {
+        disk: Disk,
+        part_start: u64,
+        sectors_per_cluster: u64,
+        fat_start: u64,
+        data_start: u64,
+        root_cluster: u32,
+    }
+
+    impl Fat32 {
+        fn new(mut disk: Disk) -> Fat32 {
+            let mbr = read_sector(&mut disk, 0);
+            assert_eq!(&mbr[510..], &[0x55, 0xaa]);
+            // firmware partition, followed by the FAT32 (LBA) partition
+            assert_eq!(mbr[446 + 4], 0x00);
+            assert_eq!(
+                LittleEndian::read_u32(&mbr[446 + 8..]) as u64,
+                FW_PART_START
+            );
+            assert_eq!(mbr[462 + 4], 0x0b);
+            let part_start = LittleEndian::read_u32(&mbr[462 + 8..]) as u64;
+            assert_eq!(part_start % 2048, 0);
+
+            let bs = read_sector(&mut disk, part_start);
+            assert_eq!(&bs[510..], &[0x55, 0xaa]);
+            assert_eq!(&bs[82..90], b"FAT32   ");
+            assert_eq!(&bs[71..82], VOLUME_LABEL);
+            assert_eq!(LittleEndian::read_u16(&bs[11..]) as u64, SECTOR_SIZE);
+            // the backup boot sector is an exact copy
+            assert_eq!(read_sector(&mut disk, part_start + 6), bs);
+            let fsinfo = read_sector(&mut disk, part_start + 1);
+            assert_eq!(&fsinfo[..4], b"RRaA");
+            assert_eq!(&fsinfo[484..488], b"rrAa");
+
+            let sectors_per_cluster = bs[13] as u64;
+            let reserved = LittleEndian::read_u16(&bs[14..]) as u64;
+            let num_fats = bs[16] as u64;
+            let fat_sectors = LittleEndian::read_u32(&bs[36..]) as u64;
+            let root_cluster = LittleEndian::read_u32(&bs[44..]);
+
+            let fat_start = part_start + reserved;
+            // both FATs are identical
+            for i in 0..fat_sectors.min(4) {
+                assert_eq!(
+                    read_sector(&mut disk, fat_start + i),
+                    read_sector(&mut disk, fat_start + fat_sectors + i)
+                );
+            }
+
+            Fat32 {
+                disk,
+                part_start,
+                sectors_per_cluster,
+                fat_start,
+                data_start: fat_start + num_fats * fat_sectors,
+                root_cluster,
+            }
+        }
+
+        fn fat_entry(&mut self, cluster: u32) -> u32 {
+            let offset = cluster as u64 * 4;
+            let sector = read_sector(&mut self.disk, self.fat_start + offset / SECTOR_SIZE);
+            LittleEndian::read_u32(&sector[(offset % SECTOR_SIZE) as usize..]) & 0x0fff_ffff
+        }
+
+        fn chain(&mut self, mut cluster: u32) -> Vec<u32> {
+            let mut chain = Vec::new();
+            while cluster != END_OF_CHAIN {
+                assert!(cluster >= 2, "bad cluster in chain: {:#x}", cluster);
+                chain.push(cluster);
+                cluster = self.fat_entry(cluster);
+            }
+            chain
+        }
+
+        fn read_chain(&mut self, cluster: u32) -> Vec<u8> {
+            let mut data = Vec::new();
+            for cluster in self.chain(cluster) {
+                let first = self.data_start + (cluster - 2) as u64 * self.sectors_per_cluster;
+                for sector in first..first + self.sectors_per_cluster {
+                    data.extend_from_slice(&read_sector(&mut self.disk, sector));
+                }
+            }
+            data
+        }
+
+        fn read_file(&mut self, entry: &Entry) -> Vec<u8> {
+            let mut data = self.read_chain(entry.cluster);
+            data.truncate(entry.size as usize);
+            data
+        }
+
+        fn read_dir(&mut self, cluster: u32) -> Vec<Entry> {
+            let data = self.read_chain(cluster);
+
+            let mut entries = Vec::new();
+            let mut lfn: Vec<[u8; 32]> = Vec::new();
+            for raw in data.chunks_exact(32) {
+                if raw[0] == 0 {
+                    break;
+                }
+                let mut raw_entry = [0; 32];
+                raw_entry.copy_from_slice(raw);
+                if raw[11] == ATTR_LONG_NAME {
+                    lfn.push(raw_entry);
+                    continue;
+                }
+
+                let mut short_name = [0; 11];
+                short_name.copy_from_slice(&raw[..11]);
+                let long_name = if lfn.is_empty() {
+                    None
+                } else {
+                    let checksum = short_name
+                        .iter()
+                        .fold(0u8, |sum, c| sum.rotate_right(1).wrapping_add(*c));
+                    let mut name = Vec::new();
+                    for (i, entry) in lfn.iter().rev().enumerate() {
+                        let last = if i == lfn.len() - 1 { 0x40 } else { 0 };
+                        assert_eq!(entry[0], (i + 1) as u8 | last);
+                        assert_eq!(entry[13], checksum);
+                        let chars = [&entry[1..11], &entry[14..26], &entry[28..32]].concat();
+                        name.extend(chars.chunks_exact(2).map(LittleEndian::read_u16));
+                    }
+                    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+                    Some(String::from_utf16(&name[..len]).unwrap())
+                };
+                lfn.clear();
+
+                entries.push(Entry {
+                    long_name,
+                    short_name,
+                    attr: raw[11],
+                    cluster: (LittleEndian::read_u16(&raw[20..]) as u32) << 16
+                        | LittleEndian::read_u16(&raw[26..]) as u32,
+                    size: LittleEndian::read_u32(&raw[28..]),
+                });
+            }
+            assert!(lfn.is_empty(), "dangling LFN entries");
+            entries
+        }
+    }
+
+    fn read_sector(disk: &mut Disk, sector: u64) -> Vec<u8> {
+        let mut buf = vec![0; SECTOR_SIZE as usize];
+        disk.seek(SeekFrom::Start(sector * SECTOR_SIZE)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn directory_layout() {
+        let dir = TempPath::dir("vvfat-layout");
+        fs::create_dir_all(dir.join("Music")).unwrap();
+
+        let big = (0..1300u32).map(|i| i as u8).collect::<Vec<_>>();
+        fs::write(dir.join("README.TXT"), b"hello").unwrap();
+        fs::write(dir.join("longfilename1.txt"), &big).unwrap();
+        fs::write(dir.join("longfilename2.txt"), b"").unwrap();
+        fs::write(dir.join("Music").join("song.mp3"), b"la la la").unwrap();
+
+        let firmware = vec![0xaa; 1000].into_boxed_slice();
+        let disk = Disk::new(Some(&dir), firmware, DISK_LEN).unwrap();
+        let mut fat = Fat32::new(disk);
+
+        // the firmware partition contains the firmware image
+        let fw = read_sector(&mut fat.disk, FW_PART_START + 1);
+        assert_eq!(&fw[..1000 - 512], &[0xaa; 1000 - 512][..]);
+        assert_eq!(&fw[1000 - 512..], &[0; 1024 - 1000][..]);
+
+        let root_cluster = fat.root_cluster;
+        let root = fat.read_dir(root_cluster);
+        let names = root
+            .iter()
+            .map(|e| (e.long_name.as_deref(), &e.short_name))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                (None, VOLUME_LABEL),
+                (Some("Music"), b"MUSIC      "),
+                (None, b"README  TXT"),
+                (Some("longfilename1.txt"), b"LONGFI~1TXT"),
+                (Some("longfilename2.txt"), b"LONGFI~2TXT"),
+            ]
+        );
+        assert_eq!(root[0].attr, ATTR_VOLUME_ID);
+
+        let readme = &root[2];
+        assert_eq!(readme.attr, ATTR_ARCHIVE);
+        assert_eq!(fat.read_file(readme), b"hello");
+
+        // 1300 bytes spans a 3 cluster chain (with 512 byte clusters)
+        let long = &root[3];
+        assert_eq!(fat.sectors_per_cluster, 1);
+        assert_eq!(fat.chain(long.cluster).len(), 3);
+        assert_eq!(fat.read_file(long), big);
+
+        // empty files have no clusters
+        assert_eq!((root[4].cluster, root[4].size), (0, 0));
+
+        let music = &root[1];
+        assert_eq!(music.attr, ATTR_DIRECTORY);
+        let music_cluster = music.cluster;
+        let children = fat.read_dir(music_cluster);
+        assert_eq!(children.len(), 3);
+        assert_eq!(&children[0].short_name, b".          ");
+        assert_eq!(children[0].cluster, music_cluster);
+        // the root directory is referred to as cluster 0
+        assert_eq!(&children[1].short_name, b"..         ");
+        assert_eq!(children[1].cluster, 0);
+        assert_eq!(children[2].long_name.as_deref(), Some("song.mp3"));
+        assert_eq!(&children[2].short_name, b"SONG    MP3");
+        assert_eq!(fat.read_file(&children[2]), b"la la la");
+
+        // writes only go to the in-memory overlay
+        let sector = fat.part_start + 100;
+        fat.disk
+            .seek(SeekFrom::Start(sector * SECTOR_SIZE))
+            .unwrap();
+        std::io::Write::write_all(&mut fat.disk, &[0x55; 4]).unwrap();
+        assert_eq!(&read_sector(&mut fat.disk, sector)[..4], &[0x55; 4]);
+        assert_eq!(fs::read(dir.join("README.TXT")).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn add_files() {
+        let orig = TempPath::dir("vvfat-add-orig");
+        let extra = TempPath::dir("vvfat-add-extra");
+        fs::create_dir_all(orig.join("Music")).unwrap();
+        fs::write(orig.join("README.TXT"), vec![1; 2000]).unwrap();
+        fs::write(orig.join("Music").join("song.mp3"), b"la la la").unwrap();
+
+        // replaces a file, adds to an existing directory, and creates a new
+        // directory with enough entries to span multiple clusters
+        fs::create_dir_all(extra.join("music")).unwrap();
+        fs::create_dir_all(extra.join("Podcasts")).unwrap();
+        fs::write(extra.join("readme.txt"), b"replaced").unwrap();
+        fs::write(extra.join("music").join("another song.mp3"), b"do re mi").unwrap();
+        for i in 0..20 {
+            let name = format!("episode number {}.mp3", i);
+            fs::write(extra.join("Podcasts").join(name), vec![i as u8; 600]).unwrap();
+        }
+
+        // (free clusters according to FSInfo, allocated clusters in the FAT)
+        let usage = |fat: &mut Fat32| {
+            let fsinfo = read_sector(&mut fat.disk, fat.part_start + 1);
+            let fat_entries = (fat.data_start - fat.fat_start) / 2 * (SECTOR_SIZE / 4);
+            let used = (2..fat_entries as u32)
+                .filter(|&c| fat.fat_entry(c) != 0)
+                .count() as u32;
+            (LittleEndian::read_u32(&fsinfo[488..]), used)
+        };
+
+        let mut fat = Fat32::new(Disk::new(Some(&orig), Box::new([]), DISK_LEN).unwrap());
+        let root_cluster = fat.root_cluster;
+        let old_readme_cluster = fat.read_dir(root_cluster)[2].cluster;
+        let (free_before, used_before) = usage(&mut fat);
+
+        let mut disk = fat.disk;
+        Vvfat::add_files(&mut disk, &extra).unwrap();
+        let mut fat = Fat32::new(disk);
+
+        let root_cluster = fat.root_cluster;
+        let root = fat.read_dir(root_cluster);
+        let names = root
+            .iter()
+            .map(|e| (e.long_name.as_deref(), &e.short_name))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            [
+                (None, VOLUME_LABEL),
+                (Some("Music"), b"MUSIC      "),
+                (None, b"README  TXT"),
+                (Some("Podcasts"), b"PODCASTS   "),
+            ]
+        );
+
+        // the replaced file's old clusters are freed
+        assert_eq!(fat.read_file(&root[2]), b"replaced");
+        assert_ne!(root[2].cluster, old_readme_cluster);
+        for i in 0..4 {
+            assert_eq!(fat.fat_entry(old_readme_cluster + i), 0);
+        }
+
+        let music_cluster = root[1].cluster;
+        let music = fat.read_dir(music_cluster);
+        assert_eq!(music.len(), 4);
+        assert_eq!(fat.read_file(&music[2]), b"la la la");
+        assert_eq!(music[3].long_name.as_deref(), Some("another song.mp3"));
+        assert_eq!(&music[3].short_name, b"ANOTHE~1MP3");
+        assert_eq!(fat.read_file(&music[3]), b"do re mi");
+
+        let podcasts_cluster = root[3].cluster;
+        assert_eq!(root[3].attr, ATTR_DIRECTORY);
+        assert!(fat.chain(podcasts_cluster).len() > 1);
+        let podcasts = fat.read_dir(podcasts_cluster);
+        assert_eq!(podcasts.len(), 22);
+        assert_eq!(podcasts[0].cluster, podcasts_cluster);
+        assert_eq!(podcasts[1].cluster, 0);
+        for entry in &podcasts[2..] {
+            let name = entry.long_name.as_deref().unwrap();
+            let i = name["episode number ".len()..name.len() - 4]
+                .parse::<u8>()
+                .unwrap();
+            assert_eq!(fat.read_file(entry), vec![i; 600]);
+        }
+
+        // the free cluster count is kept up to date
+        let (free_after, used_after) = usage(&mut fat);
+        assert!(used_after > used_before);
+        assert_eq!(free_before - free_after, used_after - used_before);
+    }
+}
@@ -1,6 +1,7 @@
 //! Block device interface and backend implementations.
 
 use std::fmt::Debug;
+use std::io::{self, SeekFrom};
 
 use futures::io::{AsyncRead, AsyncSeek, AsyncWrite};
 
@@ -12,3 +13,30 @@ pub trait BlockDev: Send + Sync + Unpin + Debug + AsyncRead + AsyncSeek + AsyncW
     /// Return the length (in bytes) of the underlying medium.
     fn len(&self) -> u64;
 }
+
+/// Resolve a seek request against a device's current position and length,
+/// returning the new position.
+///
+/// Seeking past the end of the device is allowed (as with regular files), but
+/// seeking to a negative position is an error.
+pub(crate) fn seek_pos(pos: u64, len: u64, seek: SeekFrom) -> io::Result<u64> {
+    let offset_by = |base: u64, n: i64| {
+        (base as i64)
+            .checked_add(n)
+            .filter(|&n| n >= 0)
+            .map(|n| n as u64)
+    };
+
+    let new_pos = match seek {
+        SeekFrom::Start(n) => Some(n),
+        SeekFrom::End(n) => offset_by(len, n),
+        SeekFrom::Current(n) => offset_by(pos, n),
+    };
+
+    new_pos.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
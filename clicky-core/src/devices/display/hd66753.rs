@@ -1,5 +1,6 @@
 use crate::devices::prelude::*;
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use relativity::Instant;
 
@@ -39,6 +40,31 @@ const EMU_CGRAM_BYTES: usize = (EMU_CGRAM_WIDTH * CGRAM_HEIGHT) * 2 / 8;
 const EMU_CGRAM_LEN: usize = EMU_CGRAM_BYTES / 2; // addressed as 16-bit words
 const EMU_CGRAM_OVF: usize = 0x1080; // Overflow address
 
+// The rendered shades are derived from a (very) rough model of the LCD panel:
+// the contrast adjuster sets the LCD drive voltage (V1), and each dot's
+// darkness depends on the RMS voltage across it, which the grayscale control
+// circuit varies by lighting the dot for only some fraction of frames (FRC).
+//
+// The constants were picked such that the boot contrast (VR = 0b100, CT = 40)
+// renders crisp black-on-white, with the extremes of the CT range being washed
+// out / murky, as they are on real hardware.
+
+/// V1REF voltage (as a multiple of Vreg) for each VR setting (Table 13)
+const VR_MULT: [f64; 8] = [2.8, 3.5, 4.0, 4.5, 5.0, 5.5, 6.0, 6.5];
+/// Resistance (in units of R) of the bleeder resistors which the contrast
+/// adjuster's variable resistor is in series with.
+const BLEEDER_R: f64 = 8.0;
+/// RMS voltage across an unlit dot, relative to a lit dot.
+const UNLIT_RATIO: f64 = 0.75;
+/// RMS voltage (as a multiple of Vreg) at which dots start to darken...
+const V_THRESHOLD: f64 = 2.6;
+/// ...and at which dots are fully dark.
+const V_SATURATION: f64 = 3.2;
+
+/// What's visible when none of the LCD's outputs are being driven.
+#[allow(clippy::unreadable_literal)]
+const BLANK: u32 = 0xffffffff;
+
 // TODO: migrate to bit_field crate + mod reg { const X: usize = Y; ... }
 #[derive(Debug, Default, Copy, Clone)]
 struct InternalRegs {
@@ -103,6 +129,8 @@ pub struct Hd66753 {
     cgram: Arc<RwLock<[u16; EMU_CGRAM_LEN]>>,
 
     ireg: Arc<RwLock<InternalRegs>>,
+    /// Cleared when the LCD loses power (e.g: the system is in standby).
+    powered: Arc<AtomicBool>,
 }
 
 impl std::fmt::Debug for Hd66753 {
@@ -114,6 +142,7 @@ impl std::fmt::Debug for Hd66753 {
             .field("ac", &self.ac)
             .field("cgram", &"[...]")
             .field("ireg", &self.ireg)
+            .field("powered", &self.powered)
             .finish()
     }
 }
@@ -121,8 +150,15 @@ impl std::fmt::Debug for Hd66753 {
 impl Hd66753 {
     pub fn new() -> Hd66753 {
         let cgram = Arc::new(RwLock::new([0; EMU_CGRAM_LEN]));
+        // Instead of the reset values, the registers are initialized to the state
+        // the bootloader leaves the LCD in, so that HLE-booted code is visible.
         let ireg = Arc::new(RwLock::new(InternalRegs {
             nl: 0b11111, // 168 x 132
+            ap: 0b01,
+            vr: 0b100,
+            ct: 40,
+            d: true,
+            se1: 0x83,
             ..InternalRegs::default()
         }));
 
@@ -133,9 +169,15 @@ impl Hd66753 {
             write_byte_latch: None,
             read_byte_latch: None,
             ireg,
+            powered: Arc::new(AtomicBool::new(true)),
         }
     }
 
+    /// Power the LCD on / off. An unpowered LCD is blank.
+    pub fn set_powered(&mut self, powered: bool) {
+        self.powered.store(powered, Ordering::SeqCst);
+    }
+
     fn is_in_cursor_region(ireg: &InternalRegs, p_x: usize, p_y: usize) -> bool {
         // Disabled cursor, invalid region
         if !ireg.c || ireg.hs > ireg.he || ireg.vs > ireg.ve {
@@ -145,6 +187,68 @@ impl Hd66753 {
         (ireg.hs..=ireg.he).contains(&(p_x as u8)) && (ireg.vs..=ireg.ve).contains(&(p_y as u8))
     }
 
+    /// Check if the LCD's outputs are being driven at all.
+    fn is_displaying(ireg: &InternalRegs) -> bool {
+        // AP = 0b00 turns off the LCD power supply's op-amps
+        ireg.d && !ireg.slp && !ireg.stb && ireg.ap != 0
+    }
+
+    /// Returns a mask of which rows are being driven, based on the 1st/2nd
+    /// screen driving positions and the LCD-driving duty.
+    fn driven_rows(ireg: &InternalRegs, height: usize) -> [bool; CGRAM_HEIGHT] {
+        let mut rows = [false; CGRAM_HEIGHT];
+
+        let mut screens = vec![(ireg.ss1, ireg.se1)];
+        // SPT = 2-division LCD drive
+        if ireg.spt {
+            screens.push((ireg.ss2, ireg.se2));
+        }
+
+        // if the screens contain more lines than the duty allows, the lines past
+        // the duty-setting line aren't driven (see Table 29)
+        let lines = screens
+            .into_iter()
+            .flat_map(|(start, end)| start as usize..=end as usize)
+            .filter(|&y| y < CGRAM_HEIGHT)
+            .take(height);
+        for y in lines {
+            rows[y] = true;
+        }
+
+        rows
+    }
+
+    /// Returns the shades of lit (DB = 11), brightly-colored (DB = 10),
+    /// weakly-colored (DB = 01), and unlit (DB = 00) dots.
+    fn palette(ireg: &InternalRegs) -> [u32; 4] {
+        // Table 12: CT = 0 => 6.40 x R, with each step subtracting 0.05 x R
+        let vr_res = 6.40 - 0.05 * ireg.ct as f64;
+        let v1 = VR_MULT[ireg.vr as usize] * BLEEDER_R / (BLEEDER_R + vr_res);
+
+        // Tables 14 and 15
+        let gsh = match ireg.gsh {
+            0b00 => 3. / 4.,
+            0b01 => 2. / 3.,
+            0b10 => 2. / 4.,
+            _ => 1.,
+        };
+        let gsl = match ireg.gsl {
+            0b00 => 1. / 4.,
+            0b01 => 1. / 3.,
+            0b10 => 2. / 4.,
+            _ => 1.,
+        };
+
+        let shade = |frac_lit: f64| -> u32 {
+            let v_rms = v1 * (frac_lit + (1. - frac_lit) * UNLIT_RATIO * UNLIT_RATIO).sqrt();
+            let darkness = ((v_rms - V_THRESHOLD) / (V_SATURATION - V_THRESHOLD)).clamp(0., 1.);
+            let c = (255. * (1. - darkness)).round() as u32;
+            0xff00_0000 | c << 16 | c << 8 | c
+        };
+
+        [shade(1.), shade(gsh), shade(gsl), shade(0.)]
+    }
+
     /// Returns a callback to update the framebuffer.
     ///
     /// The callback accepts a minifb framebuffer, and returns the rendered
@@ -152,13 +256,10 @@ impl Hd66753 {
     pub fn render_callback(&self) -> RenderCallback {
         let cgram = Arc::clone(&self.cgram);
         let ireg = Arc::clone(&self.ireg);
+        let powered = Arc::clone(&self.powered);
         let start = Instant::now();
 
         Box::new(move |buf: &mut Vec<u32>| -> (usize, usize) {
-            // TODO: make palette configurable?
-            #[allow(clippy::unreadable_literal)]
-            const PALETTE: [u32; 4] = [0xff000000, 0xff686868, 0xffb8b8b9, 0xffffffff];
-
             // instead of holding the locks, just copy the data locally
             let cgram = *cgram.read().unwrap();
             let ireg = *ireg.read().unwrap();
@@ -171,6 +272,15 @@ impl Hd66753 {
                 nl => (nl as usize + 1) * 8,
             };
 
+            if !powered.load(Ordering::SeqCst) || !Hd66753::is_displaying(&ireg) {
+                buf.clear();
+                buf.resize(CGRAM_WIDTH * height, BLANK);
+                return (CGRAM_WIDTH, height);
+            }
+
+            let palette = Hd66753::palette(&ireg);
+            let driven_rows = Hd66753::driven_rows(&ireg, height);
+
             let cgram_window = cgram
                     .chunks_exact(EMU_CGRAM_WIDTH * 2 / 8 / 2)
                     .take(height)
@@ -205,7 +315,7 @@ impl Hd66753 {
                     let p_x = i % CGRAM_WIDTH;
                     let p_y = i / CGRAM_WIDTH;
 
-                    if Hd66753::is_in_cursor_region(&ireg, p_x, p_y) {
+                    let x = if Hd66753::is_in_cursor_region(&ireg, p_x, p_y) {
                         // CM = Cursor display mode
                         match ireg.cm {
                             0b00 if blink_on => 3,
@@ -216,11 +326,18 @@ impl Hd66753 {
                         }
                     } else {
                         x
+                    };
+
+                    // Rows outside the screen driving positions are left unlit
+                    if driven_rows[p_y] {
+                        x
+                    } else {
+                        3
                     }
                 })
                 .map(move |x| {
                     // Apply palette
-                    PALETTE[x]
+                    palette[x]
                 });
 
             // replace in-place
@@ -232,9 +349,54 @@ impl Hd66753 {
         })
     }
 
+    /// Latch a byte written over the 8-bit interface, executing the access
+    /// once both bytes have been written.
+    fn write_byte(&mut self, offset: u32, val: u8) -> MemResult<()> {
+        let val = match self.write_byte_latch.take() {
+            None => {
+                self.write_byte_latch = Some(val);
+                return Ok(());
+            }
+            Some(hi) => (hi as u16) << 8 | (val as u16),
+        };
+
+        match offset {
+            0x8 => {
+                self.ir = val;
+
+                if self.ir > 0x12 {
+                    return Err(ContractViolation {
+                        msg: format!("set invalid LCD Command: {:#04x?}", val),
+                        severity: Error,
+                        stub_val: None,
+                    });
+                }
+
+                Ok(())
+            }
+            0x10 => Ok(self.handle_data_write(val)?),
+            _ => Err(Unexpected),
+        }
+    }
+
     fn handle_data_write(&mut self, val: u16) -> MemResult<()> {
         let mut ireg = self.ireg.write().unwrap();
 
+        // Only the power control instruction (and start oscillation, when in
+        // standby) is executed during sleep / standby mode.
+        let accepted = match self.ir {
+            0x03 => true,
+            0x00 => !ireg.slp,
+            _ => !ireg.slp && !ireg.stb,
+        };
+        if !accepted {
+            return Err(ContractViolation {
+                msg: format!("LCD command {:#x?} ignored in sleep/standby mode", self.ir),
+                severity: Warn,
+                stub_val: None,
+            });
+        }
+
         match self.ir {
             // Start Oscillation
             0x00 => {
@@ -266,7 +428,6 @@ impl Hd66753 {
             0x04 => {
                 ireg.vr = val.get_bits(8..=10) as u8;
                 ireg.ct = val.get_bits(0..=6) as u8;
-                // TODO?: use Contrast Control bits to control rendered contrast
             }
             // Entry Mode
             0x05 => {
@@ -474,32 +635,20 @@ impl Memory for Hd66753 {
             return Err(StubWrite(Error, ()));
         }
 
-        // the iPod uses the controller via an 8-bit interface
-        let val = val as u8; // FIXME: this should use trunc_to_u8, but it crashes...
-        let val = match self.write_byte_latch.take() {
-            None => {
-                self.write_byte_latch = Some(val);
-                return Ok(());
-            }
-            Some(hi) => (hi as u16) << 8 | (val as u16),
-        };
-
-        match offset {
-            0x8 => {
-                self.ir = val;
-
-                if self.ir > 0x12 {
-                    return Err(ContractViolation {
-                        msg: format!("set invalid LCD Command: {:#04x?}", val),
-                        severity: Error,
-                        stub_val: None,
-                    });
-                }
-
-                Ok(())
-            }
-            0x10 => Ok(self.handle_data_write(val)?),
-            _ => Err(Unexpected),
+        // the iPod uses the controller via an 8-bit interface, which only latches
+        // the lower 8 bits of the write
+        self.write_byte(offset, val as u8)?;
+
+        // firmware doesn't always bother masking-off the upper bits, which is
+        // harmless (unlike most other `trunc_to_u8` violations)
+        if val.trunc_to_u8().is_err() {
+            return Err(ContractViolation {
+                msg: format!("ignored upper bits of >8-bit LCD write ({:#x?})", val),
+                severity: Debug,
+                stub_val: None,
+            });
         }
+
+        Ok(())
     }
 }
@@ -35,6 +35,12 @@ impl AsanRam {
         }
     }
 
+    /// Size of the RAM (in bytes).
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.mem.len()
+    }
+
     pub fn bulk_write(&mut self, offset: u32, data: &[u8]) {
         let offset = offset as usize;
         self.mem[offset..offset + data.len()].copy_from_slice(data);
@@ -0,0 +1,130 @@
+use std::ops::Range;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Faults injected into an emulated IDE drive, used to mimic a dying disk.
+#[derive(Debug, Clone, Default)]
+pub struct IdeFaults {
+    /// Sectors which can't be read (i.e: fail with an uncorrectable error).
+    ///
+    /// Writing to an unreadable sector reallocates it, after which it can be
+    /// read successfully.
+    pub unreadable: Vec<Range<u64>>,
+    /// Sectors which take longer to read (e.g: due to repeated read retries).
+    pub slow: Vec<(Range<u64>, Duration)>,
+    /// Sectors which can't be written.
+    pub failing_writes: Vec<Range<u64>>,
+    /// Number of commands after which the drive stops responding altogether.
+    pub vanish_after: Option<u64>,
+}
+
+impl IdeFaults {
+    pub(super) fn is_unreadable(&self, lba: u64) -> bool {
+        self.unreadable.iter().any(|r| r.contains(&lba))
+    }
+
+    pub(super) fn read_delay(&self, lba: u64) -> Option<Duration> {
+        (self.slow.iter())
+            .filter(|(r, _)| r.contains(&lba))
+            .map(|(_, delay)| *delay)
+            .max()
+    }
+
+    pub(super) fn write_fails(&self, lba: u64) -> bool {
+        self.failing_writes.iter().any(|r| r.contains(&lba))
+    }
+
+    /// Mark an unreadable sector as readable again (i.e: after it's been
+    /// swapped out for a spare).
+    pub(super) fn reallocate(&mut self, lba: u64) {
+        let mut unreadable = Vec::new();
+        for r in self.unreadable.drain(..) {
+            if r.contains(&lba) {
+                unreadable.push(r.start..lba);
+                unreadable.push(lba + 1..r.end);
+            } else {
+                unreadable.push(r);
+            }
+        }
+        unreadable.retain(|r| !r.is_empty());
+        self.unreadable = unreadable;
+    }
+}
+
+/// Parse an inclusive sector range (e.g: `1000-1999`), or a single sector.
+fn parse_range(s: &str) -> Result<Range<u64>, &'static str> {
+    let mut s = s.split('-');
+    let start = s.next().unwrap();
+    let end = s.next().unwrap_or(start);
+
+    let start = start.parse::<u64>().map_err(|_| "invalid sector range")?;
+    let end = end.parse::<u64>().map_err(|_| "invalid sector range")?;
+    if end < start {
+        return Err("invalid sector range");
+    }
+    let end = end.checked_add(1).ok_or("invalid sector range")?;
+    Ok(start..end)
+}
+
+impl FromStr for IdeFaults {
+    type Err = &'static str;
+
+    /// Parse a comma separated list of faults:
+    ///
+    /// - `unreadable=<start>-<end>`
+    /// - `slow=<start>-<end>:<delay in ms>`
+    /// - `badwrite=<start>-<end>`
+    /// - `vanish=<number of commands>`
+    ///
+    /// Sector ranges are inclusive, and each kind of fault may be specified
+    /// multiple times.
+    fn from_str(s: &str) -> Result<IdeFaults, &'static str> {
+        let mut faults = IdeFaults::default();
+
+        for arg in s.split(',').filter(|s| !s.is_empty()) {
+            let mut kv = arg.split('=');
+            let key = kv.next().unwrap();
+            let val = kv.next().ok_or("missing value for fault")?;
+
+            match key {
+                "unreadable" => faults.unreadable.push(parse_range(val)?),
+                "slow" => {
+                    let mut val = val.split(':');
+                    let range = parse_range(val.next().unwrap())?;
+                    let delay = (val.next())
+                        .ok_or("missing delay for `slow` fault")?
+                        .parse::<u64>()
+                        .map_err(|_| "invalid delay for `slow` fault")?;
+                    faults.slow.push((range, Duration::from_millis(delay)))
+                }
+                "badwrite" => faults.failing_writes.push(parse_range(val)?),
+                "vanish" => {
+                    let n = val.parse().map_err(|_| "invalid command count")?;
+                    faults.vanish_after = Some(n)
+                }
+                _ => return Err("unknown fault"),
+            }
+        }
+
+        Ok(faults)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_range;
+
+    #[test]
+    fn sector_ranges() {
+        assert_eq!(parse_range("1000-1999"), Ok(1000..2000));
+        assert_eq!(parse_range("42"), Ok(42..43));
+        assert!(parse_range("2-1").is_err());
+        assert!(parse_range("1-x").is_err());
+
+        // the (exclusive) end of the range must be representable
+        let max = u64::MAX.to_string();
+        assert!(parse_range(&max).is_err());
+        assert!(parse_range(&format!("0-{}", max)).is_err());
+        assert_eq!(parse_range(&format!("0-{}", u64::MAX - 1)), Ok(0..u64::MAX));
+    }
+}
@@ -1,3 +1,7 @@
+use bit_field::BitField;
+
+use super::IdeDriveProfile;
+
 /// Structure returned by the ATA IDENTITY command.
 ///
 /// Copied from include/linux/hdreg.h.
@@ -285,11 +289,9 @@ pub struct IdeDriveMeta<'a> {
     pub total_sectors: u64,
 
     pub cylinders: u16,
-    pub heads: u16,
-    pub sectors: u16,
-    pub serial: &'a [u8],
-    pub fw_version: &'a [u8],
-    pub model: &'a [u8],
+    /// Current block size for READ / WRITE MULTIPLE (0 if disabled).
+    pub multsect: u8,
+    pub profile: &'a IdeDriveProfile,
 }
 
 impl IdeDriveMeta<'_> {
@@ -299,51 +301,65 @@ impl IdeDriveMeta<'_> {
         // Some values were cargo-culted from QEMU's source
         // (/hw/ide/core.c:ide_identify).
 
-        let capacity = self.cylinders as u32 * self.heads as u32 * self.sectors as u32;
+        let profile = self.profile;
+        let heads = profile.heads;
+        let sectors = profile.sectors_per_track;
+        let capacity = self.cylinders as u32 * heads as u32 * sectors as u32;
+
+        // feature bits which vary between drive profiles
+        let smart = profile.smart as u16;
+        let lba48 = profile.lba48 as u16;
+        let cfa = profile.compact_flash as u16;
+        let dma_modes = if profile.dma { 0x07 } else { 0 };
 
         let mut id = hd_driveid {
             config: 0x0040, // not removable controller and/or device
             cyls: self.cylinders,
-            heads: self.heads,
-            track_bytes: self.sectors * 512,
+            heads,
+            track_bytes: sectors * 512,
             sector_bytes: 512,
-            sectors: self.sectors,
+            sectors,
             // serial_no: self.serial, // no ergonomic way to init [u8; N] from &[u8]
             // fw_rev: self.fw_version,
             // model: self.model,
-            max_multsect: 1, // TODO: add proper multi-sector support
-            capabilities: 0b0000_1011_0000_0000, // DMA, LBA, IORDY
+            max_multsect: profile.max_multsect,
+            vendor3: 0x80,                       // (ATA-5) shall be 0x80
+            capabilities: 0b0010_1010_0000_0000 | (profile.dma as u16) << 8, // standby timer, LBA, IORDY, DMA (8)
             capabilities2: 0b0100_0000_0000_0000,
             field_valid: 0b11, // words 54-58,64-70 are valid (88 is not: no UDMA)
             cur_cyls: self.cylinders,
-            cur_heads: self.heads,
-            cur_sectors: self.sectors,
+            cur_heads: heads,
+            cur_sectors: sectors,
             cur_capacity0: capacity as u16,
             cur_capacity1: (capacity >> 16) as u16,
-            lba_capacity: self.total_sectors as u32,
+            multsect: self.multsect,
+            multsect_valid: (self.multsect != 0) as u8,
+            // 28-bit commands can't address anything past 0x0fffffff
+            lba_capacity: self.total_sectors.min(0x0fff_ffff) as u32,
+            lba_capacity_2: if profile.lba48 { self.total_sectors } else { 0 },
 
             // (QEMU)
             ecc_bytes: 4,
             tPIO: 2,              // fast
             tDMA: 2,              // fast
-            dma_1word: 0x07,      // single word dma0-2 supported
-            dma_mword: 0x07,      // mdma0-2 supported
+            dma_1word: dma_modes, // single word dma0-2 supported
+            dma_mword: dma_modes, // mdma0-2 supported
             eide_pio_modes: 0x07, // pio3-4 supported
             eide_dma_min: 120,
             eide_dma_time: 120,
             eide_pio: 120,
             eide_pio_iordy: 120,
 
-            major_rev_num: 0x003e, // conforms to ATA-1 .. ATA-5
+            major_rev_num: 0x007e, // conforms to ATA-1 .. ATA-6 (48-bit addressing)
             minor_rev_num: 0x0015, // ATA/ATAPI-5 T13 1321D revision 1
 
             // ATA-5 8.12.45
-            command_set_1: (1 << 0) | (1 << 3) | (1 << 5), // SMART (0), power management (3), write cache (5)
-            command_set_2: (1 << 14) | (1 << 12), // Bit 14 means 82h-83h contain valid info, FLUSH CACHE (12)
+            command_set_1: smart | (1 << 3) | (1 << 5), // SMART (0), power management (3), write cache (5)
+            command_set_2: (1 << 14) | (lba48 << 13) | (1 << 12) | (lba48 << 10) | (cfa << 2), // Bit 14 means 82h-83h contain valid info, FLUSH CACHE EXT (13), FLUSH CACHE (12), 48-bit (10), CFA (2)
             
             // ATA-5 8.12.46
-            cfs_enable_1: (1 << 0) | (1 << 3) | (1 << 5), // SMART (0), power management (3), write cache en (5)
-            cfs_enable_2: (1 << 14) | (1 << 12), // // NOP command (14), write buffer (12)
+            cfs_enable_1: smart | (1 << 3) | (1 << 5), // SMART (0), power management (3), write cache en (5)
+            cfs_enable_2: (1 << 14) | (lba48 << 13) | (1 << 12) | (lba48 << 10) | (cfa << 2), // // NOP command (14), FLUSH CACHE EXT (13), write buffer (12), 48-bit (10), CFA (2)
             csf_default: 1 << 14, // Bit 14 means 85h-87h contain valid info
 
             ..hd_driveid::default()
@@ -357,9 +373,19 @@ impl IdeDriveMeta<'_> {
             }
         };
 
-        pad_ascii(&mut id.serial_no, self.serial);
-        pad_ascii(&mut id.fw_rev, self.fw_version);
-        pad_ascii(&mut id.model, self.model);
+        pad_ascii(&mut id.serial_no, profile.serial.as_bytes());
+        pad_ascii(&mut id.fw_rev, profile.fw_version.as_bytes());
+        pad_ascii(&mut id.model, profile.model.as_bytes());
+
+        // (word 217) nominal media rotation rate. 1 = non-rotating media
+        id.words206_254[217 - 206] = profile.rpm.unwrap_or(1);
+
+        if profile.compact_flash {
+            // CFA signature: removable, non-ATAPI
+            id.config = 0x848a;
+            // (word 160) CFA power mode 1 supported, drawing at most 100mA
+            id.cfa_power = *0u16.set_bit(15, true).set_bits(0..=11, 100);
+        }
 
         id
     }
@@ -2,19 +2,34 @@ use crate::devices::prelude::*;
 
 use std::convert::TryFrom;
 use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::time::Duration;
 
+use futures::future::{self, Either, FutureExt, RemoteHandle};
 use futures::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
 use num_enum::TryFromPrimitive;
+use pin_utils::pin_mut;
+use relativity::{Instant, Timeout};
 
 use crate::block::BlockDev;
 
-// TODO?: make num heads / num sectors configurable?
-const NUM_HEADS: usize = 16;
-const NUM_SECTORS: usize = 63;
+/// Largest address reachable by 28-bit commands.
+const MAX_LBA28: u64 = 0x0fff_ffff;
 
+mod faults;
 mod identify;
+mod profile;
 mod reg;
 mod smart;
+mod timing;
+
+pub use faults::IdeFaults;
+pub use profile::IdeDriveProfile;
+pub use smart::SmartConfig;
+pub use timing::IdeTiming;
+
+use smart::SmartModel;
+use timing::Mechanics;
 
 /// IDE Device (either 0 or 1)
 #[derive(Debug, Copy, Clone)]
@@ -97,6 +112,59 @@ enum IdeCmd {
 
     // not strictly ATA-2, but the iPod flash ROM seems to use this cmd...
     FlushCache = 0xe7,
+
+    ReadVerifySectors = 0x40,
+    ReadVerifySectorsNoRetry = 0x41,
+    ExecuteDeviceDiagnostic = 0x90,
+
+    IdleImmediate = 0xe1,
+    IdleImmediateAlt = 0x95,
+    Idle = 0xe3,
+    IdleAlt = 0x97,
+    Standby = 0xe2,
+    StandbyAlt = 0x96,
+    CheckPowerMode = 0xe5,
+    CheckPowerModeAlt = 0x98,
+
+    ReadNativeMaxAddress = 0xf8,
+
+    // 48-bit Address feature set
+    ReadSectorsExt = 0x24,
+    ReadDMAExt = 0x25,
+    ReadNativeMaxAddressExt = 0x27,
+    ReadMultipleExt = 0x29,
+    WriteSectorsExt = 0x34,
+    WriteDMAExt = 0x35,
+    WriteMultipleExt = 0x39,
+    ReadVerifySectorsExt = 0x42,
+    FlushCacheExt = 0xea,
+}
+
+impl IdeCmd {
+    /// Part of the 48-bit Address feature set.
+    fn is_ext(&self) -> bool {
+        use IdeCmd::*;
+        matches!(
+            self,
+            ReadSectorsExt
+                | ReadDMAExt
+                | ReadNativeMaxAddressExt
+                | ReadMultipleExt
+                | WriteSectorsExt
+                | WriteDMAExt
+                | WriteMultipleExt
+                | ReadVerifySectorsExt
+                | FlushCacheExt
+        )
+    }
+
+    fn is_dma(&self) -> bool {
+        use IdeCmd::*;
+        matches!(
+            self,
+            ReadDMA | ReadDMANoRetry | ReadDMAExt | WriteDMA | WriteDMANoRetry | WriteDMAExt
+        )
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, TryFromPrimitive)]
@@ -242,11 +310,20 @@ struct IdeRegs {
     lba3_dev_head: u8,
     status: u8,
 
+    /// Previous contents of the sector count and LBA0-2 registers (i.e: the
+    /// "high order bytes" used by 48-bit commands).
+    hob_sector_count: u8,
+    hob_lba0: u8,
+    hob_lba1: u8,
+    hob_lba2: u8,
+
     // Device Control
     /// software reset
     srst: bool,
     /// irq disabled
     nein: bool,
+    /// read back the high order bytes
+    hob: bool,
 }
 
 /// Various IDE toggles and features.
@@ -259,14 +336,280 @@ struct IdeDriveConfig {
     transfer_mode: IdeTransferMode,
 }
 
+/// An in-progress data transfer.
+#[derive(Debug, Default)]
+struct Transfer {
+    /// Sector currently held in the iobuf.
+    lba: u64,
+    /// Sectors left to transfer (including the one currently in the iobuf).
+    remaining: usize,
+    /// Number of sectors per DRQ data block.
+    block_size: usize,
+    /// Sectors left in the current DRQ data block.
+    block_left: usize,
+    /// Set when the sector in the iobuf is the first of a DRQ data block.
+    new_block: bool,
+    dma: bool,
+}
+
+impl Transfer {
+    fn new(lba: u64, count: usize, block_size: usize, dma: bool) -> Transfer {
+        Transfer {
+            lba,
+            remaining: count,
+            block_size,
+            block_left: block_size.min(count),
+            new_block: true,
+            dma,
+        }
+    }
+
+    /// Move on to the next sector, returning `true` if the sector that was
+    /// just transferred was the last one in its DRQ data block.
+    fn advance(&mut self) -> bool {
+        self.lba += 1;
+        self.remaining -= 1;
+        self.block_left -= 1;
+        self.new_block = self.block_left == 0;
+        if self.new_block {
+            self.block_left = self.block_size.min(self.remaining);
+        }
+        self.new_block
+    }
+}
+
+/// ATA power management modes.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum PowerMode {
+    Active,
+    Idle,
+    Standby,
+    /// Only a reset will wake the drive back up.
+    Sleep,
+}
+
+/// Decode the standby timer period passed to the IDLE / STANDBY commands (via
+/// the sector count register). Returns `None` if the timer is disabled.
+fn standby_timer_period(val: u8) -> Option<Duration> {
+    let secs = match val {
+        0 => return None,
+        1..=240 => val as u64 * 5,
+        241..=251 => (val as u64 - 240) * 30 * 60,
+        252 => 21 * 60,
+        // vendor specific. the spec suggests something between 8 and 12 hours.
+        253 => 8 * 60 * 60,
+        254 => return None, // reserved
+        255 => 21 * 60 + 15,
+    };
+    Some(Duration::from_secs(secs))
+}
+
+/// Media accesses serviced by a drive's I/O task.
+#[derive(Debug)]
+enum IoReq {
+    SetTiming(Option<IdeTiming>),
+    SetFaults(IdeFaults),
+    /// Spin-up the drive after it's been powered on.
+    PowerOn {
+        gen: usize,
+    },
+    /// (Re)start the standby timer, which spins down the drive once it
+    /// expires. `None` disables the timer.
+    StandbyTimer(Option<Duration>),
+    /// Spin-down the drive, completing the current command.
+    SpinDown {
+        gen: usize,
+    },
+    Read {
+        gen: usize,
+        lba: u64,
+    },
+    Write {
+        gen: usize,
+        lba: u64,
+        data: Box<[u8; 512]>,
+    },
+    Verify {
+        gen: usize,
+        lba: u64,
+        count: usize,
+    },
+}
+
+/// The result of an `IoReq`.
+#[derive(Debug)]
+enum IoDone {
+    PowerOn,
+    SpinDown,
+    Read(io::Result<Box<[u8; 512]>>),
+    /// Writing to an unreadable sector reallocates it.
+    Write {
+        res: io::Result<()>,
+        reallocated: bool,
+    },
+    /// On error, includes the sector which couldn't be read.
+    Verify(Result<(), (u64, io::Error)>),
+}
+
+/// Services a drive's media accesses (and its standby timer), exiting once
+/// the drive has been dropped.
+async fn io_task(
+    drive: Weak<Mutex<IdeDrive>>,
+    mut blockdev: Box<dyn BlockDev>,
+    reqs: async_channel::Receiver<IoReq>,
+) {
+    let mut mech = Mechanics::new(blockdev.len() / 512);
+    let mut faults = IdeFaults::default();
+    let mut standby_timer = None;
+
+    loop {
+        let req = match standby_timer {
+            None => reqs.recv().await,
+            Some(period) => {
+                let req_fut = reqs.recv();
+                pin_mut!(req_fut);
+
+                match future::select(req_fut, Timeout::new(period)).await {
+                    Either::Left((req, _)) => req,
+                    Either::Right(_) => {
+                        standby_timer = None;
+                        let spin_down = match drive.upgrade() {
+                            Some(drive) => drive.lock().unwrap().standby_timer_expired(),
+                            None => break,
+                        };
+                        if spin_down {
+                            mech.spin_down().await;
+                        }
+                        continue;
+                    }
+                }
+            }
+        };
+
+        let req = match req {
+            Ok(req) => req,
+            // the drive was detached
+            Err(async_channel::RecvError) => break,
+        };
+
+        let (gen, done) = match req {
+            IoReq::StandbyTimer(period) => {
+                standby_timer = period;
+                continue;
+            }
+            IoReq::SetTiming(timing) => {
+                mech.set_timing(timing);
+                continue;
+            }
+            IoReq::SetFaults(new_faults) => {
+                faults = new_faults;
+                continue;
+            }
+            IoReq::PowerOn { gen } => {
+                mech.power_on().await;
+                (gen, IoDone::PowerOn)
+            }
+            IoReq::SpinDown { gen } => {
+                mech.spin_down().await;
+                (gen, IoDone::SpinDown)
+            }
+            IoReq::Read { gen, lba } => {
+                mech.access(lba).await;
+                let mut data = Box::new([0; 512]);
+                let res = async {
+                    injected_read_fault(&faults, lba).await?;
+                    blockdev.seek(io::SeekFrom::Start(lba * 512)).await?;
+                    blockdev.read_exact(&mut data[..]).await
+                };
+                (gen, IoDone::Read(res.await.map(|_| data)))
+            }
+            IoReq::Write { gen, lba, data } => {
+                mech.access(lba).await;
+                let res = async {
+                    if faults.write_fails(lba) {
+                        return Err(injected_fault("sector can't be written"));
+                    }
+                    blockdev.seek(io::SeekFrom::Start(lba * 512)).await?;
+                    blockdev.write_all(&data[..]).await
+                };
+                let res = res.await;
+
+                let reallocated = res.is_ok() && faults.is_unreadable(lba);
+                if reallocated {
+                    faults.reallocate(lba);
+                }
+                (gen, IoDone::Write { res, reallocated })
+            }
+            IoReq::Verify { gen, lba, count } => {
+                let mut res = Ok(());
+                let mut data = [0; 512];
+                for lba in lba..lba + count as u64 {
+                    mech.access(lba).await;
+                    let sector_res = async {
+                        injected_read_fault(&faults, lba).await?;
+                        blockdev.seek(io::SeekFrom::Start(lba * 512)).await?;
+                        blockdev.read_exact(&mut data).await
+                    };
+                    if let Err(e) = sector_res.await {
+                        res = Err((lba, e));
+                        break;
+                    }
+                }
+                (gen, IoDone::Verify(res))
+            }
+        };
+
+        match drive.upgrade() {
+            Some(drive) => drive.lock().unwrap().io_done(gen, done),
+            // the drive was detached
+            None => break,
+        }
+    }
+}
+
+fn injected_fault(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("injected fault: {}", msg))
+}
+
+/// Apply any faults injected into the sector being read.
+async fn injected_read_fault(faults: &IdeFaults, lba: u64) -> io::Result<()> {
+    if let Some(delay) = faults.read_delay(lba) {
+        timing::delay(delay).await;
+    }
+    if faults.is_unreadable(lba) {
+        return Err(injected_fault("sector can't be read"));
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 struct IdeDrive {
-    blockdev: Box<dyn BlockDev>,
+    profile: IdeDriveProfile,
+    /// Size of the underlying blockdev (in sectors).
+    media_sectors: u64,
+    /// Capacity reported to the host (in sectors).
+    total_sectors: u64,
     irq: irq::Sender,   // shared between both drives
     dmarq: irq::Sender, // shared between both drives
 
+    io_tx: async_channel::Sender<IoReq>,
+    /// Incremented on reset, so that the results of any in-flight media
+    /// accesses are discarded.
+    io_gen: usize,
+
     state: IdeDriveState,
-    remaining_sectors: usize,
+    xfer: Transfer,
+
+    power: PowerMode,
+    standby_timer: Option<Duration>,
+    last_cmd: Instant,
+
+    smart: SmartModel,
+    /// Number of commands after which the drive stops responding.
+    vanish_after: Option<u64>,
+    num_cmds: u64,
+    /// The drive has stopped responding, and appears to be disconnected.
+    vanished: bool,
 
     iobuf: IdeIoBuf,
     reg: IdeRegs,
@@ -274,14 +617,33 @@ struct IdeDrive {
 }
 
 impl IdeDrive {
-    fn new(irq: irq::Sender, dmarq: irq::Sender, blockdev: Box<dyn BlockDev>) -> IdeDrive {
+    fn new(
+        irq: irq::Sender,
+        dmarq: irq::Sender,
+        media_sectors: u64,
+        io_tx: async_channel::Sender<IoReq>,
+    ) -> IdeDrive {
         IdeDrive {
-            blockdev,
+            profile: IdeDriveProfile::default(),
+            media_sectors,
+            total_sectors: media_sectors,
             irq,
             dmarq,
 
+            io_tx,
+            io_gen: 0,
+
             state: IdeDriveState::Idle,
-            remaining_sectors: 0,
+            xfer: Transfer::default(),
+
+            power: PowerMode::Active,
+            standby_timer: None,
+            last_cmd: Instant::now(),
+
+            smart: SmartModel::new(SmartConfig::default()),
+            vanish_after: None,
+            num_cmds: 0,
+            vanished: false,
 
             iobuf: IdeIoBuf::empty(),
             reg: IdeRegs {
@@ -296,6 +658,28 @@ impl IdeDrive {
         }
     }
 
+    fn set_profile(&mut self, profile: IdeDriveProfile) {
+        self.total_sectors = match profile.max_sectors {
+            Some(max) => self.media_sectors.min(max),
+            None => self.media_sectors,
+        };
+        self.profile = profile;
+    }
+
+    fn set_faults(&mut self, faults: IdeFaults) -> MemResult<()> {
+        self.vanish_after = faults.vanish_after;
+        self.submit(IoReq::SetFaults(faults))
+    }
+
+    /// Spin the drive back up ahead of a media access (if required). The I/O
+    /// task takes care of actually spinning up the platters.
+    fn wake(&mut self) {
+        if self.power == PowerMode::Standby {
+            self.smart.spin_up();
+        }
+        self.power = PowerMode::Active;
+    }
+
     /// Handles LBA/CHS offset translation, returning the offset into the
     /// blockdev (in blocks, _not bytes_).
     ///
@@ -312,27 +696,378 @@ impl IdeDrive {
             let cyl = ((self.reg.lba2_cyl_hi as u16) << 8 | (self.reg.lba1_cyl_lo as u16)) as u64;
             let head = self.reg.lba3_dev_head.get_bits(reg::DEVHEAD::HS) as u64;
 
-            let total_cyls = self.blockdev.len() / (NUM_HEADS * NUM_SECTORS * 512) as u64;
+            let heads = self.profile.heads as u64;
+            let sectors = self.profile.sectors_per_track as u64;
+            let total_cyls = self.total_sectors / (heads * sectors);
 
-            if sector < NUM_SECTORS as _ || cyl < total_cyls || head < NUM_HEADS as _ {
+            // sectors are 1-indexed
+            if sector == 0 || sector > sectors || cyl >= total_cyls || head >= heads {
                 return None;
             }
 
-            ((cyl * NUM_HEADS as u64 + head) * NUM_SECTORS as u64 + sector) as u64
+            (cyl * heads + head) * sectors + (sector - 1)
         };
 
         Some(offset)
     }
 
+    /// Decode the starting sector and sector count of a data command, aborting
+    /// the command if the address is invalid.
+    ///
+    /// `ext` selects between 28-bit and 48-bit commands.
+    fn get_lba_and_count(&mut self, ext: bool) -> MemResult<(u64, usize)> {
+        if ext {
+            let lba = (self.reg.hob_lba2 as u64) << 40
+                | (self.reg.hob_lba1 as u64) << 32
+                | (self.reg.hob_lba0 as u64) << 24
+                | (self.reg.lba2_cyl_hi as u64) << 16
+                | (self.reg.lba1_cyl_lo as u64) << 8
+                | (self.reg.lba0_sector_no as u64);
+            let count =
+                match (self.reg.hob_sector_count as usize) << 8 | self.reg.sector_count as usize {
+                    0 => 65536,
+                    n => n,
+                };
+            return Ok((lba, count));
+        }
+
+        let lba = match self.get_sector_offset() {
+            Some(lba) => lba,
+            None => {
+                self.abort(reg::ERROR::IDNF);
+                return Err(ContractViolation {
+                    msg: "invalid CHS address".into(),
+                    severity: Info,
+                    stub_val: None,
+                });
+            }
+        };
+        let count = match self.reg.sector_count {
+            0 => 256,
+            n => n as usize,
+        };
+        Ok((lba, count))
+    }
+
+    /// Report a sector address back to the host (e.g: the location of an error,
+    /// or the drive's max address).
+    fn set_lba_regs(&mut self, lba: u64, ext: bool) {
+        self.reg.lba0_sector_no = lba as u8;
+        self.reg.lba1_cyl_lo = (lba >> 8) as u8;
+        self.reg.lba2_cyl_hi = (lba >> 16) as u8;
+        if ext {
+            self.reg.hob_lba0 = (lba >> 24) as u8;
+            self.reg.hob_lba1 = (lba >> 32) as u8;
+            self.reg.hob_lba2 = (lba >> 40) as u8;
+        } else {
+            (self.reg.lba3_dev_head).set_bits(reg::DEVHEAD::HS, (lba >> 24) as u8 & 0xf);
+        }
+    }
+
+    /// Load the device signature into the command block registers, as done
+    /// after a reset / EXECUTE DEVICE DIAGNOSTIC.
+    fn set_signature(&mut self) {
+        self.reg.sector_count = 1;
+        self.reg.lba0_sector_no = 1;
+        self.reg.lba1_cyl_lo = 0;
+        self.reg.lba2_cyl_hi = 0;
+        self.reg.lba3_dev_head &= 1 << reg::DEVHEAD::DEV;
+        // diagnostic code: "device 0 passed"
+        self.reg.error = 0x01;
+    }
+
+    /// Software reset (i.e: the host pulsed SRST).
+    fn reset(&mut self) {
+        self.io_gen = self.io_gen.wrapping_add(1);
+        self.state = IdeDriveState::Idle;
+        self.xfer = Transfer::default();
+        if self.power == PowerMode::Sleep {
+            self.power = PowerMode::Standby;
+        }
+
+        self.set_signature();
+        self.reg.status = 0;
+        (self.reg.status)
+            .set_bit(reg::STATUS::DRDY, true)
+            .set_bit(reg::STATUS::DSC, true);
+
+        self.dmarq.clear();
+        self.irq.clear();
+    }
+
+    fn write_dev_control(&mut self, val: u8) {
+        let srst = val.get_bit(2);
+        if srst {
+            (self.reg.status).set_bit(reg::STATUS::BSY, true);
+        } else if self.reg.srst {
+            self.reset();
+        }
+
+        self.reg.srst = srst;
+        self.reg.nein = val.get_bit(1);
+        self.reg.hob = val.get_bit(7);
+
+        if self.reg.nein {
+            self.irq.clear();
+        }
+    }
+
+    /// Called by the I/O task once the standby timer expires. Returns `true`
+    /// if the drive should spin down.
+    fn standby_timer_expired(&mut self) -> bool {
+        let timeout = match self.standby_timer {
+            Some(timeout) => timeout,
+            None => return false,
+        };
+
+        // a command may have snuck in just as the timer expired
+        let idle = matches!(self.power, PowerMode::Active | PowerMode::Idle)
+            && matches!(self.state, IdeDriveState::Idle);
+        if !idle || Instant::now() - self.last_cmd < timeout {
+            return false;
+        }
+
+        debug!("IDE standby timer expired");
+        self.power = PowerMode::Standby;
+        true
+    }
+
+    /// Queue up a media access on the drive's I/O task.
+    ///
+    /// If the I/O task has exited (e.g: its executor was shut down), the
+    /// current command is aborted instead.
+    fn submit(&mut self, req: IoReq) -> MemResult<()> {
+        if self.io_tx.try_send(req).is_err() {
+            self.abort(reg::ERROR::ABRT);
+            return Err(ContractViolation {
+                msg: "IDE I/O task exited while drive is attached".into(),
+                severity: Error,
+                stub_val: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Mark the drive as busy while a media access is in-flight.
+    fn set_busy(&mut self) {
+        (self.reg.status)
+            .set_bit(reg::STATUS::BSY, true)
+            .set_bit(reg::STATUS::DRQ, false);
+        self.dmarq.clear();
+    }
+
+    /// Successfully complete the current command.
+    fn complete(&mut self) {
+        self.state = IdeDriveState::Idle;
+        self.xfer = Transfer::default();
+        (self.reg.status)
+            .set_bit(reg::STATUS::BSY, false)
+            .set_bit(reg::STATUS::DRDY, true)
+            .set_bit(reg::STATUS::DRQ, false);
+
+        self.assert_intrq();
+        self.dmarq.clear();
+    }
+
+    /// Abort the current command, reporting the provided `reg::ERROR` bit back
+    /// to the host.
+    fn abort(&mut self, error: usize) {
+        self.complete();
+        (self.reg.status).set_bit(reg::STATUS::ERR, true);
+        self.reg.error = *0u8.set_bit(error, true);
+    }
+
+    /// Abort the current transfer due to an I/O error on the underlying
+    /// blockdev, reporting the offending sector back to the host.
+    fn io_error(&mut self, lba: u64, e: io::Error, write: bool) {
+        if write {
+            // write errors aren't recoverable, so they're reported as device
+            // faults
+            self.abort(reg::ERROR::ABRT);
+            (self.reg.status).set_bit(reg::STATUS::DF, true);
+        } else {
+            self.abort(reg::ERROR::UNC);
+            self.smart.read_error(lba);
+        }
+        if self.reg.lba3_dev_head.get_bit(reg::DEVHEAD::L) {
+            self.set_lba_regs(lba, lba > MAX_LBA28);
+        }
+
+        warn!(
+            "I/O error while {} sector {}: {}",
+            if write { "writing" } else { "reading" },
+            lba,
+            e
+        );
+    }
+
+    /// Handle the result of a media access.
+    fn io_done(&mut self, gen: usize, done: IoDone) {
+        if gen != self.io_gen {
+            // the drive was reset while the access was in-flight
+            return;
+        }
+
+        match done {
+            IoDone::PowerOn => {
+                (self.reg.status)
+                    .set_bit(reg::STATUS::BSY, false)
+                    .set_bit(reg::STATUS::DRDY, true)
+                    .set_bit(reg::STATUS::DSC, true);
+            }
+            IoDone::SpinDown => self.complete(),
+            IoDone::Read(Err(e)) => self.io_error(self.xfer.lba, e, false),
+            IoDone::Read(Ok(data)) => {
+                self.smart.access();
+                self.iobuf.as_raw().copy_from_slice(&data[..]);
+                self.iobuf.new_transfer();
+
+                self.state = IdeDriveState::ReadReady;
+                (self.reg.status)
+                    .set_bit(reg::STATUS::BSY, false)
+                    .set_bit(reg::STATUS::DSC, true)
+                    .set_bit(reg::STATUS::DRDY, true)
+                    .set_bit(reg::STATUS::DRQ, true);
+
+                // PIO transfers fire an IRQ at the start of each DRQ data
+                // block, whereas DMA only fires a single IRQ at the end of the
+                // transfer
+                if self.xfer.dma {
+                    self.dmarq.assert();
+                } else if self.xfer.new_block {
+                    self.assert_intrq();
+                }
+            }
+            IoDone::Write { res: Err(e), .. } => self.io_error(self.xfer.lba, e, true),
+            IoDone::Write {
+                res: Ok(()),
+                reallocated,
+            } => {
+                self.smart.access();
+                if reallocated {
+                    debug!("IDE reallocated sector {}", self.xfer.lba);
+                    self.smart.reallocate(self.xfer.lba);
+                }
+
+                // check if there are no more sectors remaining
+                let end_of_block = self.xfer.advance();
+                if self.xfer.remaining == 0 {
+                    self.complete();
+                    return;
+                }
+
+                self.iobuf.new_transfer();
+                self.state = IdeDriveState::WriteReady;
+                (self.reg.status)
+                    .set_bit(reg::STATUS::DRQ, true)
+                    .set_bit(reg::STATUS::BSY, false);
+
+                // PIO transfers fire an IRQ once each DRQ data block has been
+                // written, whereas DMA only fires a single IRQ at the end of
+                // the transfer
+                if self.xfer.dma {
+                    self.dmarq.assert();
+                } else if end_of_block {
+                    self.assert_intrq();
+                }
+            }
+            IoDone::Verify(Err((lba, e))) => self.io_error(lba, e, false),
+            IoDone::Verify(Ok(())) => self.complete(),
+        }
+    }
+
+    /// Abort the command if `lba..lba+count` extends past the end of the disk.
+    fn check_range(&mut self, lba: u64, count: usize) -> MemResult<()> {
+        if lba + count as u64 > self.total_sectors {
+            self.abort(reg::ERROR::IDNF);
+            return Err(ContractViolation {
+                msg: format!(
+                    "tried to access sectors past the end of the disk (lba={}, count={})",
+                    lba, count
+                ),
+                severity: Info,
+                stub_val: None,
+            });
+        }
+        Ok(())
+    }
+
+    fn start_read(
+        &mut self,
+        lba: u64,
+        count: usize,
+        block_size: usize,
+        dma: bool,
+    ) -> MemResult<()> {
+        self.check_range(lba, count)?;
+        self.wake();
+
+        self.xfer = Transfer::new(lba, count, block_size, dma);
+        self.state = IdeDriveState::ReadAsyncLoad;
+        self.set_busy();
+        self.submit(IoReq::Read {
+            gen: self.io_gen,
+            lba,
+        })?;
+
+        Ok(())
+    }
+
+    fn start_write(
+        &mut self,
+        lba: u64,
+        count: usize,
+        block_size: usize,
+        dma: bool,
+    ) -> MemResult<()> {
+        self.check_range(lba, count)?;
+        self.wake();
+
+        // unlike reads, there's no IRQ before the first DRQ data block
+        self.xfer = Transfer::new(lba, count, block_size, dma);
+        self.iobuf.new_transfer();
+        self.state = IdeDriveState::WriteReady;
+        (self.reg.status)
+            .set_bit(reg::STATUS::BSY, false)
+            .set_bit(reg::STATUS::DSC, true)
+            .set_bit(reg::STATUS::DRDY, true)
+            .set_bit(reg::STATUS::DRQ, true);
+
+        if dma {
+            self.dmarq.assert();
+        }
+
+        Ok(())
+    }
+
+    /// Read the sectors without transferring any data to the host.
+    fn verify(&mut self, lba: u64, count: usize) -> MemResult<()> {
+        self.check_range(lba, count)?;
+        self.wake();
+
+        self.xfer = Transfer::new(lba, count, count, false);
+        self.set_busy();
+        self.submit(IoReq::Verify {
+            gen: self.io_gen,
+            lba,
+            count,
+        })?;
+
+        Ok(())
+    }
+
     fn data_read8(&mut self) -> MemResult<u8> {
         match self.state {
             IdeDriveState::ReadReady => {}
             _ => {
-                // FIXME: this should set some error bits
-                return Err(Fatal(format!(
-                    "cannot read data while drive is in an invalid state: {:?}",
-                    self.state
-                )));
+                return Err(ContractViolation {
+                    msg: format!(
+                        "cannot read data while drive is in an invalid state: {:?}",
+                        self.state
+                    ),
+                    severity: Warn,
+                    stub_val: Some(0xff),
+                });
             }
         }
 
@@ -342,46 +1077,21 @@ impl IdeDrive {
             .ok_or_else(|| Fatal("assert: read past end of IDE iobuf".into()))?;
 
         if self.iobuf.is_done_transfer() {
-            // check if there are no more sectors remaining
-            self.remaining_sectors -= 1; // TODO: this varies under `ReadMultiple`
-            if self.remaining_sectors == 0 {
-                self.state = IdeDriveState::Idle;
-                (self.reg.status)
-                    .set_bit(reg::STATUS::DRDY, true)
-                    .set_bit(reg::STATUS::DRQ, false)
-                    .set_bit(reg::STATUS::BSY, false);
-
-                self.assert_intrq();
-                self.dmarq.clear();
-            } else {
-                // the next sector needs to be loaded
-                (self.reg.status)
-                    .set_bit(reg::STATUS::DRQ, false)
-                    .set_bit(reg::STATUS::BSY, true);
-
-                self.state = IdeDriveState::ReadAsyncLoad;
-
-                futures_executor::block_on(async {
-                    // TODO: async this!
-                    if let Err(e) = self.blockdev.read_exact(self.iobuf.as_raw()).await {
-                        // XXX: actually set error bits
-                        return Err(e);
-                    }
-
-                    self.iobuf.new_transfer();
-                    self.state = IdeDriveState::ReadReady;
-                    (self.reg.status)
-                        .set_bit(reg::STATUS::DRQ, true)
-                        .set_bit(reg::STATUS::BSY, false);
+            self.xfer.advance();
 
-                    // DMA only fires a single IRQ at the end of the transfer
-                    if !self.cfg.transfer_mode.is_dma() {
-                        self.assert_intrq();
-                    }
-
-                    Ok(())
-                })?;
+            // check if there are no more sectors remaining
+            if self.xfer.remaining == 0 {
+                self.complete();
+                return Ok(ret);
             }
+
+            // the next sector needs to be loaded
+            self.state = IdeDriveState::ReadAsyncLoad;
+            self.set_busy();
+            self.submit(IoReq::Read {
+                gen: self.io_gen,
+                lba: self.xfer.lba,
+            })?;
         }
 
         Ok(ret)
@@ -391,11 +1101,14 @@ impl IdeDrive {
         match self.state {
             IdeDriveState::WriteReady => {}
             _ => {
-                // FIXME: this should set some error bits
-                return Err(Fatal(format!(
-                    "cannot write data while drive is in an invalid state: {:?}",
-                    self.state
-                )));
+                return Err(ContractViolation {
+                    msg: format!(
+                        "cannot write data while drive is in an invalid state: {:?}",
+                        self.state
+                    ),
+                    severity: Warn,
+                    stub_val: None,
+                });
             }
         }
 
@@ -405,45 +1118,15 @@ impl IdeDrive {
 
         // check if the sector needs to be flushed to disk
         if self.iobuf.is_done_transfer() {
-            assert!(self.remaining_sectors != 0);
-
-            (self.reg.status)
-                .set_bit(reg::STATUS::DRQ, false)
-                .set_bit(reg::STATUS::BSY, true);
+            assert!(self.xfer.remaining != 0);
 
             self.state = IdeDriveState::WriteAsyncFlush;
-
-            // TODO: async this!
-            futures_executor::block_on(async {
-                if let Err(e) = self.blockdev.write_all(self.iobuf.as_raw()).await {
-                    // XXX: actually set error bits
-                    return Err(e);
-                }
-
-                self.iobuf.new_transfer();
-                self.state = IdeDriveState::WriteReady;
-                (self.reg.status)
-                    .set_bit(reg::STATUS::DRQ, true)
-                    .set_bit(reg::STATUS::BSY, false);
-
-                // DMA only fires a single IRQ at the end of the transfer
-                if !self.cfg.transfer_mode.is_dma() {
-                    self.assert_intrq();
-                }
-
-                // check if there are no more sectors remaining
-                self.remaining_sectors -= 1; // FIXME: this varies under `WriteMultiple`
-                if self.remaining_sectors == 0 {
-                    self.state = IdeDriveState::Idle;
-                    (self.reg.status)
-                        .set_bit(reg::STATUS::DRDY, true)
-                        .set_bit(reg::STATUS::DRQ, false);
-
-                    self.assert_intrq();
-                    self.dmarq.clear();
-                }
-
-                Ok(())
+            self.set_busy();
+            let data = Box::new(*self.iobuf.as_raw());
+            self.submit(IoReq::Write {
+                gen: self.io_gen,
+                lba: self.xfer.lba,
+                data,
             })?;
         }
 
@@ -465,8 +1148,41 @@ impl IdeDrive {
             });
         }
 
+        if self.power == PowerMode::Sleep {
+            return Err(ContractViolation {
+                msg: format!(
+                    "ignored IDE cmd {:#04x?} while the drive is asleep (only a reset wakes it)",
+                    cmd
+                ),
+                severity: Warn,
+                stub_val: None,
+            });
+        }
+
+        self.num_cmds += 1;
+        if matches!(self.vanish_after, Some(n) if self.num_cmds > n) {
+            warn!("IDE drive vanished after {} commands", self.num_cmds - 1);
+            self.vanished = true;
+            self.io_gen = self.io_gen.wrapping_add(1);
+            self.irq.clear();
+            self.dmarq.clear();
+            return Err(ContractViolation {
+                msg: format!("IDE drive vanished (ignored cmd {:#04x?})", cmd),
+                severity: Warn,
+                stub_val: None,
+            });
+        }
+
+        let spinning = matches!(self.power, PowerMode::Active | PowerMode::Idle);
+        self.smart.update(spinning);
+        self.last_cmd = Instant::now();
+        if self.standby_timer.is_some() {
+            self.submit(IoReq::StandbyTimer(self.standby_timer))?;
+        }
+
         (self.reg.status)
             .set_bit(reg::STATUS::BSY, true)
+            .set_bit(reg::STATUS::DF, false)
             .set_bit(reg::STATUS::ERR, false);
         self.reg.error = 0;
 
@@ -476,21 +1192,39 @@ impl IdeDrive {
             return Ok(());
         }
 
+        // reject commands from feature sets the drive doesn't support
+        let unsupported = match IdeCmd::try_from(cmd) {
+            Ok(cmd) if cmd.is_ext() && !self.profile.lba48 => Some("48-bit addressing"),
+            Ok(cmd) if cmd.is_dma() && !self.profile.dma => Some("DMA"),
+            Ok(IdeCmd::Smart) if !self.profile.smart => Some("SMART"),
+            _ => None,
+        };
+        if let Some(feature) = unsupported {
+            self.abort(reg::ERROR::ABRT);
+            return Err(ContractViolation {
+                msg: format!(
+                    "IDE cmd {:#04x?} requires {}, which isn't supported by the drive (aborted)",
+                    cmd, feature
+                ),
+                severity: Info,
+                stub_val: None,
+            });
+        }
+
         use IdeCmd::*;
         match IdeCmd::try_from(cmd) {
             Ok(IdentifyDevice) => {
-                let len = self.blockdev.len();
+                let total_sectors = self.total_sectors;
+                let track_sectors =
+                    self.profile.heads as u64 * self.profile.sectors_per_track as u64;
 
                 // fill the iobuf with identification info
                 let drive_meta = identify::IdeDriveMeta {
-                    total_sectors: len / 512,
-                    cylinders: (len / (NUM_HEADS * NUM_SECTORS * 512) as u64) as u16,
-                    heads: NUM_HEADS as u16,     // ?
-                    sectors: NUM_SECTORS as u16, // ?
-                    // TODO: generate these strings though blockdev interface?
-                    serial: b"serials_are_4_chumps",
-                    fw_version: b"0",
-                    model: b"clickydrive",
+                    total_sectors,
+                    // CHS addressing tops out at 16383 cylinders
+                    cylinders: (total_sectors / track_sectors).min(16383) as u16,
+                    multsect: self.cfg.multi_sect,
+                    profile: &self.profile,
                 };
 
                 // won't panic, since `hd_driveid` is statically asserted to be
@@ -500,7 +1234,7 @@ impl IdeDrive {
 
                 self.iobuf.new_transfer();
                 self.state = IdeDriveState::ReadReady;
-                self.remaining_sectors = 1;
+                self.xfer = Transfer::new(0, 1, 1, false);
 
                 (self.reg.status)
                     .set_bit(reg::STATUS::BSY, false)
@@ -510,181 +1244,89 @@ impl IdeDrive {
 
                 Ok(())
             }
-            Ok(ReadMultiple) => {
+            Ok(ReadSectors) | Ok(ReadSectorsNoRetry) | Ok(ReadSectorsExt) => {
+                let (lba, count) = self.get_lba_and_count(cmd == ReadSectorsExt as u8)?;
+                self.start_read(lba, count, 1, false)
+            }
+            Ok(ReadMultiple) | Ok(ReadMultipleExt) => {
                 if self.cfg.multi_sect == 0 {
-                    // TODO?: use the ATA abort mechanism instead of loudly failing
+                    self.abort(reg::ERROR::ABRT);
                     return Err(ContractViolation {
                         msg: "Called ReadMultiple before successful call to SetMultipleMode".into(),
-                        severity: Error,
+                        severity: Warn,
                         stub_val: None,
                     });
                 }
 
-                if self.cfg.multi_sect != 1 {
-                    Err(Fatal("(stubbed Multi-Sector support) cannot ReadMultiple (0xc4) with multi_sect > 1".into()))
-                } else {
-                    (self.reg.status).set_bit(reg::STATUS::BSY, false);
-                    self.exec_cmd(ReadSectors as u8)
-                }
+                let (lba, count) = self.get_lba_and_count(cmd == ReadMultipleExt as u8)?;
+                self.start_read(lba, count, self.cfg.multi_sect as usize, false)
             }
-            Ok(ReadDMA) | Ok(ReadDMANoRetry) => {
+            Ok(ReadDMA) | Ok(ReadDMANoRetry) | Ok(ReadDMAExt) => {
                 if !self.cfg.transfer_mode.is_dma() {
-                    // TODO?: use the ATA abort mechanism instead of loudly failing
+                    self.abort(reg::ERROR::ABRT);
                     return Err(ContractViolation {
                         msg: "Called ReadDMA without setting DMA transfer mode".into(),
-                        severity: Error,
+                        severity: Warn,
                         stub_val: None,
                     });
                 }
 
                 // basically just ReadSectors, except it only fires a _single_
                 // IRQ at the end of the transfer, and asserts dmarq
-                self.dmarq.assert();
-                (self.reg.status).set_bit(reg::STATUS::BSY, false);
-                self.exec_cmd(ReadSectors as u8)
+                let (lba, count) = self.get_lba_and_count(cmd == ReadDMAExt as u8)?;
+                self.start_read(lba, count, count, true)
             }
-            Ok(ReadSectors) | Ok(ReadSectorsNoRetry) => {
-                let offset = match self.get_sector_offset() {
-                    Some(offset) => offset,
-                    None => {
-                        // XXX: actually set error bits
-                        return Err(Fatal("invalid offset".into()));
-                    }
-                };
-
-                self.state = IdeDriveState::ReadAsyncLoad;
-                futures_executor::block_on(async {
-                    // Seek into the blockdev
-                    if let Err(e) = self.blockdev.seek(io::SeekFrom::Start(offset * 512)).await {
-                        // XXX: actually set error bits
-                        return Err(e);
-                    }
-
-                    // Read the first sector from the blockdev
-                    // TODO: this should be done asynchronously, with a separate task/thread
-                    // notifying the IDE device when the read is completed.
-                    if let Err(e) = self.blockdev.read_exact(self.iobuf.as_raw()).await {
-                        // XXX: actually set error bits
-                        return Err(e);
-                    }
-
-                    self.remaining_sectors = if self.reg.sector_count == 0 {
-                        256
-                    } else {
-                        self.reg.sector_count as usize
-                    };
-
-                    self.iobuf.new_transfer();
-                    self.state = IdeDriveState::ReadReady;
-                    (self.reg.status)
-                        .set_bit(reg::STATUS::BSY, false)
-                        .set_bit(reg::STATUS::DSC, true)
-                        .set_bit(reg::STATUS::DRDY, true)
-                        .set_bit(reg::STATUS::DRQ, true);
-
-                    // TODO: fire interrupt?
-                    if !self.cfg.transfer_mode.is_dma() {
-                        self.assert_intrq();
-                    }
-
-                    Ok(())
-                })?;
-
-                Ok(())
+            Ok(ReadVerifySectors) | Ok(ReadVerifySectorsNoRetry) | Ok(ReadVerifySectorsExt) => {
+                let (lba, count) = self.get_lba_and_count(cmd == ReadVerifySectorsExt as u8)?;
+                self.verify(lba, count)
             }
-            Ok(StandbyImmediate) | Ok(StandbyImmediateAlt) => {
-                // I mean, it's a virtual disk, there is no "spin up / spin down"
-                self.reg.status.set_bit(reg::STATUS::BSY, false);
-
-                self.assert_intrq();
-                Ok(())
+            Ok(WriteSectors) | Ok(WriteSectorsNoRetry) | Ok(WriteSectorsExt) => {
+                let (lba, count) = self.get_lba_and_count(cmd == WriteSectorsExt as u8)?;
+                self.start_write(lba, count, 1, false)
             }
-            Ok(WriteMultiple) => {
+            Ok(WriteMultiple) | Ok(WriteMultipleExt) => {
                 if self.cfg.multi_sect == 0 {
-                    // TODO?: use the ATA abort mechanism instead of loudly failing
+                    self.abort(reg::ERROR::ABRT);
                     return Err(ContractViolation {
                         msg: "Called WriteMultiple before successful call to SetMultipleMode"
                             .into(),
-                        severity: Error,
+                        severity: Warn,
                         stub_val: None,
                     });
                 }
 
-                if self.cfg.multi_sect != 1 {
-                    Err(Fatal("(stubbed Multi-Sector support) cannot WriteMultiple (0xc4) with multi_sect > 1".into()))
-                } else {
-                    (self.reg.status).set_bit(reg::STATUS::BSY, false);
-                    self.exec_cmd(WriteSectors as u8)
-                }
+                let (lba, count) = self.get_lba_and_count(cmd == WriteMultipleExt as u8)?;
+                self.start_write(lba, count, self.cfg.multi_sect as usize, false)
             }
-            Ok(WriteDMA) | Ok(WriteDMANoRetry) => {
+            Ok(WriteDMA) | Ok(WriteDMANoRetry) | Ok(WriteDMAExt) => {
                 if !self.cfg.transfer_mode.is_dma() {
-                    // TODO?: use the ATA abort mechanism instead of loudly failing
+                    self.abort(reg::ERROR::ABRT);
                     return Err(ContractViolation {
                         msg: "Called WriteDMA without setting DMA transfer mode".into(),
-                        severity: Error,
+                        severity: Warn,
                         stub_val: None,
                     });
                 }
 
                 // basically just WriteSectors, except it only fires a _single_
                 // IRQ at the end of the transfer, and asserts dmarq
-                self.dmarq.assert();
-                (self.reg.status).set_bit(reg::STATUS::BSY, false);
-                self.exec_cmd(WriteSectors as u8)
-            }
-            Ok(WriteSectors) | Ok(WriteSectorsNoRetry) => {
-                // NOTE: this code is somewhat UNTESTED
-
-                let offset = match self.get_sector_offset() {
-                    Some(offset) => offset,
-                    None => {
-                        // XXX: actually set error bits
-                        return Err(Fatal("invalid offset".into()));
-                    }
-                };
-
-                self.state = IdeDriveState::WriteAsyncFlush;
-                futures_executor::block_on(async {
-                    // Seek into the blockdev
-                    if let Err(e) = self.blockdev.seek(io::SeekFrom::Start(offset * 512)).await {
-                        // XXX: actually set error bits
-                        return Err(e);
-                    }
-
-                    self.remaining_sectors = if self.reg.sector_count == 0 {
-                        256
-                    } else {
-                        self.reg.sector_count as usize
-                    };
-
-                    self.iobuf.new_transfer();
-                    self.state = IdeDriveState::WriteReady;
-                    (self.reg.status)
-                        .set_bit(reg::STATUS::BSY, false)
-                        .set_bit(reg::STATUS::DSC, true)
-                        .set_bit(reg::STATUS::DRDY, false)
-                        .set_bit(reg::STATUS::DRQ, true);
-
-                    // TODO: fire interrupt?
-
-                    Ok(())
-                })?;
-
-                Ok(())
+                let (lba, count) = self.get_lba_and_count(cmd == WriteDMAExt as u8)?;
+                self.start_write(lba, count, count, true)
             }
 
             Ok(SetMultipleMode) => {
-                self.cfg.multi_sect = self.reg.sector_count;
-
-                // TODO: implement proper multi-sector support
-                if self.cfg.multi_sect > 1 {
-                    return Err(Fatal (
-                        "(stubbed Multi-Sector support) SetMultipleMode (0xc6) must be either 0 or 1".into(),
-                    ));
+                let multi_sect = self.reg.sector_count;
+                if multi_sect > self.profile.max_multsect || !(multi_sect == 0 || multi_sect.is_power_of_two()) {
+                    self.abort(reg::ERROR::ABRT);
+                    return Err(ContractViolation {
+                        msg: format!("unsupported SetMultipleMode (0xc6) count: {}", multi_sect),
+                        severity: Info,
+                        stub_val: None,
+                    });
                 }
 
-                (self.reg.status).set_bit(reg::STATUS::BSY, false);
+                self.cfg.multi_sect = multi_sect;
+                self.complete();
                 Ok(())
             }
 
@@ -711,43 +1353,81 @@ impl IdeDrive {
                         => {}
 
                     other => {
-                        return Err(Fatal(format!(
-                            "SetFeatures (0xef) subcommand not implemented: {:#04x?}",
-                            other
-                        )))
+                        self.abort(reg::ERROR::ABRT);
+                        return Err(ContractViolation {
+                            msg: format!(
+                                "unsupported SetFeatures (0xef) subcommand (aborted): {:#04x?}",
+                                other
+                            ),
+                            severity: Warn,
+                            stub_val: None,
+                        });
                     }
                 };
 
-                (self.reg.status)
-                    .set_bit(reg::STATUS::BSY, false)
-                    .set_bit(reg::STATUS::DRDY, true);
-
-                self.assert_intrq();
+                self.complete();
                 Ok(())
             }
 
-            Ok(FlushCache) => {
+            Ok(FlushCache) | Ok(FlushCacheExt) => {
                 // uhh, we don't implement caching
-                (self.reg.status)
-                    .set_bit(reg::STATUS::BSY, false)
-                    .set_bit(reg::STATUS::DRDY, true)
-                    .set_bit(reg::STATUS::DRQ, false);
-
+                self.complete();
                 Ok(())
             }
 
+            Ok(IdleImmediate) | Ok(IdleImmediateAlt) => {
+                self.power = PowerMode::Idle;
+                self.complete();
+                Ok(())
+            }
+            Ok(Idle) | Ok(IdleAlt) => {
+                self.standby_timer = standby_timer_period(self.reg.sector_count);
+                self.submit(IoReq::StandbyTimer(self.standby_timer))?;
+                self.power = PowerMode::Idle;
+                self.complete();
+                Ok(())
+            }
+            // the drive stays busy until it's finished spinning down
+            Ok(StandbyImmediate) | Ok(StandbyImmediateAlt) => {
+                self.power = PowerMode::Standby;
+                self.submit(IoReq::SpinDown { gen: self.io_gen })
+            }
+            Ok(Standby) | Ok(StandbyAlt) => {
+                self.standby_timer = standby_timer_period(self.reg.sector_count);
+                self.submit(IoReq::StandbyTimer(self.standby_timer))?;
+                self.power = PowerMode::Standby;
+                self.submit(IoReq::SpinDown { gen: self.io_gen })
+            }
+            Ok(CheckPowerMode) | Ok(CheckPowerModeAlt) => {
+                self.reg.sector_count = match self.power {
+                    PowerMode::Standby => 0x00,
+                    PowerMode::Idle => 0x80,
+                    PowerMode::Active | PowerMode::Sleep => 0xff,
+                };
+                self.complete();
+                Ok(())
+            }
             Ok(Sleep) | Ok(SleepAlt) => {
-                // uhh, it's an emulated drive.
-                // just assert the irq and go on our merry way
-                (self.reg.status).set_bit(reg::STATUS::BSY, false);
+                // the IRQ is still fired once the drive has spun down
+                self.power = PowerMode::Sleep;
+                self.submit(IoReq::SpinDown { gen: self.io_gen })
+            }
 
-                self.assert_intrq();
+            Ok(ExecuteDeviceDiagnostic) => {
+                self.set_signature();
+                self.complete();
+                Ok(())
+            }
+
+            Ok(ReadNativeMaxAddress) | Ok(ReadNativeMaxAddressExt) => {
+                let max_lba = self.total_sectors.saturating_sub(1);
+                self.set_lba_regs(max_lba, cmd == ReadNativeMaxAddressExt as u8);
+                self.complete();
                 Ok(())
             }
 
             Ok(InitializeDriveParameters) => {
-                (self.reg.status).set_bit(reg::STATUS::BSY, false);
-                self.assert_intrq();
+                self.complete();
                 Ok(())
             }
 
@@ -755,12 +1435,7 @@ impl IdeDrive {
                 // The SMART command set is keyed off a signature in the
                 // cylinder registers -- without it the command must abort.
                 if self.reg.lba1_cyl_lo != 0x4f || self.reg.lba2_cyl_hi != 0xc2 {
-                    (self.reg.status)
-                        .set_bit(reg::STATUS::BSY, false)
-                        .set_bit(reg::STATUS::DRDY, true)
-                        .set_bit(reg::STATUS::ERR, true);
-                    self.reg.error = *0u8.set_bit(reg::ERROR::ABRT, true);
-                    self.assert_intrq();
+                    self.abort(reg::ERROR::ABRT);
                     return Err(ContractViolation {
                         msg: format!(
                             "SMART (0xb0) without the 0xc24f signature (cyl={:02x}{:02x})",
@@ -785,10 +1460,16 @@ impl IdeDrive {
                     }
                     // RETURN STATUS. The drive reports health back through the
                     // cylinder registers: the unchanged 0xc24f signature means
-                    // "no threshold exceeded", i.e. a healthy drive.
+                    // "no threshold exceeded", i.e. a healthy drive, whereas
+                    // 0x2cf4 means the drive is about to fail.
                     0xda => {
-                        self.reg.lba1_cyl_lo = 0x4f;
-                        self.reg.lba2_cyl_hi = 0xc2;
+                        if self.smart.threshold_exceeded() {
+                            self.reg.lba1_cyl_lo = 0xf4;
+                            self.reg.lba2_cyl_hi = 0x2c;
+                        } else {
+                            self.reg.lba1_cyl_lo = 0x4f;
+                            self.reg.lba2_cyl_hi = 0xc2;
+                        }
                         (self.reg.status)
                             .set_bit(reg::STATUS::BSY, false)
                             .set_bit(reg::STATUS::DRDY, true);
@@ -798,45 +1479,15 @@ impl IdeDrive {
                     // READ DATA / READ ATTRIBUTE THRESHOLDS. Both hand back a
                     // 512 byte structure.
                     0xd0 | 0xd1 => {
-                        use smart::*;
-                        let mut smart = Smart::new();
-                        const ATTRIBUTES: &[(AttributeId, u16, u8, u8, u64, u8)] = &[
-                            (AttributeId::ReadErrorRate, 0x000f, 100, 100, 0, 51),
-                            (AttributeId::SpinUpTime, 0x0027, 100, 100, 1200, 1),
-                            (AttributeId::StartStopCount, 0x0032, 100, 100, 128, 0),
-                            (AttributeId::ReallocatedSectorCount, 0x0033, 100, 100, 0, 5),
-                            (AttributeId::SeekErrorRate, 0x000f, 100, 100, 0, 51),
-                            (AttributeId::PowerOnHours, 0x0032, 100, 100, 42, 0),
-                            (AttributeId::SpinRetryCount, 0x0013, 100, 100, 0, 51),
-                            (AttributeId::PowerCycleCount, 0x0032, 100, 100, 64, 0),
-                            (AttributeId::UnexpectedPowerOffCount, 0x0032, 100, 100, 12, 0),
-                            (AttributeId::Temperature, 0x0022, 100, 100, 30, 0),
-                        ];
-                        for (id, flags, current, worst, raw, threshold) in ATTRIBUTES {
-                            if let Err(e) = smart.add_attribute(Attribute {
-                                id: *id as u8,
-                                flags: *flags,
-                                current: *current,
-                                worst: *worst,
-                                raw: *raw,
-                                threshold: *threshold,
-                            }) {
-                                panic!("Error while adding attribute to SMART: {:?}", e);
-                            }
-                        }
-
-                        match self.reg.feature {
-                            0xd0 => {
-                                self.iobuf.as_raw().copy_from_slice(&smart.serialize_data());
-                            },
-                            0xd1 => {
-                                self.iobuf.as_raw().copy_from_slice(&smart.serialize_threshold());
-                            },
+                        let data = match self.reg.feature {
+                            0xd0 => self.smart.serialize_data(),
+                            0xd1 => self.smart.serialize_threshold(),
                             _ => unreachable!("unknown feature"),
-                        }
+                        };
+                        self.iobuf.as_raw().copy_from_slice(&data);
                         self.iobuf.new_transfer();
                         self.state = IdeDriveState::ReadReady;
-                        self.remaining_sectors = 1;
+                        self.xfer = Transfer::new(0, 1, 1, false);
 
                         (self.reg.status)
                             .set_bit(reg::STATUS::BSY, false)
@@ -846,12 +1497,7 @@ impl IdeDrive {
                         Ok(())
                     }
                     other => {
-                        (self.reg.status)
-                            .set_bit(reg::STATUS::BSY, false)
-                            .set_bit(reg::STATUS::DRDY, true)
-                            .set_bit(reg::STATUS::ERR, true);
-                        self.reg.error = *0u8.set_bit(reg::ERROR::ABRT, true);
-                        self.assert_intrq();
+                        self.abort(reg::ERROR::ABRT);
                         Err(ContractViolation {
                             msg: format!("unsupported SMART subcommand (aborted): {:#04x?}", other),
                             severity: Warn,
@@ -862,17 +1508,9 @@ impl IdeDrive {
             }
 
             Err(_) => {
-                (self.reg.status)
-                    .set_bit(reg::STATUS::BSY, false)
-                    .set_bit(reg::STATUS::DRDY, true)
-                    .set_bit(reg::STATUS::DRQ, false)
-                    .set_bit(reg::STATUS::ERR, true);
-                self.reg.error = *0u8.set_bit(reg::ERROR::ABRT, true);
-
-                self.assert_intrq();
-
+                self.abort(reg::ERROR::ABRT);
                 Err(ContractViolation {
-                    msg: format!("unknown IDE command: {:#04x?}", cmd),
+                    msg: format!("unknown IDE command (aborted): {:#04x?}", cmd),
                     severity: Warn,
                     stub_val: None,
                 })
@@ -887,23 +1525,40 @@ impl IdeDrive {
 pub struct IdeController {
     common_irq_line: irq::Sender,
     dmarq: irq::Sender,
+    task_spawner: Spawner,
 
     selected_device: IdeIdx,
-    ide0: Option<IdeDrive>,
-    ide1: Option<IdeDrive>,
+    ide0: Option<AttachedDrive>,
+    ide1: Option<AttachedDrive>,
+}
+
+/// A drive, along with the I/O task servicing its media accesses.
+#[derive(Debug)]
+struct AttachedDrive {
+    drive: Arc<Mutex<IdeDrive>>,
+    io_task: RemoteHandle<()>,
+}
+
+impl AttachedDrive {
+    fn lock(&self) -> MutexGuard<'_, IdeDrive> {
+        self.drive.lock().unwrap()
+    }
 }
 
 /// It'd be nice if this was a method, but it makes borrowing other fields of
 /// (&mut self) as pain, since the borrow checker doesn't work across function
 /// boundaries.
 ///
-/// Returns MemResult<&mut IdeDrive>
+/// Returns MemResult<MutexGuard<IdeDrive>>
 macro_rules! selected_ide {
     ($self:ident) => {
         match $self.selected_device {
-            IdeIdx::IDE0 => $self.ide0.as_mut(),
-            IdeIdx::IDE1 => $self.ide1.as_mut(),
+            IdeIdx::IDE0 => $self.ide0.as_ref(),
+            IdeIdx::IDE1 => $self.ide1.as_ref(),
         }
+        .map(AttachedDrive::lock)
+        // a vanished drive is indistinguishable from a disconnected one
+        .filter(|ide| !ide.vanished)
         // not a real error. The OS might just be probing for IDE devices.
         .ok_or(ContractViolation {
             msg: format!(
@@ -917,47 +1572,134 @@ macro_rules! selected_ide {
 }
 
 impl IdeController {
-    pub fn new(irq: irq::Sender, dmarq: irq::Sender) -> IdeController {
+    pub fn new(irq: irq::Sender, dmarq: irq::Sender, task_spawner: Spawner) -> IdeController {
         IdeController {
             common_irq_line: irq,
             dmarq,
+            task_spawner,
             selected_device: IdeIdx::IDE0,
             ide0: None,
             ide1: None,
         }
     }
 
-    /// Attach a block device to the IDE controller. Returns the
+    /// Attach a block device to the IDE controller, detaching the
     /// previously-attached block device (if applicable).
-    pub fn attach(
-        &mut self,
-        idx: IdeIdx,
-        blockdev: Box<dyn BlockDev>,
-    ) -> Option<Box<dyn BlockDev>> {
-        let old_drive = self.detach(idx);
+    pub fn attach(&mut self, idx: IdeIdx, blockdev: Box<dyn BlockDev>) {
+        self.detach(idx);
+
+        let (io_tx, io_rx) = async_channel::unbounded();
+        let drive = Arc::new(Mutex::new(IdeDrive::new(
+            self.common_irq_line.clone(),
+            self.dmarq.clone(),
+            blockdev.len() / 512,
+            io_tx,
+        )));
+
+        let (io_task, io_task_handle) =
+            io_task(Arc::downgrade(&drive), blockdev, io_rx).remote_handle();
+        self.task_spawner
+            .spawn(io_task)
+            .expect("failed to spawn IDE I/O task");
 
         let ide = match idx {
             IdeIdx::IDE0 => &mut self.ide0,
             IdeIdx::IDE1 => &mut self.ide1,
         };
 
-        *ide = Some(IdeDrive::new(
-            self.common_irq_line.clone(),
-            self.dmarq.clone(),
-            blockdev,
-        ));
-        old_drive
+        *ide = Some(AttachedDrive {
+            drive,
+            io_task: io_task_handle,
+        });
     }
 
-    /// Detaches a block device from the IDE drive. Returns the
-    /// previously-attached block device (if applicable).
-    pub fn detach(&mut self, idx: IdeIdx) -> Option<Box<dyn BlockDev>> {
+    /// Detaches a block device from the IDE drive.
+    ///
+    /// Any in-flight media access is allowed to finish in the background,
+    /// after which the block device is dropped.
+    pub fn detach(&mut self, idx: IdeIdx) {
         let ide = match idx {
             IdeIdx::IDE0 => &mut self.ide0,
             IdeIdx::IDE1 => &mut self.ide1,
         };
 
-        ide.take().map(|ide| ide.blockdev)
+        if let Some(AttachedDrive { drive, io_task }) = ide.take() {
+            // dropping the drive closes the I/O task's request channel,
+            // signaling it to shut down
+            drop(drive);
+            io_task.forget();
+        }
+    }
+
+    /// Set the identity and capabilities reported by an IDE drive.
+    pub fn set_profile(&mut self, idx: IdeIdx, profile: IdeDriveProfile) {
+        let ide = match idx {
+            IdeIdx::IDE0 => &self.ide0,
+            IdeIdx::IDE1 => &self.ide1,
+        };
+
+        if let Some(ide) = ide.as_ref() {
+            ide.lock().set_profile(profile)
+        }
+    }
+
+    /// Set the initial state of an IDE drive's SMART attributes.
+    pub fn set_smart(&mut self, idx: IdeIdx, cfg: SmartConfig) {
+        let ide = match idx {
+            IdeIdx::IDE0 => &self.ide0,
+            IdeIdx::IDE1 => &self.ide1,
+        };
+
+        if let Some(ide) = ide.as_ref() {
+            ide.lock().smart = SmartModel::new(cfg)
+        }
+    }
+
+    /// Inject faults into an IDE drive (e.g: unreadable sectors), replacing
+    /// any previously injected faults.
+    pub fn set_faults(&mut self, idx: IdeIdx, faults: IdeFaults) {
+        let ide = match idx {
+            IdeIdx::IDE0 => &self.ide0,
+            IdeIdx::IDE1 => &self.ide1,
+        };
+
+        if let Some(ide) = ide.as_ref() {
+            if let Err(e) = ide.lock().set_faults(faults) {
+                warn!("failed to set IDE faults: {:?}", e)
+            }
+        }
+    }
+
+    /// Set (or clear) the timing model used to delay an IDE drive's media
+    /// accesses.
+    ///
+    /// Enabling the timing model power-cycles the drive, which then remains
+    /// busy until it's finished spinning up.
+    pub fn set_timing(&mut self, idx: IdeIdx, timing: Option<IdeTiming>) {
+        let ide = match idx {
+            IdeIdx::IDE0 => &self.ide0,
+            IdeIdx::IDE1 => &self.ide1,
+        };
+
+        let mut ide = match ide.as_ref() {
+            Some(ide) => ide.lock(),
+            None => return,
+        };
+
+        let power_on = timing.is_some();
+        let mut res = ide.submit(IoReq::SetTiming(timing));
+        if power_on && res.is_ok() {
+            ide.reset();
+            (ide.reg.status)
+                .set_bit(reg::STATUS::BSY, true)
+                .set_bit(reg::STATUS::DRDY, false);
+            let gen = ide.io_gen;
+            res = ide.submit(IoReq::PowerOn { gen });
+        }
+
+        if let Err(e) = res {
+            warn!("failed to set IDE timing model: {:?}", e)
+        }
     }
 
     /// Check if an IDE drive is currently asserting an IRQ.
@@ -968,7 +1710,7 @@ impl IdeController {
         };
 
         ide.as_ref()
-            .map(|ide| ide.irq.is_asserting())
+            .map(|ide| ide.lock().irq.is_asserting())
             .unwrap_or(false)
     }
 
@@ -980,7 +1722,7 @@ impl IdeController {
         };
 
         if let Some(ide) = ide.as_mut() {
-            ide.irq.clear()
+            ide.lock().irq.clear()
         }
     }
 
@@ -992,7 +1734,7 @@ impl IdeController {
     pub fn read16(&mut self, reg: IdeReg) -> MemResult<u16> {
         match reg {
             IdeReg::Data => {
-                let ide = selected_ide!(self)?;
+                let mut ide = selected_ide!(self)?;
 
                 let val = ide.data_read8()?;
                 let ret = if ide.cfg.eightbit {
@@ -1016,7 +1758,7 @@ impl IdeController {
     pub fn write16(&mut self, reg: IdeReg, val: u16) -> MemResult<()> {
         match reg {
             IdeReg::Data => {
-                let ide = selected_ide!(self)?;
+                let mut ide = selected_ide!(self)?;
 
                 if ide.cfg.eightbit {
                     let val = val.trunc_to_u8()?;
@@ -1036,11 +1778,15 @@ impl IdeController {
     pub fn read8(&mut self, reg: IdeReg) -> MemResult<u8> {
         use IdeReg::*;
 
-        let ide = selected_ide!(self)?;
+        let mut ide = selected_ide!(self)?;
 
         match reg {
             Data => ide.data_read8(),
             Error | Features => Ok(ide.reg.error),
+            SectorCount if ide.reg.hob => Ok(ide.reg.hob_sector_count),
+            SectorNo | Lba0 if ide.reg.hob => Ok(ide.reg.hob_lba0),
+            CylinderLo | Lba1 if ide.reg.hob => Ok(ide.reg.hob_lba1),
+            CylinderHi | Lba2 if ide.reg.hob => Ok(ide.reg.hob_lba2),
             SectorCount => Ok(ide.reg.sector_count),
             SectorNo | Lba0 => Ok(ide.reg.lba0_sector_no),
             CylinderLo | Lba1 => Ok(ide.reg.lba1_cyl_lo),
@@ -1060,11 +1806,18 @@ impl IdeController {
         use IdeReg::*;
 
         // set-up a convenient alias to the currently selected IDE device
-        let ide = match reg {
+        let mut ide = match reg {
+            // device control is shared between both drives
+            DevControl | AltStatus => {
+                for ide in self.ide0.iter().chain(self.ide1.iter()) {
+                    ide.lock().write_dev_control(val);
+                }
+                return Ok(());
+            }
             DeviceHead | Lba3 => {
                 // FIXME?: Actually strip-out reserved bits?
                 self.selected_device = val.get_bit(reg::DEVHEAD::DEV).into();
-                let ide = selected_ide!(self)?;
+                let mut ide = selected_ide!(self)?;
                 return Ok(ide.reg.lba3_dev_head = val);
             }
             _ => selected_ide!(self)?,
@@ -1073,18 +1826,258 @@ impl IdeController {
         match reg {
             Data => ide.data_write8(val),
             Features | Error => Ok(ide.reg.feature = val),
-            SectorCount => Ok(ide.reg.sector_count = val),
-            SectorNo | Lba0 => Ok(ide.reg.lba0_sector_no = val),
-            CylinderLo | Lba1 => Ok(ide.reg.lba1_cyl_lo = val),
-            CylinderHi | Lba2 => Ok(ide.reg.lba2_cyl_hi = val),
+            // writes push the previous value into the "high order byte" for
+            // use by 48-bit commands
+            SectorCount => {
+                ide.reg.hob = false;
+                ide.reg.hob_sector_count = ide.reg.sector_count;
+                Ok(ide.reg.sector_count = val)
+            }
+            SectorNo | Lba0 => {
+                ide.reg.hob = false;
+                ide.reg.hob_lba0 = ide.reg.lba0_sector_no;
+                Ok(ide.reg.lba0_sector_no = val)
+            }
+            CylinderLo | Lba1 => {
+                ide.reg.hob = false;
+                ide.reg.hob_lba1 = ide.reg.lba1_cyl_lo;
+                Ok(ide.reg.lba1_cyl_lo = val)
+            }
+            CylinderHi | Lba2 => {
+                ide.reg.hob = false;
+                ide.reg.hob_lba2 = ide.reg.lba2_cyl_hi;
+                Ok(ide.reg.lba2_cyl_hi = val)
+            }
             DeviceHead | Lba3 => unreachable!("should be handled above"),
             Command | Status => ide.exec_cmd(val),
-            DevControl | AltStatus => {
-                ide.reg.srst = val.get_bit(2);
-                ide.reg.nein = val.get_bit(1);
-                Ok(())
-            }
+            DevControl | AltStatus => unreachable!("should be handled above"),
             DataLatch => Err(Unimplemented),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use byteorder::{ByteOrder, LittleEndian};
+
+    use crate::block::backend::{Mem, Null};
+    use crate::executor::{ClickyExecutor, Executor};
+
+    fn controller(blockdev: Box<dyn BlockDev>) -> (IdeController, Executor) {
+        let executor = Executor::new().unwrap();
+        let (irq, _) = irq::new(irq::Pending::new(), "IDE");
+        let (dmarq, _) = irq::new(irq::Pending::new(), "IDE DMARQ");
+        let mut ide = IdeController::new(irq, dmarq, executor.spawner());
+        ide.attach(IdeIdx::IDE0, blockdev);
+        (ide, executor)
+    }
+
+    /// Disk where the first 8 bytes of each sector contain its own LBA.
+    fn numbered_disk(sectors: u64) -> Box<dyn BlockDev> {
+        let mut data = vec![0; sectors as usize * 512];
+        for (lba, sector) in data.chunks_exact_mut(512).enumerate() {
+            sector[..8].copy_from_slice(&(lba as u64).to_le_bytes());
+        }
+        Box::new(Mem::new(data.into_boxed_slice()))
+    }
+
+    /// Wait for the in-flight media access to finish, returning the status.
+    fn wait(ide: &mut IdeController, executor: &mut Executor) -> u8 {
+        for _ in 0..10_000 {
+            executor.run_until_stalled();
+            let status = ide.read8(IdeReg::AltStatus).unwrap();
+            if !status.get_bit(reg::STATUS::BSY) {
+                return status;
+            }
+            std::thread::sleep(Duration::from_micros(100));
+        }
+        panic!("IDE drive stuck busy");
+    }
+
+    fn set_lba28(ide: &mut IdeController, lba: u64, count: u8) {
+        ide.write8(IdeReg::SectorCount, count).unwrap();
+        ide.write8(IdeReg::Lba0, lba as u8).unwrap();
+        ide.write8(IdeReg::Lba1, (lba >> 8) as u8).unwrap();
+        ide.write8(IdeReg::Lba2, (lba >> 16) as u8).unwrap();
+        ide.write8(IdeReg::Lba3, 0xe0 | (lba >> 24) as u8 & 0xf)
+            .unwrap();
+    }
+
+    /// The "previous" contents of each register hold the high order bytes.
+    fn set_lba48(ide: &mut IdeController, lba: u64, count: u16) {
+        ide.write8(IdeReg::SectorCount, (count >> 8) as u8).unwrap();
+        ide.write8(IdeReg::SectorCount, count as u8).unwrap();
+        for i in 0..3 {
+            let reg = || match i {
+                0 => IdeReg::Lba0,
+                1 => IdeReg::Lba1,
+                _ => IdeReg::Lba2,
+            };
+            ide.write8(reg(), (lba >> (24 + 8 * i)) as u8).unwrap();
+            ide.write8(reg(), (lba >> (8 * i)) as u8).unwrap();
+        }
+        ide.write8(IdeReg::Lba3, 0xe0).unwrap();
+    }
+
+    /// Read a single sector's worth of data from the data register.
+    fn read_sector(ide: &mut IdeController) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(512);
+        for _ in 0..256 {
+            let val = ide.read16(IdeReg::Data).unwrap();
+            buf.extend_from_slice(&val.to_le_bytes());
+        }
+        buf
+    }
+
+    fn write_sector(ide: &mut IdeController, data: &[u8]) {
+        for word in data.chunks_exact(2) {
+            ide.write16(IdeReg::Data, u16::from_le_bytes([word[0], word[1]]))
+                .unwrap();
+        }
+    }
+
+    fn sector_lba(data: &[u8]) -> u64 {
+        LittleEndian::read_u64(&data[..8])
+    }
+
+    #[test]
+    fn read_multiple_irq_per_block() {
+        let (mut ide, mut executor) = controller(numbered_disk(64));
+
+        ide.write8(IdeReg::SectorCount, 4).unwrap();
+        ide.write8(IdeReg::Lba3, 0xe0).unwrap();
+        ide.write8(IdeReg::Command, IdeCmd::SetMultipleMode as u8)
+            .unwrap();
+        assert!(!ide.read8(IdeReg::Status).unwrap().get_bit(reg::STATUS::ERR));
+
+        set_lba28(&mut ide, 10, 6);
+        ide.write8(IdeReg::Command, IdeCmd::ReadMultiple as u8)
+            .unwrap();
+
+        let mut irqs = Vec::new();
+        for lba in 10..16 {
+            let status = wait(&mut ide, &mut executor);
+            assert!(status.get_bit(reg::STATUS::DRQ));
+            if ide.irq_state(IdeIdx::IDE0) {
+                irqs.push(lba);
+                ide.clear_irq(IdeIdx::IDE0);
+            }
+            assert_eq!(sector_lba(&read_sector(&mut ide)), lba);
+        }
+
+        // one IRQ per DRQ data block: sectors 10..14, then 14..16
+        assert_eq!(irqs, [10, 14]);
+        let status = wait(&mut ide, &mut executor);
+        assert!(!status.get_bit(reg::STATUS::DRQ));
+        assert!(!status.get_bit(reg::STATUS::ERR));
+    }
+
+    #[test]
+    fn write_multiple_rejects_multiple_mode_disabled() {
+        let (mut ide, _executor) = controller(numbered_disk(64));
+
+        set_lba28(&mut ide, 0, 1);
+        assert!(ide
+            .write8(IdeReg::Command, IdeCmd::WriteMultiple as u8)
+            .is_err());
+        let status = ide.read8(IdeReg::Status).unwrap();
+        assert!(status.get_bit(reg::STATUS::ERR));
+        assert!(ide.read8(IdeReg::Error).unwrap().get_bit(reg::ERROR::ABRT));
+    }
+
+    #[test]
+    fn lba48_write_read_round_trip() {
+        let (mut ide, mut executor) = controller(numbered_disk(64));
+
+        set_lba48(&mut ide, 40, 2);
+        ide.write8(IdeReg::Command, IdeCmd::WriteSectorsExt as u8)
+            .unwrap();
+        for i in 0..2u8 {
+            let status = wait(&mut ide, &mut executor);
+            assert!(status.get_bit(reg::STATUS::DRQ));
+            write_sector(&mut ide, &[0xa0 | i; 512]);
+        }
+        let status = wait(&mut ide, &mut executor);
+        assert!(!status.get_bit(reg::STATUS::ERR));
+
+        set_lba48(&mut ide, 39, 4);
+        ide.write8(IdeReg::Command, IdeCmd::ReadSectorsExt as u8)
+            .unwrap();
+        let mut sectors = Vec::new();
+        for _ in 0..4 {
+            let status = wait(&mut ide, &mut executor);
+            assert!(status.get_bit(reg::STATUS::DRQ));
+            sectors.push(read_sector(&mut ide));
+        }
+
+        assert_eq!(sector_lba(&sectors[0]), 39);
+        assert_eq!(sectors[1], [0xa0; 512]);
+        assert_eq!(sectors[2], [0xa1; 512]);
+        assert_eq!(sector_lba(&sectors[3]), 42);
+    }
+
+    #[test]
+    fn lba48_reads_past_lba28_limit() {
+        // 2^28 + 16 sectors, most of which read back as zeros
+        let (mut ide, mut executor) = controller(Box::new(Null::new((MAX_LBA28 + 1 + 16) * 512)));
+
+        set_lba48(&mut ide, MAX_LBA28 + 8, 1);
+        ide.write8(IdeReg::Command, IdeCmd::ReadSectorsExt as u8)
+            .unwrap();
+        let status = wait(&mut ide, &mut executor);
+        assert!(status.get_bit(reg::STATUS::DRQ));
+        assert_eq!(read_sector(&mut ide), [0; 512]);
+
+        // the high order bytes can be read back via the HOB bit
+        ide.write8(IdeReg::DevControl, 0x80).unwrap();
+        assert_eq!(ide.read8(IdeReg::Lba0).unwrap(), 0x10);
+        ide.write8(IdeReg::DevControl, 0x00).unwrap();
+        assert_eq!(ide.read8(IdeReg::Lba0).unwrap(), 0x07);
+    }
+
+    #[test]
+    fn lba48_zero_count_is_65536_sectors() {
+        let (mut ide, _executor) = controller(numbered_disk(64));
+
+        // a count of 0 requests 65536 sectors, which runs off the disk
+        set_lba48(&mut ide, 0, 0);
+        assert!(ide
+            .write8(IdeReg::Command, IdeCmd::ReadSectorsExt as u8)
+            .is_err());
+        let status = ide.read8(IdeReg::Status).unwrap();
+        assert!(status.get_bit(reg::STATUS::ERR));
+        assert!(ide.read8(IdeReg::Error).unwrap().get_bit(reg::ERROR::IDNF));
+    }
+
+    #[test]
+    fn reattach_mid_transfer() {
+        let (mut ide, mut executor) = controller(numbered_disk(64));
+
+        set_lba28(&mut ide, 0, 8);
+        ide.write8(IdeReg::Command, IdeCmd::ReadSectors as u8)
+            .unwrap();
+        ide.attach(IdeIdx::IDE0, numbered_disk(128));
+
+        set_lba28(&mut ide, 100, 1);
+        ide.write8(IdeReg::Command, IdeCmd::ReadSectors as u8)
+            .unwrap();
+        let status = wait(&mut ide, &mut executor);
+        assert!(status.get_bit(reg::STATUS::DRQ));
+        assert_eq!(sector_lba(&read_sector(&mut ide)), 100);
+    }
+
+    #[test]
+    fn lba28_commands_ignore_high_order_bytes() {
+        let (mut ide, mut executor) = controller(numbered_disk(64));
+
+        // the high order bytes would put an ext command past the end of the disk
+        set_lba48(&mut ide, 0xff_0000_0000 | 5, 1);
+        ide.write8(IdeReg::Command, IdeCmd::ReadSectors as u8)
+            .unwrap();
+        let status = wait(&mut ide, &mut executor);
+        assert!(status.get_bit(reg::STATUS::DRQ));
+        assert_eq!(sector_lba(&read_sector(&mut ide)), 5);
+    }
+}
@@ -0,0 +1,165 @@
+use std::str::FromStr;
+
+/// Identity and capabilities reported by an emulated IDE drive (via the
+/// IDENTIFY DEVICE command), along with the features it actually implements.
+///
+/// NOTE: None of the built-in profiles' serial numbers or firmware revisions
+/// were taken from a real drive. They're synthetic placeholders, and are
+/// deliberately recognizable as such.
+#[derive(Debug, Clone)]
+pub struct IdeDriveProfile {
+    pub model: String,
+    pub serial: String,
+    pub fw_version: String,
+
+    /// Logical number of heads used for CHS addressing.
+    pub heads: u16,
+    /// Logical number of sectors per track used for CHS addressing.
+    pub sectors_per_track: u16,
+    /// Caps the capacity reported by the drive (in sectors), regardless of the
+    /// size of the underlying blockdev.
+    pub max_sectors: Option<u64>,
+
+    /// Spindle speed (in revolutions per minute), or `None` for solid state
+    /// media.
+    pub rpm: Option<u16>,
+    /// Report the drive as a CompactFlash card (in True IDE mode).
+    pub compact_flash: bool,
+
+    /// Largest block size supported by READ / WRITE MULTIPLE.
+    pub max_multsect: u8,
+    /// Support the 48-bit Address feature set.
+    pub lba48: bool,
+    /// Support (multiword) DMA transfers.
+    pub dma: bool,
+    /// Support the SMART feature set.
+    pub smart: bool,
+}
+
+impl Default for IdeDriveProfile {
+    fn default() -> IdeDriveProfile {
+        IdeDriveProfile::generic()
+    }
+}
+
+impl IdeDriveProfile {
+    /// A generic drive, which supports every feature implemented by the
+    /// emulator.
+    pub fn generic() -> IdeDriveProfile {
+        IdeDriveProfile {
+            model: "clickydrive".into(),
+            serial: "serials_are_4_chumps".into(),
+            fw_version: "0".into(),
+
+            heads: 16,
+            sectors_per_track: 63,
+            max_sectors: None,
+
+            rpm: None,
+            compact_flash: false,
+
+            max_multsect: 16,
+            lba48: true,
+            dma: true,
+            smart: true,
+        }
+    }
+
+    /// The 20GB Toshiba drive shipped in the iPod 4g.
+    pub fn toshiba_mk2004gal() -> IdeDriveProfile {
+        IdeDriveProfile {
+            model: "TOSHIBA MK2004GAL".into(),
+            serial: "CLICKY00000001".into(),
+            fw_version: "CLICKY01".into(),
+
+            max_sectors: Some(39_070_080),
+            rpm: Some(4200),
+            // predates ATA-6, and is far too small to need it anyway
+            lba48: false,
+            ..IdeDriveProfile::generic()
+        }
+    }
+
+    /// The 40GB Toshiba drive shipped in the iPod 4g.
+    pub fn toshiba_mk4004gah() -> IdeDriveProfile {
+        IdeDriveProfile {
+            model: "TOSHIBA MK4004GAH".into(),
+            serial: "CLICKY00000002".into(),
+            fw_version: "CLICKY01".into(),
+
+            max_sectors: Some(78_140_160),
+            rpm: Some(4200),
+            lba48: false,
+            ..IdeDriveProfile::generic()
+        }
+    }
+
+    /// A 4GB Hitachi Microdrive (as found in the iPod mini). Microdrives are
+    /// spinning disks in a CompactFlash form factor, and identify themselves
+    /// as such.
+    pub fn hitachi_microdrive() -> IdeDriveProfile {
+        IdeDriveProfile {
+            model: "HMS360404D5CF00".into(),
+            serial: "CLICKY00000003".into(),
+            fw_version: "CLICKY01".into(),
+
+            max_sectors: Some(7_999_488),
+            rpm: Some(3600),
+            compact_flash: true,
+            lba48: false,
+            ..IdeDriveProfile::generic()
+        }
+    }
+
+    /// A CompactFlash card, connected via a CF-to-ZIF adapter.
+    pub fn compact_flash() -> IdeDriveProfile {
+        IdeDriveProfile {
+            model: "SanDisk SDCFH-008G".into(),
+            serial: "CLICKY00000004".into(),
+            fw_version: "CLICKY01".into(),
+
+            rpm: None,
+            compact_flash: true,
+            // most cards only support PIO in True IDE mode
+            max_multsect: 1,
+            lba48: false,
+            dma: false,
+            smart: false,
+            ..IdeDriveProfile::generic()
+        }
+    }
+
+    /// An SD-to-IDE adapter (e.g: the iFlash), which presents one or more SD
+    /// cards as a regular ATA drive.
+    ///
+    /// The real adapters' identify strings vary between hardware revisions
+    /// and firmware versions, and none of them are documented, so this profile
+    /// only emulates the adapter's capabilities. Its identity is synthetic.
+    pub fn iflash() -> IdeDriveProfile {
+        IdeDriveProfile {
+            model: "CLICKY SD-IDE ADAPTER".into(),
+            serial: "CLICKY00000005".into(),
+            fw_version: "CLICKY01".into(),
+
+            rpm: None,
+            smart: false,
+            ..IdeDriveProfile::generic()
+        }
+    }
+}
+
+impl FromStr for IdeDriveProfile {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<IdeDriveProfile, &'static str> {
+        Ok(match s {
+            "generic" => IdeDriveProfile::generic(),
+            "mk2004gal" => IdeDriveProfile::toshiba_mk2004gal(),
+            "mk4004gah" => IdeDriveProfile::toshiba_mk4004gah(),
+            "microdrive" => IdeDriveProfile::hitachi_microdrive(),
+            "cf" => IdeDriveProfile::compact_flash(),
+            "iflash" => IdeDriveProfile::iflash(),
+            _ => return Err("unknown drive profile"),
+        })
+    }
+}
@@ -1,3 +1,8 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use relativity::Instant;
+
 /// Revision of the data structure itself, not the drive.
 const REVISION: u16 = 0x0010;
 
@@ -28,6 +33,7 @@ pub enum AttributeId {
     UnexpectedPowerOffCount = 0xC0,
     Temperature = 0xC2,
     ReallocationEventCount = 0xC4,
+    CurrentPendingSectorCount = 0xC5,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -63,6 +69,9 @@ impl Attribute {
     }
 }
 
+/// Number of attributes which fit in the SMART data structure.
+const MAX_ATTRIBUTES: usize = 12;
+
 pub struct Smart {
     attributes: Vec<Attribute>,
 }
@@ -76,7 +85,7 @@ impl Smart {
 
     pub fn add_attribute(&mut self, attribute: Attribute) -> Result<(), Error> {
         use Error::*;
-        if self.attributes.len() >= 12 {
+        if self.attributes.len() >= MAX_ATTRIBUTES {
             return Err(TooManyAttributes);
         }
 
@@ -133,3 +142,218 @@ impl Smart {
         buf
     }
 }
+
+/// Initial state of a drive's SMART attributes. The attributes then evolve
+/// over (virtual) time and disk activity.
+#[derive(Debug, Clone)]
+pub struct SmartConfig {
+    /// Temperature of the drive's surroundings (in degrees Celsius), which the
+    /// drive cools down towards while spun-down.
+    pub ambient_temp: u8,
+    pub power_on_hours: u64,
+    pub start_stop_count: u64,
+    pub power_cycle_count: u64,
+    pub reallocated_sectors: u64,
+}
+
+impl Default for SmartConfig {
+    fn default() -> SmartConfig {
+        SmartConfig {
+            ambient_temp: 25,
+            power_on_hours: 42,
+            start_stop_count: 128,
+            power_cycle_count: 64,
+            reallocated_sectors: 0,
+        }
+    }
+}
+
+impl FromStr for SmartConfig {
+    type Err = &'static str;
+
+    /// Parse a comma separated list of `key=value` pairs (e.g:
+    /// `temp=30,hours=1234,realloc=100`). Omitted keys keep their default
+    /// values.
+    fn from_str(s: &str) -> Result<SmartConfig, &'static str> {
+        let mut cfg = SmartConfig::default();
+
+        for arg in s.split(',').filter(|s| !s.is_empty()) {
+            let mut kv = arg.split('=');
+            let key = kv.next().unwrap();
+            let val = kv.next().ok_or("missing value for SMART option")?;
+            let val = || val.parse::<u64>().map_err(|_| "invalid SMART option value");
+
+            match key {
+                "temp" => cfg.ambient_temp = val()?.min(u8::MAX as u64) as u8,
+                "hours" => cfg.power_on_hours = val()?,
+                "starts" => cfg.start_stop_count = val()?,
+                "cycles" => cfg.power_cycle_count = val()?,
+                "realloc" => cfg.reallocated_sectors = val()?,
+                _ => return Err("unknown SMART option"),
+            }
+        }
+
+        Ok(cfg)
+    }
+}
+
+/// Number of spare sectors available for reallocation. The drive's health
+/// degrades as they're used up.
+const SPARE_SECTORS: u64 = 1024;
+
+/// How quickly the drive's temperature approaches its target.
+const THERMAL_TIME_CONSTANT: f64 = 5.0 * 60.0;
+/// How quickly the heat generated by disk activity dissipates.
+const ACTIVITY_TIME_CONSTANT: f64 = 60.0;
+
+/// Number of attributes reported by `SmartModel`.
+const NUM_MODEL_ATTRIBUTES: usize = 11;
+const_assert!(NUM_MODEL_ATTRIBUTES <= MAX_ATTRIBUTES);
+
+/// Models a drive's SMART attributes as they evolve over time.
+#[derive(Debug)]
+pub(super) struct SmartModel {
+    cfg: SmartConfig,
+    /// When the drive was powered on.
+    epoch: Instant,
+    last_update: Instant,
+
+    temp: f64,
+    min_temp: f64,
+    max_temp: f64,
+    /// Recent disk activity, from 0.0 (idle) to 1.0 (constant seeking).
+    activity: f64,
+
+    start_stop_count: u64,
+    reallocated_sectors: u64,
+    read_errors: u64,
+    /// Sectors which couldn't be read, and are waiting to be reallocated.
+    pending: BTreeSet<u64>,
+}
+
+impl SmartModel {
+    pub fn new(cfg: SmartConfig) -> SmartModel {
+        let temp = cfg.ambient_temp as f64;
+        SmartModel {
+            epoch: Instant::now(),
+            last_update: Instant::now(),
+
+            temp,
+            min_temp: temp,
+            max_temp: temp,
+            activity: 0.0,
+
+            start_stop_count: cfg.start_stop_count,
+            reallocated_sectors: cfg.reallocated_sectors,
+            read_errors: 0,
+            pending: BTreeSet::new(),
+
+            cfg,
+        }
+    }
+
+    /// Advance the model to the current time. `spinning` indicates whether
+    /// the drive has been spun-up since the last update.
+    pub fn update(&mut self, spinning: bool) {
+        let now = Instant::now();
+        let dt = (now - self.last_update).as_secs_f64();
+        self.last_update = now;
+
+        self.activity *= (-dt / ACTIVITY_TIME_CONSTANT).exp();
+
+        let ambient = self.cfg.ambient_temp as f64;
+        let target = if spinning {
+            ambient + 8.0 + 7.0 * self.activity
+        } else {
+            ambient
+        };
+        self.temp += (target - self.temp) * (1.0 - (-dt / THERMAL_TIME_CONSTANT).exp());
+        self.min_temp = self.min_temp.min(self.temp);
+        self.max_temp = self.max_temp.max(self.temp);
+    }
+
+    /// Record a media access.
+    pub fn access(&mut self) {
+        self.activity = (self.activity + 0.001).min(1.0);
+    }
+
+    /// Record the drive spinning back up after being in standby.
+    pub fn spin_up(&mut self) {
+        self.start_stop_count += 1;
+    }
+
+    /// Record a sector which couldn't be read.
+    pub fn read_error(&mut self, lba: u64) {
+        self.read_errors += 1;
+        self.pending.insert(lba);
+    }
+
+    /// Record a bad sector being swapped out for a spare.
+    pub fn reallocate(&mut self, lba: u64) {
+        self.pending.remove(&lba);
+        self.reallocated_sectors += 1;
+    }
+
+    fn attributes(&self) -> Smart {
+        let hours = self.cfg.power_on_hours + self.epoch.elapsed().as_secs() / 3600;
+        let temp = self.temp.round() as u64;
+        let spares_used = self.reallocated_sectors * 100 / SPARE_SECTORS;
+        let realloc_health = 100u64.saturating_sub(spares_used).max(1) as u8;
+        let read_health = 100u64.saturating_sub(self.read_errors).max(1) as u8;
+
+        #[rustfmt::skip]
+        let attributes: [(AttributeId, u16, u8, u64, u8); NUM_MODEL_ATTRIBUTES] = [
+            (AttributeId::ReadErrorRate, 0x000f, read_health, self.read_errors, 51),
+            (AttributeId::SpinUpTime, 0x0027, 100, 1200, 1),
+            (AttributeId::StartStopCount, 0x0032, 100, self.start_stop_count, 0),
+            (AttributeId::ReallocatedSectorCount, 0x0033, realloc_health, self.reallocated_sectors, 5),
+            (AttributeId::SeekErrorRate, 0x000f, 100, 0, 51),
+            (AttributeId::PowerOnHours, 0x0032, 100u64.saturating_sub(hours / 1000).max(1) as u8, hours, 0),
+            (AttributeId::SpinRetryCount, 0x0013, 100, 0, 51),
+            (AttributeId::PowerCycleCount, 0x0032, 100, self.cfg.power_cycle_count + 1, 0),
+            (AttributeId::UnexpectedPowerOffCount, 0x0032, 100, 12, 0),
+            // raw value also includes the lifetime min / max temperatures
+            (AttributeId::Temperature, 0x0022, 100u64.saturating_sub(temp) as u8, temp
+                | (self.min_temp.round() as u64) << 16
+                | (self.max_temp.round() as u64) << 32, 0),
+            (AttributeId::CurrentPendingSectorCount, 0x0032, 100, self.pending.len() as u64, 0),
+        ];
+
+        let mut smart = Smart::new();
+        for &(id, flags, current, raw, threshold) in &attributes {
+            let attribute = Attribute {
+                id: id as u8,
+                flags,
+                current,
+                // attributes only ever degrade, so the worst value is the
+                // current one
+                worst: current,
+                raw,
+                threshold,
+            };
+            // the table above has a fixed number of entries (checked against
+            // MAX_ATTRIBUTES at compile time), each with a distinct id
+            smart
+                .add_attribute(attribute)
+                .expect("SMART attribute table has a duplicate id");
+        }
+        smart
+    }
+
+    /// SMART READ DATA
+    pub fn serialize_data(&self) -> [u8; 512] {
+        self.attributes().serialize_data()
+    }
+
+    /// SMART READ ATTRIBUTE THRESHOLDS
+    pub fn serialize_threshold(&self) -> [u8; 512] {
+        self.attributes().serialize_threshold()
+    }
+
+    /// Check if any of the drive's attributes have crossed their threshold
+    /// (i.e: the drive is about to fail).
+    pub fn threshold_exceeded(&self) -> bool {
+        let smart = self.attributes();
+        (smart.attributes.iter()).any(|a| a.threshold != 0 && a.current <= a.threshold)
+    }
+}
@@ -0,0 +1,160 @@
+use std::time::Duration;
+
+use relativity::{Instant, Timeout};
+
+/// Mechanical characteristics of a spinning hard drive, used to delay media
+/// accesses by a realistic amount of time.
+#[derive(Debug, Clone)]
+pub struct IdeTiming {
+    /// Time taken to spin-up the platters (on power-on, or when leaving
+    /// standby).
+    pub spin_up: Duration,
+    /// Time taken to spin-down the platters (when entering standby).
+    pub spin_down: Duration,
+    /// Time taken to seek between adjacent tracks.
+    pub track_to_track_seek: Duration,
+    /// Time taken to seek from one end of the disk to the other.
+    pub full_stroke_seek: Duration,
+    /// Spindle speed (in revolutions per minute).
+    pub rpm: u32,
+    /// Number of sectors per track.
+    pub sectors_per_track: u32,
+}
+
+impl IdeTiming {
+    /// Rough characteristics of the 1.8" 4200 RPM drives shipped in the iPod
+    /// 4g (e.g: the Toshiba MK2004GAL).
+    pub fn ipod_4g() -> IdeTiming {
+        IdeTiming {
+            spin_up: Duration::from_millis(1500),
+            spin_down: Duration::from_millis(500),
+            track_to_track_seek: Duration::from_millis(3),
+            full_stroke_seek: Duration::from_millis(30),
+            rpm: 4200,
+            sectors_per_track: 400,
+        }
+    }
+
+    fn revolution(&self) -> Duration {
+        Duration::from_secs(60) / self.rpm.max(1)
+    }
+
+    /// Time taken for a single sector to pass under the head.
+    fn sector_time(&self) -> Duration {
+        self.revolution() / self.sectors_per_track.max(1)
+    }
+
+    /// Seek time between two sectors, scaling with the square root of the
+    /// seek distance (i.e: the head accelerates, coasts, then decelerates).
+    fn seek_time(&self, from: u64, to: u64, total_sectors: u64) -> Duration {
+        let spt = self.sectors_per_track.max(1) as u64;
+        if from / spt == to / spt {
+            return Duration::from_secs(0);
+        }
+
+        let dist = (from.max(to) - from.min(to)) as f64 / total_sectors.max(1) as f64;
+        let extra = self
+            .full_stroke_seek
+            .saturating_sub(self.track_to_track_seek);
+        self.track_to_track_seek + extra.mul_f64(dist.min(1.0).sqrt())
+    }
+}
+
+pub(super) async fn delay(duration: Duration) {
+    if duration != Duration::from_secs(0) {
+        Timeout::new(duration).await
+    }
+}
+
+/// Tracks the mechanical state of a drive (i.e: whether the platters are
+/// spinning, and where the head is), delaying media accesses accordingly.
+///
+/// Without an `IdeTiming` model, all accesses complete immediately.
+#[derive(Debug)]
+pub(super) struct Mechanics {
+    timing: Option<IdeTiming>,
+    total_sectors: u64,
+
+    spinning: bool,
+    /// Sector currently under the head.
+    head: u64,
+    /// Reference point for the platter's rotational position.
+    epoch: Instant,
+}
+
+impl Mechanics {
+    pub fn new(total_sectors: u64) -> Mechanics {
+        Mechanics {
+            timing: None,
+            total_sectors,
+
+            spinning: true,
+            head: 0,
+            epoch: Instant::now(),
+        }
+    }
+
+    pub fn set_timing(&mut self, timing: Option<IdeTiming>) {
+        self.timing = timing;
+    }
+
+    /// Spin-up the platters after the drive has been powered on.
+    pub async fn power_on(&mut self) {
+        self.spinning = false;
+        self.spin_up().await;
+    }
+
+    async fn spin_up(&mut self) {
+        if self.spinning {
+            return;
+        }
+
+        debug!("IDE drive spinning up");
+        if let Some(timing) = &self.timing {
+            delay(timing.spin_up).await;
+        }
+        self.spinning = true;
+    }
+
+    pub async fn spin_down(&mut self) {
+        if !self.spinning {
+            return;
+        }
+
+        debug!("IDE drive spinning down");
+        if let Some(timing) = &self.timing {
+            delay(timing.spin_down).await;
+        }
+        self.spinning = false;
+    }
+
+    /// Wait until the head has read / written `lba`, spinning up the drive if
+    /// required.
+    pub async fn access(&mut self, lba: u64) {
+        self.spin_up().await;
+
+        if let Some(timing) = &self.timing {
+            let mut wait = timing.sector_time();
+
+            // sequential accesses don't need to seek, or wait for the sector to
+            // come back around
+            if lba != self.head {
+                let seek = timing.seek_time(self.head, lba, self.total_sectors);
+
+                // figure out where the platter will be once the seek finishes
+                let rev = timing.revolution().as_secs_f64();
+                let spt = timing.sectors_per_track.max(1) as u64;
+                let now = (Instant::now() - self.epoch + seek).as_secs_f64();
+                let pos = (now / rev).fract();
+                let target = (lba % spt) as f64 / spt as f64;
+                let latency = (target - pos).rem_euclid(1.0) * rev;
+
+                wait += seek + Duration::from_secs_f64(latency);
+            }
+
+            delay(wait).await;
+        }
+
+        self.head = lba + 1;
+    }
+}
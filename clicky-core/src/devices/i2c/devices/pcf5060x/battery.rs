@@ -0,0 +1,319 @@
+//! PCF5060x Main Battery Charger + a simple battery model.
+
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use relativity::{Instant, Timeout};
+
+use crate::executor::*;
+
+use super::{int2, int3, Interrupts};
+
+/// How often the battery model is advanced (in the absence of any other
+/// accesses).
+const TICK: Duration = Duration::from_secs(1);
+
+/// Battery parameters.
+#[derive(Debug, Clone)]
+pub struct BatteryConfig {
+    /// Battery capacity (in mAh).
+    pub capacity_mah: f64,
+    /// Open-circuit voltage curve, as a list of `(charge %, mV)` points sorted
+    /// by charge. Voltages between points are linearly interpolated.
+    pub voltage_curve: Vec<(f64, f64)>,
+    /// Current drawn while both CPUs are asleep (in mA).
+    pub idle_ma: f64,
+    /// Additional current drawn while either CPU is running (in mA).
+    pub cpu_ma: f64,
+    /// Additional current drawn while the backlight is on (in mA).
+    pub backlight_ma: f64,
+    /// Charging current (in mA).
+    pub charge_ma: f64,
+}
+
+impl Default for BatteryConfig {
+    /// Roughly matches the iPod 4g's stock 700 mAh Li-ion battery.
+    fn default() -> BatteryConfig {
+        BatteryConfig {
+            capacity_mah: 700.0,
+            voltage_curve: vec![
+                (0.0, 3000.0),
+                (2.0, 3300.0),
+                (5.0, 3550.0),
+                (10.0, 3650.0),
+                (20.0, 3700.0),
+                (40.0, 3760.0),
+                (60.0, 3840.0),
+                (80.0, 3960.0),
+                (100.0, 4150.0),
+            ],
+            idle_ma: 5.0,
+            cpu_ma: 50.0,
+            backlight_ma: 25.0,
+            charge_ma: 350.0,
+        }
+    }
+}
+
+/// External power sources.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PowerSource {
+    FireWire,
+    Usb,
+}
+
+#[derive(Debug)]
+struct BatteryState {
+    config: BatteryConfig,
+    /// Remaining charge (in mAh).
+    charge_mah: f64,
+    last_update: Instant,
+
+    firewire: bool,
+    usb: bool,
+    charger_enabled: bool,
+    cpu_active: bool,
+    backlight: bool,
+
+    /// Voltage below which the battery is considered low (in mV), if the
+    /// PCF5060x's Battery Voltage Monitor is enabled.
+    lowbat_threshold: Option<f64>,
+    lowbat: bool,
+}
+
+impl BatteryState {
+    fn has_ext_power(&self) -> bool {
+        self.firewire || self.usb
+    }
+
+    fn is_full(&self) -> bool {
+        self.charge_mah >= self.config.capacity_mah
+    }
+
+    fn is_charging(&self) -> bool {
+        self.has_ext_power() && self.charger_enabled && !self.is_full()
+    }
+
+    fn level(&self) -> f64 {
+        100.0 * self.charge_mah / self.config.capacity_mah
+    }
+
+    fn voltage_mv(&self) -> f64 {
+        let curve = &self.config.voltage_curve;
+        let level = self.level();
+
+        let mv = match curve.iter().position(|&(pct, _)| pct >= level) {
+            None => curve.last().map(|&(_, mv)| mv).unwrap_or(0.0),
+            Some(0) => curve[0].1,
+            Some(i) => {
+                let (lo_pct, lo_mv) = curve[i - 1];
+                let (hi_pct, hi_mv) = curve[i];
+                lo_mv + (hi_mv - lo_mv) * (level - lo_pct) / (hi_pct - lo_pct)
+            }
+        };
+
+        // the charger raises the terminal voltage a bit
+        if self.is_charging() {
+            mv + 50.0
+        } else {
+            mv
+        }
+    }
+
+    fn current_ma(&self) -> f64 {
+        if self.is_charging() {
+            return self.config.charge_ma;
+        }
+
+        // the system runs off external power whenever it's available
+        if self.has_ext_power() {
+            return 0.0;
+        }
+
+        let mut ma = -self.config.idle_ma;
+        if self.cpu_active {
+            ma -= self.config.cpu_ma;
+        }
+        if self.backlight {
+            ma -= self.config.backlight_ma;
+        }
+        ma
+    }
+
+    /// Advance the battery model up to the current (virtual) time.
+    fn update(&mut self, ints: &Mutex<Interrupts>) {
+        let now = Instant::now();
+        let hours = (now - self.last_update).as_secs_f64() / 3600.0;
+        self.last_update = now;
+
+        let was_charging = self.is_charging();
+        self.charge_mah =
+            (self.charge_mah + self.current_ma() * hours).clamp(0.0, self.config.capacity_mah);
+        if was_charging && self.is_full() {
+            debug!("battery fully charged");
+            ints.lock().unwrap().raise(1, int2::CHGFRDY);
+        }
+
+        if self.charge_mah <= 0.0 && !self.has_ext_power() {
+            let ints = ints.lock().unwrap();
+            if !ints.on_off.power_off_requested.swap(true, Ordering::SeqCst) {
+                info!("battery is depleted, powering off");
+            }
+        }
+
+        let lowbat = match self.lowbat_threshold {
+            Some(threshold) => self.voltage_mv() < threshold,
+            None => false,
+        };
+        if lowbat && !self.lowbat {
+            debug!("battery is low ({:.0}mV)", self.voltage_mv());
+            ints.lock().unwrap().raise(2, int3::LOWBAT);
+        }
+        self.lowbat = lowbat;
+    }
+}
+
+async fn battery_task(state: Arc<Mutex<BatteryState>>, ints: Arc<Mutex<Interrupts>>) {
+    loop {
+        Timeout::new(TICK).await;
+        state.lock().unwrap().update(&ints);
+    }
+}
+
+/// Handle to the PCF5060x's battery and charger.
+///
+/// The battery discharges over (virtual) time, depending on whether the CPUs
+/// are running and the backlight is on, and charges whenever an external
+/// power source is plugged in.
+#[derive(Debug, Clone)]
+pub struct PcfPower {
+    state: Arc<Mutex<BatteryState>>,
+    ints: Arc<Mutex<Interrupts>>,
+}
+
+impl PcfPower {
+    pub(super) fn new(
+        ints: Arc<Mutex<Interrupts>>,
+        task_spawner: Spawner,
+        lowbat_threshold: Option<f64>,
+    ) -> PcfPower {
+        let config = BatteryConfig::default();
+        let state = Arc::new(Mutex::new(BatteryState {
+            charge_mah: config.capacity_mah,
+            config,
+            last_update: Instant::now(),
+
+            firewire: false,
+            usb: false,
+            charger_enabled: true,
+            cpu_active: true,
+            backlight: false,
+
+            lowbat_threshold,
+            lowbat: false,
+        }));
+
+        task_spawner
+            .spawn(battery_task(state.clone(), ints.clone()))
+            .expect("failed to spawn PCF5060x battery task");
+
+        PcfPower { state, ints }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut BatteryState) -> T) -> T {
+        let mut state = self.state.lock().unwrap();
+        state.update(&self.ints);
+        f(&mut state)
+    }
+
+    /// Replace the battery's parameters, preserving the current charge level.
+    pub fn set_config(&self, config: BatteryConfig) {
+        self.with_state(|state| {
+            let level = state.level();
+            state.config = config;
+            state.charge_mah = state.config.capacity_mah * level / 100.0;
+        })
+    }
+
+    /// Return the battery's charge level (in %).
+    pub fn level(&self) -> f64 {
+        self.with_state(|state| state.level())
+    }
+
+    /// Set the battery's charge level (in %).
+    pub fn set_level(&self, level: f64) {
+        self.with_state(|state| {
+            state.charge_mah = state.config.capacity_mah * level.clamp(0.0, 100.0) / 100.0;
+        })
+    }
+
+    /// Return the battery's voltage (in mV).
+    pub fn voltage_mv(&self) -> f64 {
+        self.with_state(|state| state.voltage_mv())
+    }
+
+    /// Check if the battery is currently charging.
+    pub fn is_charging(&self) -> bool {
+        self.with_state(|state| state.is_charging())
+    }
+
+    /// Plug / unplug an external power source.
+    pub fn set_power(&self, source: PowerSource, present: bool) {
+        self.with_state(|state| {
+            let had_power = state.has_ext_power();
+            match source {
+                PowerSource::FireWire => state.firewire = present,
+                PowerSource::Usb => state.usb = present,
+            }
+
+            match (had_power, state.has_ext_power()) {
+                (false, true) => self.ints.lock().unwrap().raise(1, int2::CHGINS),
+                (true, false) => self.ints.lock().unwrap().raise(1, int2::CHGRM),
+                _ => {}
+            }
+        })
+    }
+
+    /// Check if an external power source is plugged in.
+    pub fn has_power(&self, source: PowerSource) -> bool {
+        self.with_state(|state| match source {
+            PowerSource::FireWire => state.firewire,
+            PowerSource::Usb => state.usb,
+        })
+    }
+
+    /// Notify the battery model that the backlight was turned on / off.
+    pub fn set_backlight(&self, on: bool) {
+        self.with_state(|state| state.backlight = on)
+    }
+
+    /// Notify the battery model that the CPUs started / stopped running.
+    pub(crate) fn set_cpu_active(&self, active: bool) {
+        self.with_state(|state| state.cpu_active = active)
+    }
+
+    pub(super) fn has_ext_power(&self) -> bool {
+        self.with_state(|state| state.has_ext_power())
+    }
+
+    pub(super) fn is_full(&self) -> bool {
+        self.with_state(|state| state.is_full())
+    }
+
+    pub(super) fn is_lowbat(&self) -> bool {
+        self.with_state(|state| state.lowbat)
+    }
+
+    pub(super) fn charge_current_ma(&self) -> f64 {
+        self.with_state(|state| state.current_ma().max(0.0))
+    }
+
+    pub(super) fn set_charger_enabled(&self, enabled: bool) {
+        self.with_state(|state| state.charger_enabled = enabled)
+    }
+
+    pub(super) fn set_lowbat_threshold(&self, mv: Option<f64>) {
+        self.with_state(|state| state.lowbat_threshold = mv)
+    }
+}
@@ -0,0 +1,747 @@
+use crate::devices::i2c::prelude::*;
+
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use num_enum::TryFromPrimitive;
+
+use crate::signal::gpio;
+
+mod battery;
+mod rtc;
+
+pub use battery::{BatteryConfig, PcfPower, PowerSource};
+pub use rtc::PcfRtc;
+
+use rtc::{bcd2dec, dec2bcd, field};
+
+/// INT1 register bits.
+mod int1 {
+    pub const ALARM: usize = 7;
+}
+
+/// INT2 register bits.
+mod int2 {
+    pub const CHGINS: usize = 0;
+    pub const CHGRM: usize = 1;
+    pub const CHGFRDY: usize = 4;
+}
+
+/// INT3 register bits.
+mod int3 {
+    pub const ADCRDY: usize = 0;
+    pub const LOWBAT: usize = 6;
+}
+
+/// OOCC1 register bits.
+mod oocc1 {
+    pub const GOSTDBY: usize = 0;
+    pub const RTCWAK: usize = 4;
+    pub const CHGWAK: usize = 5;
+}
+
+/// OOCS register bits.
+mod oocs {
+    pub const BATOK: usize = 3;
+    pub const CHGOK: usize = 5;
+}
+
+/// MBCC1 register bits.
+mod mbcc1 {
+    use core::ops::RangeInclusive;
+
+    pub const CHGMOD: RangeInclusive<usize> = 2..=4;
+
+    pub const CHGMOD_OFF: u8 = 0b110;
+}
+
+/// MBCS1 register fields (see PCF50606 datasheet, Table 38).
+mod mbcs1 {
+    use core::ops::RangeInclusive;
+
+    pub const VBATSTAT: RangeInclusive<usize> = 0..=1;
+    pub const TBATSTAT: RangeInclusive<usize> = 2..=3;
+    pub const CHGVINSTAT: RangeInclusive<usize> = 4..=5;
+    pub const CHGCURSTAT: RangeInclusive<usize> = 6..=7;
+
+    /// Vbat,min < Vbat < Vchgcon
+    pub const VBAT_OK: u8 = 0b01;
+    /// Vchgcon < Vbat < Vbatov
+    pub const VBAT_CHGCON: u8 = 0b10;
+    /// Tbat,min < Tbat < Tbat,max
+    pub const TBAT_OK: u8 = 0b01;
+    /// Charger voltage within limits
+    pub const CHGVIN_OK: u8 = 0b01;
+    /// currat * Ifst < Ichg < 2 * Ifst
+    pub const CHGCUR_FAST: u8 = 0b01;
+}
+
+/// BVMC register fields (see PCF50606 datasheet, Table 47).
+mod bvmc {
+    use core::ops::RangeInclusive;
+
+    pub const LOWBAT: usize = 0;
+    pub const THRSHLD: RangeInclusive<usize> = 1..=3;
+
+    /// The THRSHLD reset value is mask-programmed, and differs between PCF5060x
+    /// variants (see PCF50606 datasheet, Table 76). Which variant the iPod uses
+    /// isn't documented, so this picks 3.3V, a sensible cut-off for a Li-ion
+    /// cell.
+    pub const RESET: u8 = 0b110 << 1;
+
+    /// LOWBAT threshold (in mV), or `None` if the BVM is disabled.
+    pub fn threshold_mv(val: u8) -> Option<f64> {
+        use bit_field::BitField;
+
+        match val.get_bits(THRSHLD) {
+            0 => None,
+            n => Some(2700.0 + 100.0 * n as f64),
+        }
+    }
+}
+
+/// Power state changes requested by the PCF5060x's On/Off Control (OOC).
+///
+/// In standby, the PMU cuts power to the rest of the system, and waits for a
+/// wake-up event (e.g: an RTC alarm) to power it back on.
+#[derive(Debug, Default)]
+pub struct PcfOnOff {
+    standby: AtomicBool,
+    standby_requested: AtomicBool,
+    wake_requested: AtomicBool,
+    power_off_requested: AtomicBool,
+}
+
+impl PcfOnOff {
+    /// Check (and clear) if the PMU requested the system enter standby.
+    pub fn take_standby_request(&self) -> bool {
+        self.standby_requested.swap(false, Ordering::SeqCst)
+    }
+
+    /// Check (and clear) if the PMU requested the system wake from standby.
+    pub fn take_wake_request(&self) -> bool {
+        self.wake_requested.swap(false, Ordering::SeqCst)
+    }
+
+    /// Check if the PMU has powered off the system for good (i.e: the battery
+    /// is depleted).
+    pub fn is_power_off_requested(&self) -> bool {
+        self.power_off_requested.load(Ordering::SeqCst)
+    }
+
+    /// Notify the PMU that the system has entered / left standby.
+    pub fn set_standby(&self, standby: bool) {
+        self.standby.store(standby, Ordering::SeqCst);
+        if !standby {
+            self.wake_requested.store(false, Ordering::SeqCst);
+        }
+    }
+
+    /// Check if the system is in standby.
+    pub fn is_standby(&self) -> bool {
+        self.standby.load(Ordering::SeqCst)
+    }
+}
+
+/// Interrupt status + mask registers, along with the PCF5060x's (active-low)
+/// INT line.
+#[derive(Debug)]
+struct Interrupts {
+    status: [u8; 3],
+    mask: [u8; 3],
+    line: gpio::Sender,
+
+    on_off: Arc<PcfOnOff>,
+    /// Interrupts which wake the system from standby.
+    wake_events: [u8; 3],
+}
+
+impl Interrupts {
+    fn new(mut line: gpio::Sender) -> Interrupts {
+        line.set_high();
+        Interrupts {
+            status: [0; 3],
+            mask: [0; 3],
+            line,
+
+            on_off: Arc::new(PcfOnOff::default()),
+            wake_events: [0; 3],
+        }
+    }
+
+    /// Set a bit in one of the INT registers. Masked interrupts are still
+    /// latched, but won't pull the INT line low.
+    fn raise(&mut self, reg: usize, bit: usize) {
+        self.status[reg].set_bit(bit, true);
+        self.update();
+
+        if self.wake_events[reg].get_bit(bit) && self.on_off.is_standby() {
+            self.on_off.wake_requested.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Read (and clear) one of the INT registers.
+    fn take(&mut self, reg: usize) -> u8 {
+        let status = std::mem::replace(&mut self.status[reg], 0);
+        self.update();
+        status
+    }
+
+    fn set_mask(&mut self, reg: usize, mask: u8) {
+        self.mask[reg] = mask;
+        self.update();
+    }
+
+    fn update(&mut self) {
+        let pending = (self.status.iter().zip(self.mask.iter())).any(|(s, m)| s & !m != 0);
+        if pending {
+            self.line.set_low()
+        } else {
+            self.line.set_high()
+        }
+    }
+}
+
+/// PCF5060x - Controller for Power Supply and Battery Management + RTC
+#[derive(Debug)]
+pub struct Pcf5060x {
+    last_op_was_write: bool,
+    register: Option<u8>,
+    inner: Pcf5060xImpl,
+}
+
+impl Pcf5060x {
+    /// Create a new PCF5060x, which signals interrupts by pulling `int_line`
+    /// low.
+    pub fn new(task_spawner: Spawner, int_line: gpio::Sender) -> Pcf5060x {
+        Pcf5060x {
+            last_op_was_write: false,
+            register: None,
+            inner: Pcf5060xImpl::new(task_spawner, int_line),
+        }
+    }
+
+    /// Return a handle to the PCF5060x's Real Time Clock.
+    pub fn rtc(&self) -> PcfRtc {
+        self.inner.rtc.clone()
+    }
+
+    /// Return a handle to the PCF5060x's battery and charger.
+    pub fn power(&self) -> PcfPower {
+        self.inner.power.clone()
+    }
+
+    /// Return a handle to the PCF5060x's On/Off Control.
+    pub fn on_off(&self) -> Arc<PcfOnOff> {
+        self.inner.ints.lock().unwrap().on_off.clone()
+    }
+}
+
+impl Device for Pcf5060x {
+    fn kind(&self) -> &'static str {
+        "Pcf5060x"
+    }
+
+    fn probe(&self, _offset: u32) -> Probe {
+        let reg = match self.register {
+            Some(reg) => reg,
+            None => return Probe::Register("<no register selected>"),
+        };
+
+        // need to subtract 1 due to auto increment behavior
+        let reg = match reg - 1 {
+            0x00 => "ID",
+            0x01 => "OOCS",
+            0x02 => "INT1",
+            0x03 => "INT2",
+            0x04 => "INT3",
+            0x05 => "INT1M",
+            0x06 => "INT2M",
+            0x07 => "INT3M",
+            0x08 => "OOCC1",
+            0x09 => "OOCC2",
+            0x0a => "RTCSC",
+            0x0b => "RTCMN",
+            0x0c => "RTCHR",
+            0x0d => "RTCWD",
+            0x0e => "RTCDT",
+            0x0f => "RTCMT",
+            0x10 => "RTCYR",
+            0x11 => "RTCSCA",
+            0x12 => "RTCMNA",
+            0x13 => "RTCHRA",
+            0x14 => "RTCWDA",
+            0x15 => "RTCDTA",
+            0x16 => "RTCMTA",
+            0x17 => "RTCYRA",
+            0x18 => "PSSC",
+            0x19 => "PWROKM",
+            0x1a => "PWROKS",
+            0x1b => "DCDC1",
+            0x1c => "DCDC2",
+            0x1d => "DCDC3",
+            0x1e => "DCDC4",
+            0x1f => "DCDEC1",
+            0x20 => "DCDEC2",
+            0x21 => "DCUDC1",
+            0x22 => "DCUDC2",
+            0x23 => "IOREGC",
+            0x24 => "D1REGC1",
+            0x25 => "D2REGC1",
+            0x26 => "D3REGC1",
+            0x27 => "LPREGC1",
+            0x28 => "LPREGC2",
+            0x29 => "MBCC1",
+            0x2a => "MBCC2",
+            0x2b => "MBCC3",
+            0x2c => "MBCS1",
+            0x2d => "BBCC",
+            0x2e => "ADCC1",
+            0x2f => "ADCC2",
+            0x30 => "ADCS1",
+            0x31 => "ADCS2",
+            0x32 => "ADCS3",
+            0x33 => "ACDC1",
+            0x34 => "BVMC",
+            0x35 => "PWMC1",
+            0x36 => "LEDC1",
+            0x37 => "LEDC2",
+            0x38 => "GPOC1",
+            0x39 => "GPOC2",
+            0x3a => "GPOC3",
+            0x3b => "GPOC4",
+            0x3c => "GPOC5",
+            _ => "<invalid>",
+        };
+
+        Probe::Register(reg)
+    }
+}
+
+impl I2CDevice for Pcf5060x {
+    fn read(&mut self) -> MemResult<u8> {
+        self.last_op_was_write = false;
+
+        match self.register {
+            None => Err(Fatal("no register specified for read".into())),
+            Some(ref mut reg) => {
+                let reg_ = *reg;
+                *reg += 1;
+                let reg = Reg::try_from(reg_).map_err(|_| Fatal("invalid register".into()))?;
+                self.inner.read(reg)
+            }
+        }
+    }
+
+    fn write(&mut self, data: u8) -> MemResult<()> {
+        if !self.last_op_was_write {
+            self.register = None; // reset the register
+        }
+        self.last_op_was_write = true;
+
+        match self.register {
+            None => Ok(self.register = Some(data)),
+            Some(ref mut reg) => {
+                let reg_ = *reg;
+                *reg += 1;
+                let reg = Reg::try_from(reg_).map_err(|_| Fatal("invalid register".into()))?;
+                self.inner.write(reg, data)
+            }
+        }
+    }
+
+    fn write_done(&mut self) -> MemResult<()> {
+        self.last_op_was_write = false;
+        self.inner.write_done()
+    }
+}
+
+#[derive(Debug, TryFromPrimitive)]
+#[repr(u8)]
+enum Reg {
+    ID_____ = 0x00,
+    OOCS___ = 0x01,
+    INT1___ = 0x02,
+    INT2___ = 0x03,
+    INT3___ = 0x04,
+    INT1M__ = 0x05,
+    INT2M__ = 0x06,
+    INT3M__ = 0x07,
+    OOCC1__ = 0x08,
+    OOCC2__ = 0x09,
+    RTCSC__ = 0x0a,
+    RTCMN__ = 0x0b,
+    RTCHR__ = 0x0c,
+    RTCWD__ = 0x0d,
+    RTCDT__ = 0x0e,
+    RTCMT__ = 0x0f,
+    RTCYR__ = 0x10,
+    RTCSCA_ = 0x11,
+    RTCMNA_ = 0x12,
+    RTCHRA_ = 0x13,
+    RTCWDA_ = 0x14,
+    RTCDTA_ = 0x15,
+    RTCMTA_ = 0x16,
+    RTCYRA_ = 0x17,
+    PSSC___ = 0x18,
+    PWROKM_ = 0x19,
+    PWROKS_ = 0x1a,
+    DCDC1__ = 0x1b,
+    DCDC2__ = 0x1c,
+    DCDC3__ = 0x1d,
+    DCDC4__ = 0x1e,
+    DCDEC1_ = 0x1f,
+    DCDEC2_ = 0x20,
+    DCUDC1_ = 0x21,
+    DCUDC2_ = 0x22,
+    IOREGC_ = 0x23,
+    D1REGC1 = 0x24,
+    D2REGC1 = 0x25,
+    D3REGC1 = 0x26,
+    LPREGC1 = 0x27,
+    LPREGC2 = 0x28,
+    MBCC1__ = 0x29,
+    MBCC2__ = 0x2a,
+    MBCC3__ = 0x2b,
+    MBCS1__ = 0x2c,
+    BBCC___ = 0x2d,
+    ADCC1__ = 0x2e,
+    ADCC2__ = 0x2f,
+    ADCS1__ = 0x30,
+    ADCS2__ = 0x31,
+    ADCS3__ = 0x32,
+    ACDC1__ = 0x33,
+    BVMC___ = 0x34,
+    PWMC1__ = 0x35,
+    LEDC1__ = 0x36,
+    LEDC2__ = 0x37,
+    GPOC1__ = 0x38,
+    GPOC2__ = 0x39,
+    GPOC3__ = 0x3a,
+    GPOC4__ = 0x3b,
+    GPOC5__ = 0x3c,
+}
+
+#[derive(Debug)]
+struct Pcf5060xImpl {
+    ints: Arc<Mutex<Interrupts>>,
+    rtc: PcfRtc,
+    power: PcfPower,
+    /// RTC fields written by the guest, which are only applied once the
+    /// entire write sequence has completed.
+    rtc_latch: Option<[u8; 7]>,
+    oocc1: u8,
+    oocc2: u8,
+    lpregc1: u8,
+    dxregc1: [u8; 3],
+    dcdcx: [u8; 4],
+    mbcc1: u8,
+    mbcc2: u8,
+    mbcc3: u8,
+    bvmc: u8,
+    gp0c1: u8,
+    adcc1: u8,
+    adcc2: u8,
+    acdc1: u8,
+}
+
+impl Pcf5060xImpl {
+    fn new(task_spawner: Spawner, int_line: gpio::Sender) -> Pcf5060xImpl {
+        let ints = Arc::new(Mutex::new(Interrupts::new(int_line)));
+        let rtc = PcfRtc::new(ints.clone(), task_spawner.clone());
+        let power = PcfPower::new(ints.clone(), task_spawner, bvmc::threshold_mv(bvmc::RESET));
+
+        Pcf5060xImpl {
+            ints,
+            rtc,
+            power,
+            rtc_latch: None,
+            oocc1: 0,
+            oocc2: 0,
+            lpregc1: 0,
+            dxregc1: [0; 3],
+            dcdcx: [0; 4],
+            mbcc1: 0,
+            mbcc2: 0,
+            mbcc3: 0,
+            bvmc: bvmc::RESET,
+            gp0c1: 0x04,
+            adcc1: 0,
+            adcc2: 0,
+            acdc1: 0,
+        }
+    }
+
+    fn get_current_time(&self, idx: usize) -> MemResult<u8> {
+        let fields = match self.rtc_latch {
+            Some(latch) => latch,
+            None => rtc::to_fields(self.rtc.now()),
+        };
+
+        Ok(dec2bcd(fields[idx]))
+    }
+
+    fn set_current_time(&mut self, idx: usize, val: u8) -> MemResult<()> {
+        let rtc = &self.rtc;
+        let latch = self
+            .rtc_latch
+            .get_or_insert_with(|| rtc::to_fields(rtc.now()));
+        latch[idx] = bcd2dec(val);
+        Ok(())
+    }
+
+    fn write_done(&mut self) -> MemResult<()> {
+        let latch = match self.rtc_latch.take() {
+            Some(latch) => latch,
+            None => return Ok(()),
+        };
+
+        match rtc::from_fields(&latch) {
+            Some(time) => {
+                info!("guest set RTC to {}", time);
+                self.rtc.set_now(time);
+                Ok(())
+            }
+            None => Err(ContractViolation {
+                msg: format!("guest set RTC to an invalid date: {:?}", latch),
+                severity: Warn,
+                stub_val: None,
+            }),
+        }
+    }
+
+    fn get_adc_readout(&mut self, reg: Reg) -> MemResult<u8> {
+        const ADCRDY: u8 = 0x80;
+
+        // Scaling factors for the BATVOLT channels were picked to match the
+        // readouts of a ~3.6V battery that the ADC used to be stubbed with.
+        let batvolt = self.power.voltage_mv();
+        let chgcur = self.power.charge_current_ma();
+
+        let convert = |mux_sel: u8| -> Option<u16> {
+            let readout = match mux_sel {
+                0 => batvolt * 1024.0 / 6000.0, // BATVOLT, resistive divider
+                1 => (batvolt - 2800.0) * 1024.0 / 3700.0, // BATVOLT, substractor
+                2 | 3 => chgcur * 1024.0 / 1000.0, // ADCIN1 (charge current sense)
+                4 => 385.0,                     // BATTEMP, radiometric
+                _ => return None,
+            };
+            Some(readout.clamp(0.0, 1023.0) as u16)
+        };
+
+        // ADCDAT1 holds the result of a single conversion, or the first result
+        // of a conversion sequence. ADCDAT2 holds the second result of a
+        // sequence (see PCF50606 datasheet, 8.13 "ADC").
+        let (adcdat1, adcdat2) = match self.adcc2.get_bits(1..=4) {
+            // BATVOLT + ADCIN1 subtractor sequence
+            0b1100 => (convert(1), convert(3)),
+            mux_sel => (convert(mux_sel), Some(0)),
+        };
+        let (adcdat1, adcdat2) = match (adcdat1, adcdat2) {
+            (Some(adcdat1), Some(adcdat2)) => (adcdat1, adcdat2),
+            _ => return Err(Unimplemented),
+        };
+
+        use Reg::*;
+        match reg {
+            ADCS1__ => Ok((adcdat1 >> 2) as u8),
+            ADCS2__ => Ok(ADCRDY | ((adcdat2 & 0x3) as u8) << 2 | (adcdat1 & 0x3) as u8),
+            ADCS3__ => Ok((adcdat2 >> 2) as u8),
+            _ => Err(Unimplemented),
+        }
+    }
+
+    fn get_oocs(&self) -> u8 {
+        let mut val = 0u8;
+        val.set_bit(oocs::BATOK, !self.power.is_lowbat());
+        val.set_bit(oocs::CHGOK, self.power.has_ext_power());
+        val
+    }
+
+    fn get_mbcs1(&self) -> u8 {
+        let mut val = 0u8;
+        // the battery model never discharges the battery below Vbat,min
+        let vbatstat = if self.power.is_full() {
+            mbcs1::VBAT_CHGCON
+        } else {
+            mbcs1::VBAT_OK
+        };
+        val.set_bits(mbcs1::VBATSTAT, vbatstat);
+        val.set_bits(mbcs1::TBATSTAT, mbcs1::TBAT_OK);
+        if self.power.has_ext_power() {
+            val.set_bits(mbcs1::CHGVINSTAT, mbcs1::CHGVIN_OK);
+        }
+        if self.power.is_charging() {
+            val.set_bits(mbcs1::CHGCURSTAT, mbcs1::CHGCUR_FAST);
+        }
+        val
+    }
+
+    fn get_bvmc(&self) -> u8 {
+        let mut val = self.bvmc;
+        val.set_bit(bvmc::LOWBAT, self.power.is_lowbat());
+        val
+    }
+
+    fn set_oocc1(&mut self, mut val: u8) {
+        let mut ints = self.ints.lock().unwrap();
+
+        ints.wake_events = [0; 3];
+        (ints.wake_events[0]).set_bit(int1::ALARM, val.get_bit(oocc1::RTCWAK));
+        (ints.wake_events[1]).set_bit(int2::CHGINS, val.get_bit(oocc1::CHGWAK));
+
+        if val.get_bit(oocc1::GOSTDBY) {
+            info!("PCF5060x entering standby");
+            ints.on_off.standby_requested.store(true, Ordering::SeqCst);
+        }
+
+        // GOSTDBY is a command, not a setting
+        val.set_bit(oocc1::GOSTDBY, false);
+        self.oocc1 = val;
+    }
+
+    fn set_mbcc1(&mut self, val: u8) {
+        self.mbcc1 = val;
+        let chgmod = val.get_bits(mbcc1::CHGMOD);
+        self.power.set_charger_enabled(chgmod != mbcc1::CHGMOD_OFF);
+    }
+
+    fn set_bvmc(&mut self, mut val: u8) {
+        // LOWBAT is a read-only status bit
+        val.set_bit(bvmc::LOWBAT, false);
+        self.bvmc = val;
+        self.power.set_lowbat_threshold(bvmc::threshold_mv(val));
+    }
+
+    fn start_adc_conversion(&mut self) {
+        // conversions complete instantly
+        self.ints.lock().unwrap().raise(2, int3::ADCRDY);
+    }
+
+    fn read(&mut self, reg: Reg) -> MemResult<u8> {
+        use Reg::*;
+        match reg {
+            ID_____ => Ok(74),
+            OOCS___ => Ok(self.get_oocs()),
+            // On/Off control (OOC)
+            OOCC1__ => Ok(self.oocc1),
+            OOCC2__ => Err(StubRead(Info, self.oocc2 as u32)),
+            // low drop-out linear regulators
+            LPREGC1 => Ok(self.lpregc1),
+            D1REGC1 => Ok(self.dxregc1[0]),
+            D2REGC1 => Ok(self.dxregc1[1]),
+            D3REGC1 => Ok(self.dxregc1[2]),
+            // DC/DC step down converter (DCD)
+            DCDC1__ => Ok(self.dcdcx[0]),
+            DCDC2__ => Ok(self.dcdcx[1]),
+            DCDC3__ => Ok(self.dcdcx[2]),
+            DCDC4__ => Ok(self.dcdcx[3]),
+            // Main Battery Charger (MBC)
+            // maximum charging time watchdog timer
+            MBCC1__ => Ok(self.mbcc1),
+            MBCC2__ => Err(StubRead(Info, self.mbcc2 as u32)),
+            MBCC3__ => Ok(self.mbcc3),
+            MBCS1__ => Ok(self.get_mbcs1()),
+            // Interrupt Status registers
+            // NOTE: reading from INT registers also clears interrupts
+            INT1___ => Ok(self.ints.lock().unwrap().take(0)),
+            INT2___ => Ok(self.ints.lock().unwrap().take(1)),
+            INT3___ => Ok(self.ints.lock().unwrap().take(2)),
+            // Interrupt Mask registers
+            INT1M__ => Ok(self.ints.lock().unwrap().mask[0]),
+            INT2M__ => Ok(self.ints.lock().unwrap().mask[1]),
+            INT3M__ => Ok(self.ints.lock().unwrap().mask[2]),
+            // RTC registers
+            RTCSC__ => self.get_current_time(field::SEC),
+            RTCMN__ => self.get_current_time(field::MIN),
+            RTCHR__ => self.get_current_time(field::HOUR),
+            RTCWD__ => self.get_current_time(field::WDAY),
+            RTCDT__ => self.get_current_time(field::DAY),
+            RTCMT__ => self.get_current_time(field::MONTH),
+            RTCYR__ => self.get_current_time(field::YEAR),
+            // RTC Alarm registers
+            RTCSCA_ => Ok(self.rtc.alarm(field::SEC)),
+            RTCMNA_ => Ok(self.rtc.alarm(field::MIN)),
+            RTCHRA_ => Ok(self.rtc.alarm(field::HOUR)),
+            RTCWDA_ => Ok(self.rtc.alarm(field::WDAY)),
+            RTCDTA_ => Ok(self.rtc.alarm(field::DAY)),
+            RTCMTA_ => Ok(self.rtc.alarm(field::MONTH)),
+            RTCYRA_ => Ok(self.rtc.alarm(field::YEAR)),
+            // Analog / Digital Converter (ADC)
+            ADCC1__ => Ok(self.adcc1),
+            ADCC2__ => Ok(self.adcc2 & 0b1111_1110), // Don't store ADCSTART bit
+            ADCS1__ | ADCS2__ | ADCS3__ => self.get_adc_readout(reg),
+            ACDC1__ => Ok(self.acdc1),
+            // Battery Voltage Monitor (BVM)
+            BVMC___ => Ok(self.get_bvmc()),
+            GPOC1__ => Ok(self.gp0c1),
+            _ => Err(Unimplemented),
+        }
+    }
+
+    fn write(&mut self, reg: Reg, data: u8) -> MemResult<()> {
+        use Reg::*;
+        match reg {
+            ID_____ => Err(InvalidAccess),
+            // On/Off control (OOC)
+            OOCC1__ => Ok(self.set_oocc1(data)),
+            OOCC2__ => Err(StubWrite(Info, self.oocc2 = data)),
+            // low drop-out linear regulators
+            LPREGC1 => Ok(self.lpregc1 = data),
+            D1REGC1 => Ok(self.dxregc1[0] = data),
+            D2REGC1 => Ok(self.dxregc1[1] = data),
+            D3REGC1 => Ok(self.dxregc1[2] = data),
+            // DC/DC step down converter (DCD)
+            DCDC1__ => Ok(self.dcdcx[0] = data),
+            DCDC2__ => Ok(self.dcdcx[1] = data),
+            DCDC3__ => Ok(self.dcdcx[2] = data),
+            DCDC4__ => Ok(self.dcdcx[3] = data),
+            // Main Battery Charger (MBC)
+            // maximum charging time watchdog timer
+            MBCC1__ => Ok(self.set_mbcc1(data)),
+            MBCC2__ => Err(StubWrite(Info, self.mbcc2 = data)),
+            MBCC3__ => Ok(self.mbcc3 = data),
+            MBCS1__ => Err(InvalidAccess),
+            // Interrupt Status registers
+            INT1___ | INT2___ | INT3___ => Err(InvalidAccess),
+            // Interrupt Mask registers
+            INT1M__ => Ok(self.ints.lock().unwrap().set_mask(0, data)),
+            INT2M__ => Ok(self.ints.lock().unwrap().set_mask(1, data)),
+            INT3M__ => Ok(self.ints.lock().unwrap().set_mask(2, data)),
+            // RTC registers
+            RTCSC__ => self.set_current_time(field::SEC, data),
+            RTCMN__ => self.set_current_time(field::MIN, data),
+            RTCHR__ => self.set_current_time(field::HOUR, data),
+            RTCWD__ => self.set_current_time(field::WDAY, data),
+            RTCDT__ => self.set_current_time(field::DAY, data),
+            RTCMT__ => self.set_current_time(field::MONTH, data),
+            RTCYR__ => self.set_current_time(field::YEAR, data),
+            // RTC Alarm registers
+            RTCSCA_ => Ok(self.rtc.set_alarm(field::SEC, data)),
+            RTCMNA_ => Ok(self.rtc.set_alarm(field::MIN, data)),
+            RTCHRA_ => Ok(self.rtc.set_alarm(field::HOUR, data)),
+            RTCWDA_ => Ok(self.rtc.set_alarm(field::WDAY, data)),
+            RTCDTA_ => Ok(self.rtc.set_alarm(field::DAY, data)),
+            RTCMTA_ => Ok(self.rtc.set_alarm(field::MONTH, data)),
+            RTCYRA_ => Ok(self.rtc.set_alarm(field::YEAR, data)),
+            // Analog / Digital Converter (ADC)
+            ADCC1__ => Ok(self.adcc1 = data & 0b0111_1111), // Bit 7 is read-only (TSCINT)
+            ADCC2__ => {
+                self.adcc2 = data;
+                if data.get_bit(0) {
+                    self.start_adc_conversion();
+                }
+                Ok(())
+            }
+            ADCS1__ => Err(InvalidAccess),
+            ADCS2__ => Err(InvalidAccess),
+            ADCS3__ => Err(InvalidAccess),
+            ACDC1__ => Ok(self.acdc1 = data & 0b1001_1110), // Writable bitmask
+            // Battery Voltage Monitor (BVM)
+            BVMC___ => Ok(self.set_bvmc(data)),
+            GPOC1__ => Ok(self.gp0c1 = data),
+            _ => Err(Unimplemented),
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! PCF5060x Real Time Clock + Alarm.
+
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use chrono::{Datelike, Duration, Local, NaiveDate, NaiveDateTime, Timelike};
+use futures::future::{self, Either};
+use pin_utils::pin_mut;
+use relativity::{Instant, Timeout};
+
+use crate::executor::*;
+
+use super::{int1, Interrupts};
+
+/// Index of each RTC field, in register order (i.e: RTCSC..RTCYR, and
+/// RTCSCA..RTCYRA).
+pub(super) mod field {
+    pub const SEC: usize = 0;
+    pub const MIN: usize = 1;
+    pub const HOUR: usize = 2;
+    pub const WDAY: usize = 3;
+    pub const DAY: usize = 4;
+    pub const MONTH: usize = 5;
+    pub const YEAR: usize = 6;
+}
+
+pub(super) fn dec2bcd(x: u8) -> u8 {
+    ((x / 10) << 4) | (x % 10)
+}
+
+pub(super) fn bcd2dec(x: u8) -> u8 {
+    (((x >> 4) & 0x0f) * 10) + (x & 0xf)
+}
+
+/// Split a timestamp into its (decimal) RTC fields.
+pub(super) fn to_fields(time: NaiveDateTime) -> [u8; 7] {
+    let mut fields = [0; 7];
+    fields[field::SEC] = time.second() as u8;
+    fields[field::MIN] = time.minute() as u8;
+    fields[field::HOUR] = time.hour() as u8;
+    fields[field::WDAY] = ((time.weekday().num_days_from_monday() + 1) % 8) as u8;
+    fields[field::DAY] = time.day() as u8;
+    fields[field::MONTH] = time.month() as u8;
+    fields[field::YEAR] = (time.year() % 100) as u8;
+    fields
+}
+
+/// Assemble a timestamp from (decimal) RTC fields, returning `None` if the
+/// fields don't describe a valid date.
+///
+/// The weekday field is ignored, as it's fully determined by the date.
+pub(super) fn from_fields(fields: &[u8; 7]) -> Option<NaiveDateTime> {
+    NaiveDate::from_ymd_opt(
+        2000 + fields[field::YEAR] as i32,
+        fields[field::MONTH] as u32,
+        fields[field::DAY] as u32,
+    )?
+    .and_hms_opt(
+        fields[field::HOUR] as u32,
+        fields[field::MIN] as u32,
+        fields[field::SEC] as u32,
+    )
+}
+
+#[derive(Debug)]
+struct RtcState {
+    base: NaiveDateTime,
+    started: Instant,
+    /// Offset (in seconds) set by the guest.
+    offset: i64,
+    sidecar: Option<PathBuf>,
+
+    /// Raw (BCD) alarm registers.
+    alarm: [u8; 7],
+    alarm_tx: async_channel::Sender<Option<Instant>>,
+}
+
+impl RtcState {
+    fn now(&self) -> NaiveDateTime {
+        let elapsed =
+            Duration::from_std(Instant::now() - self.started).unwrap_or_else(|_| Duration::zero());
+        self.base + elapsed + Duration::seconds(self.offset)
+    }
+
+    /// Re-calculate when the alarm should go off, and notify the alarm task.
+    fn rearm(&self) {
+        let mut alarm = [0; 7];
+        for (dec, bcd) in alarm.iter_mut().zip(self.alarm.iter()) {
+            *dec = bcd2dec(*bcd);
+        }
+
+        // The alarm goes off when the RTC ticks over to the alarm time, so if
+        // the RTC is already partway through that second, it's still "now".
+        let deadline = from_fields(&alarm)
+            .map(|alarm| alarm - self.now())
+            .filter(|delta| *delta > Duration::seconds(-1))
+            .map(|delta| Instant::now() + delta.to_std().unwrap_or_default());
+
+        if self.alarm_tx.try_send(deadline).is_err() {
+            // the alarm task only goes away if the executor is shutting down
+            warn!("PCF5060x alarm task isn't running");
+        }
+    }
+
+    fn persist(&self) {
+        if let Some(ref path) = self.sidecar {
+            if let Err(e) = std::fs::write(path, format!("{}\n", self.offset)) {
+                warn!("failed to persist RTC offset to {}: {}", path.display(), e)
+            }
+        }
+    }
+}
+
+async fn alarm_task(
+    ints: Arc<Mutex<Interrupts>>,
+    msg_rx: async_channel::Receiver<Option<Instant>>,
+) {
+    let mut deadline = None;
+
+    loop {
+        let next = match deadline {
+            Some(next) => next,
+            None => match msg_rx.recv().await {
+                Ok(new_deadline) => {
+                    deadline = new_deadline;
+                    continue;
+                }
+                Err(async_channel::RecvError) => {
+                    // shutting down
+                    return;
+                }
+            },
+        };
+
+        let now = Instant::now();
+        if next > now {
+            let msg_fut = msg_rx.recv();
+            pin_mut!(msg_fut);
+
+            match future::select(msg_fut, Timeout::new(next - now)).await {
+                Either::Left((Ok(new_deadline), _)) => {
+                    deadline = new_deadline;
+                    continue;
+                }
+                Either::Left((Err(async_channel::RecvError), _)) => {
+                    // shutting down
+                    return;
+                }
+                Either::Right(_) => {}
+            }
+        }
+
+        debug!("PCF5060x RTC alarm went off");
+        ints.lock().unwrap().raise(0, int1::ALARM);
+        deadline = None;
+    }
+}
+
+/// Handle to the PCF5060x's Real Time Clock.
+///
+/// The RTC runs as an offset (set by the guest) from a base time, which
+/// defaults to the host's local time at system startup. If a sidecar file is
+/// provided, the offset is persisted to it whenever the guest sets the time.
+#[derive(Debug, Clone)]
+pub struct PcfRtc {
+    state: Arc<Mutex<RtcState>>,
+}
+
+impl PcfRtc {
+    pub(super) fn new(ints: Arc<Mutex<Interrupts>>, task_spawner: Spawner) -> PcfRtc {
+        let (alarm_tx, alarm_rx) = async_channel::unbounded();
+
+        task_spawner
+            .spawn(alarm_task(ints, alarm_rx))
+            .expect("failed to spawn PCF5060x alarm task");
+
+        PcfRtc {
+            state: Arc::new(Mutex::new(RtcState {
+                base: Local::now().naive_local(),
+                started: Instant::now(),
+                offset: 0,
+                sidecar: None,
+                alarm: [0; 7],
+                alarm_tx,
+            })),
+        }
+    }
+
+    /// Set the base time the RTC is offset from.
+    pub fn set_base(&self, base: NaiveDateTime) {
+        let mut state = self.state.lock().unwrap();
+        state.base = base;
+        state.started = Instant::now();
+        state.rearm();
+    }
+
+    /// Persist the RTC offset in the provided sidecar file, loading the
+    /// previously persisted offset (if the file exists).
+    pub fn set_sidecar(&self, path: impl Into<PathBuf>) -> io::Result<()> {
+        let path = path.into();
+        let mut state = self.state.lock().unwrap();
+
+        match std::fs::read_to_string(&path) {
+            Ok(s) => {
+                state.offset = s
+                    .trim()
+                    .parse()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid RTC offset"))?
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        state.sidecar = Some(path);
+        state.rearm();
+        Ok(())
+    }
+
+    /// Return the time currently reported by the RTC.
+    pub fn now(&self) -> NaiveDateTime {
+        self.state.lock().unwrap().now()
+    }
+
+    /// Set the time reported by the RTC.
+    pub fn set_now(&self, time: NaiveDateTime) {
+        let mut state = self.state.lock().unwrap();
+        // the RTC only has 1s resolution, so drop the sub-second component
+        let now = state.now();
+        let now = now.with_nanosecond(0).unwrap_or(now);
+        state.offset += (time - now).num_seconds();
+        state.persist();
+        state.rearm();
+    }
+
+    pub(super) fn alarm(&self, idx: usize) -> u8 {
+        self.state.lock().unwrap().alarm[idx]
+    }
+
+    pub(super) fn set_alarm(&self, idx: usize, val: u8) {
+        let mut state = self.state.lock().unwrap();
+        state.alarm[idx] = val;
+        state.rearm();
+    }
+}
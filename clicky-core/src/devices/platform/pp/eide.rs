@@ -38,11 +38,11 @@ pub struct EIDECon {
 pub struct DmaErr;
 
 impl EIDECon {
-    pub fn new(irq: irq::Sender, dmarq: irq::Sender) -> EIDECon {
+    pub fn new(irq: irq::Sender, dmarq: irq::Sender, task_spawner: Spawner) -> EIDECon {
         EIDECon {
             ide0_cfg: Default::default(),
             ide1_cfg: Default::default(),
-            ide: IdeController::new(irq, dmarq),
+            ide: IdeController::new(irq, dmarq, task_spawner),
 
             dma_control: 0,
             dma_length: 0,
@@ -0,0 +1,1476 @@
+// FireWire controller for PP5020. Unlike PP5002, PP5020 features its own internal
+// FireWire peripheral.
+//
+// Memory mapping is quite close to OHCI, albeit with some usage in a reserved zone.
+// Offsets and bit names below follow the 1394 Open Host Controller Interface
+// spec; Linux's `drivers/ieee1394/ohci1394.h` is a convenient cross-reference.
+// Two values are byte-for-byte what Linux's own driver writes: LinkControlSet
+// gets 0x0030_0000 then 0x0000_0200 (ohci1394.c:530 and :541), and the async
+// contexts are started by writing the run bit to ContextControlSet.
+//
+// The link is modelled at the granularity of whole asynchronous packets: bus
+// resets (with generated self-ID packets), the four async DMA contexts, the
+// config ROM / physical request auto-responders, and the request filters. The
+// other end of the cable is a [FirewirePeer], which sits on a two-node bus with
+// the iPod. Isochronous contexts, lock transactions, and PHY packets are not
+// modelled, and everything else in the window reads back whatever was last
+// written to it.
+//
+// Since the DMA descriptors and buffers live in guest memory, the link can't do
+// much on its own. Instead, it sets a flag whenever it has work to do, and the
+// system calls [Firewire::service] with access to the memory bus.
+//
+// The one range that does *not* fit OHCI is +0x174..+0x17c, which the spec
+// leaves reserved. +0x178 gets a 16-byte aligned pointer into the same region
+// as the self-ID buffer and the DMA descriptors, so it's presumably a
+// PortalPlayer extension.
+
+use crate::devices::prelude::*;
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+mod peer;
+pub mod sbp2;
+
+pub use peer::{FirewirePeer, FwError, PEER_MEM_SIZE, PEER_NODE_ID};
+
+use peer::{rcode, tcode, Packet, PeerMem, PeerMsg};
+
+mod reg {
+    pub const AT_RETRIES: u32 = 0x008;
+    pub const CONFIG_ROM_HDR: u32 = 0x018;
+    pub const BUS_ID: u32 = 0x01c;
+    pub const BUS_OPTIONS: u32 = 0x020;
+    pub const GUID_HI: u32 = 0x024;
+    pub const GUID_LO: u32 = 0x028;
+    pub const CONFIG_ROM_MAP: u32 = 0x034;
+    pub const HC_CONTROL_SET: u32 = 0x050;
+    pub const HC_CONTROL_CLEAR: u32 = 0x054;
+    pub const SELF_ID_BUFFER: u32 = 0x064;
+    pub const SELF_ID_COUNT: u32 = 0x068;
+    pub const INT_EVENT_SET: u32 = 0x080;
+    pub const INT_EVENT_CLEAR: u32 = 0x084;
+    pub const INT_MASK_SET: u32 = 0x088;
+    pub const INT_MASK_CLEAR: u32 = 0x08c;
+    pub const FAIRNESS_CONTROL: u32 = 0x0dc;
+    pub const LINK_CONTROL_SET: u32 = 0x0e0;
+    pub const LINK_CONTROL_CLEAR: u32 = 0x0e4;
+    pub const NODE_ID: u32 = 0x0e8;
+    pub const PHY_CONTROL: u32 = 0x0ec;
+    pub const AS_REQ_FILTER_HI_SET: u32 = 0x100;
+    pub const AS_REQ_FILTER_HI_CLEAR: u32 = 0x104;
+    pub const AS_REQ_FILTER_LO_SET: u32 = 0x108;
+    pub const AS_REQ_FILTER_LO_CLEAR: u32 = 0x10c;
+    pub const PHYS_REQ_FILTER_HI_SET: u32 = 0x110;
+    pub const PHYS_REQ_FILTER_HI_CLEAR: u32 = 0x114;
+    pub const PHYS_REQ_FILTER_LO_SET: u32 = 0x118;
+    pub const PHYS_REQ_FILTER_LO_CLEAR: u32 = 0x11c;
+    pub const PHYS_UPPER_BOUND: u32 = 0x120;
+
+    /// Async DMA contexts. Each is a 0x20-byte block: ContextControlSet at
+    /// +0x00, ContextControlClear at +0x04, CommandPtr at +0x0c.
+    pub const AS_REQ_TR_CONTEXT: u32 = 0x180;
+    pub const AS_RSP_TR_CONTEXT: u32 = 0x1a0;
+    pub const AS_REQ_RCV_CONTEXT: u32 = 0x1c0;
+    pub const AS_RSP_RCV_CONTEXT: u32 = 0x1e0;
+
+    pub const CONTEXT_LEN: u32 = 0x20;
+    pub const CONTEXT_CONTROL_SET: u32 = 0x00;
+    pub const CONTEXT_CONTROL_CLEAR: u32 = 0x04;
+    pub const COMMAND_PTR: u32 = 0x0c;
+}
+
+/// `HCControl` bits.
+///
+/// RetailOS brings the link up with the canonical OHCI sequence: softReset,
+/// LPS, postedWriteEnable, linkEnable -- each followed by a read-back.
+mod hc_control {
+    /// Self-clearing. The firmware polls for it to come back down.
+    pub const SOFT_RESET: usize = 16;
+    pub const LINK_ENABLE: usize = 17;
+}
+
+/// `LinkControl` bits.
+mod link_control {
+    /// Write self-ID packets into the `SelfIDBuffer` on bus reset.
+    pub const RCV_SELF_ID: usize = 9;
+}
+
+/// `IntEvent` / `IntMask` bits.
+mod int_event {
+    pub const REQ_TX_COMPLETE: usize = 0;
+    pub const RESP_TX_COMPLETE: usize = 1;
+    pub const ARRQ: usize = 2;
+    pub const ARRS: usize = 3;
+    pub const RQ_PKT: usize = 4;
+    pub const RS_PKT: usize = 5;
+    pub const SELF_ID_COMPLETE2: usize = 15;
+    pub const SELF_ID_COMPLETE: usize = 16;
+    pub const BUS_RESET: usize = 17;
+    /// `IntMask` only.
+    pub const MASTER_INT_ENABLE: usize = 31;
+}
+
+/// `PhyControl` (0xec).
+///
+/// The firmware drives it as: write `REG_ADDR` with `RD_REG` set, then spin
+/// until `RD_DONE` comes up and take the result out of `RD_DATA`. Linux does
+/// the identical handshake -- `ohci1394.c:237` polls `PhyControl & 0x80000000`.
+///
+/// RetailOS reads PHY register 5 (a single write of 0x0000_8500); diagnostics
+/// reads PHY register 8 (built up across two writes into 0x0000_8800).
+mod phy_control {
+    use std::ops::RangeInclusive;
+
+    /// Value to write to the PHY.
+    pub const WR_DATA: RangeInclusive<usize> = 0..=7;
+    /// PHY register to access.
+    pub const REG_ADDR: RangeInclusive<usize> = 8..=11;
+    /// Kicks off a write. Cleared once the transfer completes.
+    pub const WR_REG: usize = 14;
+    /// Kicks off a read. Cleared once the transfer completes.
+    pub const RD_REG: usize = 15;
+    /// Value read back from the PHY.
+    pub const RD_DATA: RangeInclusive<usize> = 16..=23;
+    /// Address the `RD_DATA` value came from.
+    pub const RD_ADDR: RangeInclusive<usize> = 24..=28;
+    /// Set once a read has completed.
+    pub const RD_DONE: usize = 31;
+}
+
+/// PHY register bits which kick off a bus reset.
+mod phy_reg {
+    /// Register 1: Initiate Bus Reset.
+    pub const IBR: (usize, u8) = (1, 1 << 6);
+    /// Register 5: Initiate Short (arbitrated) Bus Reset.
+    pub const ISBR: (usize, u8) = (5, 1 << 6);
+}
+
+/// `ContextControl` bits.
+mod context_control {
+    use std::ops::RangeInclusive;
+
+    pub const RUN: usize = 15;
+    pub const WAKE: usize = 12;
+    pub const DEAD: usize = 11;
+    pub const ACTIVE: usize = 10;
+    pub const EVENT_CODE: RangeInclusive<usize> = 0..=4;
+}
+
+/// DMA descriptor layout (16 bytes).
+mod desc {
+    use std::ops::RangeInclusive;
+
+    pub const CONTROL: u32 = 0x0;
+    pub const DATA_ADDRESS: u32 = 0x4;
+    pub const BRANCH: u32 = 0x8;
+    /// xferStatus (31..16), and timeStamp / resCount (15..0).
+    pub const STATUS: u32 = 0xc;
+    pub const SIZE: u32 = 0x10;
+
+    pub const CMD: RangeInclusive<usize> = 28..=31;
+    pub const KEY: RangeInclusive<usize> = 24..=26;
+    pub const INT: RangeInclusive<usize> = 20..=21;
+    pub const REQ_COUNT: RangeInclusive<usize> = 0..=15;
+
+    pub const CMD_OUTPUT_LAST: u32 = 1;
+    pub const KEY_IMMEDIATE: u32 = 2;
+    pub const INT_ALWAYS: u32 = 3;
+}
+
+/// Event codes, as reported in `ContextControl` / xferStatus.
+mod evt {
+    pub const MISSING_ACK: u32 = 0x03;
+    pub const ACK_COMPLETE: u32 = 0x11;
+    pub const ACK_PENDING: u32 = 0x12;
+}
+
+mod ctx {
+    pub const ATRQ: usize = 0;
+    pub const ATRS: usize = 1;
+    pub const ARRQ: usize = 2;
+    pub const ARRS: usize = 3;
+}
+
+/// Config ROM, as visible on the bus.
+const CONFIG_ROM_BASE: u64 = 0xffff_f000_0400;
+const CONFIG_ROM_LEN: u64 = 0x400;
+
+#[derive(Debug, Default)]
+struct Context {
+    control: u32,
+    /// Bits 3..0 are `Z` (the descriptor count), the rest is the 16-byte
+    /// aligned address of the descriptor block.
+    command_ptr: u32,
+    /// Address of the last descriptor processed, whose branch address is
+    /// re-read when the context is woken up.
+    last_desc: u32,
+}
+
+impl Context {
+    fn is_running(&self) -> bool {
+        self.control.get_bit(context_control::RUN)
+    }
+
+    fn is_active(&self) -> bool {
+        self.control.get_bit(context_control::ACTIVE)
+    }
+
+    /// Reload the command pointer from the last descriptor's branch address,
+    /// in case the firmware has appended more descriptors.
+    fn resume(&mut self, mem: &mut dyn Memory) -> MemResult<()> {
+        if self.command_ptr & 0xf == 0 && self.last_desc != 0 {
+            self.command_ptr = mem.r32(self.last_desc + desc::BRANCH)?;
+        }
+        let active = self.command_ptr & 0xf != 0;
+        self.control.set_bit(context_control::ACTIVE, active);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct Firewire {
+    irq: irq::Sender,
+
+    hc_control: u32,
+    link_control: u32,
+    int_event: u32,
+    int_mask: u32,
+    phy_control: u32,
+    self_id_buffer: u32,
+    self_id_count: u32,
+    node_id: u32,
+    as_req_filter: u64,
+    phys_req_filter: u64,
+    phy_regs: [u8; 16],
+
+    /// ATRQ, ATRS, ARRQ, ARRS -- in that order.
+    contexts: [Context; 4],
+
+    /// Backing store for the parts of the window we haven't identified.
+    /// The observed window is 0x200 bytes, addressed 1:1.
+    reg: Box<[u32; 0x80]>,
+
+    peer: FirewirePeer,
+    peer_rx: async_channel::Receiver<PeerMsg>,
+    peer_mem: Arc<Mutex<PeerMem>>,
+    service_requested: Arc<AtomicBool>,
+
+    peer_connected: bool,
+    bus_reset_pending: bool,
+    generation: u8,
+    /// Packets waiting for space in the ARRQ / ARRS buffers.
+    ar_queue: [VecDeque<Packet>; 2],
+    /// Peer requests awaiting a response from the firmware, indexed by tLabel.
+    outstanding: HashMap<u8, async_channel::Sender<Result<Packet, FwError>>>,
+}
+
+impl Firewire {
+    pub fn new(irq: irq::Sender) -> Firewire {
+        let (peer_tx, peer_rx) = async_channel::unbounded();
+        let service_requested = Arc::new(AtomicBool::new(false));
+        let (peer, peer_mem) = FirewirePeer::new(peer_tx, Arc::clone(&service_requested));
+
+        let mut reg = Box::new([0; 0x80]);
+        reg[(reg::BUS_ID / 4) as usize] = 0x3133_3934; // "1394"
+
+        Firewire {
+            irq,
+
+            hc_control: 0,
+            link_control: 0,
+            int_event: 0,
+            int_mask: 0,
+            phy_control: 0,
+            self_id_buffer: 0,
+            self_id_count: 0,
+            node_id: 0,
+            as_req_filter: 0,
+            phys_req_filter: 0,
+            phy_regs: [0; 16],
+            contexts: Default::default(),
+            reg,
+
+            peer,
+            peer_rx,
+            peer_mem,
+            service_requested,
+
+            peer_connected: false,
+            bus_reset_pending: false,
+            generation: 0,
+            ar_queue: Default::default(),
+            outstanding: HashMap::new(),
+        }
+    }
+
+    /// Return a handle to the virtual 1394 peer on the other end of the cable.
+    pub fn peer(&self) -> FirewirePeer {
+        self.peer.clone()
+    }
+
+    /// Flag which is set whenever the link requires servicing via
+    /// [Firewire::service].
+    pub fn service_requested(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.service_requested)
+    }
+
+    fn request_service(&self) {
+        self.service_requested.store(true, Ordering::SeqCst);
+    }
+
+    fn update_irq(&mut self) {
+        let pending = self.int_event & self.int_mask & 0x7fff_ffff;
+        if self.int_mask.get_bit(int_event::MASTER_INT_ENABLE) && pending != 0 {
+            self.irq.assert()
+        } else {
+            self.irq.clear()
+        }
+    }
+
+    fn link_enabled(&self) -> bool {
+        self.hc_control.get_bit(hc_control::LINK_ENABLE)
+    }
+
+    fn read_phy(&self, addr: u8) -> u8 {
+        self.phy_regs[addr as usize & 0xf]
+    }
+
+    fn write_phy(&mut self, addr: u8, val: u8) {
+        let addr = addr as usize & 0xf;
+        self.phy_regs[addr] = val;
+
+        for &(reg, bit) in &[phy_reg::IBR, phy_reg::ISBR] {
+            if addr == reg && val & bit != 0 {
+                self.phy_regs[addr] &= !bit;
+                self.bus_reset_pending = true;
+                self.request_service();
+            }
+        }
+    }
+
+    /// Map a window offset onto one of the four async DMA contexts, returning
+    /// the context index and the offset within its 0x20-byte block.
+    fn context_at(offset: u32) -> Option<(usize, u32)> {
+        let idx = match offset & !(reg::CONTEXT_LEN - 1) {
+            reg::AS_REQ_TR_CONTEXT => ctx::ATRQ,
+            reg::AS_RSP_TR_CONTEXT => ctx::ATRS,
+            reg::AS_REQ_RCV_CONTEXT => ctx::ARRQ,
+            reg::AS_RSP_RCV_CONTEXT => ctx::ARRS,
+            _ => return None,
+        };
+
+        Some((idx, offset & (reg::CONTEXT_LEN - 1)))
+    }
+
+    fn bus_reset(&mut self, mem: &mut dyn Memory) -> MemResult<()> {
+        self.bus_reset_pending = false;
+        self.generation = self.generation.wrapping_add(1);
+        info!(
+            "FireWire bus reset (generation {}, peer {})",
+            self.generation,
+            if self.peer_connected {
+                "connected"
+            } else {
+                "disconnected"
+            }
+        );
+
+        // in-flight transactions don't survive a bus reset
+        for (_, done) in self.outstanding.drain() {
+            let _ = done.try_send(Err(FwError::BusReset));
+        }
+        self.ar_queue.iter_mut().for_each(VecDeque::clear);
+
+        // the filters are cleared on every bus reset
+        self.as_req_filter = 0;
+        self.phys_req_filter = 0;
+
+        // The iPod is always node 0, and the peer (if any) is the root.
+        let is_root = !self.peer_connected;
+        self.node_id = 0;
+        self.node_id
+            .set_bit(31, true) // iDValid
+            .set_bit(30, is_root)
+            .set_bits(6..=15, 0x3ff) // busNumber (local bus)
+            .set_bits(0..=5, 0); // nodeNumber
+        self.phy_regs[0] = (is_root as u8) << 1 | 1; // R, CPS
+
+        let self_id = |phy_id: u32, port0: u32| -> u32 {
+            0b10 << 30
+                | phy_id << 24
+                | 1 << 22 // link active
+                | 0x3f << 16 // gap count
+                | 2 << 14 // S400
+                | 1 << 11 // contender
+                | port0 << 6
+        };
+        let mut self_ids = Vec::new();
+        if self.peer_connected {
+            self_ids.push(self_id(0, 0b10)); // connected to parent
+            self_ids.push(self_id(1, 0b11)); // connected to child
+        } else {
+            self_ids.push(self_id(0, 0b01)); // not connected
+        }
+
+        if self.link_control.get_bit(link_control::RCV_SELF_ID) && self.self_id_buffer != 0 {
+            let addr = self.self_id_buffer & !0x7ff;
+            mem.w32(addr, (self.generation as u32) << 16)?;
+            for (i, id) in self_ids.iter().enumerate() {
+                mem.w32(addr + 4 + i as u32 * 8, *id)?;
+                mem.w32(addr + 8 + i as u32 * 8, !*id)?;
+            }
+
+            let size = 1 + 2 * self_ids.len() as u32;
+            self.self_id_count = 0;
+            self.self_id_count
+                .set_bits(16..=23, self.generation as u32)
+                .set_bits(2..=10, size);
+        }
+
+        self.int_event
+            .set_bit(int_event::BUS_RESET, true)
+            .set_bit(int_event::SELF_ID_COMPLETE, true)
+            .set_bit(int_event::SELF_ID_COMPLETE2, true);
+
+        Ok(())
+    }
+
+    /// Read a quadlet of the iPod's config ROM, in bus byte order.
+    fn config_rom_quadlet(&self, mem: &mut dyn Memory, offset: u32) -> MemResult<[u8; 4]> {
+        let reg = |offset: u32| self.reg[(offset / 4) as usize].to_be_bytes();
+        Ok(match offset {
+            0x00 => reg(reg::CONFIG_ROM_HDR),
+            0x04 => reg(reg::BUS_ID),
+            0x08 => reg(reg::BUS_OPTIONS),
+            0x0c => reg(reg::GUID_HI),
+            0x10 => reg(reg::GUID_LO),
+            _ => {
+                let base = self.reg[(reg::CONFIG_ROM_MAP / 4) as usize] & !0x3ff;
+                let mut buf = [0; 4];
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = mem.r8(base + offset + i as u32)?;
+                }
+                buf
+            }
+        })
+    }
+
+    /// Serve a peer request using the physical DMA engine, returning the
+    /// response data (or `None` if the address is invalid).
+    fn physical_request(mem: &mut dyn Memory, req: &Packet) -> Option<Vec<u8>> {
+        let len = match req.tcode {
+            tcode::WRITE_QUADLET_REQ | tcode::WRITE_BLOCK_REQ => req.data.len(),
+            tcode::READ_QUADLET_REQ => 4,
+            tcode::READ_BLOCK_REQ => req.len,
+            _ => return None,
+        };
+
+        // the 48-bit offset has to fit in the (32-bit) system address space
+        let addr = u32::try_from(req.offset).ok()?;
+        addr.checked_add(u32::try_from(len).ok()?)?;
+
+        match req.tcode {
+            tcode::WRITE_QUADLET_REQ | tcode::WRITE_BLOCK_REQ => {
+                for (i, b) in req.data.iter().enumerate() {
+                    mem.w8(addr + i as u32, *b).ok()?;
+                }
+                Some(Vec::new())
+            }
+            _ => (0..len as u32).map(|i| mem.r8(addr + i).ok()).collect(),
+        }
+    }
+
+    fn handle_peer_msg(&mut self, mem: &mut dyn Memory, msg: PeerMsg) -> MemResult<()> {
+        match msg {
+            PeerMsg::Connect(connected) => {
+                if connected != self.peer_connected {
+                    self.peer_connected = connected;
+                    self.bus_reset_pending = true;
+                }
+            }
+            PeerMsg::BusReset => {
+                if self.peer_connected {
+                    self.bus_reset_pending = true;
+                }
+            }
+            PeerMsg::Request { mut packet, done } => {
+                if !self.peer_connected {
+                    let _ = done.try_send(Err(FwError::Disconnected));
+                    return Ok(());
+                }
+                if !self.link_enabled() || self.bus_reset_pending {
+                    let _ = done.try_send(Err(FwError::NoAck));
+                    return Ok(());
+                }
+
+                packet.src = PEER_NODE_ID;
+                packet.dst = self.node_id as u16;
+
+                let peer_bit = PEER_NODE_ID as usize & 0x3f;
+                let phys_upper_bound = match self.reg[(reg::PHYS_UPPER_BOUND / 4) as usize] {
+                    0 => 0x1_0000_0000,
+                    bound => (bound as u64) << 16,
+                };
+
+                let offset = packet.offset;
+                if (CONFIG_ROM_BASE..CONFIG_ROM_BASE + CONFIG_ROM_LEN).contains(&offset)
+                    && packet.tcode == tcode::READ_QUADLET_REQ
+                {
+                    let data = self.config_rom_quadlet(mem, (offset - CONFIG_ROM_BASE) as u32)?;
+                    let resp = Packet::response(&packet, rcode::COMPLETE, data.to_vec());
+                    let _ = done.try_send(Ok(resp));
+                } else if offset < phys_upper_bound && self.phys_req_filter.get_bit(peer_bit) {
+                    let resp = match Firewire::physical_request(mem, &packet) {
+                        Some(data) => Packet::response(&packet, rcode::COMPLETE, data),
+                        None => Packet::response(&packet, rcode::ADDRESS_ERROR, Vec::new()),
+                    };
+                    let _ = done.try_send(Ok(resp));
+                } else if self.as_req_filter.get_bit(peer_bit) || self.as_req_filter.get_bit(63) {
+                    // hand it off to the firmware
+                    if let Some(old) = self.outstanding.insert(packet.tlabel, done) {
+                        let _ = old.try_send(Err(FwError::Timeout));
+                    }
+                    self.ar_queue[0].push_back(packet);
+                } else {
+                    let _ = done.try_send(Err(FwError::NoAck));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Transmit a packet from one of the AT contexts, returning the resulting
+    /// event code.
+    fn transmit(&mut self, packet: Packet) -> u32 {
+        if !self.peer_connected || packet.dst & 0x3f != PEER_NODE_ID & 0x3f {
+            return evt::MISSING_ACK;
+        }
+
+        if tcode::is_response(packet.tcode) {
+            match self.outstanding.remove(&packet.tlabel) {
+                Some(done) => {
+                    let _ = done.try_send(Ok(packet));
+                }
+                None => warn!("Unexpected FireWire response: {:x?}", packet),
+            }
+            return evt::ACK_COMPLETE;
+        }
+
+        let resp = self.peer_mem.lock().unwrap().handle_request(&packet);
+        match resp {
+            None => evt::ACK_COMPLETE,
+            Some(resp) => {
+                self.ar_queue[1].push_back(resp);
+                evt::ACK_PENDING
+            }
+        }
+    }
+
+    /// Run an AT (transmit) context until it runs out of descriptors.
+    fn run_at(&mut self, mem: &mut dyn Memory, idx: usize) -> MemResult<()> {
+        loop {
+            let context = &mut self.contexts[idx];
+            if !context.is_running() {
+                return Ok(());
+            }
+            if !context.is_active() || context.control.get_bit(context_control::WAKE) {
+                context.control.set_bit(context_control::WAKE, false);
+                context.resume(mem)?;
+                if !context.is_active() {
+                    return Ok(());
+                }
+            }
+
+            let addr = context.command_ptr & !0xf;
+            let control = mem.r32(addr + desc::CONTROL)?;
+            if control.get_bits(desc::KEY) != desc::KEY_IMMEDIATE {
+                warn!("Unexpected FireWire AT descriptor: {:#010x}", control);
+                context.control.set_bit(context_control::DEAD, true);
+                context.control.set_bit(context_control::ACTIVE, false);
+                return Ok(());
+            }
+
+            // packet header is stored immediately after the first descriptor
+            let header_addr = addr + desc::SIZE;
+            let mut header = [0; 4];
+            for (i, q) in header.iter_mut().enumerate() {
+                *q = mem.r32(header_addr + i as u32 * 4)?;
+            }
+            let mut quadlet_data = [0; 4];
+            for (i, b) in quadlet_data.iter_mut().enumerate() {
+                *b = mem.r8(header_addr + 12 + i as u32)?;
+            }
+
+            let (last, payload) = if control.get_bits(desc::CMD) == desc::CMD_OUTPUT_LAST {
+                (addr, Vec::new())
+            } else {
+                let last = addr + 2 * desc::SIZE;
+                let len = mem.r32(last + desc::CONTROL)?.get_bits(desc::REQ_COUNT);
+                let data_addr = mem.r32(last + desc::DATA_ADDRESS)?;
+                let payload = (0..len)
+                    .map(|i| mem.r8(data_addr + i))
+                    .collect::<MemResult<Vec<u8>>>()?;
+                (last, payload)
+            };
+
+            let tcode = header[0].get_bits(4..=7) as u8;
+            let mut packet = Packet {
+                tcode,
+                tlabel: header[0].get_bits(10..=15) as u8,
+                src: self.node_id as u16,
+                dst: header[1].get_bits(16..=31) as u16,
+                offset: (header[1].get_bits(0..=15) as u64) << 32 | header[2] as u64,
+                rcode: header[1].get_bits(12..=15) as u8,
+                len: header[3].get_bits(16..=31) as usize,
+                data: payload,
+            };
+            match tcode {
+                tcode::WRITE_QUADLET_REQ | tcode::READ_QUADLET_RESP => {
+                    packet.data = quadlet_data.to_vec()
+                }
+                tcode::WRITE_BLOCK_REQ | tcode::READ_BLOCK_RESP => packet.data.truncate(packet.len),
+                _ => {}
+            }
+            if tcode::is_response(tcode) {
+                packet.offset = 0;
+            } else {
+                packet.rcode = 0;
+            }
+
+            let event = self.transmit(packet);
+
+            let context = &mut self.contexts[idx];
+            context.control.set_bits(context_control::EVENT_CODE, event);
+            let last_control = mem.r32(last + desc::CONTROL)?;
+            mem.w32(last + desc::STATUS, (context.control & 0xffff) << 16)?;
+            context.last_desc = last;
+            context.command_ptr = mem.r32(last + desc::BRANCH)?;
+            if context.command_ptr & 0xf == 0 {
+                context.control.set_bit(context_control::ACTIVE, false);
+            }
+
+            if last_control.get_bits(desc::INT) == desc::INT_ALWAYS {
+                let bit = match idx {
+                    ctx::ATRQ => int_event::REQ_TX_COMPLETE,
+                    _ => int_event::RESP_TX_COMPLETE,
+                };
+                self.int_event.set_bit(bit, true);
+            }
+        }
+    }
+
+    /// Serialize a packet into the layout used by the AR contexts.
+    fn encode_ar(packet: &Packet) -> Vec<u8> {
+        let mut buf = Vec::new();
+        let mut push = |q: u32| buf.extend_from_slice(&q.to_le_bytes());
+
+        let mut q0 = 0;
+        q0.set_bits(16..=31, packet.dst as u32)
+            .set_bits(10..=15, packet.tlabel as u32)
+            .set_bits(8..=9, 1) // retry_X
+            .set_bits(4..=7, packet.tcode as u32);
+        push(q0);
+
+        let is_response = tcode::is_response(packet.tcode);
+        if is_response {
+            push((packet.src as u32) << 16 | (packet.rcode as u32) << 12);
+            push(0);
+        } else {
+            push((packet.src as u32) << 16 | (packet.offset >> 32) as u32 & 0xffff);
+            push(packet.offset as u32);
+        }
+
+        match packet.tcode {
+            tcode::WRITE_QUADLET_REQ | tcode::READ_QUADLET_RESP => {
+                let mut data = packet.data.clone();
+                data.resize(4, 0);
+                buf.extend_from_slice(&data);
+            }
+            tcode::WRITE_BLOCK_REQ | tcode::READ_BLOCK_RESP => {
+                buf.extend_from_slice(&((packet.data.len() as u32) << 16).to_le_bytes());
+                buf.extend_from_slice(&packet.data);
+                let padded_len = (buf.len() + 3) & !3;
+                buf.resize(padded_len, 0);
+            }
+            tcode::READ_BLOCK_REQ => {
+                buf.extend_from_slice(&((packet.len as u32) << 16).to_le_bytes());
+            }
+            _ => {}
+        }
+
+        // trailer: xferStatus (reporting the ack sent by the link) + timeStamp
+        let event = match is_response {
+            true => evt::ACK_COMPLETE,
+            false => evt::ACK_PENDING,
+        };
+        buf.extend_from_slice(&(event << 16).to_le_bytes());
+        buf
+    }
+
+    /// Write a packet into an AR (receive) context's buffers, using
+    /// buffer-fill mode. Returns `false` if there isn't enough space for the
+    /// packet.
+    fn ar_receive(&mut self, mem: &mut dyn Memory, idx: usize, data: &[u8]) -> MemResult<bool> {
+        let context = &mut self.contexts[idx];
+        if !context.is_running() {
+            return Ok(false);
+        }
+        if !context.is_active() || context.control.get_bit(context_control::WAKE) {
+            context.control.set_bit(context_control::WAKE, false);
+            context.resume(mem)?;
+            if !context.is_active() {
+                return Ok(false);
+            }
+        }
+
+        // make sure the whole packet fits before writing anything
+        let mut avail = 0;
+        let mut addr = context.command_ptr;
+        // descriptors are often chained into a ring, so cap the search
+        for _ in 0..64 {
+            if addr & 0xf == 0 {
+                break;
+            }
+            let addr_base = addr & !0xf;
+            avail += mem.r32(addr_base + desc::STATUS)? & 0xffff;
+            if avail as usize >= data.len() {
+                break;
+            }
+            addr = mem.r32(addr_base + desc::BRANCH)?;
+        }
+        if (avail as usize) < data.len() {
+            return Ok(false);
+        }
+
+        let mut written = 0;
+        let mut filled_buffer = false;
+        while written < data.len() {
+            let addr = context.command_ptr & !0xf;
+            let control = mem.r32(addr + desc::CONTROL)?;
+            let req_count = control.get_bits(desc::REQ_COUNT);
+            let res_count = mem.r32(addr + desc::STATUS)? & 0xffff;
+
+            if res_count != 0 {
+                let buf_addr = mem.r32(addr + desc::DATA_ADDRESS)? + (req_count - res_count);
+                let n = (res_count as usize).min(data.len() - written);
+                for (i, b) in data[written..written + n].iter().enumerate() {
+                    mem.w8(buf_addr + i as u32, *b)?;
+                }
+                written += n;
+
+                let res_count = res_count - n as u32;
+                mem.w32(
+                    addr + desc::STATUS,
+                    (context.control & 0xffff) << 16 | res_count,
+                )?;
+                if res_count != 0 {
+                    continue;
+                }
+                filled_buffer |= control.get_bits(desc::INT) == desc::INT_ALWAYS;
+            }
+
+            // buffer is full, move on to the next one
+            context.last_desc = addr;
+            context.command_ptr = mem.r32(addr + desc::BRANCH)?;
+            if context.command_ptr & 0xf == 0 {
+                context.control.set_bit(context_control::ACTIVE, false);
+                break;
+            }
+        }
+
+        let (pkt_bit, ctx_bit) = match idx {
+            ctx::ARRQ => (int_event::RQ_PKT, int_event::ARRQ),
+            _ => (int_event::RS_PKT, int_event::ARRS),
+        };
+        self.int_event.set_bit(pkt_bit, true);
+        if filled_buffer {
+            self.int_event.set_bit(ctx_bit, true);
+        }
+
+        Ok(true)
+    }
+
+    /// Process any outstanding work.
+    ///
+    /// `mem` should provide access to the system's physical address space, as
+    /// the DMA descriptors / buffers all live in guest memory.
+    pub fn service(&mut self, mem: &mut dyn Memory) -> MemResult<()> {
+        while let Ok(msg) = self.peer_rx.try_recv() {
+            self.handle_peer_msg(mem, msg)?;
+        }
+
+        if self.bus_reset_pending && self.link_enabled() {
+            self.bus_reset(mem)?;
+        }
+
+        self.run_at(mem, ctx::ATRQ)?;
+        self.run_at(mem, ctx::ATRS)?;
+
+        for (i, idx) in [ctx::ARRQ, ctx::ARRS].iter().enumerate() {
+            while let Some(packet) = self.ar_queue[i].front() {
+                let data = Firewire::encode_ar(packet);
+                if !self.ar_receive(mem, *idx, &data)? {
+                    break;
+                }
+                self.ar_queue[i].pop_front();
+            }
+        }
+
+        self.update_irq();
+        Ok(())
+    }
+}
+
+impl Device for Firewire {
+    fn kind(&self) -> &'static str {
+        "Firewire (OHCI)"
+    }
+
+    fn probe(&self, offset: u32) -> Probe {
+        if let Some((idx, off)) = Firewire::context_at(offset) {
+            let ctx = ["ATRQ", "ATRS", "ARRQ", "ARRS"][idx];
+            return Probe::Register(match off {
+                reg::CONTEXT_CONTROL_SET => ctx,
+                reg::CONTEXT_CONTROL_CLEAR => ctx,
+                reg::COMMAND_PTR => ctx,
+                _ => "(?) context",
+            });
+        }
+
+        let reg = match offset {
+            reg::AT_RETRIES => "ATRetries",
+            reg::CONFIG_ROM_HDR => "ConfigROMhdr",
+            reg::BUS_ID => "BusID",
+            reg::BUS_OPTIONS => "BusOptions",
+            reg::GUID_HI => "GUIDHi",
+            reg::GUID_LO => "GUIDLo",
+            reg::CONFIG_ROM_MAP => "ConfigROMmap",
+            reg::HC_CONTROL_SET => "HCControlSet",
+            reg::HC_CONTROL_CLEAR => "HCControlClear",
+            reg::SELF_ID_BUFFER => "SelfIDBuffer",
+            reg::SELF_ID_COUNT => "SelfIDCount",
+            reg::INT_EVENT_SET => "IntEventSet",
+            reg::INT_EVENT_CLEAR => "IntEventClear",
+            reg::INT_MASK_SET => "IntMaskSet",
+            reg::INT_MASK_CLEAR => "IntMaskClear",
+            reg::FAIRNESS_CONTROL => "FairnessControl",
+            reg::LINK_CONTROL_SET => "LinkControlSet",
+            reg::LINK_CONTROL_CLEAR => "LinkControlClear",
+            reg::NODE_ID => "NodeID",
+            reg::PHY_CONTROL => "PhyControl",
+            reg::AS_REQ_FILTER_HI_SET => "AsReqFilterHiSet",
+            reg::AS_REQ_FILTER_HI_CLEAR => "AsReqFilterHiClear",
+            reg::AS_REQ_FILTER_LO_SET => "AsReqFilterLoSet",
+            reg::AS_REQ_FILTER_LO_CLEAR => "AsReqFilterLoClear",
+            reg::PHYS_REQ_FILTER_HI_SET => "PhysicalReqFilterHiSet",
+            reg::PHYS_REQ_FILTER_HI_CLEAR => "PhysicalReqFilterHiClear",
+            reg::PHYS_REQ_FILTER_LO_SET => "PhysicalReqFilterLoSet",
+            reg::PHYS_REQ_FILTER_LO_CLEAR => "PhysicalReqFilterLoClear",
+            reg::PHYS_UPPER_BOUND => "PhysicalUpperBound",
+            _ => "(?)",
+        };
+
+        Probe::Register(reg)
+    }
+}
+
+impl Memory for Firewire {
+    fn r32(&mut self, offset: u32) -> MemResult<u32> {
+        if offset & 0b11 != 0 {
+            return Err(Misaligned);
+        }
+
+        let idx = (offset / 4) as usize;
+        if idx >= self.reg.len() {
+            return Err(Unexpected);
+        }
+
+        if let Some((ctx, off)) = Firewire::context_at(offset) {
+            let val = match off {
+                // The Set and Clear aliases read back the same value.
+                reg::CONTEXT_CONTROL_SET | reg::CONTEXT_CONTROL_CLEAR => self.contexts[ctx].control,
+                reg::COMMAND_PTR => self.contexts[ctx].command_ptr,
+                _ => return Err(StubRead(Debug, self.reg[idx])),
+            };
+            return Ok(val);
+        }
+
+        let val = match offset {
+            reg::HC_CONTROL_SET | reg::HC_CONTROL_CLEAR => self.hc_control,
+            reg::SELF_ID_BUFFER => self.self_id_buffer,
+            reg::SELF_ID_COUNT => self.self_id_count,
+            reg::INT_EVENT_SET => self.int_event,
+            // reading IntEventClear returns only the unmasked events
+            reg::INT_EVENT_CLEAR => self.int_event & self.int_mask,
+            reg::INT_MASK_SET | reg::INT_MASK_CLEAR => self.int_mask,
+            reg::LINK_CONTROL_SET | reg::LINK_CONTROL_CLEAR => self.link_control,
+            reg::NODE_ID => self.node_id,
+            reg::PHY_CONTROL => self.phy_control,
+            reg::AS_REQ_FILTER_HI_SET | reg::AS_REQ_FILTER_HI_CLEAR => {
+                (self.as_req_filter >> 32) as u32
+            }
+            reg::AS_REQ_FILTER_LO_SET | reg::AS_REQ_FILTER_LO_CLEAR => self.as_req_filter as u32,
+            reg::PHYS_REQ_FILTER_HI_SET | reg::PHYS_REQ_FILTER_HI_CLEAR => {
+                (self.phys_req_filter >> 32) as u32
+            }
+            reg::PHYS_REQ_FILTER_LO_SET | reg::PHYS_REQ_FILTER_LO_CLEAR => {
+                self.phys_req_filter as u32
+            }
+            reg::CONFIG_ROM_HDR
+            | reg::BUS_ID
+            | reg::BUS_OPTIONS
+            | reg::GUID_HI
+            | reg::GUID_LO
+            | reg::CONFIG_ROM_MAP
+            | reg::PHYS_UPPER_BOUND => self.reg[idx],
+            _ => return Err(StubRead(Debug, self.reg[idx])),
+        };
+
+        Ok(val)
+    }
+
+    fn w32(&mut self, offset: u32, val: u32) -> MemResult<()> {
+        if offset & 0b11 != 0 {
+            return Err(Misaligned);
+        }
+
+        let idx = (offset / 4) as usize;
+        if idx >= self.reg.len() {
+            return Err(Unexpected);
+        }
+
+        if let Some((ctx, off)) = Firewire::context_at(offset) {
+            let context = &mut self.contexts[ctx];
+            match off {
+                reg::CONTEXT_CONTROL_SET => {
+                    let mut mask = 0;
+                    mask.set_bit(context_control::RUN, true)
+                        .set_bit(context_control::WAKE, true);
+                    context.control |= val & mask;
+                    if val.get_bit(context_control::RUN) && !context.is_active() {
+                        let active = context.command_ptr & 0xf != 0;
+                        context.control.set_bit(context_control::ACTIVE, active);
+                    }
+                    self.request_service();
+                }
+                reg::CONTEXT_CONTROL_CLEAR => {
+                    let mut mask = 0;
+                    mask.set_bit(context_control::RUN, true)
+                        .set_bit(context_control::WAKE, true)
+                        .set_bit(context_control::DEAD, true);
+                    context.control &= !(val & mask);
+                    if !context.is_running() {
+                        context.control.set_bit(context_control::ACTIVE, false);
+                    }
+                }
+                reg::COMMAND_PTR => {
+                    context.command_ptr = val;
+                    context.last_desc = 0;
+                }
+                _ => return Err(StubWrite(Debug, self.reg[idx] = val)),
+            }
+            return Ok(());
+        }
+
+        match offset {
+            reg::HC_CONTROL_SET => {
+                let was_enabled = self.link_enabled();
+                self.hc_control |= val;
+                // softReset never stays set: the reset completes instantly, and
+                // the firmware polls for the bit to come back down.
+                self.hc_control.set_bit(hc_control::SOFT_RESET, false);
+
+                // enabling the link with a peer on the bus kicks off a reset
+                if !was_enabled && self.link_enabled() && self.peer_connected {
+                    self.bus_reset_pending = true;
+                }
+                self.request_service();
+            }
+            reg::HC_CONTROL_CLEAR => self.hc_control &= !val,
+
+            reg::SELF_ID_BUFFER => self.self_id_buffer = val,
+
+            reg::INT_EVENT_SET => {
+                self.int_event |= val;
+                self.update_irq();
+            }
+            reg::INT_EVENT_CLEAR => {
+                self.int_event &= !val;
+                self.update_irq();
+            }
+            reg::INT_MASK_SET => {
+                self.int_mask |= val;
+                self.update_irq();
+            }
+            reg::INT_MASK_CLEAR => {
+                self.int_mask &= !val;
+                self.update_irq();
+            }
+
+            reg::LINK_CONTROL_SET => self.link_control |= val,
+            reg::LINK_CONTROL_CLEAR => self.link_control &= !val,
+
+            // only busNumber is writable
+            reg::NODE_ID => {
+                self.node_id.set_bits(6..=15, val.get_bits(6..=15));
+            }
+
+            reg::AS_REQ_FILTER_HI_SET => self.as_req_filter |= (val as u64) << 32,
+            reg::AS_REQ_FILTER_HI_CLEAR => self.as_req_filter &= !((val as u64) << 32),
+            reg::AS_REQ_FILTER_LO_SET => self.as_req_filter |= val as u64,
+            reg::AS_REQ_FILTER_LO_CLEAR => self.as_req_filter &= !(val as u64),
+            reg::PHYS_REQ_FILTER_HI_SET => self.phys_req_filter |= (val as u64) << 32,
+            reg::PHYS_REQ_FILTER_HI_CLEAR => self.phys_req_filter &= !((val as u64) << 32),
+            reg::PHYS_REQ_FILTER_LO_SET => self.phys_req_filter |= val as u64,
+            reg::PHYS_REQ_FILTER_LO_CLEAR => self.phys_req_filter &= !(val as u64),
+
+            reg::PHY_CONTROL => {
+                let mut val = val;
+                let addr = val.get_bits(phy_control::REG_ADDR) as u8;
+
+                // Transfers complete instantly: there's no bus to arbitrate
+                // for, so there's nothing to make the firmware wait on. Leaving
+                // RD_DONE clear instead would wedge it -- diagnostics spins on
+                // that bit at pc 0x1000bfb0 with no timeout.
+                if val.get_bit(phy_control::WR_REG) {
+                    self.write_phy(addr, val.get_bits(phy_control::WR_DATA) as u8);
+                    val.set_bit(phy_control::WR_REG, false);
+                }
+
+                if val.get_bit(phy_control::RD_REG) {
+                    let data = self.read_phy(addr);
+
+                    val.set_bit(phy_control::RD_REG, false)
+                        .set_bits(phy_control::RD_ADDR, addr as u32)
+                        .set_bits(phy_control::RD_DATA, data as u32)
+                        .set_bit(phy_control::RD_DONE, true);
+                }
+
+                self.phy_control = val;
+            }
+
+            reg::CONFIG_ROM_HDR
+            | reg::BUS_OPTIONS
+            | reg::GUID_HI
+            | reg::GUID_LO
+            | reg::CONFIG_ROM_MAP
+            | reg::PHYS_UPPER_BOUND => self.reg[idx] = val,
+            // read-only
+            reg::BUS_ID | reg::SELF_ID_COUNT => return Err(InvalidAccess),
+
+            _ => return Err(StubWrite(Debug, self.reg[idx] = val)),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::sbp2::Sbp2Initiator;
+    use super::*;
+
+    use std::thread::{self, JoinHandle};
+
+    use crate::devices::generic::Ram;
+
+    const CONFIG_ROM: u32 = 0x0400;
+    const SELF_ID_BUF: u32 = 0x0800;
+    const AT_DESC: [u32; 2] = [0x1000, 0x1100];
+    const AT_BUF: u32 = 0x2000;
+    const AR_DESC: [u32; 2] = [0x1200, 0x1300];
+    const AR_BUF: [u32; 2] = [0x4000, 0x6000];
+    const AR_BUF_LEN: u32 = 0x2000;
+
+    /// SBP-2 agents, as advertised in the config ROM.
+    const MGMT_AGENT: u64 = 0xffff_f001_0000;
+    const CMD_AGENT: u64 = 0xffff_f002_0000;
+
+    const BLOCK_SIZE: usize = 512;
+
+    /// Contents of the (virtual) disk exposed over SBP-2.
+    fn block(lba: u32) -> Vec<u8> {
+        (0..BLOCK_SIZE).map(|i| (lba as usize + i) as u8).collect()
+    }
+
+    /// Minimal guest-side link driver, implementing a single-LUN SBP-2 target
+    /// on top of the async DMA contexts.
+    struct Guest {
+        fw: Firewire,
+        ram: Ram,
+        /// Read cursor into each AR context's buffer.
+        ar_pos: [u32; 2],
+        tlabel: u8,
+        status_fifo: u64,
+    }
+
+    impl Guest {
+        fn new() -> Guest {
+            let (irq, _) = irq::new(irq::Pending::new(), "FireWire");
+            let mut guest = Guest {
+                fw: Firewire::new(irq),
+                ram: Ram::new(0x10000),
+                ar_pos: [0; 2],
+                tlabel: 0,
+                status_fifo: 0,
+            };
+
+            // bus info block is 4 quadlets long, and the root directory points
+            // at a single SBP-2 unit directory
+            let rom: [u32; 6] = [
+                1 << 16,                // root directory
+                0xd1 << 24 | 1,         // unit directory
+                3 << 16,                // unit directory
+                0x12 << 24 | 0x00_609e, // specifier_ID
+                0x13 << 24 | 0x01_0483, // version
+                0x54 << 24 | 0x4000,    // Management_Agent_Offset
+            ];
+            let rom = rom.iter().flat_map(|q| q.to_be_bytes()).collect::<Vec<_>>();
+            guest.ram.bulk_write(CONFIG_ROM + 0x14, &rom);
+
+            let fw = &mut guest.fw;
+            fw.w32(reg::CONFIG_ROM_HDR, 0x0404_0000).unwrap();
+            fw.w32(reg::CONFIG_ROM_MAP, CONFIG_ROM).unwrap();
+            fw.w32(reg::SELF_ID_BUFFER, SELF_ID_BUF).unwrap();
+            fw.w32(reg::LINK_CONTROL_SET, 1 << link_control::RCV_SELF_ID)
+                .unwrap();
+            fw.w32(reg::HC_CONTROL_SET, 1 << hc_control::LINK_ENABLE)
+                .unwrap();
+
+            // one big buffer-fill descriptor per AR context
+            for i in 0..2 {
+                let (desc, buf) = (AR_DESC[i], AR_BUF[i]);
+                guest.ram.w32(desc + desc::CONTROL, AR_BUF_LEN).unwrap();
+                guest.ram.w32(desc + desc::DATA_ADDRESS, buf).unwrap();
+                guest.ram.w32(desc + desc::BRANCH, 0).unwrap();
+                guest.ram.w32(desc + desc::STATUS, AR_BUF_LEN).unwrap();
+
+                let ctx = [reg::AS_REQ_RCV_CONTEXT, reg::AS_RSP_RCV_CONTEXT][i];
+                let fw = &mut guest.fw;
+                fw.w32(ctx + reg::COMMAND_PTR, desc | 1).unwrap();
+                fw.w32(ctx + reg::CONTEXT_CONTROL_SET, 1 << context_control::RUN)
+                    .unwrap();
+            }
+
+            guest
+        }
+
+        /// Pop the next packet received by an AR context (0 = ARRQ, 1 = ARRS).
+        fn receive(&mut self, i: usize) -> Option<Packet> {
+            let res_count = self.ram.r32(AR_DESC[i] + desc::STATUS).unwrap() & 0xffff;
+            let base = AR_BUF[i] + self.ar_pos[i];
+            if self.ar_pos[i] == AR_BUF_LEN - res_count {
+                return None;
+            }
+
+            let q = |ram: &mut Ram, n: u32| ram.r32(base + n * 4).unwrap();
+            let (q0, q1, q2) = (
+                q(&mut self.ram, 0),
+                q(&mut self.ram, 1),
+                q(&mut self.ram, 2),
+            );
+            let tcode = q0.get_bits(4..=7) as u8;
+            let mut packet = Packet {
+                tcode,
+                tlabel: q0.get_bits(10..=15) as u8,
+                src: q1.get_bits(16..=31) as u16,
+                dst: q0.get_bits(16..=31) as u16,
+                offset: (q1.get_bits(0..=15) as u64) << 32 | q2 as u64,
+                rcode: q1.get_bits(12..=15) as u8,
+                len: 0,
+                data: Vec::new(),
+            };
+
+            let data_len = match tcode {
+                tcode::WRITE_QUADLET_REQ | tcode::READ_QUADLET_RESP => 4,
+                tcode::WRITE_BLOCK_REQ | tcode::READ_BLOCK_RESP | tcode::READ_BLOCK_REQ => {
+                    packet.len = q(&mut self.ram, 3).get_bits(16..=31) as usize;
+                    match tcode {
+                        tcode::READ_BLOCK_REQ => 0,
+                        _ => packet.len,
+                    }
+                }
+                _ => 0,
+            };
+            let data_start = match tcode {
+                tcode::WRITE_QUADLET_REQ | tcode::READ_QUADLET_RESP => 12,
+                tcode::WRITE_BLOCK_REQ | tcode::READ_BLOCK_RESP | tcode::READ_BLOCK_REQ => 16,
+                _ => 12,
+            };
+            packet.data = vec![0; data_len];
+            self.ram.bulk_read(base + data_start, &mut packet.data);
+
+            // skip over the (padded) payload and the trailer
+            self.ar_pos[i] += (data_start + data_len as u32 + 3) & !3;
+            self.ar_pos[i] += 4;
+            Some(packet)
+        }
+
+        /// Transmit a packet from an AT context (0 = ATRQ, 1 = ATRS).
+        fn transmit(&mut self, i: usize, header: [u32; 4], payload: &[u8]) {
+            let desc = AT_DESC[i];
+            let mut control = 0;
+            control
+                .set_bits(desc::KEY, desc::KEY_IMMEDIATE)
+                .set_bits(desc::REQ_COUNT, 16);
+            if payload.is_empty() {
+                control.set_bits(desc::CMD, desc::CMD_OUTPUT_LAST);
+            }
+            self.ram.w32(desc + desc::CONTROL, control).unwrap();
+            self.ram.w32(desc + desc::BRANCH, 0).unwrap();
+            for (n, q) in header.iter().enumerate() {
+                self.ram.w32(desc + desc::SIZE + n as u32 * 4, *q).unwrap();
+            }
+
+            let z = if payload.is_empty() {
+                2
+            } else {
+                let last = desc + 2 * desc::SIZE;
+                let mut control = 0;
+                control
+                    .set_bits(desc::CMD, desc::CMD_OUTPUT_LAST)
+                    .set_bits(desc::REQ_COUNT, payload.len() as u32);
+                self.ram.w32(last + desc::CONTROL, control).unwrap();
+                self.ram.w32(last + desc::DATA_ADDRESS, AT_BUF).unwrap();
+                self.ram.w32(last + desc::BRANCH, 0).unwrap();
+                self.ram.bulk_write(AT_BUF, payload);
+                3
+            };
+
+            let ctx = [reg::AS_REQ_TR_CONTEXT, reg::AS_RSP_TR_CONTEXT][i];
+            self.fw.w32(ctx + reg::COMMAND_PTR, desc | z).unwrap();
+            self.fw
+                .w32(ctx + reg::CONTEXT_CONTROL_SET, 1 << context_control::RUN)
+                .unwrap();
+            self.fw.service(&mut self.ram).unwrap();
+        }
+
+        fn next_tlabel(&mut self) -> u32 {
+            self.tlabel = (self.tlabel + 1) & 0x3f;
+            (self.tlabel as u32) << 10
+        }
+
+        fn peer_read(&mut self, offset: u64, len: usize) -> Vec<u8> {
+            let header = [
+                self.next_tlabel() | (tcode::READ_BLOCK_REQ as u32) << 4,
+                (PEER_NODE_ID as u32) << 16 | (offset >> 32) as u32,
+                offset as u32,
+                (len as u32) << 16,
+            ];
+            self.transmit(ctx::ATRQ, header, &[]);
+
+            let resp = self.receive(1).expect("no response from peer");
+            assert_eq!(resp.tcode, tcode::READ_BLOCK_RESP);
+            assert_eq!(resp.rcode, rcode::COMPLETE);
+            resp.data
+        }
+
+        fn peer_write(&mut self, offset: u64, data: &[u8]) {
+            let header = [
+                self.next_tlabel() | (tcode::WRITE_BLOCK_REQ as u32) << 4,
+                (PEER_NODE_ID as u32) << 16 | (offset >> 32) as u32,
+                offset as u32,
+                (data.len() as u32) << 16,
+            ];
+            self.transmit(ctx::ATRQ, header, data);
+        }
+
+        /// Read an ORB from the address written to an agent's ORB_POINTER.
+        fn fetch_orb(&mut self, orb_pointer: &[u8]) -> Vec<u8> {
+            let addr = u64::from_be_bytes([
+                0,
+                0,
+                orb_pointer[2],
+                orb_pointer[3],
+                orb_pointer[4],
+                orb_pointer[5],
+                orb_pointer[6],
+                orb_pointer[7],
+            ]);
+            self.peer_read(addr, 32)
+        }
+
+        fn write_status(&mut self) {
+            // src = 0 (ORB), resp = 0, len = 1 (i.e: no sense data)
+            self.peer_write(self.status_fifo, &[0x01, 0, 0, 0, 0, 0, 0, 0]);
+        }
+
+        fn handle_request(&mut self, req: Packet) {
+            // acknowledge the ORB_POINTER write
+            let header = [
+                (req.tlabel as u32) << 10 | (tcode::WRITE_RESP as u32) << 4,
+                (req.src as u32) << 16 | (rcode::COMPLETE as u32) << 12,
+                0,
+                0,
+            ];
+            self.transmit(ctx::ATRS, header, &[]);
+
+            let orb = self.fetch_orb(&req.data);
+            let addr = |i: usize| {
+                u64::from_be_bytes([
+                    0,
+                    0,
+                    orb[i + 2],
+                    orb[i + 3],
+                    orb[i + 4],
+                    orb[i + 5],
+                    orb[i + 6],
+                    orb[i + 7],
+                ])
+            };
+
+            match req.offset {
+                MGMT_AGENT => {
+                    // login (the only management function exercised here)
+                    assert_eq!(orb[17] & 0xf, 0);
+                    self.status_fifo = addr(24);
+
+                    let mut resp = Vec::new();
+                    resp.extend_from_slice(&(16u32 << 16).to_be_bytes());
+                    resp.extend_from_slice(&((CMD_AGENT >> 32) as u32).to_be_bytes());
+                    resp.extend_from_slice(&(CMD_AGENT as u32).to_be_bytes());
+                    resp.extend_from_slice(&0u32.to_be_bytes());
+                    self.peer_write(addr(8), &resp);
+                }
+                offset if offset == CMD_AGENT + 0x08 => {
+                    // READ (10)
+                    assert_eq!(orb[20], 0x28);
+                    let lba = u32::from_be_bytes([orb[22], orb[23], orb[24], orb[25]]);
+                    let count = u16::from_be_bytes([orb[27], orb[28]]) as u32;
+                    let data = (lba..lba + count).flat_map(block).collect::<Vec<_>>();
+                    self.peer_write(addr(8), &data);
+                }
+                offset => panic!("unexpected request to {:#x}", offset),
+            }
+
+            self.write_status();
+        }
+
+        fn poll(&mut self) {
+            self.fw.service(&mut self.ram).unwrap();
+
+            // the request filters are cleared on bus reset
+            if self.fw.r32(reg::INT_EVENT_SET).unwrap() & 1 << int_event::BUS_RESET != 0 {
+                self.fw
+                    .w32(reg::INT_EVENT_CLEAR, 1 << int_event::BUS_RESET)
+                    .unwrap();
+                self.fw.w32(reg::AS_REQ_FILTER_HI_SET, 1 << 31).unwrap();
+                self.fw.w32(reg::PHYS_REQ_FILTER_LO_SET, 1 << 1).unwrap();
+            }
+
+            while let Some(req) = self.receive(0) {
+                self.handle_request(req);
+            }
+        }
+
+        /// Run the guest until the peer is done with it.
+        fn run<T>(&mut self, peer: JoinHandle<T>) -> T {
+            while !peer.is_finished() {
+                self.poll();
+            }
+            peer.join().unwrap()
+        }
+
+        /// Plug in the peer, and wait for the resulting bus reset.
+        fn connect(&mut self) -> FirewirePeer {
+            let peer = self.fw.peer();
+            peer.connect().unwrap();
+            self.poll();
+            peer
+        }
+    }
+
+    fn self_ids(guest: &mut Guest) -> Vec<u32> {
+        let count = guest.fw.r32(reg::SELF_ID_COUNT).unwrap();
+        let size = count.get_bits(2..=10);
+        (0..size)
+            .map(|i| guest.ram.r32(SELF_ID_BUF + i * 4).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn bus_reset() {
+        let mut guest = Guest::new();
+        let peer = guest.fw.peer();
+
+        // plugging in the peer makes it the root of a two-node bus
+        peer.connect().unwrap();
+        guest.fw.service(&mut guest.ram).unwrap();
+        let int_event = guest.fw.r32(reg::INT_EVENT_SET).unwrap();
+        assert!(int_event.get_bit(int_event::BUS_RESET));
+        assert!(int_event.get_bit(int_event::SELF_ID_COMPLETE));
+        assert_eq!(
+            guest.fw.r32(reg::SELF_ID_COUNT).unwrap().get_bits(16..=23),
+            1
+        );
+        let node_id = guest.fw.r32(reg::NODE_ID).unwrap();
+        assert!(node_id.get_bit(31) && !node_id.get_bit(30));
+        assert_eq!(node_id.get_bits(0..=5), 0);
+
+        let ids = self_ids(&mut guest);
+        assert_eq!(ids.len(), 5);
+        assert_eq!(ids[0].get_bits(16..=23), 1); // generation
+        for (phy_id, pair) in ids[1..].chunks(2).enumerate() {
+            assert_eq!(pair[0].get_bits(30..=31), 0b10);
+            assert_eq!(pair[0].get_bits(24..=29), phy_id as u32);
+            assert_eq!(pair[1], !pair[0]);
+        }
+
+        // unplugging it leaves the iPod on its own
+        guest
+            .fw
+            .w32(reg::INT_EVENT_CLEAR, 1 << int_event::BUS_RESET)
+            .unwrap();
+        peer.disconnect().unwrap();
+        guest.fw.service(&mut guest.ram).unwrap();
+        assert!(guest
+            .fw
+            .r32(reg::INT_EVENT_SET)
+            .unwrap()
+            .get_bit(int_event::BUS_RESET));
+        assert!(guest.fw.r32(reg::NODE_ID).unwrap().get_bit(30));
+
+        let ids = self_ids(&mut guest);
+        assert_eq!(ids.len(), 3);
+        assert_eq!(ids[0].get_bits(16..=23), 2);
+        assert_eq!(ids[1].get_bits(24..=29), 0);
+    }
+
+    #[test]
+    fn sbp2_login_and_read() {
+        let mut guest = Guest::new();
+        let peer = guest.connect();
+
+        let data = guest.run(thread::spawn(move || {
+            let mut sbp2 = Sbp2Initiator::new(peer);
+            sbp2.login()?;
+            sbp2.read10(5, 2, BLOCK_SIZE as u32)
+        }));
+        assert_eq!(data.unwrap(), [block(5), block(6)].concat());
+    }
+
+    #[test]
+    fn physical_request() {
+        let mut guest = Guest::new();
+        let peer = guest.connect();
+        guest.fw.w32(reg::PHYS_UPPER_BOUND, 0xffff_0000).unwrap();
+
+        let res = guest.run(thread::spawn(move || {
+            peer.write_quadlet(0x8000, 0x1234_5678)?;
+            let val = peer.read_quadlet(0x8000)?;
+            // above the 32-bit system address space
+            let err = peer.read_quadlet(0x1_0000_8000).unwrap_err();
+            Ok::<_, FwError>((val, err))
+        }));
+
+        let (val, err) = res.unwrap();
+        assert_eq!(val, 0x1234_5678);
+        assert!(matches!(err, FwError::Rcode(rcode::ADDRESS_ERROR)));
+        assert_eq!(guest.ram.r32(0x8000).unwrap(), 0x7856_3412);
+    }
+}
@@ -0,0 +1,327 @@
+//! In-process virtual 1394 peer, used to drive the emulated OHCI link.
+
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use pin_utils::pin_mut;
+use relativity::Timeout;
+use thiserror::Error;
+
+/// Transaction codes.
+pub(super) mod tcode {
+    pub const WRITE_QUADLET_REQ: u8 = 0x0;
+    pub const WRITE_BLOCK_REQ: u8 = 0x1;
+    pub const WRITE_RESP: u8 = 0x2;
+    pub const READ_QUADLET_REQ: u8 = 0x4;
+    pub const READ_BLOCK_REQ: u8 = 0x5;
+    pub const READ_QUADLET_RESP: u8 = 0x6;
+    pub const READ_BLOCK_RESP: u8 = 0x7;
+
+    pub fn is_response(tcode: u8) -> bool {
+        matches!(tcode, WRITE_RESP | READ_QUADLET_RESP | READ_BLOCK_RESP)
+    }
+}
+
+/// Response codes.
+pub(super) mod rcode {
+    pub const COMPLETE: u8 = 0x0;
+    pub const TYPE_ERROR: u8 = 0x6;
+    pub const ADDRESS_ERROR: u8 = 0x7;
+}
+
+/// Node ID of the virtual peer, whenever it's connected.
+///
+/// The peer always ends up as the root (i.e: highest physical ID) of a
+/// two-node bus, with the iPod as node 0.
+pub const PEER_NODE_ID: u16 = 0xffc1;
+
+/// Size of the peer's address space, which is mapped starting at offset 0.
+pub const PEER_MEM_SIZE: usize = 0x10_0000;
+
+/// A single asynchronous packet, decoupled from its on-the-wire / in-memory
+/// representation.
+#[derive(Debug, Clone)]
+pub(super) struct Packet {
+    pub tcode: u8,
+    pub tlabel: u8,
+    pub src: u16,
+    pub dst: u16,
+    /// 48-bit destination offset (requests only).
+    pub offset: u64,
+    /// Response code (responses only).
+    pub rcode: u8,
+    /// Requested length (block read requests only).
+    pub len: usize,
+    /// Payload, in bus byte order. Quadlet packets always carry 4 bytes.
+    pub data: Vec<u8>,
+}
+
+impl Packet {
+    pub fn request(tcode: u8, tlabel: u8, offset: u64) -> Packet {
+        Packet {
+            tcode,
+            tlabel,
+            src: PEER_NODE_ID,
+            dst: 0,
+            offset,
+            rcode: 0,
+            len: 0,
+            data: Vec::new(),
+        }
+    }
+
+    /// Construct a response to the given request.
+    pub fn response(req: &Packet, rcode: u8, data: Vec<u8>) -> Packet {
+        let tcode = match req.tcode {
+            tcode::READ_QUADLET_REQ => tcode::READ_QUADLET_RESP,
+            tcode::READ_BLOCK_REQ => tcode::READ_BLOCK_RESP,
+            _ => tcode::WRITE_RESP,
+        };
+        Packet {
+            tcode,
+            tlabel: req.tlabel,
+            src: req.dst,
+            dst: req.src,
+            offset: 0,
+            rcode,
+            len: data.len(),
+            data,
+        }
+    }
+}
+
+/// Messages sent from the virtual peer to the link.
+#[derive(Debug)]
+pub(super) enum PeerMsg {
+    /// Plug / unplug the cable.
+    Connect(bool),
+    /// Initiate a bus reset.
+    BusReset,
+    /// Send a request to the iPod.
+    Request {
+        packet: Packet,
+        done: async_channel::Sender<Result<Packet, FwError>>,
+    },
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum FwError {
+    #[error("timed out waiting for the iPod")]
+    Timeout,
+    #[error("peer is not connected")]
+    Disconnected,
+    #[error("iPod did not acknowledge the packet")]
+    NoAck,
+    #[error("transaction was aborted by a bus reset")]
+    BusReset,
+    #[error("iPod responded with rcode {0:#x}")]
+    Rcode(u8),
+}
+
+/// The peer's address space, which the iPod can access using regular
+/// asynchronous transactions.
+#[derive(Debug)]
+pub(super) struct PeerMem {
+    data: Vec<u8>,
+    /// Notifies the peer of writes (by offset).
+    writes_tx: async_channel::Sender<u64>,
+}
+
+impl PeerMem {
+    fn range(&self, offset: u64, len: usize) -> Option<std::ops::Range<usize>> {
+        let end = offset.checked_add(len as u64)?;
+        if end > self.data.len() as u64 {
+            return None;
+        }
+        Some(offset as usize..end as usize)
+    }
+
+    /// Serve a request from the iPod, returning a response packet (if the
+    /// transaction is split).
+    pub fn handle_request(&mut self, req: &Packet) -> Option<Packet> {
+        match req.tcode {
+            tcode::WRITE_QUADLET_REQ | tcode::WRITE_BLOCK_REQ => {
+                match self.range(req.offset, req.data.len()) {
+                    Some(range) => {
+                        self.data[range].copy_from_slice(&req.data);
+                        // if nobody's listening, nobody cares
+                        let _ = self.writes_tx.try_send(req.offset);
+                    }
+                    None => warn!("iPod wrote out-of-bounds peer address {:#x}", req.offset),
+                }
+                // writes are always acked as complete
+                None
+            }
+            tcode::READ_QUADLET_REQ | tcode::READ_BLOCK_REQ => {
+                let len = match req.tcode {
+                    tcode::READ_QUADLET_REQ => 4,
+                    _ => req.len,
+                };
+                Some(match self.range(req.offset, len) {
+                    Some(range) => {
+                        Packet::response(req, rcode::COMPLETE, self.data[range].to_vec())
+                    }
+                    None => Packet::response(req, rcode::ADDRESS_ERROR, Vec::new()),
+                })
+            }
+            _ => Some(Packet::response(req, rcode::TYPE_ERROR, Vec::new())),
+        }
+    }
+}
+
+/// Handle to the virtual 1394 peer connected to the iPod's FireWire port.
+///
+/// Transactions block until the iPod responds (or the handle's timeout
+/// elapses).
+///
+/// The peer also exposes [PEER_MEM_SIZE] bytes of its own address space (at
+/// offset 0), which the iPod can read / write using regular asynchronous
+/// transactions. This is what makes it possible to act as an SBP-2 initiator.
+#[derive(Debug, Clone)]
+pub struct FirewirePeer {
+    msg_tx: async_channel::Sender<PeerMsg>,
+    service: Arc<AtomicBool>,
+    mem: Arc<Mutex<PeerMem>>,
+    writes_rx: async_channel::Receiver<u64>,
+    next_tlabel: Arc<AtomicU8>,
+    timeout: Duration,
+}
+
+impl FirewirePeer {
+    pub(super) fn new(
+        msg_tx: async_channel::Sender<PeerMsg>,
+        service: Arc<AtomicBool>,
+    ) -> (FirewirePeer, Arc<Mutex<PeerMem>>) {
+        let (writes_tx, writes_rx) = async_channel::bounded(256);
+        let mem = Arc::new(Mutex::new(PeerMem {
+            data: vec![0; PEER_MEM_SIZE],
+            writes_tx,
+        }));
+
+        let peer = FirewirePeer {
+            msg_tx,
+            service,
+            mem: Arc::clone(&mem),
+            writes_rx,
+            next_tlabel: Arc::new(AtomicU8::new(0)),
+            timeout: Duration::from_secs(5),
+        };
+        (peer, mem)
+    }
+
+    /// Set how long to wait for the iPod to respond.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn send(&self, msg: PeerMsg) -> Result<(), FwError> {
+        self.msg_tx
+            .try_send(msg)
+            .map_err(|_| FwError::Disconnected)?;
+        self.service.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn wait<T>(&self, rx: &async_channel::Receiver<T>) -> Result<T, FwError> {
+        futures_executor::block_on(async {
+            let recv_fut = rx.recv();
+            pin_mut!(recv_fut);
+
+            match future::select(recv_fut, Timeout::new(self.timeout)).await {
+                Either::Left((Ok(res), _)) => Ok(res),
+                Either::Left((Err(_), _)) => Err(FwError::Disconnected),
+                Either::Right(_) => Err(FwError::Timeout),
+            }
+        })
+    }
+
+    /// Plug the cable into the iPod (triggering a bus reset).
+    pub fn connect(&self) -> Result<(), FwError> {
+        self.send(PeerMsg::Connect(true))
+    }
+
+    /// Unplug the cable from the iPod (triggering a bus reset).
+    pub fn disconnect(&self) -> Result<(), FwError> {
+        self.send(PeerMsg::Connect(false))
+    }
+
+    /// Initiate a bus reset.
+    pub fn bus_reset(&self) -> Result<(), FwError> {
+        self.send(PeerMsg::BusReset)
+    }
+
+    fn transaction(&self, mut packet: Packet) -> Result<Packet, FwError> {
+        packet.tlabel = self.next_tlabel.fetch_add(1, Ordering::Relaxed) & 0x3f;
+
+        let (done, rx) = async_channel::bounded(1);
+        self.send(PeerMsg::Request { packet, done })?;
+        let resp = self.wait(&rx)??;
+        match resp.rcode {
+            rcode::COMPLETE => Ok(resp),
+            rcode => Err(FwError::Rcode(rcode)),
+        }
+    }
+
+    /// Read a quadlet from the iPod's address space.
+    pub fn read_quadlet(&self, offset: u64) -> Result<u32, FwError> {
+        let req = Packet::request(tcode::READ_QUADLET_REQ, 0, offset);
+        let resp = self.transaction(req)?;
+        let mut buf = [0; 4];
+        let len = resp.data.len().min(4);
+        buf[..len].copy_from_slice(&resp.data[..len]);
+        Ok(u32::from_be_bytes(buf))
+    }
+
+    /// Write a quadlet to the iPod's address space.
+    pub fn write_quadlet(&self, offset: u64, val: u32) -> Result<(), FwError> {
+        let mut req = Packet::request(tcode::WRITE_QUADLET_REQ, 0, offset);
+        req.data = val.to_be_bytes().to_vec();
+        self.transaction(req).map(drop)
+    }
+
+    /// Read a block of data from the iPod's address space.
+    pub fn read_block(&self, offset: u64, len: usize) -> Result<Vec<u8>, FwError> {
+        let mut req = Packet::request(tcode::READ_BLOCK_REQ, 0, offset);
+        req.len = len;
+        self.transaction(req).map(|resp| resp.data)
+    }
+
+    /// Write a block of data to the iPod's address space.
+    pub fn write_block(&self, offset: u64, data: &[u8]) -> Result<(), FwError> {
+        let mut req = Packet::request(tcode::WRITE_BLOCK_REQ, 0, offset);
+        req.len = data.len();
+        req.data = data.to_vec();
+        self.transaction(req).map(drop)
+    }
+
+    /// Read from the peer's own address space.
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn read_mem(&self, offset: usize, len: usize) -> Vec<u8> {
+        self.mem.lock().unwrap().data[offset..offset + len].to_vec()
+    }
+
+    /// Write to the peer's own address space.
+    ///
+    /// Panics if the range is out of bounds.
+    pub fn write_mem(&self, offset: usize, data: &[u8]) {
+        self.mem.lock().unwrap().data[offset..offset + data.len()].copy_from_slice(data)
+    }
+
+    /// Discard any pending write notifications.
+    pub fn clear_writes(&self) {
+        while self.writes_rx.try_recv().is_ok() {}
+    }
+
+    /// Wait for the iPod to write to the given offset in the peer's address
+    /// space.
+    pub fn wait_for_write(&self, offset: u64) -> Result<(), FwError> {
+        loop {
+            if self.wait(&self.writes_rx)? == offset {
+                return Ok(());
+            }
+        }
+    }
+}
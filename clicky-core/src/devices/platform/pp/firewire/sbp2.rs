@@ -0,0 +1,325 @@
+//! A minimal SBP-2 initiator, built on top of a [FirewirePeer].
+//!
+//! This is what a Mac / PC does when an iPod is in FireWire disk mode: find
+//! the SBP-2 unit in the iPod's config ROM, log in via its management agent,
+//! and then submit SCSI commands to its command block agent. The ORBs, status
+//! FIFO, and data buffers all live in the peer's own address space, which the
+//! iPod accesses using regular asynchronous transactions.
+
+use thiserror::Error;
+
+use super::peer::{FirewirePeer, FwError, PEER_MEM_SIZE, PEER_NODE_ID};
+
+/// Layout of the peer's address space.
+mod layout {
+    pub const MGMT_ORB: usize = 0x000;
+    pub const LOGIN_RESPONSE: usize = 0x100;
+    pub const STATUS_FIFO: usize = 0x200;
+    pub const CMD_ORB: usize = 0x300;
+    pub const DATA: usize = 0x1000;
+}
+
+/// Largest transfer which fits in the peer's data buffer.
+const MAX_XFER: usize = PEER_MEM_SIZE - layout::DATA;
+
+const CSR_REGISTER_BASE: u64 = 0xffff_f000_0000;
+const CONFIG_ROM_BASE: u64 = 0xffff_f000_0400;
+
+/// SBP-2 unit directory `specifier_ID` / `version`.
+const SBP2_SPEC_ID: u32 = 0x00_609e;
+const SBP2_VERSION: u32 = 0x01_0483;
+
+mod function {
+    pub const LOGIN: u32 = 0x0;
+    pub const LOGOUT: u32 = 0x7;
+}
+
+#[derive(Error, Debug)]
+pub enum Sbp2Error {
+    #[error("FireWire error: {0}")]
+    Firewire(#[from] FwError),
+    #[error("malformed config ROM: {0}")]
+    BadConfigRom(&'static str),
+    #[error("no SBP-2 unit found in config ROM")]
+    NoUnit,
+    #[error("not logged in")]
+    NotLoggedIn,
+    #[error("transfer is too large ({0} bytes)")]
+    TooLarge(usize),
+    #[error("request failed (resp={resp}, sbp_status={sbp_status:#x})")]
+    Status { resp: u8, sbp_status: u8 },
+    #[error("CHECK CONDITION (sense key {sense_key:#x}, asc {asc:#x}, ascq {ascq:#x})")]
+    CheckCondition { sense_key: u8, asc: u8, ascq: u8 },
+}
+
+/// Direction of a command's data phase.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DataDir {
+    /// iPod to initiator (e.g: READ)
+    In,
+    /// Initiator to iPod (e.g: WRITE)
+    Out,
+}
+
+/// An SBP-2 initiator session with the iPod.
+#[derive(Debug)]
+pub struct Sbp2Initiator {
+    peer: FirewirePeer,
+    mgmt_agent: Option<u64>,
+    lun: u16,
+    login: Option<(u16, u64)>,
+}
+
+/// Encode a 48-bit address in the peer's address space, as used by ORBs.
+fn peer_addr(offset: usize) -> [u8; 8] {
+    let hi = (PEER_NODE_ID as u32) << 16;
+    let lo = offset as u32;
+    let mut buf = [0; 8];
+    buf[..4].copy_from_slice(&hi.to_be_bytes());
+    buf[4..].copy_from_slice(&lo.to_be_bytes());
+    buf
+}
+
+impl Sbp2Initiator {
+    pub fn new(peer: FirewirePeer) -> Sbp2Initiator {
+        Sbp2Initiator {
+            peer,
+            mgmt_agent: None,
+            lun: 0,
+            login: None,
+        }
+    }
+
+    /// Scan the iPod's config ROM for an SBP-2 unit directory.
+    pub fn discover(&mut self) -> Result<(), Sbp2Error> {
+        let rom = |offset: u64| self.peer.read_quadlet(CONFIG_ROM_BASE + offset);
+
+        let info_len = rom(0)? >> 24;
+        if info_len == 0 {
+            return Err(Sbp2Error::BadConfigRom("minimal config ROM"));
+        }
+
+        let root_dir = 4 * (1 + info_len as u64);
+        for (entry_offset, entry) in self.read_dir(root_dir)? {
+            // unit directory
+            if entry >> 24 != 0xd1 {
+                continue;
+            }
+
+            let unit_dir = entry_offset + 4 * (entry & 0xff_ffff) as u64;
+            let mut spec_id = 0;
+            let mut version = 0;
+            let mut mgmt_agent = None;
+            let mut lun = None;
+            for (entry_offset, entry) in self.read_dir(unit_dir)? {
+                let val = entry & 0xff_ffff;
+                match entry >> 24 {
+                    0x12 => spec_id = val,
+                    0x13 => version = val,
+                    0x54 => mgmt_agent = Some(CSR_REGISTER_BASE + 4 * val as u64),
+                    0x14 => lun = Some(val as u16),
+                    // logical unit directory
+                    0xd4 => {
+                        let lu_dir = entry_offset + 4 * val as u64;
+                        for (_, entry) in self.read_dir(lu_dir)? {
+                            if entry >> 24 == 0x14 {
+                                lun = Some(entry as u16);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            if spec_id == SBP2_SPEC_ID && version == SBP2_VERSION {
+                self.mgmt_agent = Some(
+                    mgmt_agent.ok_or(Sbp2Error::BadConfigRom("missing Management_Agent_Offset"))?,
+                );
+                self.lun = lun.unwrap_or(0);
+                return Ok(());
+            }
+        }
+
+        Err(Sbp2Error::NoUnit)
+    }
+
+    /// Read a config ROM directory, returning (offset, entry) pairs.
+    fn read_dir(&self, offset: u64) -> Result<Vec<(u64, u32)>, Sbp2Error> {
+        let header = self.peer.read_quadlet(CONFIG_ROM_BASE + offset)?;
+        let len = (header >> 16) as u64;
+        if offset + 4 * len >= 0x400 {
+            return Err(Sbp2Error::BadConfigRom("directory out of bounds"));
+        }
+
+        (1..=len)
+            .map(|i| {
+                let entry_offset = offset + 4 * i;
+                let entry = self.peer.read_quadlet(CONFIG_ROM_BASE + entry_offset)?;
+                Ok((entry_offset, entry))
+            })
+            .collect()
+    }
+
+    /// Point an agent's ORB_POINTER register at an ORB, and wait for the
+    /// resulting status block.
+    fn submit(&self, orb_pointer: u64, orb: usize) -> Result<[u8; 32], Sbp2Error> {
+        // zero the status FIFO, so stale status can't be mistaken for new status
+        self.peer.write_mem(layout::STATUS_FIFO, &[0; 32]);
+        self.peer.clear_writes();
+
+        self.peer.write_block(orb_pointer, &peer_addr(orb))?;
+        self.peer.wait_for_write(layout::STATUS_FIFO as u64)?;
+
+        let mut status = [0; 32];
+        status.copy_from_slice(&self.peer.read_mem(layout::STATUS_FIFO, 32));
+
+        let resp = (status[0] >> 4) & 0b11;
+        let sbp_status = status[1];
+        if resp != 0 || sbp_status != 0 {
+            return Err(Sbp2Error::Status { resp, sbp_status });
+        }
+
+        Ok(status)
+    }
+
+    /// Send a management ORB, and wait for it to complete.
+    fn management(&self, function: u32, arg: u16, response_len: u16) -> Result<(), Sbp2Error> {
+        let mgmt_agent = self.mgmt_agent.ok_or(Sbp2Error::NoUnit)?;
+
+        let mut orb = [0; 32];
+        if response_len != 0 {
+            orb[8..16].copy_from_slice(&peer_addr(layout::LOGIN_RESPONSE));
+        }
+        let q4 = 1 << 31 | function << 16 | arg as u32; // notify
+        orb[16..20].copy_from_slice(&q4.to_be_bytes());
+        orb[20..24].copy_from_slice(&(response_len as u32).to_be_bytes());
+        orb[24..32].copy_from_slice(&peer_addr(layout::STATUS_FIFO));
+        self.peer.write_mem(layout::MGMT_ORB, &orb);
+
+        self.submit(mgmt_agent, layout::MGMT_ORB)?;
+        Ok(())
+    }
+
+    /// Log in to the iPod's SBP-2 unit, running [Sbp2Initiator::discover]
+    /// first if required.
+    pub fn login(&mut self) -> Result<(), Sbp2Error> {
+        if self.mgmt_agent.is_none() {
+            self.discover()?;
+        }
+
+        self.management(function::LOGIN, self.lun, 16)?;
+
+        let resp = self.peer.read_mem(layout::LOGIN_RESPONSE, 16);
+        let quadlet =
+            |i: usize| u32::from_be_bytes([resp[i], resp[i + 1], resp[i + 2], resp[i + 3]]);
+        let login_id = quadlet(0) as u16;
+        let cmd_agent = (quadlet(4) as u64 & 0xffff) << 32 | quadlet(8) as u64;
+        self.login = Some((login_id, cmd_agent));
+
+        info!(
+            "SBP-2 login ok (login_ID {}, command block agent {:#x})",
+            login_id, cmd_agent
+        );
+        Ok(())
+    }
+
+    /// Log out of the iPod's SBP-2 unit.
+    pub fn logout(&mut self) -> Result<(), Sbp2Error> {
+        let (login_id, _) = self.login.take().ok_or(Sbp2Error::NotLoggedIn)?;
+        self.management(function::LOGOUT, login_id, 0)
+    }
+
+    /// Execute a SCSI command, returning any data sent back by the iPod.
+    ///
+    /// For `DataDir::Out` commands, `data` is sent to the iPod. For
+    /// `DataDir::In` commands, `data.len()` bytes are requested.
+    pub fn command(&mut self, cdb: &[u8], dir: DataDir, data: &[u8]) -> Result<Vec<u8>, Sbp2Error> {
+        let (_, cmd_agent) = self.login.ok_or(Sbp2Error::NotLoggedIn)?;
+        if data.len() > MAX_XFER || data.len() > 0xffff {
+            return Err(Sbp2Error::TooLarge(data.len()));
+        }
+
+        if dir == DataDir::Out {
+            self.peer.write_mem(layout::DATA, data);
+        }
+
+        let mut orb = [0; 32];
+        orb[0] = 0x80; // next_ORB: null
+        if !data.is_empty() {
+            orb[8..16].copy_from_slice(&peer_addr(layout::DATA));
+        }
+        let q4 = 1 << 31 // notify
+            | ((dir == DataDir::In) as u32) << 27 // direction
+            | 2 << 24 // spd: S400
+            | 8 << 20 // max_payload: 2^(8+2) bytes
+            | data.len() as u32;
+        orb[16..20].copy_from_slice(&q4.to_be_bytes());
+        let cdb_len = cdb.len().min(12);
+        orb[20..20 + cdb_len].copy_from_slice(&cdb[..cdb_len]);
+        self.peer.write_mem(layout::CMD_ORB, &orb);
+
+        // ORB_POINTER lives at +0x08 in the command block agent
+        let status = self.submit(cmd_agent + 0x08, layout::CMD_ORB)?;
+
+        // status block with sense data
+        let len = (status[0] & 0b111) as usize;
+        if len > 1 {
+            let scsi_status = status[8] & 0x3f;
+            if scsi_status != 0 {
+                return Err(Sbp2Error::CheckCondition {
+                    sense_key: status[9] & 0xf,
+                    asc: status[10],
+                    ascq: status[11],
+                });
+            }
+        }
+
+        Ok(match dir {
+            DataDir::In => self.peer.read_mem(layout::DATA, data.len()),
+            DataDir::Out => Vec::new(),
+        })
+    }
+
+    /// SCSI INQUIRY, returning the vendor / product identification.
+    pub fn inquiry(&mut self) -> Result<(String, String), Sbp2Error> {
+        let data = self.command(&[0x12, 0, 0, 0, 36, 0], DataDir::In, &[0; 36])?;
+        let vendor = String::from_utf8_lossy(&data[8..16]).trim().to_string();
+        let product = String::from_utf8_lossy(&data[16..32]).trim().to_string();
+        Ok((vendor, product))
+    }
+
+    /// SCSI TEST UNIT READY.
+    pub fn test_unit_ready(&mut self) -> Result<(), Sbp2Error> {
+        self.command(&[0; 6], DataDir::In, &[]).map(drop)
+    }
+
+    /// SCSI READ CAPACITY (10), returning the number of blocks and the block
+    /// size.
+    pub fn read_capacity(&mut self) -> Result<(u64, u32), Sbp2Error> {
+        let data = self.command(&[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0], DataDir::In, &[0; 8])?;
+        let last_lba = u32::from_be_bytes([data[0], data[1], data[2], data[3]]);
+        let block_size = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        Ok((last_lba as u64 + 1, block_size))
+    }
+
+    /// SCSI READ (10).
+    pub fn read10(&mut self, lba: u32, blocks: u16, block_size: u32) -> Result<Vec<u8>, Sbp2Error> {
+        let lba = lba.to_be_bytes();
+        let count = blocks.to_be_bytes();
+        let cdb = [
+            0x28, 0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0,
+        ];
+        let len = blocks as usize * block_size as usize;
+        self.command(&cdb, DataDir::In, &vec![0; len])
+    }
+
+    /// SCSI WRITE (10). `data` must be a multiple of the block size.
+    pub fn write10(&mut self, lba: u32, data: &[u8], block_size: u32) -> Result<(), Sbp2Error> {
+        let lba = lba.to_be_bytes();
+        let count = ((data.len() / block_size as usize) as u16).to_be_bytes();
+        let cdb = [
+            0x2a, 0, lba[0], lba[1], lba[2], lba[3], 0, count[0], count[1], 0,
+        ];
+        self.command(&cdb, DataDir::Out, data).map(drop)
+    }
+}
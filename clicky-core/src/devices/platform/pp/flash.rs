@@ -1,6 +1,23 @@
 use crate::devices::prelude::*;
 
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::time::Duration;
+
 use byteorder::{ByteOrder, LittleEndian};
+use relativity::Instant;
+
+const FLASH_LEN: usize = 0x100000;
+const SECTOR_LEN: usize = 0x1000; // 2 KWord
+const BLOCK_LEN: usize = 0x10000; // 32 KWord
+
+// Maximum program / erase times, as per the SST39VF800A datasheet.
+const PROGRAM_TIME: Duration = Duration::from_micros(20);
+const SECTOR_ERASE_TIME: Duration = Duration::from_millis(25);
+const BLOCK_ERASE_TIME: Duration = Duration::from_millis(25);
+const CHIP_ERASE_TIME: Duration = Duration::from_millis(50);
 
 #[derive(PartialEq, Clone, Copy)]
 enum CFIState {
@@ -8,20 +25,46 @@ enum CFIState {
     CommandPreambleAA,
     CommandPreamble55,
     ReadSoftwareID,
+    /// Waiting for the address + data of the word to program.
+    Program,
+    EraseSetup,
+    EraseSetupAA,
+    EraseSetup55,
+}
+
+/// An in-progress program / erase operation.
+struct BusyOp {
+    done: Instant,
+    /// DQ7 reads back as the complement of the final value (i.e: "Data#
+    /// Polling") until the operation completes.
+    dq7: bool,
+    /// DQ6 toggles on each consecutive read until the operation completes.
+    dq6: bool,
 }
 
 /// Internal iPod Flash ROM. Defaults to HLE mode (where only a few critical
 /// memory locations can be read). Use the `use_dump` method if you have a dump
 /// of a real iPod's flash ROM.
+///
+/// Supports the SST39VF-style program / erase command sequences. Programming
+/// the flash in HLE mode switches it over to a blank (fully erased) flash
+/// array, which makes it possible to create a flash ROM dump by running an
+/// Apple updater.
 pub struct Flash {
     dump: Option<Box<[u8]>>,
     state: CFIState,
+    busy: Option<BusyOp>,
+    write_back: Option<File>,
+    /// Part of the flash array which was modified since it was last written
+    /// back.
+    dirty: Option<Range<usize>>,
 }
 
 impl std::fmt::Debug for Flash {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Flash")
             .field("dump", &self.dump.as_ref().map(|_| "[...]"))
+            .field("write_back", &self.write_back)
             .finish()
     }
 }
@@ -31,17 +74,37 @@ impl Flash {
         Flash {
             dump: None,
             state: CFIState::ReadArrayMode,
+            busy: None,
+            write_back: None,
+            dirty: None,
         }
     }
 
     pub fn use_dump(&mut self, dump: Box<[u8]>) -> Result<(), &'static str> {
-        if dump.len() != 0x100000 {
+        if dump.len() != FLASH_LEN {
             return Err("Flash ROM dump must be exactly 1MB");
         }
         self.dump = Some(dump);
         Ok(())
     }
 
+    /// Write any modifications to the flash's contents back to the provided
+    /// file as soon as they are made. If a dump is already loaded, the file
+    /// is immediately overwritten with its contents.
+    pub fn set_write_back(&mut self, path: &Path) -> io::Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        self.write_back = Some(file);
+
+        if self.dump.is_some() {
+            self.persist(0..FLASH_LEN)?;
+        }
+        Ok(())
+    }
+
     pub fn is_hle(&self) -> bool {
         self.dump.is_none()
     }
@@ -56,6 +119,116 @@ impl Flash {
             _ => Err(Unimplemented),
         }
     }
+
+    fn persist(&mut self, range: Range<usize>) -> io::Result<()> {
+        if let (Some(file), Some(dump)) = (self.write_back.as_mut(), self.dump.as_ref()) {
+            file.seek(SeekFrom::Start(range.start as u64))?;
+            file.write_all(&dump[range])?;
+        }
+        Ok(())
+    }
+
+    /// Mark part of the flash array as modified, to be written back by the
+    /// next call to `persist_or_warn`.
+    fn mark_dirty(&mut self, range: Range<usize>) {
+        if self.write_back.is_none() {
+            return;
+        }
+
+        self.dirty = Some(match self.dirty.take() {
+            Some(dirty) => dirty.start.min(range.start)..dirty.end.max(range.end),
+            None => range,
+        });
+    }
+
+    fn persist_or_warn(&mut self) {
+        if let Some(range) = self.dirty.take() {
+            if let Err(e) = self.persist(range) {
+                warn!("failed to write back Flash ROM contents: {}", e)
+            }
+        }
+    }
+
+    /// Return the flash array, switching over to a blank flash array when
+    /// running in HLE mode.
+    fn array_mut(&mut self) -> &mut [u8] {
+        if self.dump.is_none() {
+            info!("Flash ROM modified in HLE mode, switching to a blank flash array");
+            self.dump = Some(vec![0xff; FLASH_LEN].into_boxed_slice());
+            self.mark_dirty(0..FLASH_LEN);
+        }
+        self.dump.as_mut().unwrap()
+    }
+
+    fn start_op(&mut self, duration: Duration, dq7: bool) {
+        self.state = CFIState::ReadArrayMode;
+        self.busy = Some(BusyOp {
+            done: Instant::now() + duration,
+            dq7,
+            dq6: false,
+        });
+    }
+
+    fn program(&mut self, offset: u32, val: u16) {
+        let offset = offset as usize & !1;
+        let word = &mut self.array_mut()[offset..offset + 2];
+        let old = LittleEndian::read_u16(word);
+        if val & !old != 0 {
+            // programming can only clear bits, and never set them
+            warn!(
+                "programming {:#06x} over non-erased flash word {:#06x} (at {:#x})",
+                val, old, offset
+            );
+        }
+        LittleEndian::write_u16(word, old & val);
+
+        // written back immediately, as the system may never be torn down
+        // cleanly (e.g: the desktop frontend exits without joining it)
+        self.mark_dirty(offset..offset + 2);
+        self.persist_or_warn();
+        self.start_op(PROGRAM_TIME, val & 0x80 != 0);
+    }
+
+    fn erase(&mut self, range: Range<usize>, duration: Duration) {
+        debug!("erasing flash {:#x?}", range);
+        for b in &mut self.array_mut()[range.clone()] {
+            *b = 0xff;
+        }
+
+        self.mark_dirty(range);
+        self.persist_or_warn();
+        self.start_op(duration, true);
+    }
+
+    /// Check if a program / erase operation is still in progress (without
+    /// affecting the status bits).
+    fn is_busy(&mut self) -> bool {
+        match &self.busy {
+            Some(busy) if Instant::now() < busy.done => true,
+            _ => {
+                self.busy = None;
+                false
+            }
+        }
+    }
+
+    /// If a program / erase operation is in progress, return the status bits
+    /// reported in place of the flash array's contents.
+    fn poll_status(&mut self) -> Option<u8> {
+        if !self.is_busy() {
+            return None;
+        }
+
+        let busy = self.busy.as_mut()?;
+        busy.dq6 = !busy.dq6;
+        Some((!busy.dq7 as u8) << 7 | (busy.dq6 as u8) << 6)
+    }
+}
+
+impl Drop for Flash {
+    fn drop(&mut self) {
+        self.persist_or_warn();
+    }
 }
 
 impl Device for Flash {
@@ -82,6 +255,10 @@ impl Memory for Flash {
             return Err(Unexpected);
         }
 
+        if let Some(status) = self.poll_status() {
+            return Ok(status);
+        }
+
         if let Some(dump) = self.dump.as_ref() {
             let offset = offset as usize;
             let val = dump[offset];
@@ -97,6 +274,10 @@ impl Memory for Flash {
             return Err(Unexpected);
         }
 
+        if let Some(status) = self.poll_status() {
+            return Ok(status as u16);
+        }
+
         match (self.state, offset >> 1) {
             (CFIState::ReadArrayMode, _) => {
                 if let Some(dump) = self.dump.as_ref() {
@@ -106,12 +287,11 @@ impl Memory for Flash {
                 } else {
                     Err(Unimplemented)
                 }
-                
             }
             (CFIState::ReadSoftwareID, 0x0) => Ok(0x00BF), // Manufacturer ID (SST)
             (CFIState::ReadSoftwareID, 0x1) => Ok(0x273F), // Device ID (SST39WF800A)
             _ => Err(Unimplemented),
-        }        
+        }
     }
 
     fn r32(&mut self, offset: u32) -> MemResult<u32> {
@@ -119,6 +299,10 @@ impl Memory for Flash {
             return Err(Unexpected);
         }
 
+        if let Some(status) = self.poll_status() {
+            return Ok(status as u32 | (status as u32) << 16);
+        }
+
         if let Some(dump) = self.dump.as_ref() {
             let offset = offset as usize;
             let val = LittleEndian::read_u32(&dump[offset..offset + 4]);
@@ -133,12 +317,22 @@ impl Memory for Flash {
     }
 
     fn w16(&mut self, offset: u32, val: u16) -> MemResult<()> {
+        if self.is_busy() {
+            // the flash ignores all commands until the operation completes
+            warn!("ignoring flash command while a program / erase is in progress");
+            return Ok(());
+        }
+
         // Simplified CFI state machine
         match (offset, val & 0xFF, self.state) {
             (0xAAAA, 0xAA, CFIState::ReadArrayMode) => {
                 self.state = CFIState::CommandPreambleAA;
                 Ok(())
-            },
+            }
+            (_, _, CFIState::Program) => {
+                self.program(offset, val);
+                Ok(())
+            }
             (0x0000, 0xFF, _) => {
                 // See 'A1.2 CFI Query Flowchart' from Intel AP-646
                 self.state = CFIState::ReadArrayMode;
@@ -152,10 +346,40 @@ impl Memory for Flash {
                 self.state = CFIState::ReadSoftwareID;
                 Ok(())
             }
-            (0x0000, 0xF0, CFIState::ReadSoftwareID) => {
+            (0xAAAA, 0xF0, CFIState::CommandPreamble55) | (_, 0xF0, CFIState::ReadSoftwareID) => {
                 self.state = CFIState::ReadArrayMode;
                 Ok(())
             }
+            (0xAAAA, 0xA0, CFIState::CommandPreamble55) => {
+                self.state = CFIState::Program;
+                Ok(())
+            }
+            (0xAAAA, 0x80, CFIState::CommandPreamble55) => {
+                self.state = CFIState::EraseSetup;
+                Ok(())
+            }
+            (0xAAAA, 0xAA, CFIState::EraseSetup) => {
+                self.state = CFIState::EraseSetupAA;
+                Ok(())
+            }
+            (0x5554, 0x55, CFIState::EraseSetupAA) => {
+                self.state = CFIState::EraseSetup55;
+                Ok(())
+            }
+            (_, 0x30, CFIState::EraseSetup55) => {
+                let start = offset as usize & !(SECTOR_LEN - 1);
+                self.erase(start..start + SECTOR_LEN, SECTOR_ERASE_TIME);
+                Ok(())
+            }
+            (_, 0x50, CFIState::EraseSetup55) => {
+                let start = offset as usize & !(BLOCK_LEN - 1);
+                self.erase(start..start + BLOCK_LEN, BLOCK_ERASE_TIME);
+                Ok(())
+            }
+            (0xAAAA, 0x10, CFIState::EraseSetup55) => {
+                self.erase(0..FLASH_LEN, CHIP_ERASE_TIME);
+                Ok(())
+            }
             _ => Err(Unimplemented),
         }
     }
@@ -164,3 +388,103 @@ impl Memory for Flash {
         Err(Unimplemented)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Flash, FLASH_LEN, SECTOR_LEN};
+
+    use crate::memory::Memory;
+    use crate::testutil::TempPath;
+
+    fn command(flash: &mut Flash, cmd: u16) {
+        flash.w16(0xAAAA, 0xAA).unwrap();
+        flash.w16(0x5554, 0x55).unwrap();
+        flash.w16(0xAAAA, cmd).unwrap();
+    }
+
+    fn program(flash: &mut Flash, offset: u32, val: u16) {
+        command(flash, 0xA0);
+        flash.w16(offset, val).unwrap();
+        while flash.is_busy() {}
+    }
+
+    fn erase(flash: &mut Flash, offset: u32, cmd: u16) {
+        command(flash, 0x80);
+        flash.w16(0xAAAA, 0xAA).unwrap();
+        flash.w16(0x5554, 0x55).unwrap();
+        flash.w16(offset, cmd).unwrap();
+    }
+
+    #[test]
+    fn program_word_at_reset_address() {
+        let mut flash = Flash::new();
+        flash
+            .use_dump(vec![0xff; FLASH_LEN].into_boxed_slice())
+            .unwrap();
+
+        // 0x00FF written to offset 0 is data, not a "reset" command
+        program(&mut flash, 0x0000, 0x12FF);
+        assert_eq!(flash.r16(0x0000).unwrap(), 0x12FF);
+
+        // outside of a program sequence, it resets to read array mode
+        command(&mut flash, 0x90);
+        assert_eq!(flash.r16(0x0000).unwrap(), 0x00BF);
+        flash.w16(0x0000, 0xFF).unwrap();
+        assert_eq!(flash.r16(0x0000).unwrap(), 0x12FF);
+    }
+
+    #[test]
+    fn busy_status() {
+        let mut flash = Flash::new();
+        flash
+            .use_dump(vec![0; FLASH_LEN].into_boxed_slice())
+            .unwrap();
+
+        // chip erase takes long enough to reliably observe the busy status
+        erase(&mut flash, 0xAAAA, 0x10);
+
+        // DQ7 reads as 0 (i.e: the complement of the erased value), and DQ6
+        // toggles on every read
+        let first = flash.r16(0).unwrap();
+        assert_eq!(first & 0x80, 0);
+        let second = flash.r16(0).unwrap();
+        assert_eq!(first ^ second, 0x40);
+
+        // writes are ignored, and don't toggle DQ6
+        flash.w16(0x0000, 0xFF).unwrap();
+        let third = flash.r16(0).unwrap();
+        assert_eq!(second ^ third, 0x40);
+
+        while flash.is_busy() {}
+        assert_eq!(flash.r16(0).unwrap(), 0xFFFF);
+        assert_eq!(flash.r16(FLASH_LEN as u32 - 2).unwrap(), 0xFFFF);
+    }
+
+    #[test]
+    fn write_back() {
+        let path = TempPath::file("flash-write-back", &[]);
+
+        let mut flash = Flash::new();
+        flash
+            .use_dump(vec![0xff; FLASH_LEN].into_boxed_slice())
+            .unwrap();
+        flash.set_write_back(&path).unwrap();
+        assert_eq!(path.contents(), vec![0xff; FLASH_LEN]);
+
+        // programmed words are written back immediately (without dropping the
+        // flash)
+        program(&mut flash, 0x10, 0x1234);
+        program(&mut flash, 0x2000, 0x5678);
+        let data = path.contents();
+        assert_eq!(&data[0x10..0x12], &[0x34, 0x12]);
+        assert_eq!(&data[0x2000..0x2002], &[0x78, 0x56]);
+
+        // as are erased sectors
+        erase(&mut flash, 0x0000, 0x30);
+        while flash.is_busy() {}
+        let data = path.contents();
+        assert_eq!(&data[0x10..0x12], &[0xff, 0xff]);
+        assert_eq!(&data[0x2000..0x2002], &[0x78, 0x56]);
+        assert_eq!(&data[SECTOR_LEN..0x2000], &[0xff; 0x2000 - SECTOR_LEN][..]);
+    }
+}
@@ -3,12 +3,33 @@ use crate::devices::prelude::*;
 use crate::devices::util::ArcMutexDevice;
 use crate::signal::{gpio, irq};
 
+/// How a GPIO input is turned into an interrupt.
+///
+/// In both cases, IntLevel selects the interrupt's polarity (i.e: 0 = low /
+/// falling edge, 1 = high / rising edge).
+///
+/// The PP5020 doesn't have a register to pick between the two: all 8
+/// registers in each port's register window are accounted for (see Rockbox's
+/// pp5020.h). Instead, the trigger is configured per-pin when wiring up the
+/// system.
+///
+/// Note that inputs default to `Level`, which matches how ipodloader2 handles
+/// the keypad / Hold switch. Before the trigger was configurable, every input
+/// was (effectively) edge-triggered, so inputs which relied on that (e.g: the
+/// iPod 4G's dock detect and PCF5060x INT lines) have to opt into `Edge`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum InterruptTrigger {
+    /// Interrupt for as long as the input matches IntLevel.
+    Level,
+    /// Interrupt when the input transitions to IntLevel.
+    Edge,
+}
+
 /// 8-bit GPIO Port
 #[derive(Debug)]
 struct GpioPort {
     label: &'static str,
 
-    irq: irq::Sender,
     inputs: [Option<gpio::Reciever>; 8],
     outputs: [Option<gpio::Sender>; 8],
 
@@ -19,14 +40,15 @@ struct GpioPort {
     interrupt_status: u8,
     interrupt_enable: u8,
     interrupt_level: u8,
+    /// Pins which use `InterruptTrigger::Edge`
+    edge_triggered: u8,
 }
 
 impl GpioPort {
-    fn new(irq: irq::Sender, label: &'static str) -> GpioPort {
+    fn new(label: &'static str) -> GpioPort {
         GpioPort {
             label,
 
-            irq,
             inputs: Default::default(),
             outputs: Default::default(),
 
@@ -36,7 +58,8 @@ impl GpioPort {
             input_val: 0,
             interrupt_status: 0,
             interrupt_enable: 0,
-            interrupt_level: 0, // 0 = irq on falling edge, 1 = irq on rising edge
+            interrupt_level: 0, // 0 = irq while low, 1 = irq while high
+            edge_triggered: 0,
         }
     }
 
@@ -67,15 +90,25 @@ impl GpioPort {
             }
 
             // set the output line
+            //
+            // A set OUTPUT_VAL bit drives the pin high, e.g: ipodloader2's
+            // fb.c / ipodhw.c turn the 4g's backlight on by setting GPIO B03:
+            //
+            // `outl(((0x100 | (on ? 1 : 0)) << 3), 0x6000d824);`
+            //
+            // (the original implementation had this inverted, but since no
+            // outputs were ever registered, nothing depended on it)
             if let Some(output) = output {
                 match self.output_val.get_bit(i) {
-                    false => output.set_high(),
-                    true => output.set_low(),
+                    true => output.set_high(),
+                    false => output.set_low(),
                 }
             }
         }
 
         // update inputs
+        let prev_input_val = self.input_val;
+        let mut connected = 0;
         for (i, input) in self.inputs.iter().enumerate() {
             // if the port isn't enabled, don't do anything
             if !self.enable.get_bit(i) {
@@ -89,25 +122,41 @@ impl GpioPort {
             };
 
             // set the input level
-            let prev_level = self.input_val.get_bit(i);
             self.input_val.set_bit(i, level);
-
-            // update the IRQ status register
-            let trigger_irq = match self.interrupt_level.get_bit(i) {
-                // falling edge trigger
-                false => prev_level && !level,
-                // rising edge trigger
-                true => !prev_level && level,
-            };
-            self.interrupt_status.set_bit(i, trigger_irq);
+            connected.set_bit(i, true);
         }
 
-        // check if the IRQ line should be asserted / cleared
-        if (self.interrupt_status & self.interrupt_enable) != 0 {
-            self.irq.assert()
-        } else {
-            self.irq.clear()
-        }
+        // GPIO interrupts are level-sensitive by default, with IntLevel
+        // selecting which level triggers the interrupt. As such, firmware has to
+        // flip IntLevel after handling an interrupt, e.g: ipodloader2's
+        // keypad.c:
+        //
+        // `outb(~state, 0x6000d060);  // toggle interrupt level`
+        //
+        // Level-triggered status bits latch whenever the input matches the
+        // selected level, and IntClear only sticks once the input has moved away
+        // from it. Edge-triggered status bits only latch when the input changes
+        // to the selected level.
+        //
+        // Pins with nothing connected to them are assumed to never interrupt.
+        let active = !(self.input_val ^ self.interrupt_level) & connected;
+        let changed = prev_input_val ^ self.input_val;
+        self.interrupt_status |=
+            (active & !self.edge_triggered) | (active & changed & self.edge_triggered);
+    }
+
+    /// # Panics
+    ///
+    /// Panics if `idx >= 8`
+    fn set_trigger(&mut self, idx: usize, trigger: InterruptTrigger) {
+        assert!(idx < 8, "idx must be less than 8");
+        self.edge_triggered
+            .set_bit(idx, trigger == InterruptTrigger::Edge);
+    }
+
+    /// Check if any enabled interrupts are pending.
+    fn irq_pending(&self) -> bool {
+        (self.interrupt_status & self.interrupt_enable) != 0
     }
 }
 
@@ -176,17 +225,20 @@ impl Memory for GpioPort {
 /// Block of 4 GPIO ports on the PP5020.
 #[derive(Debug)]
 pub struct GpioBlock {
+    // all 4 ports share a single IRQ line
+    irq: irq::Sender,
     port: [GpioPort; 4],
 }
 
 impl GpioBlock {
     pub fn new(irq: irq::Sender, labels: [&'static str; 4]) -> GpioBlock {
         GpioBlock {
+            irq,
             port: [
-                GpioPort::new(irq.clone(), labels[0]),
-                GpioPort::new(irq.clone(), labels[1]),
-                GpioPort::new(irq.clone(), labels[2]),
-                GpioPort::new(irq, labels[3]),
+                GpioPort::new(labels[0]),
+                GpioPort::new(labels[1]),
+                GpioPort::new(labels[2]),
+                GpioPort::new(labels[3]),
             ],
         }
     }
@@ -213,12 +265,34 @@ impl GpioBlock {
         self
     }
 
+    /// Set how a GPIO input triggers interrupts (defaults to
+    /// `InterruptTrigger::Level`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx >= 32`
+    pub fn set_trigger(&mut self, idx: usize, trigger: InterruptTrigger) -> &mut Self {
+        assert!(idx < 32, "idx must be less than 32");
+        self.port[idx / 8].set_trigger(idx % 8, trigger);
+        self
+    }
+
     /// Propagate GPIO signal changes through the GPIO controller (triggering an
     /// IRQ if necessary).
     pub fn update(&mut self) {
         for port in self.port.iter_mut() {
             port.update()
         }
+        self.update_irq();
+    }
+
+    /// Assert / clear the IRQ line based on the ports' pending interrupts.
+    fn update_irq(&mut self) {
+        if self.port.iter().any(GpioPort::irq_pending) {
+            self.irq.assert()
+        } else {
+            self.irq.clear()
+        }
     }
 }
 
@@ -241,7 +315,9 @@ impl Memory for GpioBlock {
 
     fn w32(&mut self, offset: u32, val: u32) -> MemResult<()> {
         let port = (offset / 4) % 4;
-        self.port[port as usize].w32(offset - 4 * port, val)
+        let res = self.port[port as usize].w32(offset - 4 * port, val);
+        self.update_irq();
+        res
     }
 }
 
@@ -299,3 +375,73 @@ impl Memory for GpioBlockAtomicMirror {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(trigger: InterruptTrigger) -> (GpioBlock, gpio::Sender, irq::Reciever) {
+        let (irq_tx, irq_rx) = irq::new(irq::Pending::new(), "GPIO");
+        let (line_tx, line_rx) = gpio::new(gpio::Changed::new(), "test");
+
+        let mut block = GpioBlock::new(irq_tx, ["A", "B", "C", "D"]);
+        block.register_in(1, line_rx).set_trigger(1, trigger);
+        block.w32(0x00, 0x02).unwrap(); // enable A1
+        block.w32(0x50, 0x02).unwrap(); // enable A1 interrupts
+        (block, line_tx, irq_rx)
+    }
+
+    fn set_line(block: &mut GpioBlock, line: &mut gpio::Sender, high: bool) {
+        if high {
+            line.set_high()
+        } else {
+            line.set_low()
+        }
+        block.update();
+    }
+
+    #[test]
+    fn level_triggered() {
+        let (mut block, mut line, irq) = setup(InterruptTrigger::Level);
+
+        // IntLevel = 0, and the line is low
+        assert!(irq.asserted());
+        // clearing doesn't stick while the line is still low
+        block.w32(0x70, 0x02).unwrap();
+        assert!(irq.asserted());
+
+        // flip IntLevel, then clear
+        block.w32(0x60, 0x02).unwrap();
+        block.w32(0x70, 0x02).unwrap();
+        assert!(!irq.asserted());
+
+        set_line(&mut block, &mut line, true);
+        assert!(irq.asserted());
+        assert_eq!(block.r32(0x40).unwrap(), 0x02);
+    }
+
+    #[test]
+    fn edge_triggered() {
+        let (mut block, mut line, irq) = setup(InterruptTrigger::Edge);
+
+        // the line is already low, so IntLevel = 0 doesn't interrupt
+        assert!(!irq.asserted());
+
+        // rising edge with IntLevel = 0
+        set_line(&mut block, &mut line, true);
+        assert!(!irq.asserted());
+
+        // falling edge with IntLevel = 0
+        set_line(&mut block, &mut line, false);
+        assert!(irq.asserted());
+        // clearing sticks, even though the line is still low
+        block.w32(0x70, 0x02).unwrap();
+        assert!(!irq.asserted());
+
+        // rising edge with IntLevel = 1
+        block.w32(0x60, 0x02).unwrap();
+        assert!(!irq.asserted());
+        set_line(&mut block, &mut line, true);
+        assert!(irq.asserted());
+    }
+}
@@ -0,0 +1,316 @@
+//! Clickwheel finger input model.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use pin_utils::pin_mut;
+use relativity::{Instant, Timeout};
+
+use crate::executor::*;
+use crate::signal;
+
+/// Number of distinct finger positions reported around the wheel.
+///
+/// From rockbox button-clickwheel.c:
+/// `#define WHEELCLICKS_PER_ROTATION 96 /* wheelclicks per full rotation */`
+pub const WHEEL_POSITIONS: u8 = 96;
+
+const DEG_PER_POSITION: f64 = 360.0 / WHEEL_POSITIONS as f64;
+
+/// Relative rotations are played back in roughly this amount of time, such
+/// that bigger (i.e: faster) scrolls result in a faster moving finger...
+const PLAYBACK_TIME: f64 = 0.2;
+/// ...within the bounds of how fast a finger can actually move (in deg/s).
+const MIN_PLAYBACK_SPEED: f64 = 90.0;
+const MAX_PLAYBACK_SPEED: f64 = 1440.0;
+/// How long the finger lingers on the wheel after a relative rotation has
+/// been played back.
+const RELEASE_DELAY: Duration = Duration::from_millis(300);
+
+/// Normalize an angle to [0, 360).
+fn normalize(angle: f64) -> f64 {
+    angle.rem_euclid(360.0)
+}
+
+#[derive(Debug)]
+struct WheelState {
+    touched: bool,
+    /// Finger angle (in degrees, clockwise from the top of the wheel).
+    angle: f64,
+    /// Rotational velocity (in deg/s, clockwise).
+    velocity: f64,
+    /// Relative rotation (in degrees, clockwise) yet to be played back.
+    pending: f64,
+    /// When to lift the finger after playing back a relative rotation.
+    release_at: Option<Instant>,
+    last_update: Instant,
+
+    /// Degrees of rotation per unit of scrolling.
+    scroll_sensitivity: f64,
+}
+
+impl WheelState {
+    fn position(&self) -> u8 {
+        (self.angle / DEG_PER_POSITION) as u8 % WHEEL_POSITIONS
+    }
+
+    /// The finger's current speed (in deg/s, clockwise).
+    fn speed(&self) -> f64 {
+        if self.velocity != 0.0 {
+            self.velocity
+        } else if self.pending != 0.0 {
+            (self.pending.abs() / PLAYBACK_TIME)
+                .clamp(MIN_PLAYBACK_SPEED, MAX_PLAYBACK_SPEED)
+                .copysign(self.pending)
+        } else {
+            0.0
+        }
+    }
+
+    /// Time until the reported state will next change (if ever).
+    fn next_change(&self) -> Option<Duration> {
+        let speed = self.speed();
+        if speed != 0.0 {
+            // distance to the next position boundary, in the direction of travel
+            let offset = self.angle % DEG_PER_POSITION;
+            let mut dist = if speed > 0.0 {
+                DEG_PER_POSITION - offset
+            } else if offset == 0.0 {
+                DEG_PER_POSITION
+            } else {
+                offset
+            };
+            if self.velocity == 0.0 {
+                dist = dist.min(self.pending.abs());
+            }
+
+            // overshoot a smidge, to make sure the boundary is actually crossed
+            return Some(Duration::from_secs_f64(dist / speed.abs()) + Duration::from_micros(10));
+        }
+
+        self.release_at
+            .map(|release_at| release_at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Advance the finger's movement up to the current time, returning `true`
+    /// if the reported state changed.
+    fn update(&mut self) -> bool {
+        let now = Instant::now();
+        let secs = (now - self.last_update).as_secs_f64();
+        self.last_update = now;
+
+        let old = (self.touched, self.position());
+
+        let speed = self.speed();
+        if speed != 0.0 {
+            let mut delta = speed * secs;
+            if self.velocity == 0.0 {
+                // don't overshoot the relative rotation
+                if delta.abs() >= self.pending.abs() {
+                    delta = self.pending;
+                    self.release_at = Some(now + RELEASE_DELAY);
+                }
+                self.pending -= delta;
+            }
+            self.angle = normalize(self.angle + delta);
+        }
+
+        if matches!(self.release_at, Some(release_at) if release_at <= now) {
+            self.touched = false;
+            self.release_at = None;
+        }
+
+        old != (self.touched, self.position())
+    }
+}
+
+/// Run `f` on the up-to-date wheel state, notifying the opto controller if the
+/// reported state changed.
+fn with_state<T>(
+    state: &Mutex<WheelState>,
+    notify: &signal::Trigger,
+    f: impl FnOnce(&mut WheelState) -> T,
+) -> T {
+    let mut state = state.lock().unwrap();
+    let mut changed = state.update();
+
+    let old = (state.touched, state.position());
+    let ret = f(&mut state);
+    changed |= old != (state.touched, state.position());
+
+    if changed {
+        notify.set();
+    }
+    ret
+}
+
+async fn wheel_task(
+    state: Arc<Mutex<WheelState>>,
+    notify: signal::Trigger,
+    kick_rx: async_channel::Receiver<()>,
+) {
+    loop {
+        let next_change = state.lock().unwrap().next_change();
+        match next_change {
+            None => {
+                if kick_rx.recv().await.is_err() {
+                    // shutting down
+                    return;
+                }
+            }
+            Some(timeout) => {
+                let kick_fut = kick_rx.recv();
+                pin_mut!(kick_fut);
+
+                match future::select(kick_fut, Timeout::new(timeout)).await {
+                    Either::Left((Err(async_channel::RecvError), _)) => {
+                        // shutting down
+                        return;
+                    }
+                    Either::Left((Ok(()), _)) | Either::Right(_) => {}
+                }
+            }
+        }
+
+        with_state(&state, &notify, |_| ());
+    }
+}
+
+/// Handle to the clickwheel's touch sensor.
+///
+/// The wheel can either be driven directly (by placing the finger at a
+/// specific angle, or by spinning it at a specific velocity), or using relative
+/// rotations (e.g: from a mouse's scroll wheel), which are played back by
+/// moving the finger around the wheel at a realistic speed. Either way, each
+/// position the finger passes over is reported to the firmware, so that any
+/// acceleration it implements works the same as on real hardware.
+///
+/// Angles are in degrees, clockwise from the top of the wheel.
+#[derive(Debug, Clone)]
+pub struct Clickwheel {
+    state: Arc<Mutex<WheelState>>,
+    notify: signal::Trigger,
+    kick_tx: async_channel::Sender<()>,
+}
+
+impl Clickwheel {
+    pub(super) fn new(notify: signal::Trigger, task_spawner: Spawner) -> Clickwheel {
+        let (kick_tx, kick_rx) = async_channel::bounded(1);
+
+        let state = Arc::new(Mutex::new(WheelState {
+            touched: false,
+            angle: 0.0,
+            velocity: 0.0,
+            pending: 0.0,
+            release_at: None,
+            last_update: Instant::now(),
+
+            scroll_sensitivity: 2.0 * DEG_PER_POSITION,
+        }));
+
+        task_spawner
+            .spawn(wheel_task(state.clone(), notify.clone(), kick_rx))
+            .expect("failed to spawn clickwheel task");
+
+        Clickwheel {
+            state,
+            notify,
+            kick_tx,
+        }
+    }
+
+    fn with_state<T>(&self, f: impl FnOnce(&mut WheelState) -> T) -> T {
+        with_state(&self.state, &self.notify, f)
+    }
+
+    /// Update the finger's state, and have the wheel task re-evaluate when
+    /// the next change is due.
+    fn set_state(&self, f: impl FnOnce(&mut WheelState)) {
+        self.with_state(f);
+        // if the channel is full, the wheel task already has a wakeup queued
+        let _ = self.kick_tx.try_send(());
+    }
+
+    /// Place the finger on the wheel (or move it, if it's already touching
+    /// the wheel) at the given angle.
+    pub fn touch(&self, angle: f64) {
+        self.set_state(|state| {
+            state.touched = true;
+            state.angle = normalize(angle);
+            state.velocity = 0.0;
+            state.pending = 0.0;
+            state.release_at = None;
+        })
+    }
+
+    /// Lift the finger off the wheel.
+    pub fn release(&self) {
+        self.set_state(|state| {
+            state.touched = false;
+            state.velocity = 0.0;
+            state.pending = 0.0;
+            state.release_at = None;
+        })
+    }
+
+    /// Spin the finger around the wheel at the given velocity (in deg/s,
+    /// clockwise), placing it on the wheel if it isn't already touching it.
+    ///
+    /// A velocity of 0 leaves the finger resting on the wheel.
+    pub fn set_velocity(&self, velocity: f64) {
+        self.set_state(|state| {
+            state.touched = true;
+            state.velocity = velocity;
+            state.pending = 0.0;
+            state.release_at = None;
+        })
+    }
+
+    /// Rotate the finger around the wheel by the given amount (in degrees,
+    /// clockwise), lifting it shortly after the rotation has been played back.
+    pub fn rotate(&self, degrees: f64) {
+        self.set_state(|state| {
+            state.touched = true;
+            state.velocity = 0.0;
+            state.pending += degrees;
+            state.release_at = None;
+        })
+    }
+
+    /// Rotate the wheel in response to a scroll event (e.g: from a mouse's
+    /// scroll wheel). Scrolling down rotates the wheel clockwise.
+    pub fn scroll(&self, delta: f64) {
+        let degrees = -delta * self.state.lock().unwrap().scroll_sensitivity;
+        self.rotate(degrees)
+    }
+
+    /// Set how many wheel positions a single unit of scrolling corresponds to.
+    pub fn set_scroll_sensitivity(&self, positions: f64) {
+        self.state.lock().unwrap().scroll_sensitivity = positions * DEG_PER_POSITION;
+    }
+
+    /// Return the finger's current angle, or `None` if it isn't touching the
+    /// wheel.
+    pub fn angle(&self) -> Option<f64> {
+        self.with_state(|state| {
+            if state.touched {
+                Some(state.angle)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Return the wheel position reported by the opto controller, or `None` if
+    /// the wheel isn't being touched.
+    pub fn position(&self) -> Option<u8> {
+        self.with_state(|state| {
+            if state.touched {
+                Some(state.position())
+            } else {
+                None
+            }
+        })
+    }
+}
@@ -1,9 +1,11 @@
 use crate::devices::prelude::*;
 
-use std::sync::{Arc, Mutex};
-
 use crate::signal::{self, gpio};
 
+mod clickwheel;
+
+pub use clickwheel::{Clickwheel, WHEEL_POSITIONS};
+
 #[derive(Debug)]
 pub struct Controls<T> {
     pub action: T,
@@ -11,21 +13,21 @@ pub struct Controls<T> {
     pub down: T,
     pub left: T,
     pub right: T,
-    pub wheel: (T, Arc<Mutex<u8>>),
+    pub wheel: Clickwheel,
 }
 
 impl Controls<()> {
     pub fn new_tx_rx(
         notify: signal::Trigger,
+        task_spawner: Spawner,
     ) -> (Controls<signal::Master>, Controls<signal::Slave>) {
         let (action_tx, action_rx) = signal::new(notify.clone(), "Controls", "KeyAction");
         let (up_tx, up_rx) = signal::new(notify.clone(), "Controls", "KeyUp");
         let (down_tx, down_rx) = signal::new(notify.clone(), "Controls", "KeyDown");
         let (left_tx, left_rx) = signal::new(notify.clone(), "Controls", "KeyLeft");
         let (right_tx, right_rx) = signal::new(notify.clone(), "Controls", "KeyRight");
-        let (wheel_tx, wheel_rx) = signal::new(notify, "Controls", "Wheel");
 
-        let wheel_data = Arc::new(Mutex::new(0));
+        let wheel = Clickwheel::new(notify, task_spawner);
 
         (
             Controls {
@@ -34,7 +36,7 @@ impl Controls<()> {
                 down: down_tx,
                 left: left_tx,
                 right: right_tx,
-                wheel: (wheel_tx, wheel_data.clone()),
+                wheel: wheel.clone(),
             },
             Controls {
                 action: action_rx,
@@ -42,7 +44,7 @@ impl Controls<()> {
                 down: down_rx,
                 left: left_rx,
                 right: right_rx,
-                wheel: (wheel_rx, wheel_data),
+                wheel,
             },
         )
     }
@@ -79,6 +81,21 @@ impl OptoWheel {
     pub fn on_change(&mut self) {
         self.irq.assert()
     }
+
+    /// Return a handle to the clickwheel (if controls have been registered).
+    pub fn clickwheel(&self) -> Option<&Clickwheel> {
+        self.controls.as_ref().map(|c| &c.wheel)
+    }
+
+    /// Check if any of the buttons are currently pressed.
+    pub fn any_button_pressed(&self) -> bool {
+        match self.controls {
+            Some(ref c) => [&c.action, &c.up, &c.down, &c.left, &c.right]
+                .iter()
+                .any(|btn| btn.asserted()),
+            None => false,
+        }
+    }
 }
 
 impl Device for OptoWheel {
@@ -119,6 +136,9 @@ impl Memory for OptoWheel {
                     _ => return Err(Fatal("no controls registered with i2c".into())),
                 };
 
+                // the wheel position is only valid while the wheel is being touched
+                let wheel_pos = controls.wheel.position();
+
                 let val = *0u32
                     .set_bits(0..=7, if hold.is_high() { 0x1a } else { 0 }) // 0x1a, or 0 if hold is engaged
                     .set_bit(8, controls.action.asserted())
@@ -126,8 +146,8 @@ impl Memory for OptoWheel {
                     .set_bit(10, controls.left.asserted())
                     .set_bit(11, controls.down.asserted())
                     .set_bit(12, controls.up.asserted())
-                    .set_bits(16..=22, *controls.wheel.1.lock().unwrap() as u32)
-                    .set_bit(30, true) // FIXME: don't always return clickwheel active?
+                    .set_bits(16..=22, wheel_pos.unwrap_or(0) as u32) // clockwise increases
+                    .set_bit(30, wheel_pos.is_some()) // set while the wheel is touched
                     .set_bit(31, hold.is_high()); // set unless hold switch is engaged
 
                 Err(StubRead(Debug, val))
@@ -1,5 +1,9 @@
 use crate::devices::prelude::*;
 
+use std::sync::{Arc, Mutex};
+
+use crate::signal::{Trigger, TriggerKind};
+
 #[derive(Debug, Clone, Copy)]
 pub struct PWMConfiguration {
     enabled: bool,
@@ -30,17 +34,58 @@ impl PWMConfiguration {
     }
 }
 
+/// Handle to a single PWM channel's output.
+#[derive(Debug, Clone)]
+pub struct PwmChannel {
+    channels: Arc<Mutex<[PWMConfiguration; 4]>>,
+    idx: usize,
+}
+
+impl PwmChannel {
+    /// Return the channel's duty cycle (between 0.0 and 1.0), or `None` if the
+    /// channel is disabled.
+    pub fn duty_cycle(&self) -> Option<f32> {
+        let channel = self.channels.lock().unwrap()[self.idx];
+        if channel.enabled {
+            Some(channel.duty as f32 / 255.0)
+        } else {
+            None
+        }
+    }
+}
+
 // Looks faily similar to Tegra's PWM controller: https://github.com/torvalds/linux/blob/master/drivers/pwm/pwm-tegra.c
 // See also "Tegra 4 Technical Reference Manual", Section 39.2 PWM Registers
 #[derive(Debug)]
 pub struct PWMCon {
-    channels: [PWMConfiguration; 4],
+    channels: Arc<Mutex<[PWMConfiguration; 4]>>,
+    changed: Trigger,
 }
 
 impl PWMCon {
     pub fn new() -> PWMCon {
         PWMCon {
-            channels: [PWMConfiguration::new(); 4],
+            channels: Arc::new(Mutex::new([PWMConfiguration::new(); 4])),
+            changed: Trigger::new(TriggerKind::Edge),
+        }
+    }
+
+    /// Return a trigger which is set whenever any channel's configuration is
+    /// written to.
+    pub fn changed(&self) -> Trigger {
+        self.changed.clone()
+    }
+
+    /// Return a handle to one of the PWM channel's outputs.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `idx >= 4`
+    pub fn channel(&self, idx: usize) -> PwmChannel {
+        assert!(idx < 4, "idx must be less than 4");
+        PwmChannel {
+            channels: Arc::clone(&self.channels),
+            idx,
         }
     }
 }
@@ -65,21 +110,25 @@ impl Device for PWMCon {
 
 impl Memory for PWMCon {
     fn r32(&mut self, offset: u32) -> MemResult<u32> {
+        let channels = self.channels.lock().unwrap();
         match offset {
-            0x00 => Ok(self.channels[0].read()),
-            0x10 => Ok(self.channels[1].read()),
-            0x20 => Ok(self.channels[2].read()),
-            0x30 => Ok(self.channels[3].read()),
+            0x00 => Ok(channels[0].read()),
+            0x10 => Ok(channels[1].read()),
+            0x20 => Ok(channels[2].read()),
+            0x30 => Ok(channels[3].read()),
             _ => Err(Unexpected),
         }
     }
 
     fn w32(&mut self, offset: u32, val: u32) -> MemResult<()> {
+        self.changed.set();
+
+        let mut channels = self.channels.lock().unwrap();
         match offset {
-            0x00 => Ok(self.channels[0].write(val)),
-            0x10 => Ok(self.channels[1].write(val)),
-            0x20 => Ok(self.channels[2].write(val)),
-            0x30 => Ok(self.channels[3].write(val)),
+            0x00 => Ok(channels[0].write(val)),
+            0x10 => Ok(channels[1].write(val)),
+            0x20 => Ok(channels[2].write(val)),
+            0x30 => Ok(channels[3].write(val)),
             _ => Err(Unexpected),
         }
     }
@@ -1,29 +1,412 @@
 use crate::devices::prelude::*;
 
-/// PP5020 serial controller
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{self, Either};
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use pin_utils::pin_mut;
+
+use crate::serial::{SerialBackend, SerialRx, SerialTx};
+
+/// Depth of the RX and TX FIFOs.
+const FIFO_LEN: usize = 16;
+
+mod reg {
+    /// Receive Buffer / Transmit Holding / Divisor Latch (LSB)
+    pub const RBR_THR_DLL: u32 = 0x00;
+    /// Interrupt Enable / Divisor Latch (MSB)
+    pub const IER_DLM: u32 = 0x04;
+    /// Interrupt Identification / FIFO Control
+    pub const IIR_FCR: u32 = 0x08;
+    /// Line Control
+    pub const LCR: u32 = 0x0c;
+    /// Modem Control
+    pub const MCR: u32 = 0x10;
+    /// Line Status
+    pub const LSR: u32 = 0x14;
+    /// Modem Status
+    pub const MSR: u32 = 0x18;
+    /// Scratch Pad
+    pub const SPR: u32 = 0x1c;
+    /// IrDA Pulse Coding
+    pub const IRDA: u32 = 0x20;
+    /// Autobaud Sense
+    pub const ASR: u32 = 0x3c;
+}
+
+/// Interrupt Enable register bits
+mod ier {
+    /// Received Data Available
+    pub const ERBFI: usize = 0;
+    /// Transmit Holding Register Empty
+    pub const ETBEI: usize = 1;
+    /// Receiver Line Status
+    pub const ELSI: usize = 2;
+}
+
+/// Interrupt Identification register values
+mod iir {
+    pub const NONE: u8 = 0x01;
+    pub const LINE_STATUS: u8 = 0x06;
+    pub const RX_DATA: u8 = 0x04;
+    pub const RX_TIMEOUT: u8 = 0x0c;
+    pub const THR_EMPTY: u8 = 0x02;
+    pub const FIFOS_ENABLED: u8 = 0xc0;
+}
+
+/// FIFO Control register bits
+mod fcr {
+    type Range = std::ops::RangeInclusive<usize>;
+    pub const ENABLE: usize = 0;
+    pub const RX_RESET: usize = 1;
+    pub const TX_RESET: usize = 2;
+    pub const RX_TRIGGER: Range = 6..=7;
+}
+
+/// Line Control register bits
+mod lcr {
+    /// Divisor Latch Access
+    pub const DLAB: usize = 7;
+}
+
+/// Modem Control register bits
+mod mcr {
+    pub const DTR: usize = 0;
+    pub const RTS: usize = 1;
+    pub const OUT1: usize = 2;
+    pub const OUT2: usize = 3;
+    pub const LOOP: usize = 4;
+}
+
+/// Line Status register bits
+mod lsr {
+    /// Data Ready
+    pub const DR: usize = 0;
+    /// Overrun Error
+    pub const OE: usize = 1;
+    /// Transmit Holding Register Empty
+    pub const THRE: usize = 5;
+    /// Transmitter Empty
+    pub const TEMT: usize = 6;
+}
+
+/// UART state shared between the device and the host backend tasks.
 #[derive(Debug)]
-pub struct Serial {
-    label: &'static str,
+struct Uart {
+    irq: irq::Sender,
+
+    rx_fifo: VecDeque<u8>,
+    tx_fifo: VecDeque<u8>,
+    overrun: bool,
+    thre_pending: bool,
+
+    /// Bumped whenever a new backend is attached, signaling any tasks
+    /// associated with the old backend to shut down.
+    backend_gen: usize,
+    tx_kick: Option<async_channel::Sender<()>>,
+    rx_drained: Option<async_channel::Sender<()>>,
 
     ier: u8,
-    fcr: u8,
+    fifo_enable: bool,
+    rx_trigger: u8,
     lcr: u8,
     mcr: u8,
+    spr: u8,
+    dll: u8,
+    dlm: u8,
     asr: u8,
 }
 
+impl Uart {
+    fn rx_trigger_level(&self) -> usize {
+        if !self.fifo_enable {
+            return 1;
+        }
+
+        match self.rx_trigger {
+            0 => 1,
+            1 => 4,
+            2 => 8,
+            _ => 14,
+        }
+    }
+
+    fn fifo_len(&self) -> usize {
+        if self.fifo_enable {
+            FIFO_LEN
+        } else {
+            1
+        }
+    }
+
+    /// Push a byte into the RX FIFO, flagging an overrun if it's full.
+    fn push_rx(&mut self, val: u8) {
+        if self.rx_fifo.len() < self.fifo_len() {
+            self.rx_fifo.push_back(val)
+        } else {
+            self.overrun = true;
+        }
+    }
+
+    /// Identify the highest priority pending interrupt.
+    fn iir(&self) -> u8 {
+        let rx_len = self.rx_fifo.len();
+
+        if self.ier.get_bit(ier::ELSI) && self.overrun {
+            iir::LINE_STATUS
+        } else if self.ier.get_bit(ier::ERBFI) && rx_len >= self.rx_trigger_level() {
+            iir::RX_DATA
+        } else if self.ier.get_bit(ier::ERBFI) && rx_len != 0 {
+            // XXX: real hardware waits 4 character-times before reporting a
+            // timeout, but firing right away is indistinguishable to drivers.
+            iir::RX_TIMEOUT
+        } else if self.ier.get_bit(ier::ETBEI) && self.thre_pending {
+            iir::THR_EMPTY
+        } else {
+            iir::NONE
+        }
+    }
+
+    fn update_irq(&mut self) {
+        if self.iir() != iir::NONE {
+            self.irq.assert()
+        } else {
+            self.irq.clear()
+        }
+    }
+
+    fn lsr(&self) -> u8 {
+        *0u8.set_bit(lsr::DR, !self.rx_fifo.is_empty())
+            .set_bit(lsr::OE, self.overrun)
+            .set_bit(lsr::THRE, self.tx_fifo.is_empty())
+            .set_bit(lsr::TEMT, self.tx_fifo.is_empty())
+    }
+
+    fn msr(&self) -> u8 {
+        if self.mcr.get_bit(mcr::LOOP) {
+            // modem outputs are looped back to the modem inputs
+            *0u8.set_bit(4, self.mcr.get_bit(mcr::RTS))
+                .set_bit(5, self.mcr.get_bit(mcr::DTR))
+                .set_bit(6, self.mcr.get_bit(mcr::OUT1))
+                .set_bit(7, self.mcr.get_bit(mcr::OUT2))
+        } else {
+            // pretend that there's always something on the other end (CTS,
+            // DSR, and DCD asserted)
+            0xb0
+        }
+    }
+
+    fn transmit(&mut self, val: u8) -> MemResult<()> {
+        self.thre_pending = false;
+
+        if self.mcr.get_bit(mcr::LOOP) {
+            self.push_rx(val);
+            self.thre_pending = true;
+            return Ok(());
+        }
+
+        let tx_kick = match &self.tx_kick {
+            Some(tx_kick) => tx_kick,
+            None => {
+                // nothing connected, the byte goes nowhere
+                self.thre_pending = true;
+                return Ok(());
+            }
+        };
+
+        if self.tx_fifo.len() >= self.fifo_len() {
+            return Err(ContractViolation {
+                msg: "wrote to THR while the TX FIFO was full".into(),
+                severity: Warn,
+                stub_val: None,
+            });
+        }
+
+        self.tx_fifo.push_back(val);
+        // if the channel is full, the tx task already has a wakeup queued
+        let _ = tx_kick.try_send(());
+        Ok(())
+    }
+}
+
+async fn rx_task(
+    label: &'static str,
+    uart: Arc<Mutex<Uart>>,
+    gen: usize,
+    mut rx: SerialRx,
+    rx_drained: async_channel::Receiver<()>,
+) {
+    // Resolves once the backend has been replaced (i.e: the `rx_drained`
+    // sender is dropped). Any drain notifications are irrelevant while
+    // waiting on the host.
+    let detached = || async { while rx_drained.recv().await.is_ok() {} };
+
+    let mut buf = [0; 64];
+    loop {
+        // reads from the host can block indefinitely, so make sure they don't
+        // keep the old backend alive once it's been replaced
+        let n = {
+            let read_fut = rx.read(&mut buf);
+            let detached_fut = detached();
+            pin_mut!(read_fut, detached_fut);
+
+            match future::select(read_fut, detached_fut).await {
+                Either::Left((Ok(0), _)) => return, // EOF
+                Either::Left((Ok(n), _)) => n,
+                Either::Left((Err(e), _)) => {
+                    warn!("Serial{} backend read error: {}", label, e);
+                    return;
+                }
+                Either::Right(_) => return,
+            }
+        };
+        let mut pending = &buf[..n];
+
+        // Instead of overrunning the guest's FIFO, hold on to any data that
+        // doesn't fit until the guest has had a chance to drain it. This acts
+        // like hardware flow control, and means pasting a big chunk of text
+        // into a shell doesn't drop any characters.
+        while !pending.is_empty() {
+            {
+                let mut uart = uart.lock().unwrap();
+                if uart.backend_gen != gen {
+                    return;
+                }
+
+                let space = uart.fifo_len().saturating_sub(uart.rx_fifo.len());
+                let n = space.min(pending.len());
+                uart.rx_fifo.extend(&pending[..n]);
+                pending = &pending[n..];
+                uart.update_irq();
+            }
+
+            if !pending.is_empty() && rx_drained.recv().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+async fn tx_task(
+    label: &'static str,
+    uart: Arc<Mutex<Uart>>,
+    mut tx: SerialTx,
+    tx_kick: async_channel::Receiver<()>,
+) {
+    // once the backend fails, any further output is discarded (just like the
+    // Null backend), so that the guest doesn't end up waiting on a full FIFO.
+    let mut failed = false;
+
+    while tx_kick.recv().await.is_ok() {
+        loop {
+            let pending = {
+                let uart = uart.lock().unwrap();
+                uart.tx_fifo.iter().copied().collect::<Vec<u8>>()
+            };
+
+            if pending.is_empty() {
+                break;
+            }
+
+            if !failed {
+                let res = async {
+                    tx.write_all(&pending).await?;
+                    tx.flush().await
+                };
+                if let Err(e) = res.await {
+                    warn!(
+                        "Serial{} backend write error: {} (discarding any further output)",
+                        label, e
+                    );
+                    failed = true;
+                }
+            }
+
+            // bytes are only removed from the FIFO once the host has accepted
+            // them, so that a slow backend applies back-pressure to the guest.
+            let mut uart = uart.lock().unwrap();
+            let n = pending.len().min(uart.tx_fifo.len());
+            uart.tx_fifo.drain(..n);
+            if uart.tx_fifo.is_empty() {
+                uart.thre_pending = true;
+            }
+            uart.update_irq();
+        }
+    }
+}
+
+/// PP5020 serial controller. A 16550-compatible UART.
+#[derive(Debug)]
+pub struct Serial {
+    label: &'static str,
+    task_spawner: Spawner,
+    uart: Arc<Mutex<Uart>>,
+}
+
 impl Serial {
-    pub fn new(label: &'static str) -> Serial {
+    pub fn new(label: &'static str, irq: irq::Sender, task_spawner: Spawner) -> Serial {
         Serial {
             label,
+            task_spawner,
+            uart: Arc::new(Mutex::new(Uart {
+                irq,
+
+                rx_fifo: VecDeque::with_capacity(FIFO_LEN),
+                tx_fifo: VecDeque::with_capacity(FIFO_LEN),
+                overrun: false,
+                thre_pending: false,
 
-            ier: 0,
-            fcr: 0,
-            lcr: 0,
-            mcr: 0,
-            asr: 0,
+                backend_gen: 0,
+                tx_kick: None,
+                rx_drained: None,
+
+                ier: 0,
+                fifo_enable: false,
+                rx_trigger: 0,
+                lcr: 0,
+                mcr: 0,
+                spr: 0,
+                dll: 0,
+                dlm: 0,
+                asr: 0,
+            })),
         }
     }
+
+    /// Connect the serial port to a host backend, replacing any existing
+    /// backend.
+    pub fn attach(&mut self, backend: Box<dyn SerialBackend>) {
+        let (rx, tx) = backend.split();
+
+        let (tx_kick_tx, tx_kick_rx) = async_channel::bounded(1);
+        let (rx_drained_tx, rx_drained_rx) = async_channel::bounded(1);
+
+        let gen = {
+            let mut uart = self.uart.lock().unwrap();
+            uart.backend_gen += 1;
+            // dropping the old senders shuts down the old backend's tasks
+            uart.tx_kick = Some(tx_kick_tx);
+            uart.rx_drained = Some(rx_drained_tx);
+            uart.tx_fifo.clear();
+            uart.thre_pending = true;
+            uart.update_irq();
+            uart.backend_gen
+        };
+
+        self.task_spawner
+            .spawn(rx_task(
+                self.label,
+                self.uart.clone(),
+                gen,
+                rx,
+                rx_drained_rx,
+            ))
+            .expect("failed to spawn serial rx task");
+        self.task_spawner
+            .spawn(tx_task(self.label, self.uart.clone(), tx, tx_kick_rx))
+            .expect("failed to spawn serial tx task");
+    }
 }
 
 impl Device for Serial {
@@ -36,17 +419,21 @@ impl Device for Serial {
     }
 
     fn probe(&self, offset: u32) -> Probe {
+        let dlab = self.uart.lock().unwrap().lcr.get_bit(lcr::DLAB);
+
         let reg = match offset {
-            0x00 => "RBR/THR",
-            0x04 => "IER",
-            0x08 => "FCR/IIR",
-            0x0c => "LCR",
-            0x10 => "MCR",
-            0x14 => "LSR",
-            0x18 => "MSR", // Modem Status Register
-            0x1c => "SPR", // Scratch Pad Register
-            0x20 => "IRDA", // IrDA Pulse Coding Register
-            0x3c => "ASR", // Autobaud Sense Register
+            reg::RBR_THR_DLL if dlab => "DLL",
+            reg::RBR_THR_DLL => "RBR/THR",
+            reg::IER_DLM if dlab => "DLM",
+            reg::IER_DLM => "IER",
+            reg::IIR_FCR => "IIR/FCR",
+            reg::LCR => "LCR",
+            reg::MCR => "MCR",
+            reg::LSR => "LSR",
+            reg::MSR => "MSR",
+            reg::SPR => "SPR",
+            reg::IRDA => "IRDA",
+            reg::ASR => "ASR",
             _ => return Probe::Unmapped,
         };
 
@@ -56,42 +443,95 @@ impl Device for Serial {
 
 impl Memory for Serial {
     fn r32(&mut self, offset: u32) -> MemResult<u32> {
-        match offset {
-            0x00 => {
-                // TODO: properly wire up uart
-                Err(StubRead(Info, 0))
+        let mut uart = self.uart.lock().unwrap();
+        let dlab = uart.lcr.get_bit(lcr::DLAB);
+
+        let val = match offset {
+            reg::RBR_THR_DLL if dlab => uart.dll,
+            reg::RBR_THR_DLL => {
+                let val = uart.rx_fifo.pop_front().unwrap_or(0);
+                if let Some(rx_drained) = &uart.rx_drained {
+                    let _ = rx_drained.try_send(());
+                }
+                val
             }
-            0x04 => Ok(self.ier as u32),
-            0x08 => Ok(self.fcr as u32),
-            0x0c => Ok(self.lcr as u32),
-            0x10 => Ok(self.mcr as u32),
-            0x14 => Ok(0x0),
-            0x18..0x28 => Err(Unimplemented),
-            0x3c => Ok(self.asr as u32),
-            _ => Err(Unexpected),
-        }
+            reg::IER_DLM if dlab => uart.dlm,
+            reg::IER_DLM => uart.ier,
+            reg::IIR_FCR => {
+                let iir = uart.iir();
+                // reading IIR acknowledges a THR empty interrupt
+                if iir == iir::THR_EMPTY {
+                    uart.thre_pending = false;
+                }
+                match uart.fifo_enable {
+                    true => iir | iir::FIFOS_ENABLED,
+                    false => iir,
+                }
+            }
+            reg::LCR => uart.lcr,
+            reg::MCR => uart.mcr,
+            reg::LSR => {
+                let lsr = uart.lsr();
+                uart.overrun = false;
+                lsr
+            }
+            reg::MSR => uart.msr(),
+            reg::SPR => uart.spr,
+            reg::IRDA => return Err(Unimplemented),
+            reg::ASR => uart.asr,
+            _ => return Err(Unexpected),
+        };
+
+        uart.update_irq();
+        Ok(val as u32)
     }
 
     fn w32(&mut self, offset: u32, val: u32) -> MemResult<()> {
         let val = val.trunc_to_u8()?;
 
+        let mut uart = self.uart.lock().unwrap();
+        let dlab = uart.lcr.get_bit(lcr::DLAB);
+
         match offset {
-            0x0 => Ok({
-                // TODO: properly wire up uart
-                if val.is_ascii() {
-                    print!("{}", val as char);
-                } else {
-                    print!("\\x{:02x}", val);
+            reg::RBR_THR_DLL if dlab => uart.dll = val,
+            reg::RBR_THR_DLL => uart.transmit(val)?,
+            reg::IER_DLM if dlab => uart.dlm = val,
+            reg::IER_DLM => {
+                // enabling the THRE interrupt while the THR is already empty
+                // immediately raises an interrupt
+                if !uart.ier.get_bit(ier::ETBEI)
+                    && val.get_bit(ier::ETBEI)
+                    && uart.tx_fifo.is_empty()
+                {
+                    uart.thre_pending = true;
+                }
+                uart.ier = val & 0x0f;
+            }
+            reg::IIR_FCR => {
+                if val.get_bit(fcr::RX_RESET) {
+                    uart.rx_fifo.clear();
+                    if let Some(rx_drained) = &uart.rx_drained {
+                        let _ = rx_drained.try_send(());
+                    }
                 }
-            }),
-            0x04 => Ok(self.ier = val),
-            0x08 => Ok(self.fcr = val),
-            0x0c => Ok(self.lcr = val),
-            0x10 => Ok(self.mcr = val),
-            0x14 => Err(InvalidAccess),
-            0x18..0x28 => Err(Unimplemented),
-            0x3c => Ok(self.asr = val),
-            _ => Err(Unexpected),
+                if val.get_bit(fcr::TX_RESET) {
+                    uart.tx_fifo.clear();
+                    uart.thre_pending = true;
+                }
+                uart.fifo_enable = val.get_bit(fcr::ENABLE);
+                uart.rx_trigger = val.get_bits(fcr::RX_TRIGGER);
+            }
+            reg::LCR => uart.lcr = val,
+            reg::MCR => uart.mcr = val,
+            reg::LSR => return Err(InvalidAccess),
+            reg::MSR => return Err(InvalidAccess),
+            reg::SPR => uart.spr = val,
+            reg::IRDA => return Err(Unimplemented),
+            reg::ASR => uart.asr = val,
+            _ => return Err(Unexpected),
         }
+
+        uart.update_irq();
+        Ok(())
     }
 }
@@ -0,0 +1,363 @@
+//! In-process virtual USB host, used to drive the emulated USB controller.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use pin_utils::pin_mut;
+use relativity::Timeout;
+use thiserror::Error;
+
+pub(super) type SetupDone = async_channel::Sender<Result<(), UsbError>>;
+
+/// Messages sent from the virtual host to the device controller.
+#[derive(Debug)]
+pub(super) enum HostMsg {
+    /// Plug / unplug the cable.
+    Connect(bool),
+    /// Signal a bus reset.
+    Reset,
+    /// Deliver a SETUP packet to endpoint 0.
+    Setup { setup: [u8; 8], done: SetupDone },
+    /// Queue a transfer on an endpoint.
+    Xfer(Xfer),
+}
+
+/// A single host-initiated transfer.
+#[derive(Debug)]
+pub(super) struct Xfer {
+    pub ep: u8,
+    pub kind: XferKind,
+}
+
+#[derive(Debug)]
+pub(super) enum XferKind {
+    /// Host to device. Completes once all the data has been accepted.
+    Out {
+        data: Vec<u8>,
+        offset: usize,
+        done: async_channel::Sender<Result<usize, UsbError>>,
+    },
+    /// Device to host. Completes once `len` bytes have been received, or upon
+    /// receiving a short packet.
+    In {
+        len: usize,
+        data: Vec<u8>,
+        done: async_channel::Sender<Result<Vec<u8>, UsbError>>,
+    },
+}
+
+impl Xfer {
+    /// Bit index used by the ENDPTPRIME / ENDPTSTATUS / ENDPTCOMPLETE
+    /// registers.
+    pub fn ep_bit(&self) -> usize {
+        match self.kind {
+            XferKind::Out { .. } => self.ep as usize,
+            XferKind::In { .. } => self.ep as usize + 16,
+        }
+    }
+
+    /// Check if the host is still waiting on the transfer.
+    pub fn is_cancelled(&self) -> bool {
+        match &self.kind {
+            XferKind::Out { done, .. } => done.is_closed(),
+            XferKind::In { done, .. } => done.is_closed(),
+        }
+    }
+
+    pub fn fail(self, e: UsbError) {
+        match self.kind {
+            XferKind::Out { done, .. } => drop(done.try_send(Err(e))),
+            XferKind::In { done, .. } => drop(done.try_send(Err(e))),
+        }
+    }
+}
+
+#[derive(Error, Debug, Clone)]
+pub enum UsbError {
+    #[error("timed out waiting for the device")]
+    Timeout,
+    #[error("endpoint stalled")]
+    Stall,
+    #[error("transfer was aborted by a bus reset or new SETUP packet")]
+    Aborted,
+    #[error("device is not connected")]
+    Disconnected,
+    #[error("malformed descriptor")]
+    BadDescriptor,
+}
+
+/// Standard USB SETUP packet.
+#[derive(Debug, Copy, Clone)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub fn from_bytes(b: [u8; 8]) -> SetupPacket {
+        SetupPacket {
+            request_type: b[0],
+            request: b[1],
+            value: u16::from_le_bytes([b[2], b[3]]),
+            index: u16::from_le_bytes([b[4], b[5]]),
+            length: u16::from_le_bytes([b[6], b[7]]),
+        }
+    }
+
+    pub fn to_bytes(self) -> [u8; 8] {
+        let value = self.value.to_le_bytes();
+        let index = self.index.to_le_bytes();
+        let length = self.length.to_le_bytes();
+        [
+            self.request_type,
+            self.request,
+            value[0],
+            value[1],
+            index[0],
+            index[1],
+            length[0],
+            length[1],
+        ]
+    }
+
+    /// Check if the data stage (if any) is device to host.
+    pub fn is_in(&self) -> bool {
+        self.request_type & 0x80 != 0
+    }
+}
+
+/// Descriptors retrieved while enumerating the device.
+#[derive(Debug, Clone)]
+pub struct UsbDeviceInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Raw device descriptor.
+    pub device_descriptor: Vec<u8>,
+    /// Raw configuration descriptor (including all interface / endpoint
+    /// descriptors).
+    pub config_descriptor: Vec<u8>,
+}
+
+/// An in-flight IN transfer, returned by [UsbHost::submit_in]. The transfer
+/// is cancelled once the handle is dropped.
+#[derive(Debug)]
+pub struct PendingIn<'a> {
+    host: &'a UsbHost,
+    rx: async_channel::Receiver<Result<Vec<u8>, UsbError>>,
+}
+
+impl PendingIn<'_> {
+    /// Wait for the transfer to complete. Returns `UsbError::Timeout` if it
+    /// didn't complete within the host's timeout, in which case the transfer
+    /// remains queued, and can be waited on again.
+    pub fn wait(&self) -> Result<Vec<u8>, UsbError> {
+        self.host.wait(&self.rx)
+    }
+}
+
+/// Handle to the virtual USB host connected to the emulated USB port.
+///
+/// All methods block until the transfer completes (or the handle's timeout
+/// elapses).
+///
+/// Only a single host should be driving the port at any given time.
+#[derive(Debug, Clone)]
+pub struct UsbHost {
+    msg_tx: async_channel::Sender<HostMsg>,
+    service: Arc<AtomicBool>,
+    timeout: Duration,
+}
+
+impl UsbHost {
+    pub(super) fn new(msg_tx: async_channel::Sender<HostMsg>, service: Arc<AtomicBool>) -> UsbHost {
+        UsbHost {
+            msg_tx,
+            service,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Set how long to wait for the device to complete a transfer.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn send(&self, msg: HostMsg) -> Result<(), UsbError> {
+        self.msg_tx
+            .try_send(msg)
+            .map_err(|_| UsbError::Disconnected)?;
+        self.service.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn wait<T>(&self, rx: &async_channel::Receiver<Result<T, UsbError>>) -> Result<T, UsbError> {
+        futures_executor::block_on(async {
+            let recv_fut = rx.recv();
+            pin_mut!(recv_fut);
+
+            match future::select(recv_fut, Timeout::new(self.timeout)).await {
+                Either::Left((Ok(res), _)) => res,
+                Either::Left((Err(_), _)) => Err(UsbError::Disconnected),
+                Either::Right(_) => Err(UsbError::Timeout),
+            }
+        })
+    }
+
+    /// Plug the cable into the iPod.
+    pub fn connect(&self) -> Result<(), UsbError> {
+        self.send(HostMsg::Connect(true))
+    }
+
+    /// Unplug the cable from the iPod.
+    pub fn disconnect(&self) -> Result<(), UsbError> {
+        self.send(HostMsg::Connect(false))
+    }
+
+    /// Signal a bus reset.
+    pub fn reset(&self) -> Result<(), UsbError> {
+        self.send(HostMsg::Reset)
+    }
+
+    /// Queue an OUT transfer on the given endpoint, returning the number of
+    /// bytes accepted by the device.
+    pub fn bulk_out(&self, ep: u8, data: &[u8]) -> Result<usize, UsbError> {
+        let (done, rx) = async_channel::bounded(1);
+        self.send(HostMsg::Xfer(Xfer {
+            ep,
+            kind: XferKind::Out {
+                data: data.to_vec(),
+                offset: 0,
+                done,
+            },
+        }))?;
+        self.wait(&rx)
+    }
+
+    /// Queue an IN transfer on the given endpoint, returning once `len` bytes
+    /// have been received (or the device sends a short packet).
+    pub fn bulk_in(&self, ep: u8, len: usize) -> Result<Vec<u8>, UsbError> {
+        self.submit_in(ep, len)?.wait()
+    }
+
+    /// Queue an IN transfer on the given endpoint, without waiting for it to
+    /// complete.
+    ///
+    /// Unlike `bulk_in`, timing out while waiting on the returned transfer
+    /// doesn't cancel it, so any data the device has already sent isn't lost.
+    pub fn submit_in(&self, ep: u8, len: usize) -> Result<PendingIn<'_>, UsbError> {
+        let (done, rx) = async_channel::bounded(1);
+        self.send(HostMsg::Xfer(Xfer {
+            ep,
+            kind: XferKind::In {
+                len,
+                data: Vec::new(),
+                done,
+            },
+        }))?;
+        Ok(PendingIn { host: self, rx })
+    }
+
+    /// Perform a control transfer on endpoint 0.
+    ///
+    /// `data` is sent to the device for host-to-device requests, and ignored
+    /// otherwise. Returns any data sent back by the device.
+    pub fn control(&self, setup: SetupPacket, data: &[u8]) -> Result<Vec<u8>, UsbError> {
+        let (done, rx) = async_channel::bounded(1);
+        self.send(HostMsg::Setup {
+            setup: setup.to_bytes(),
+            done,
+        })?;
+        self.wait(&rx)?;
+
+        if setup.is_in() {
+            let data = match setup.length {
+                0 => Vec::new(),
+                len => self.bulk_in(0, len as usize)?,
+            };
+            // status stage
+            self.bulk_out(0, &[])?;
+            Ok(data)
+        } else {
+            if setup.length != 0 {
+                self.bulk_out(0, &data[..data.len().min(setup.length as usize)])?;
+            }
+            // status stage
+            self.bulk_in(0, 0)?;
+            Ok(Vec::new())
+        }
+    }
+
+    /// Perform a standard GET_DESCRIPTOR request.
+    pub fn get_descriptor(&self, kind: u8, idx: u8, len: u16) -> Result<Vec<u8>, UsbError> {
+        self.control(
+            SetupPacket {
+                request_type: 0x80,
+                request: 0x06,
+                value: (kind as u16) << 8 | idx as u16,
+                index: 0,
+                length: len,
+            },
+            &[],
+        )
+    }
+
+    /// Connect, reset, and enumerate the device, leaving it in the configured
+    /// state (using the first configuration).
+    pub fn enumerate(&self) -> Result<UsbDeviceInfo, UsbError> {
+        self.connect()?;
+        self.reset()?;
+        // give the device a chance to handle the reset
+        futures_executor::block_on(Timeout::new(Duration::from_millis(10)));
+
+        let device_descriptor = self.get_descriptor(0x01, 0, 18)?;
+        if device_descriptor.len() < 18 {
+            return Err(UsbError::BadDescriptor);
+        }
+
+        // SET_ADDRESS (the address itself doesn't matter to the virtual host)
+        self.control(
+            SetupPacket {
+                request_type: 0x00,
+                request: 0x05,
+                value: 1,
+                index: 0,
+                length: 0,
+            },
+            &[],
+        )?;
+
+        let config_header = self.get_descriptor(0x02, 0, 9)?;
+        if config_header.len() < 9 {
+            return Err(UsbError::BadDescriptor);
+        }
+        let total_len = u16::from_le_bytes([config_header[2], config_header[3]]);
+        let config_descriptor = self.get_descriptor(0x02, 0, total_len)?;
+        if config_descriptor.len() < 9 {
+            return Err(UsbError::BadDescriptor);
+        }
+
+        // SET_CONFIGURATION
+        self.control(
+            SetupPacket {
+                request_type: 0x00,
+                request: 0x09,
+                value: config_descriptor[5] as u16,
+                index: 0,
+                length: 0,
+            },
+            &[],
+        )?;
+
+        Ok(UsbDeviceInfo {
+            vendor_id: u16::from_le_bytes([device_descriptor[8], device_descriptor[9]]),
+            product_id: u16::from_le_bytes([device_descriptor[10], device_descriptor[11]]),
+            device_descriptor,
+            config_descriptor,
+        })
+    }
+}
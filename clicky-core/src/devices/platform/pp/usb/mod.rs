@@ -0,0 +1,771 @@
+use crate::devices::prelude::*;
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+mod host;
+
+pub use host::{PendingIn, SetupPacket, UsbDeviceInfo, UsbError, UsbHost};
+
+use host::{HostMsg, SetupDone, Xfer, XferKind};
+
+/// ARC/Freescale USB-OTG controller, as found on the PP5020 at 0xc5000000.
+///
+/// Register layout per rockbox's `firmware/target/arm/usb-drv-arc.c`, which
+/// drives this exact core (`USB_BASE` is named in its `pp5020.h`).
+///
+/// Only device mode is modelled. The other end of the cable is a [UsbHost],
+/// which operates at the granularity of whole transfers (i.e: there's no
+/// packet-level NAK / ACK handshaking, and no SOF timing).
+///
+/// Since the endpoint queue heads and transfer descriptors live in guest
+/// memory, the controller can't do much on its own. Instead, it sets a flag
+/// whenever it has work to do, and the system calls [Usb::service] with access
+/// to the memory bus.
+#[derive(Debug)]
+pub struct Usb {
+    irq: irq::Sender,
+    reg: Box<[u32; 0x80]>,
+
+    host_rx: async_channel::Receiver<HostMsg>,
+    host_tx: async_channel::Sender<HostMsg>,
+    service_requested: Arc<AtomicBool>,
+
+    connected: bool,
+    pending_setup: Option<([u8; 8], SetupDone)>,
+    /// Pending host transfers, indexed by ENDPTSTATUS bit.
+    xfers: Vec<VecDeque<Xfer>>,
+}
+
+mod reg {
+    /// Device controller parameters.
+    pub const DCCPARAMS: u32 = 0x124;
+    /// Run/stop + controller reset.
+    pub const USBCMD: u32 = 0x140;
+    /// Interrupt status.
+    pub const USBSTS: u32 = 0x144;
+    /// Interrupt enable.
+    pub const USBINTR: u32 = 0x148;
+    /// Base address of the endpoint queue heads.
+    pub const ENDPOINTLISTADDR: u32 = 0x158;
+    /// Port status & control.
+    pub const PORTSC1: u32 = 0x184;
+    /// Endpoint setup status.
+    pub const ENDPTSETUPSTAT: u32 = 0x1ac;
+    /// Endpoint prime.
+    pub const ENDPTPRIME: u32 = 0x1b0;
+    /// Endpoint flush.
+    pub const ENDPTFLUSH: u32 = 0x1b4;
+    /// Endpoint status (i.e: which endpoints are primed).
+    pub const ENDPTSTATUS: u32 = 0x1b8;
+    /// Endpoint complete.
+    pub const ENDPTCOMPLETE: u32 = 0x1bc;
+    /// Endpoint control (one register per endpoint).
+    pub const ENDPTCTRL0: u32 = 0x1c0;
+    pub const ENDPTCTRL15: u32 = 0x1fc;
+
+    /// OTG status & control.
+    pub const OTGSC: u32 = 0x1a4;
+    pub const OTGSC_ID: usize = 8;
+    pub const OTGSC_BSV: usize = 11;
+
+    /// `USBCMD` bit 0. Run / Stop.
+    pub const USBCMD_RS: usize = 0;
+    /// `USBCMD` bit 1. Self-clearing: firmware sets it and spins until the
+    /// controller takes it back down again.
+    pub const USBCMD_CTRL_RESET: usize = 1;
+
+    /// `USBSTS` / `USBINTR` bits
+    pub const USBSTS_UI: usize = 0;
+    pub const USBSTS_PCI: usize = 2;
+    pub const USBSTS_URI: usize = 6;
+
+    /// `PORTSC1` bits
+    pub const PORTSC_CCS: usize = 0;
+    pub const PORTSC_CSC: usize = 1;
+    pub const PORTSC_PE: usize = 2;
+    pub const PORTSC_HIGH_SPEED: usize = 27;
+
+    /// `ENDPTCTRLx` stall bits
+    pub const ENDPTCTRL_RXS: usize = 0;
+    pub const ENDPTCTRL_TXS: usize = 16;
+}
+
+/// Endpoint Queue Head (dQH) layout. Each dQH is 64 bytes, with the OUT and IN
+/// halves of each endpoint stored back-to-back.
+mod dqh {
+    type Range = std::ops::RangeInclusive<usize>;
+    pub const SIZE: u32 = 64;
+    pub const CAPABILITIES: u32 = 0x00;
+    pub const CAPABILITIES_MAX_PKT: Range = 16..=26;
+    pub const CURRENT_DTD: u32 = 0x04;
+    /// Start of the dTD overlay area
+    pub const OVERLAY: u32 = 0x08;
+    pub const SETUP_BUFFER: u32 = 0x28;
+}
+
+/// Endpoint Transfer Descriptor (dTD) layout.
+mod dtd {
+    type Range = std::ops::RangeInclusive<usize>;
+    pub const NEXT: u32 = 0x00;
+    pub const NEXT_TERMINATE: usize = 0;
+    pub const TOKEN: u32 = 0x04;
+    pub const TOKEN_TOTAL_BYTES: Range = 16..=30;
+    pub const TOKEN_IOC: usize = 15;
+    pub const TOKEN_ACTIVE: usize = 7;
+    pub const BUFFER_PTRS: u32 = 0x08;
+}
+
+impl Usb {
+    pub fn new(irq: irq::Sender) -> Usb {
+        let (host_tx, host_rx) = async_channel::unbounded();
+        let mut usb = Usb {
+            irq,
+            reg: Box::new([0; 0x80]),
+
+            host_rx,
+            host_tx,
+            service_requested: Arc::new(AtomicBool::new(false)),
+
+            connected: false,
+            pending_setup: None,
+            xfers: (0..32).map(|_| VecDeque::new()).collect(),
+        };
+        usb.reset_regs();
+        usb
+    }
+
+    /// Return a handle to the virtual USB host connected to this controller.
+    pub fn host(&self) -> UsbHost {
+        UsbHost::new(self.host_tx.clone(), Arc::clone(&self.service_requested))
+    }
+
+    /// Handle to the "controller needs servicing" flag. When set, the system
+    /// should call [Usb::service] (and clear the flag).
+    pub fn service_requested(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.service_requested)
+    }
+
+    fn reg(&self, offset: u32) -> u32 {
+        self.reg[(offset / 4) as usize]
+    }
+
+    fn reg_mut(&mut self, offset: u32) -> &mut u32 {
+        &mut self.reg[(offset / 4) as usize]
+    }
+
+    fn reset_regs(&mut self) {
+        self.reg.iter_mut().for_each(|r| *r = 0);
+        // device capable, 8 endpoints
+        *self.reg_mut(reg::DCCPARAMS) = 1 << 7 | 8;
+        // EP0 is always enabled
+        *self.reg_mut(reg::ENDPTCTRL0) = 1 << 7 | 1 << 23;
+        self.update_portsc();
+    }
+
+    fn request_service(&self) {
+        self.service_requested.store(true, Ordering::SeqCst);
+    }
+
+    fn update_irq(&mut self) {
+        if self.reg(reg::USBSTS) & self.reg(reg::USBINTR) & 0x1ff != 0 {
+            self.irq.assert()
+        } else {
+            self.irq.clear()
+        }
+    }
+
+    fn update_portsc(&mut self) {
+        let connected = self.connected;
+        self.reg_mut(reg::PORTSC1)
+            .set_bit(reg::PORTSC_CCS, connected)
+            .set_bit(reg::PORTSC_PE, connected)
+            .set_bit(reg::PORTSC_HIGH_SPEED, connected);
+    }
+
+    /// Check if the guest has started the controller. Endpoints can be primed
+    /// regardless of whether a host is connected.
+    fn is_started(&self) -> bool {
+        self.reg(reg::USBCMD).get_bit(reg::USBCMD_RS) && self.reg(reg::ENDPOINTLISTADDR) != 0
+    }
+
+    fn is_running(&self) -> bool {
+        self.connected && self.is_started()
+    }
+
+    fn is_stalled(&self, ep_bit: usize) -> bool {
+        let ctrl = self.reg(reg::ENDPTCTRL0 + (ep_bit as u32 % 16) * 4);
+        match ep_bit < 16 {
+            true => ctrl.get_bit(reg::ENDPTCTRL_RXS),
+            false => ctrl.get_bit(reg::ENDPTCTRL_TXS),
+        }
+    }
+
+    /// Address of the dQH associated with a particular ENDPTSTATUS bit.
+    fn dqh_addr(&self, ep_bit: usize) -> u32 {
+        let idx = (ep_bit as u32 % 16) * 2 + (ep_bit >= 16) as u32;
+        (self.reg(reg::ENDPOINTLISTADDR) & !0x7ff) + idx * dqh::SIZE
+    }
+
+    /// Fail all pending transfers on the given endpoints.
+    fn abort_xfers(&mut self, ep_mask: u32, e: UsbError) {
+        for (bit, xfers) in self.xfers.iter_mut().enumerate() {
+            if ep_mask.get_bit(bit) {
+                xfers.drain(..).for_each(|xfer| xfer.fail(e.clone()));
+            }
+        }
+    }
+
+    fn handle_host_msg(&mut self, msg: HostMsg) {
+        match msg {
+            HostMsg::Connect(connected) => {
+                if self.connected != connected {
+                    self.connected = connected;
+                    self.reg_mut(reg::PORTSC1).set_bit(reg::PORTSC_CSC, true);
+                    self.reg_mut(reg::USBSTS).set_bit(reg::USBSTS_PCI, true);
+                    self.update_portsc();
+                }
+
+                if !connected {
+                    if let Some((_, done)) = self.pending_setup.take() {
+                        let _ = done.try_send(Err(UsbError::Disconnected));
+                    }
+                    self.abort_xfers(!0, UsbError::Disconnected);
+                }
+            }
+            HostMsg::Reset => {
+                if !self.connected {
+                    return;
+                }
+
+                self.abort_xfers(!0, UsbError::Aborted);
+                *self.reg_mut(reg::ENDPTSETUPSTAT) = 0;
+                *self.reg_mut(reg::ENDPTCOMPLETE) = 0;
+                *self.reg_mut(reg::ENDPTSTATUS) = 0;
+                *self.reg_mut(reg::ENDPTPRIME) = 0;
+                self.reg_mut(reg::USBSTS).set_bit(reg::USBSTS_URI, true);
+            }
+            HostMsg::Setup { setup, done } => {
+                if !self.connected {
+                    let _ = done.try_send(Err(UsbError::Disconnected));
+                    return;
+                }
+
+                // a new SETUP packet aborts whatever control transfer was in
+                // progress
+                if let Some((_, done)) = self.pending_setup.take() {
+                    let _ = done.try_send(Err(UsbError::Aborted));
+                }
+                self.abort_xfers(1 | 1 << 16, UsbError::Aborted);
+                self.pending_setup = Some((setup, done));
+            }
+            HostMsg::Xfer(xfer) => {
+                if !self.connected {
+                    xfer.fail(UsbError::Disconnected);
+                    return;
+                }
+
+                let bit = xfer.ep_bit();
+                self.xfers[bit].push_back(xfer);
+            }
+        }
+    }
+
+    /// Retire a dTD, writing back the remaining byte count, and advancing the
+    /// dQH to the next dTD in the list.
+    fn retire_dtd(
+        &mut self,
+        mem: &mut dyn Memory,
+        ep_bit: usize,
+        dtd_addr: u32,
+        remaining: usize,
+    ) -> MemResult<()> {
+        let qh = self.dqh_addr(ep_bit);
+
+        let mut token = mem.r32(dtd_addr + dtd::TOKEN)?;
+        token
+            .set_bits(dtd::TOKEN_TOTAL_BYTES, remaining as u32)
+            .set_bit(dtd::TOKEN_ACTIVE, false);
+        mem.w32(dtd_addr + dtd::TOKEN, token)?;
+
+        // update the dQH overlay
+        let next = mem.r32(dtd_addr + dtd::NEXT)?;
+        mem.w32(qh + dqh::CURRENT_DTD, dtd_addr)?;
+        mem.w32(qh + dqh::OVERLAY + dtd::NEXT, next)?;
+        mem.w32(qh + dqh::OVERLAY + dtd::TOKEN, token)?;
+
+        if token.get_bit(dtd::TOKEN_IOC) {
+            self.reg_mut(reg::ENDPTCOMPLETE).set_bit(ep_bit, true);
+            self.reg_mut(reg::USBSTS).set_bit(reg::USBSTS_UI, true);
+        }
+
+        if next.get_bit(dtd::NEXT_TERMINATE) {
+            self.reg_mut(reg::ENDPTSTATUS).set_bit(ep_bit, false);
+        }
+
+        Ok(())
+    }
+
+    /// Translate an offset into a dTD's buffer into a physical address.
+    fn dtd_buf_addr(buf_ptrs: &[u32; 5], offset: usize) -> u32 {
+        let pos = (buf_ptrs[0] & 0xfff) as usize + offset;
+        let page = (pos / 0x1000).min(4);
+        (buf_ptrs[page] & !0xfff) + (pos & 0xfff) as u32
+    }
+
+    /// Make as much progress as possible on the given endpoint's transfers.
+    fn run_xfers(&mut self, mem: &mut dyn Memory, ep_bit: usize) -> MemResult<()> {
+        loop {
+            match self.xfers[ep_bit].front() {
+                None => return Ok(()),
+                Some(xfer) if xfer.is_cancelled() => {
+                    self.xfers[ep_bit].pop_front();
+                    continue;
+                }
+                Some(_) => {}
+            }
+
+            if self.is_stalled(ep_bit) {
+                let xfer = self.xfers[ep_bit].pop_front().unwrap();
+                xfer.fail(UsbError::Stall);
+                continue;
+            }
+
+            if !self.reg(reg::ENDPTSTATUS).get_bit(ep_bit) {
+                // nothing primed, wait for the guest
+                return Ok(());
+            }
+
+            let qh = self.dqh_addr(ep_bit);
+            let max_pkt = mem
+                .r32(qh + dqh::CAPABILITIES)?
+                .get_bits(dqh::CAPABILITIES_MAX_PKT);
+            let dtd_addr = mem.r32(qh + dqh::OVERLAY + dtd::NEXT)?;
+            if dtd_addr.get_bit(dtd::NEXT_TERMINATE) {
+                self.reg_mut(reg::ENDPTSTATUS).set_bit(ep_bit, false);
+                return Ok(());
+            }
+
+            let token = mem.r32(dtd_addr + dtd::TOKEN)?;
+            if !token.get_bit(dtd::TOKEN_ACTIVE) {
+                self.reg_mut(reg::ENDPTSTATUS).set_bit(ep_bit, false);
+                return Ok(());
+            }
+
+            let total = token.get_bits(dtd::TOKEN_TOTAL_BYTES) as usize;
+            let mut buf_ptrs = [0; 5];
+            for (i, ptr) in buf_ptrs.iter_mut().enumerate() {
+                *ptr = mem.r32(dtd_addr + dtd::BUFFER_PTRS + i as u32 * 4)?;
+            }
+
+            let xfer = self.xfers[ep_bit].front_mut().unwrap();
+            let (n, finished) = match &mut xfer.kind {
+                XferKind::Out { data, offset, .. } => {
+                    let n = total.min(data.len() - *offset);
+                    for i in 0..n {
+                        mem.w8(Usb::dtd_buf_addr(&buf_ptrs, i), data[*offset + i])?;
+                    }
+                    *offset += n;
+                    (n, *offset == data.len())
+                }
+                XferKind::In { len, data, .. } => {
+                    let n = total.min(*len - data.len());
+                    for i in 0..n {
+                        data.push(mem.r8(Usb::dtd_buf_addr(&buf_ptrs, i))?);
+                    }
+                    // a short (or zero-length) packet terminates the transfer
+                    let last_pkt_len = total % (max_pkt as usize).max(1);
+                    let short = total == 0 || last_pkt_len != 0;
+                    (n, data.len() == *len || short)
+                }
+            };
+
+            self.retire_dtd(mem, ep_bit, dtd_addr, total - n)?;
+
+            if finished {
+                let xfer = self.xfers[ep_bit].pop_front().unwrap();
+                match xfer.kind {
+                    XferKind::Out { data, done, .. } => drop(done.try_send(Ok(data.len()))),
+                    XferKind::In { data, done, .. } => drop(done.try_send(Ok(data))),
+                }
+            }
+        }
+    }
+
+    /// Process any outstanding work (messages from the host, primed
+    /// endpoints, etc...).
+    ///
+    /// `mem` should provide access to the system's physical address space, as
+    /// the dQHs / dTDs / transfer buffers all live in guest memory.
+    pub fn service(&mut self, mem: &mut dyn Memory) -> MemResult<()> {
+        while let Ok(msg) = self.host_rx.try_recv() {
+            self.handle_host_msg(msg);
+        }
+
+        // prime endpoints
+        let prime = std::mem::replace(self.reg_mut(reg::ENDPTPRIME), 0);
+        if self.is_started() {
+            for bit in 0..32 {
+                if !prime.get_bit(bit) {
+                    continue;
+                }
+
+                let qh = self.dqh_addr(bit);
+                let next = mem.r32(qh + dqh::OVERLAY + dtd::NEXT)?;
+                if !next.get_bit(dtd::NEXT_TERMINATE) {
+                    self.reg_mut(reg::ENDPTSTATUS).set_bit(bit, true);
+                }
+            }
+        }
+
+        if self.is_running() {
+            // deliver setup packets
+            if let Some((setup, done)) = self.pending_setup.take() {
+                let qh = self.dqh_addr(0);
+                for (i, b) in setup.iter().enumerate() {
+                    mem.w8(qh + dqh::SETUP_BUFFER + i as u32, *b)?;
+                }
+
+                // receiving a SETUP packet clears any stall on EP0
+                self.reg_mut(reg::ENDPTCTRL0)
+                    .set_bit(reg::ENDPTCTRL_RXS, false)
+                    .set_bit(reg::ENDPTCTRL_TXS, false);
+                self.reg_mut(reg::ENDPTSETUPSTAT).set_bit(0, true);
+                self.reg_mut(reg::USBSTS).set_bit(reg::USBSTS_UI, true);
+                let _ = done.try_send(Ok(()));
+            }
+
+            for bit in 0..32 {
+                self.run_xfers(mem, bit)?;
+            }
+        }
+
+        self.update_irq();
+        Ok(())
+    }
+}
+
+impl Device for Usb {
+    fn kind(&self) -> &'static str {
+        "ARC USB-OTG"
+    }
+
+    fn probe(&self, offset: u32) -> Probe {
+        let name = match offset {
+            0x000 => "Id",
+            0x004 => "HwGeneral",
+            0x008 => "HwHost",
+            0x00c => "HwDevice",
+            0x010 => "TxBuf",
+            0x014 => "RxBuf",
+            0x100 => "CapLength",
+            0x120 => "DciVersion",
+            0x124 => "DccParams",
+            0x140 => "UsbCmd",
+            0x144 => "UsbSts",
+            0x148 => "UsbIntr",
+            0x14c => "FrIndex",
+            0x154 => "DeviceAddr",
+            0x158 => "EndpointListAddr",
+            0x160 => "BurstSize",
+            0x170 => "Ulpi",
+            0x180 => "ConfigFlag",
+            0x184 => "PortSc1",
+            0x1a4 => "OtgSc",
+            0x1a8 => "UsbMode",
+            0x1ac => "EndptSetupStat",
+            0x1b0 => "EndptPrime",
+            0x1b4 => "EndptFlush",
+            0x1b8 => "EndptStatus",
+            0x1bc => "EndptComplete",
+            0x1c0..=0x1fc => "EndptCtrl<X>",
+            _ => "?",
+        };
+
+        Probe::Register(name)
+    }
+}
+
+impl Memory for Usb {
+    fn r32(&mut self, offset: u32) -> MemResult<u32> {
+        match offset {
+            reg::OTGSC => Ok({
+                let mut val = self.reg(offset);
+                val.set_bit(reg::OTGSC_ID, true) // Is a peripheral
+                    .set_bit(reg::OTGSC_BSV, true); // Receiving VBUS
+                val
+            }),
+            reg::USBCMD
+            | reg::USBSTS
+            | reg::USBINTR
+            | reg::PORTSC1
+            | reg::ENDPTSETUPSTAT
+            | reg::ENDPTPRIME
+            | reg::ENDPTSTATUS
+            | reg::ENDPTCOMPLETE
+            | reg::ENDPOINTLISTADDR
+            | reg::DCCPARAMS
+            | reg::ENDPTCTRL0..=reg::ENDPTCTRL15 => Ok(self.reg(offset)),
+            reg::ENDPTFLUSH => Ok(0),
+            0x000..=0x1ff => Err(StubRead(Debug, self.reg(offset))),
+            _ => Err(Unexpected),
+        }
+    }
+
+    fn w32(&mut self, offset: u32, val: u32) -> MemResult<()> {
+        match offset {
+            reg::USBCMD => {
+                if val.get_bit(reg::USBCMD_CTRL_RESET) {
+                    self.abort_xfers(!0, UsbError::Aborted);
+                    self.reset_regs();
+                    self.update_irq();
+                    return Ok(());
+                }
+
+                *self.reg_mut(offset) = val;
+                self.request_service();
+            }
+            // write-1-to-clear
+            reg::USBSTS | reg::ENDPTSETUPSTAT | reg::ENDPTCOMPLETE => {
+                *self.reg_mut(offset) &= !val;
+                self.update_irq();
+            }
+            reg::PORTSC1 => {
+                // only the change bits are writable (write-1-to-clear)
+                if val.get_bit(reg::PORTSC_CSC) {
+                    self.reg_mut(offset).set_bit(reg::PORTSC_CSC, false);
+                }
+            }
+            reg::ENDPTPRIME => {
+                // priming a stopped controller is a no-op, so the bits clear
+                // straight away (instead of leaving the guest spinning on them)
+                if self.is_started() {
+                    *self.reg_mut(offset) |= val;
+                    self.request_service();
+                }
+            }
+            reg::ENDPTFLUSH => {
+                *self.reg_mut(reg::ENDPTSTATUS) &= !val;
+                *self.reg_mut(reg::ENDPTPRIME) &= !val;
+            }
+            reg::ENDPTSTATUS => return Err(InvalidAccess),
+            reg::USBINTR => {
+                *self.reg_mut(offset) = val;
+                self.update_irq();
+            }
+            reg::ENDPTCTRL0..=reg::ENDPTCTRL15 => {
+                *self.reg_mut(offset) = val;
+                // stalling an endpoint fails any pending transfers
+                self.request_service();
+            }
+            reg::ENDPOINTLISTADDR => {
+                *self.reg_mut(offset) = val;
+                self.request_service();
+            }
+            0x000..=0x1ff => return Err(StubWrite(Debug, *self.reg_mut(offset) = val)),
+            _ => return Err(Unexpected),
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread::{self, JoinHandle};
+
+    use crate::devices::generic::Ram;
+
+    const DQH_BASE: u32 = 0x800;
+    const DTD_BASE: u32 = 0x1000;
+    const BUF_BASE: u32 = 0x10000;
+
+    const VENDOR_ID: u16 = 0x05ac;
+    const PRODUCT_ID: u16 = 0x1203;
+
+    const DEVICE_DESCRIPTOR: [u8; 18] = [
+        18, 0x01, 0x00, 0x02, 0, 0, 0, 64, 0xac, 0x05, 0x03, 0x12, 0x01, 0x00, 1, 2, 3, 1,
+    ];
+    const CONFIG_DESCRIPTOR: [u8; 32] = [
+        // configuration
+        9, 0x02, 32, 0, 1, 1, 0, 0xc0, 50, //
+        // interface (mass storage, bulk-only)
+        9, 0x04, 0, 0, 2, 0x08, 0x06, 0x50, 0, //
+        // EP1 OUT + EP1 IN (bulk, 512 byte packets)
+        7, 0x05, 0x01, 0x02, 0x00, 0x02, 0, //
+        7, 0x05, 0x81, 0x02, 0x00, 0x02, 0,
+    ];
+
+    /// Minimal guest-side device driver, responding to the standard requests
+    /// sent while enumerating, and priming EP1 once configured.
+    struct Guest {
+        usb: Usb,
+        ram: Ram,
+        /// Data returned by the next IN transfer on EP1.
+        ep1_in: Vec<u8>,
+        /// Data received by OUT transfers on EP1.
+        ep1_out: Vec<u8>,
+    }
+
+    impl Guest {
+        fn new() -> Guest {
+            let (irq, _) = irq::new(irq::Pending::new(), "USB");
+            let mut guest = Guest {
+                usb: Usb::new(irq),
+                ram: Ram::new(0x40000),
+                ep1_in: Vec::new(),
+                ep1_out: Vec::new(),
+            };
+
+            for i in 0..32 {
+                let qh = DQH_BASE + i * dqh::SIZE;
+                let max_pkt = if i < 2 { 64 } else { 512 };
+                for offset in (0..dqh::SIZE).step_by(4) {
+                    guest.ram.w32(qh + offset, 0).unwrap();
+                }
+                guest
+                    .ram
+                    .w32(qh + dqh::CAPABILITIES, max_pkt << 16)
+                    .unwrap();
+                guest.ram.w32(qh + dqh::OVERLAY + dtd::NEXT, 1).unwrap();
+            }
+
+            guest.usb.w32(reg::ENDPOINTLISTADDR, DQH_BASE).unwrap();
+            guest.usb.w32(reg::USBCMD, 1).unwrap();
+            guest
+        }
+
+        /// Queue a single dTD on the endpoint associated with the given
+        /// ENDPTSTATUS bit.
+        fn prime(&mut self, ep_bit: usize, data: &[u8], len: usize) {
+            let dtd_addr = DTD_BASE + ep_bit as u32 * 0x20;
+            let buf = BUF_BASE + ep_bit as u32 * 0x1000;
+            self.ram.bulk_write(buf, data);
+
+            let mut token = 0;
+            token
+                .set_bits(dtd::TOKEN_TOTAL_BYTES, len as u32)
+                .set_bit(dtd::TOKEN_IOC, true)
+                .set_bit(dtd::TOKEN_ACTIVE, true);
+            self.ram.w32(dtd_addr + dtd::NEXT, 1).unwrap();
+            self.ram.w32(dtd_addr + dtd::TOKEN, token).unwrap();
+            self.ram.w32(dtd_addr + dtd::BUFFER_PTRS, buf).unwrap();
+
+            let qh = self.usb.dqh_addr(ep_bit);
+            self.ram
+                .w32(qh + dqh::OVERLAY + dtd::NEXT, dtd_addr)
+                .unwrap();
+            self.usb.w32(reg::ENDPTPRIME, 1 << ep_bit).unwrap();
+        }
+
+        fn handle_setup(&mut self, setup: SetupPacket) {
+            let reply: &[u8] = match (setup.request, setup.value >> 8) {
+                // GET_DESCRIPTOR
+                (0x06, 0x01) => &DEVICE_DESCRIPTOR,
+                (0x06, 0x02) => &CONFIG_DESCRIPTOR,
+                // SET_CONFIGURATION
+                (0x09, _) => {
+                    self.prime(1, &[], 0x1000);
+                    let data = self.ep1_in.clone();
+                    self.prime(17, &data, data.len());
+                    &[]
+                }
+                _ => &[],
+            };
+
+            if setup.is_in() {
+                let len = reply.len().min(setup.length as usize);
+                self.prime(16, &reply[..len], len);
+                self.prime(0, &[], 0);
+            } else {
+                self.prime(16, &[], 0);
+            }
+        }
+
+        fn poll(&mut self) {
+            self.usb.service(&mut self.ram).unwrap();
+
+            if self.usb.r32(reg::ENDPTSETUPSTAT).unwrap() & 1 != 0 {
+                self.usb.w32(reg::ENDPTSETUPSTAT, 1).unwrap();
+                let mut setup = [0; 8];
+                self.ram.bulk_read(DQH_BASE + dqh::SETUP_BUFFER, &mut setup);
+                self.handle_setup(SetupPacket::from_bytes(setup));
+            }
+
+            // EP1 OUT
+            if self.usb.r32(reg::ENDPTCOMPLETE).unwrap() & 1 << 1 != 0 {
+                self.usb.w32(reg::ENDPTCOMPLETE, 1 << 1).unwrap();
+                let token = self.ram.r32(DTD_BASE + 0x20 + dtd::TOKEN).unwrap();
+                let len = 0x1000 - token.get_bits(dtd::TOKEN_TOTAL_BYTES) as usize;
+                let mut data = vec![0; len];
+                self.ram.bulk_read(BUF_BASE + 0x1000, &mut data);
+                self.ep1_out.extend(data);
+            }
+        }
+
+        /// Run the guest until the host is done with it.
+        fn run<T>(&mut self, host: JoinHandle<T>) -> T {
+            while !host.is_finished() {
+                self.poll();
+            }
+            host.join().unwrap()
+        }
+    }
+
+    #[test]
+    fn enumerate() {
+        let mut guest = Guest::new();
+        let host = guest.usb.host();
+
+        let info = guest.run(thread::spawn(move || host.enumerate())).unwrap();
+        assert_eq!(info.vendor_id, VENDOR_ID);
+        assert_eq!(info.product_id, PRODUCT_ID);
+        assert_eq!(info.device_descriptor, DEVICE_DESCRIPTOR);
+        assert_eq!(info.config_descriptor, CONFIG_DESCRIPTOR);
+    }
+
+    #[test]
+    fn bulk_out() {
+        let mut guest = Guest::new();
+        let host = guest.usb.host();
+
+        let sent = guest.run(thread::spawn(move || {
+            host.enumerate()?;
+            host.bulk_out(1, b"hello")
+        }));
+        assert_eq!(sent.unwrap(), 5);
+        assert_eq!(guest.ep1_out, b"hello");
+    }
+
+    #[test]
+    fn bulk_in() {
+        let mut guest = Guest::new();
+        guest.ep1_in = b"world".to_vec();
+        let host = guest.usb.host();
+
+        let data = guest.run(thread::spawn(move || {
+            host.enumerate()?;
+            host.bulk_in(1, 512)
+        }));
+        assert_eq!(data.unwrap(), b"world");
+    }
+
+    #[test]
+    fn prime_without_host() {
+        let mut guest = Guest::new();
+        guest.prime(1, &[], 0x1000);
+        guest.poll();
+
+        // no host is connected, but the endpoint is still primed
+        assert_eq!(guest.usb.r32(reg::ENDPTPRIME).unwrap(), 0);
+        assert_eq!(guest.usb.r32(reg::ENDPTSTATUS).unwrap(), 1 << 1);
+
+        // priming a stopped controller is ignored
+        guest.usb.w32(reg::USBCMD, 0).unwrap();
+        guest.prime(17, &[], 0);
+        assert_eq!(guest.usb.r32(reg::ENDPTPRIME).unwrap(), 0);
+    }
+}
@@ -10,6 +10,10 @@ pub type ButtonCallback = Box<dyn FnMut(/* pressed: */ bool) + Send>;
 /// `ScrollCallback` should be called on scroll, passing the delta in both
 /// directions.
 pub type ScrollCallback = Box<dyn FnMut(/* (dx, dy): */ (f32, f32)) + Send>;
+/// `DragCallback` should be called whenever the pointer is dragged across the
+/// screen, passing its position relative to the center of the screen (with
+/// the screen's edges at -1.0 and 1.0), and `None` once the drag ends.
+pub type DragCallback = Box<dyn FnMut(/* (x, y): */ Option<(f32, f32)>) + Send>;
 
 pub trait TakeControls {
     type Controls;
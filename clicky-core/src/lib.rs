@@ -10,5 +10,9 @@ pub mod error;
 pub mod executor;
 pub mod gui;
 pub mod memory;
+pub mod serial;
 pub mod signal;
 pub mod sys;
+
+#[cfg(test)]
+mod testutil;
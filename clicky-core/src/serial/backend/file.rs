@@ -0,0 +1,30 @@
+use std::fs;
+
+use blocking::Unblock;
+
+use crate::serial::{SerialBackend, SerialRx, SerialTx};
+
+/// File-backed serial port. Transmitted data is appended to a log file, and
+/// received data is (optionally) read from a separate input file.
+#[derive(Debug)]
+pub struct File {
+    log: fs::File,
+    input: Option<fs::File>,
+}
+
+impl File {
+    pub fn new(log: fs::File, input: Option<fs::File>) -> File {
+        File { log, input }
+    }
+}
+
+impl SerialBackend for File {
+    fn split(self: Box<Self>) -> (SerialRx, SerialTx) {
+        let rx: SerialRx = match self.input {
+            Some(input) => Box::new(Unblock::new(input)),
+            None => Box::new(futures::io::empty()),
+        };
+
+        (rx, Box::new(Unblock::new(self.log)))
+    }
+}
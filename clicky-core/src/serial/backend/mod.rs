@@ -0,0 +1,15 @@
+//! Serial port backends.
+
+mod file;
+mod null;
+#[cfg(unix)]
+mod pty;
+mod stdio;
+mod tcp;
+
+pub use file::File;
+pub use null::Null;
+#[cfg(unix)]
+pub use pty::Pty;
+pub use stdio::Stdio;
+pub use tcp::Tcp;
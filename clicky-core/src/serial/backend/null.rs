@@ -0,0 +1,27 @@
+use crate::serial::{SerialBackend, SerialRx, SerialTx};
+
+/// Null serial port. Transmitted data is discarded, and nothing is ever
+/// received.
+#[derive(Debug)]
+pub struct Null {}
+
+impl Null {
+    pub fn new() -> Null {
+        Null {}
+    }
+}
+
+impl Default for Null {
+    fn default() -> Null {
+        Null::new()
+    }
+}
+
+impl SerialBackend for Null {
+    fn split(self: Box<Self>) -> (SerialRx, SerialTx) {
+        (
+            Box::new(futures::io::empty()),
+            Box::new(futures::io::sink()),
+        )
+    }
+}
@@ -0,0 +1,108 @@
+use std::ffi::{CStr, OsStr};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use blocking::Unblock;
+use futures::io::AsyncRead;
+
+use crate::serial::{SerialBackend, SerialRx, SerialTx};
+
+/// Serial port connected to a freshly allocated pseudo-terminal. Use a
+/// terminal program (e.g: `screen`, `picocom`) to connect to the path returned
+/// by [`Pty::path`].
+#[derive(Debug)]
+pub struct Pty {
+    rx: fs::File,
+    tx: fs::File,
+    path: PathBuf,
+    // Reads from the master side of a pty return EIO whenever the slave side
+    // isn't open, so keep a handle around for as long as the Pty is alive.
+    slave: fs::File,
+}
+
+fn cvt(ret: libc::c_int) -> io::Result<libc::c_int> {
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        ret => Ok(ret),
+    }
+}
+
+impl Pty {
+    pub fn new() -> io::Result<Pty> {
+        let master = unsafe {
+            let fd = cvt(libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY))?;
+            fs::File::from_raw_fd(fd)
+        };
+
+        let path = unsafe {
+            cvt(libc::grantpt(master.as_raw_fd()))?;
+            cvt(libc::unlockpt(master.as_raw_fd()))?;
+
+            let name = libc::ptsname(master.as_raw_fd());
+            if name.is_null() {
+                return Err(io::Error::last_os_error());
+            }
+            PathBuf::from(OsStr::from_bytes(CStr::from_ptr(name).to_bytes()))
+        };
+
+        let slave = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+
+        // The guest expects to see bytes exactly as they were sent, so disable
+        // any line editing / echo / newline translation on the host side.
+        unsafe {
+            let mut termios = std::mem::zeroed::<libc::termios>();
+            cvt(libc::tcgetattr(slave.as_raw_fd(), &mut termios))?;
+            libc::cfmakeraw(&mut termios);
+            cvt(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios))?;
+        }
+
+        Ok(Pty {
+            rx: master.try_clone()?,
+            tx: master,
+            path,
+            slave,
+        })
+    }
+
+    /// Path to the slave side of the pseudo-terminal (e.g: `/dev/pts/3`).
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Receiving half of a [Pty], which keeps the slave side open.
+struct PtyRx {
+    master: Unblock<fs::File>,
+    _slave: fs::File,
+}
+
+impl AsyncRead for PtyRx {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        AsyncRead::poll_read(Pin::new(&mut self.master), cx, buf)
+    }
+}
+
+impl SerialBackend for Pty {
+    fn split(self: Box<Self>) -> (SerialRx, SerialTx) {
+        let rx = PtyRx {
+            master: Unblock::new(self.rx),
+            _slave: self.slave,
+        };
+
+        (Box::new(rx), Box::new(Unblock::new(self.tx)))
+    }
+}
@@ -0,0 +1,30 @@
+use std::io;
+
+use blocking::Unblock;
+
+use crate::serial::{SerialBackend, SerialRx, SerialTx};
+
+/// Serial port connected to the host's stdin / stdout.
+#[derive(Debug)]
+pub struct Stdio {}
+
+impl Stdio {
+    pub fn new() -> Stdio {
+        Stdio {}
+    }
+}
+
+impl Default for Stdio {
+    fn default() -> Stdio {
+        Stdio::new()
+    }
+}
+
+impl SerialBackend for Stdio {
+    fn split(self: Box<Self>) -> (SerialRx, SerialTx) {
+        (
+            Box::new(Unblock::new(io::stdin())),
+            Box::new(Unblock::new(io::stdout())),
+        )
+    }
+}
@@ -0,0 +1,33 @@
+use std::io;
+use std::net::TcpStream;
+
+use blocking::Unblock;
+
+use crate::serial::{SerialBackend, SerialRx, SerialTx};
+
+/// Serial port connected to a TCP socket.
+#[derive(Debug)]
+pub struct Tcp {
+    rx: TcpStream,
+    tx: TcpStream,
+}
+
+impl Tcp {
+    pub fn new(stream: TcpStream) -> io::Result<Tcp> {
+        // byte-at-a-time console traffic shouldn't sit around in Nagle's buffer
+        stream.set_nodelay(true)?;
+        Ok(Tcp {
+            rx: stream.try_clone()?,
+            tx: stream,
+        })
+    }
+}
+
+impl SerialBackend for Tcp {
+    fn split(self: Box<Self>) -> (SerialRx, SerialTx) {
+        (
+            Box::new(Unblock::new(self.rx)),
+            Box::new(Unblock::new(self.tx)),
+        )
+    }
+}
@@ -0,0 +1,468 @@
+//! An emulated accessory which speaks Apple's iPod Accessory Protocol (iAP).
+//!
+//! [Iap] is a [SerialBackend] which should be attached to one of the iPod's
+//! serial ports (typically the dock connector). The accessory doesn't do
+//! anything on its own, and is instead driven by an [IapHandle], either
+//! directly from Rust code, or via a simple [script].
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures::future::{self, Either};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::stream::Stream;
+use pin_utils::pin_mut;
+use relativity::Timeout;
+use thiserror::Error;
+
+use crate::serial::{SerialBackend, SerialRx, SerialTx};
+
+mod packet;
+pub mod script;
+
+pub use packet::{lingo, IapDecoder, IapPacket};
+
+/// General lingo commands.
+mod general {
+    pub const IDENTIFY: u16 = 0x01;
+    pub const IPOD_ACK: u16 = 0x02;
+    pub const ENTER_EXTENDED_MODE: u16 = 0x05;
+}
+
+/// Extended Interface lingo commands.
+mod extended {
+    pub const IPOD_ACK: u16 = 0x0001;
+    pub const GET_NUM_DB_RECORDS: u16 = 0x0018;
+    pub const RET_NUM_DB_RECORDS: u16 = 0x0019;
+    pub const GET_DB_RECORDS: u16 = 0x001a;
+    pub const RET_DB_RECORD: u16 = 0x001b;
+    pub const GET_PLAY_STATUS: u16 = 0x001c;
+    pub const RET_PLAY_STATUS: u16 = 0x001d;
+    pub const GET_CURRENT_TRACK: u16 = 0x001e;
+    pub const RET_CURRENT_TRACK: u16 = 0x001f;
+    pub const GET_TRACK_TITLE: u16 = 0x0020;
+    pub const GET_TRACK_ARTIST: u16 = 0x0022;
+    pub const GET_TRACK_ALBUM: u16 = 0x0024;
+    pub const PLAY_CONTROL: u16 = 0x0029;
+}
+
+/// Buttons supported by the Simple Remote lingo.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SimpleRemoteButton {
+    PlayPause = 0,
+    VolumeUp = 1,
+    VolumeDown = 2,
+    NextTrack = 3,
+    PrevTrack = 4,
+    NextAlbum = 5,
+    PrevAlbum = 6,
+    Stop = 7,
+    Play = 8,
+    Pause = 9,
+    MuteToggle = 10,
+}
+
+/// Database categories used by the Extended Interface lingo.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DbCategory {
+    Playlist = 1,
+    Artist = 2,
+    Album = 3,
+    Genre = 4,
+    Track = 5,
+    Composer = 6,
+    Audiobook = 7,
+    Podcast = 8,
+}
+
+/// Commands supported by the Extended Interface `PlayControl` command.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PlayControl {
+    PlayPause = 1,
+    Stop = 2,
+    NextTrack = 3,
+    PrevTrack = 4,
+    StartFastForward = 5,
+    StartRewind = 6,
+    EndFastForwardRewind = 7,
+}
+
+/// Playback status, as reported by the iPod.
+#[derive(Debug, Copy, Clone)]
+pub struct PlayStatus {
+    pub track_len_ms: u32,
+    pub position_ms: u32,
+    /// 0 = stopped, 1 = playing, 2 = paused
+    pub state: u8,
+}
+
+#[derive(Error, Debug)]
+pub enum IapError {
+    #[error("timed out waiting for a response from the iPod")]
+    Timeout,
+    #[error("accessory is no longer connected to the iPod")]
+    Disconnected,
+    #[error("iPod rejected command {cmd:#06x} (status {status:#04x})")]
+    Nack { cmd: u16, status: u8 },
+    #[error("malformed response from the iPod: {0:02x?}")]
+    Malformed(IapPacket),
+}
+
+/// Emulated iAP accessory. See the module-level docs for details.
+#[derive(Debug)]
+pub struct Iap {
+    to_ipod: async_channel::Receiver<Vec<u8>>,
+    from_ipod: async_channel::Sender<IapPacket>,
+}
+
+/// Handle used to drive an [Iap] accessory.
+///
+/// All methods block until the iPod responds (or the handle's timeout elapses).
+#[derive(Debug)]
+pub struct IapHandle {
+    to_ipod: async_channel::Sender<Vec<u8>>,
+    from_ipod: async_channel::Receiver<IapPacket>,
+    timeout: Duration,
+}
+
+impl Iap {
+    pub fn new() -> (Iap, IapHandle) {
+        let (to_ipod_tx, to_ipod_rx) = async_channel::unbounded();
+        let (from_ipod_tx, from_ipod_rx) = async_channel::unbounded();
+
+        let iap = Iap {
+            to_ipod: to_ipod_rx,
+            from_ipod: from_ipod_tx,
+        };
+
+        let handle = IapHandle {
+            to_ipod: to_ipod_tx,
+            from_ipod: from_ipod_rx,
+            timeout: Duration::from_secs(5),
+        };
+
+        (iap, handle)
+    }
+}
+
+impl SerialBackend for Iap {
+    fn split(self: Box<Self>) -> (SerialRx, SerialTx) {
+        let rx = IapRx {
+            to_ipod: self.to_ipod,
+            buf: Vec::new(),
+            pos: 0,
+        };
+        let tx = IapTx {
+            from_ipod: self.from_ipod,
+            decoder: IapDecoder::new(),
+        };
+
+        (Box::new(rx), Box::new(tx))
+    }
+}
+
+/// Data flowing from the accessory into the iPod.
+struct IapRx {
+    to_ipod: async_channel::Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl AsyncRead for IapRx {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        while self.pos == self.buf.len() {
+            match Pin::new(&mut self.to_ipod).poll_next(cx) {
+                Poll::Pending => return Poll::Pending,
+                // the handle was dropped
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Ready(Some(data)) => {
+                    self.buf = data;
+                    self.pos = 0;
+                }
+            }
+        }
+
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..][..n]);
+        self.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Data flowing from the iPod into the accessory.
+struct IapTx {
+    from_ipod: async_channel::Sender<IapPacket>,
+    decoder: IapDecoder,
+}
+
+impl AsyncWrite for IapTx {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        for packet in self.decoder.feed(buf) {
+            debug!("iAP: iPod -> accessory: {:02x?}", packet);
+            // nobody might be listening, which is fine
+            let _ = self.from_ipod.try_send(packet);
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn be_u32(data: &[u8]) -> Option<u32> {
+    let bytes = data.get(..4)?;
+    Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn c_string(data: &[u8]) -> String {
+    let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+impl IapHandle {
+    /// Set how long to wait for responses from the iPod.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Send a raw packet to the iPod.
+    pub fn send(&self, packet: IapPacket) -> Result<(), IapError> {
+        debug!("iAP: accessory -> iPod: {:02x?}", packet);
+        self.to_ipod
+            .try_send(packet.encode())
+            .map_err(|_| IapError::Disconnected)
+    }
+
+    /// Wait for the next packet from the iPod.
+    pub fn recv(&self) -> Result<IapPacket, IapError> {
+        futures_executor::block_on(async {
+            let recv_fut = self.from_ipod.recv();
+            pin_mut!(recv_fut);
+
+            match future::select(recv_fut, Timeout::new(self.timeout)).await {
+                Either::Left((Ok(packet), _)) => Ok(packet),
+                Either::Left((Err(_), _)) => Err(IapError::Disconnected),
+                Either::Right(_) => Err(IapError::Timeout),
+            }
+        })
+    }
+
+    /// Wait for a packet with the given lingo and command, discarding any
+    /// other packets received in the meantime.
+    pub fn expect(&self, lingo: u8, cmd: u16) -> Result<IapPacket, IapError> {
+        loop {
+            let packet = self.recv()?;
+            if packet.lingo == lingo && packet.cmd == cmd {
+                return Ok(packet);
+            }
+            debug!("iAP: ignoring unexpected packet: {:02x?}", packet);
+        }
+    }
+
+    /// Identify as an accessory which speaks the given lingo.
+    pub fn identify(&self, lingo: u8) -> Result<(), IapError> {
+        self.send(IapPacket::new(lingo::GENERAL, general::IDENTIFY, [lingo]))
+    }
+
+    /// Press (and hold) the given set of Simple Remote buttons.
+    pub fn press(&self, buttons: &[SimpleRemoteButton]) -> Result<(), IapError> {
+        let mut mask = Vec::new();
+        for &button in buttons {
+            let bit = button as usize;
+            if mask.len() <= bit / 8 {
+                mask.resize(bit / 8 + 1, 0);
+            }
+            mask[bit / 8] |= 1 << (bit % 8);
+        }
+
+        self.send(IapPacket::new(lingo::SIMPLE_REMOTE, 0x00, mask))
+    }
+
+    /// Release all Simple Remote buttons.
+    pub fn release(&self) -> Result<(), IapError> {
+        self.send(IapPacket::new(lingo::SIMPLE_REMOTE, 0x00, [0]))
+    }
+
+    /// Switch the iPod into Extended Interface mode.
+    pub fn enter_extended_mode(&self) -> Result<(), IapError> {
+        self.send(IapPacket::new(
+            lingo::GENERAL,
+            general::ENTER_EXTENDED_MODE,
+            [],
+        ))?;
+
+        let ack = self.expect(lingo::GENERAL, general::IPOD_ACK)?;
+        match ack.data.as_slice() {
+            [0, _] => Ok(()),
+            [status, _] => Err(IapError::Nack {
+                cmd: general::ENTER_EXTENDED_MODE,
+                status: *status,
+            }),
+            _ => Err(IapError::Malformed(ack)),
+        }
+    }
+
+    /// Send an Extended Interface command, and wait for a response with the
+    /// given command ID (or an ACK, if `resp_cmd` is `None`).
+    pub fn ext_request(
+        &self,
+        cmd: u16,
+        data: impl Into<Vec<u8>>,
+        resp_cmd: Option<u16>,
+    ) -> Result<IapPacket, IapError> {
+        self.send(IapPacket::new(lingo::EXTENDED_INTERFACE, cmd, data))?;
+
+        loop {
+            let packet = self.recv()?;
+            if packet.lingo != lingo::EXTENDED_INTERFACE {
+                debug!("iAP: ignoring unexpected packet: {:02x?}", packet);
+                continue;
+            }
+
+            if packet.cmd == extended::IPOD_ACK {
+                let (status, acked_cmd) = match packet.data.as_slice() {
+                    [status, hi, lo, ..] => (*status, u16::from_be_bytes([*hi, *lo])),
+                    _ => return Err(IapError::Malformed(packet)),
+                };
+
+                if acked_cmd != cmd {
+                    continue;
+                }
+
+                if status != 0 {
+                    return Err(IapError::Nack { cmd, status });
+                }
+
+                if resp_cmd.is_none() {
+                    return Ok(packet);
+                }
+                continue;
+            }
+
+            if Some(packet.cmd) == resp_cmd {
+                return Ok(packet);
+            }
+
+            debug!("iAP: ignoring unexpected packet: {:02x?}", packet);
+        }
+    }
+
+    /// Query the number of database records in a particular category.
+    pub fn num_db_records(&self, category: DbCategory) -> Result<u32, IapError> {
+        let resp = self.ext_request(
+            extended::GET_NUM_DB_RECORDS,
+            [category as u8],
+            Some(extended::RET_NUM_DB_RECORDS),
+        )?;
+        be_u32(&resp.data).ok_or(IapError::Malformed(resp))
+    }
+
+    /// Retrieve a range of database records from a particular category,
+    /// returning `(index, name)` pairs.
+    pub fn db_records(
+        &self,
+        category: DbCategory,
+        start: u32,
+        count: u32,
+    ) -> Result<Vec<(u32, String)>, IapError> {
+        let mut req = vec![category as u8];
+        req.extend_from_slice(&start.to_be_bytes());
+        req.extend_from_slice(&count.to_be_bytes());
+
+        let mut records = Vec::new();
+        if count == 0 {
+            return Ok(records);
+        }
+
+        // the iPod sends back a separate packet for each record
+        let mut packet =
+            self.ext_request(extended::GET_DB_RECORDS, req, Some(extended::RET_DB_RECORD))?;
+        loop {
+            match be_u32(&packet.data) {
+                Some(idx) => records.push((idx, c_string(&packet.data[4..]))),
+                None => return Err(IapError::Malformed(packet)),
+            }
+
+            if records.len() == count as usize {
+                break;
+            }
+
+            packet = self.expect(lingo::EXTENDED_INTERFACE, extended::RET_DB_RECORD)?;
+        }
+
+        Ok(records)
+    }
+
+    /// Query the current playback status.
+    pub fn play_status(&self) -> Result<PlayStatus, IapError> {
+        let resp = self.ext_request(
+            extended::GET_PLAY_STATUS,
+            [],
+            Some(extended::RET_PLAY_STATUS),
+        )?;
+
+        match (
+            be_u32(&resp.data),
+            resp.data.get(4..).and_then(be_u32),
+            resp.data.get(8),
+        ) {
+            (Some(track_len_ms), Some(position_ms), Some(&state)) => Ok(PlayStatus {
+                track_len_ms,
+                position_ms,
+                state,
+            }),
+            _ => Err(IapError::Malformed(resp)),
+        }
+    }
+
+    /// Query the index of the currently playing track.
+    pub fn current_track_index(&self) -> Result<u32, IapError> {
+        let resp = self.ext_request(
+            extended::GET_CURRENT_TRACK,
+            [],
+            Some(extended::RET_CURRENT_TRACK),
+        )?;
+        be_u32(&resp.data).ok_or(IapError::Malformed(resp))
+    }
+
+    fn track_string(&self, cmd: u16, idx: u32) -> Result<String, IapError> {
+        let resp = self.ext_request(cmd, idx.to_be_bytes(), Some(cmd + 1))?;
+        Ok(c_string(&resp.data))
+    }
+
+    /// Query the title of a track in the current playlist.
+    pub fn track_title(&self, idx: u32) -> Result<String, IapError> {
+        self.track_string(extended::GET_TRACK_TITLE, idx)
+    }
+
+    /// Query the artist of a track in the current playlist.
+    pub fn track_artist(&self, idx: u32) -> Result<String, IapError> {
+        self.track_string(extended::GET_TRACK_ARTIST, idx)
+    }
+
+    /// Query the album of a track in the current playlist.
+    pub fn track_album(&self, idx: u32) -> Result<String, IapError> {
+        self.track_string(extended::GET_TRACK_ALBUM, idx)
+    }
+
+    /// Control playback.
+    pub fn play_control(&self, control: PlayControl) -> Result<(), IapError> {
+        self.ext_request(extended::PLAY_CONTROL, [control as u8], None)?;
+        Ok(())
+    }
+}
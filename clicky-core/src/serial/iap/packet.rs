@@ -0,0 +1,223 @@
+//! iAP packet framing.
+
+/// Lingo IDs.
+pub mod lingo {
+    pub const GENERAL: u8 = 0x00;
+    pub const SIMPLE_REMOTE: u8 = 0x02;
+    pub const EXTENDED_INTERFACE: u8 = 0x04;
+}
+
+/// Packets start with a sync byte, followed by a start-of-packet byte.
+const SYNC: u8 = 0xff;
+const SOP: u8 = 0x55;
+
+/// A single iAP packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IapPacket {
+    pub lingo: u8,
+    /// Command ID. Only the Extended Interface lingo uses 16 bit commands.
+    pub cmd: u16,
+    pub data: Vec<u8>,
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes
+        .iter()
+        .fold(0u8, |sum, b| sum.wrapping_add(*b))
+        .wrapping_neg()
+}
+
+impl IapPacket {
+    pub fn new(lingo: u8, cmd: u16, data: impl Into<Vec<u8>>) -> IapPacket {
+        IapPacket {
+            lingo,
+            cmd,
+            data: data.into(),
+        }
+    }
+
+    fn has_wide_cmd(lingo: u8) -> bool {
+        lingo == lingo::EXTENDED_INTERFACE
+    }
+
+    /// Serialize the packet (including sync bytes and checksum).
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![self.lingo];
+        if IapPacket::has_wide_cmd(self.lingo) {
+            payload.extend_from_slice(&self.cmd.to_be_bytes());
+        } else {
+            payload.push(self.cmd as u8);
+        }
+        payload.extend_from_slice(&self.data);
+
+        let mut packet = vec![SYNC, SOP];
+        if payload.len() < 0x100 {
+            packet.push(payload.len() as u8);
+        } else {
+            // "large" packet
+            packet.push(0);
+            packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        }
+        packet.extend_from_slice(&payload);
+        packet.push(checksum(&packet[2..]));
+        packet
+    }
+
+    fn from_payload(payload: &[u8]) -> Option<IapPacket> {
+        let (&lingo, payload) = payload.split_first()?;
+        let (cmd, data) = if IapPacket::has_wide_cmd(lingo) {
+            if payload.len() < 2 {
+                return None;
+            }
+            let (cmd, data) = payload.split_at(2);
+            (u16::from_be_bytes([cmd[0], cmd[1]]), data)
+        } else {
+            let (&cmd, data) = payload.split_first()?;
+            (cmd as u16, data)
+        };
+
+        Some(IapPacket::new(lingo, cmd, data))
+    }
+}
+
+/// Incrementally decodes a stream of bytes into iAP packets.
+#[derive(Debug, Default)]
+pub struct IapDecoder {
+    buf: Vec<u8>,
+}
+
+impl IapDecoder {
+    pub fn new() -> IapDecoder {
+        IapDecoder { buf: Vec::new() }
+    }
+
+    /// Feed some bytes into the decoder, returning any packets which were
+    /// completed as a result.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<IapPacket> {
+        self.buf.extend_from_slice(data);
+
+        let mut packets = Vec::new();
+        loop {
+            // resync on the start of the next packet
+            match self.buf.windows(2).position(|w| w == [SYNC, SOP]) {
+                Some(0) => {}
+                Some(n) => drop(self.buf.drain(..n)),
+                None => {
+                    // keep a trailing sync byte around, as the SOP byte might
+                    // be in the next chunk
+                    let keep = (self.buf.last() == Some(&SYNC)) as usize;
+                    self.buf.drain(..self.buf.len() - keep);
+                    return packets;
+                }
+            }
+
+            let (hdr_len, len) = match self.buf.get(2) {
+                None => return packets,
+                Some(0) => match self.buf.get(3..5) {
+                    None => return packets,
+                    Some(len) => (5, u16::from_be_bytes([len[0], len[1]]) as usize),
+                },
+                Some(&len) => (3, len as usize),
+            };
+
+            let total_len = hdr_len + len + 1;
+            if self.buf.len() < total_len {
+                return packets;
+            }
+
+            let packet = &self.buf[..total_len];
+            let packet = if checksum(&packet[2..]) != 0 {
+                warn!("iAP: dropping packet with bad checksum: {:02x?}", packet);
+                None
+            } else {
+                IapPacket::from_payload(&packet[hdr_len..total_len - 1])
+            };
+
+            match packet {
+                Some(packet) => {
+                    self.buf.drain(..total_len);
+                    packets.push(packet);
+                }
+                None => {
+                    // skip past the bogus sync bytes and try again
+                    self.buf.drain(..2);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_small() {
+        // "Identify: Extended Interface", as sent by most docks
+        let packet = IapPacket::new(lingo::GENERAL, 0x01, vec![0x04]);
+        assert_eq!(packet.encode(), [0xff, 0x55, 0x03, 0x00, 0x01, 0x04, 0xf8]);
+
+        // Extended Interface packets use 16-bit commands
+        let packet = IapPacket::new(lingo::EXTENDED_INTERFACE, 0x0029, vec![0x01]);
+        assert_eq!(
+            packet.encode(),
+            [0xff, 0x55, 0x04, 0x04, 0x00, 0x29, 0x01, 0xce]
+        );
+    }
+
+    #[test]
+    fn encode_large() {
+        let packet = IapPacket::new(lingo::EXTENDED_INTERFACE, 0x001b, vec![0xaa; 0x200]);
+        let bytes = packet.encode();
+
+        // lingo + 2 byte command + data
+        let len = 1 + 2 + 0x200;
+        assert_eq!(bytes[..5], [0xff, 0x55, 0x00, (len >> 8) as u8, len as u8]);
+        assert_eq!(bytes.len(), 5 + len + 1);
+        assert_eq!(checksum(&bytes[2..]), 0);
+    }
+
+    #[test]
+    fn decode_roundtrip() {
+        let packets = vec![
+            IapPacket::new(lingo::GENERAL, 0x02, vec![0x00, 0x01]),
+            IapPacket::new(lingo::SIMPLE_REMOTE, 0x00, vec![]),
+            IapPacket::new(lingo::EXTENDED_INTERFACE, 0x001b, vec![0x55; 0x123]),
+        ];
+
+        let bytes = packets
+            .iter()
+            .flat_map(IapPacket::encode)
+            .collect::<Vec<_>>();
+        assert_eq!(IapDecoder::new().feed(&bytes), packets);
+    }
+
+    #[test]
+    fn decode_split() {
+        let packet = IapPacket::new(lingo::EXTENDED_INTERFACE, 0x0019, vec![0, 0, 0, 42]);
+        let bytes = packet.encode();
+
+        let mut decoder = IapDecoder::new();
+        let mut decoded = Vec::new();
+        for b in &bytes {
+            decoded.extend(decoder.feed(&[*b]));
+        }
+        assert_eq!(decoded, vec![packet]);
+    }
+
+    #[test]
+    fn decode_resync() {
+        let packet = IapPacket::new(lingo::GENERAL, 0x01, vec![0x02]);
+
+        // leading garbage (including a stray sync byte)
+        let mut bytes = vec![0x12, 0xff, 0x34];
+        // a packet with a bad checksum
+        let mut bad = packet.encode();
+        *bad.last_mut().unwrap() ^= 0xff;
+        bytes.extend(bad);
+        // a valid packet
+        bytes.extend(packet.encode());
+
+        assert_eq!(IapDecoder::new().feed(&bytes), vec![packet]);
+    }
+}
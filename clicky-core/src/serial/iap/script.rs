@@ -0,0 +1,336 @@
+//! A simple line-based scripting language for driving an [IapHandle].
+//!
+//! Each line contains a single command. Blank lines and anything following a
+//! `#` are ignored. Numbers can be written in decimal or hex (`0x` prefix).
+//!
+//! | Command                              | Description                                   |
+//! | ------------------------------------ | --------------------------------------------- |
+//! | `wait <ms>`                          | Sleep for the given number of milliseconds    |
+//! | `timeout <ms>`                       | Set how long to wait for iPod responses       |
+//! | `identify <lingo>`                   | Identify as an accessory using `lingo`        |
+//! | `send <lingo> <cmd> [<byte>...]`     | Send a raw packet                             |
+//! | `expect <lingo> <cmd>`               | Wait for a particular packet from the iPod    |
+//! | `press <button>...`                  | Press (and hold) Simple Remote buttons        |
+//! | `release`                            | Release all Simple Remote buttons             |
+//! | `tap <button> [<ms>]`                | Press and release a button (default: 100ms)   |
+//! | `extended-mode`                      | Enter Extended Interface mode                 |
+//! | `num-records <category>`             | Query the number of DB records in a category  |
+//! | `records <category> <start> <count>` | Retrieve DB records from a category           |
+//! | `play-status`                        | Query the current playback status             |
+//! | `current-track`                      | Query the current track index                 |
+//! | `track-info <idx>`                   | Query the title/artist/album of a track       |
+//! | `play-control <control>`             | Control playback                              |
+//!
+//! Buttons: `play-pause`, `volume-up`, `volume-down`, `next-track`,
+//! `prev-track`, `next-album`, `prev-album`, `stop`, `play`, `pause`, `mute`
+//!
+//! Categories: `playlist`, `artist`, `album`, `genre`, `track`, `composer`,
+//! `audiobook`, `podcast`
+//!
+//! Play controls: `play-pause`, `stop`, `next-track`, `prev-track`,
+//! `start-ff`, `start-rew`, `end-ff-rew`
+//!
+//! Query results are reported via the `log` crate, under the `iAP` target.
+
+use std::time::Duration;
+
+use thiserror::Error;
+
+use super::{DbCategory, IapError, IapHandle, IapPacket, PlayControl, SimpleRemoteButton};
+
+#[derive(Error, Debug)]
+pub enum IapScriptError {
+    #[error("line {line}: {msg}")]
+    Parse { line: usize, msg: String },
+    #[error("line {line}: {source}")]
+    Iap { line: usize, source: IapError },
+}
+
+fn parse_num<T: std::convert::TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let val = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse::<u64>(),
+    }
+    .map_err(|_| format!("invalid number `{}`", s))?;
+
+    T::try_from(val).map_err(|_| format!("number out of range `{}`", s))
+}
+
+fn parse_button(s: &str) -> Result<SimpleRemoteButton, String> {
+    use SimpleRemoteButton::*;
+    Ok(match s {
+        "play-pause" => PlayPause,
+        "volume-up" => VolumeUp,
+        "volume-down" => VolumeDown,
+        "next-track" => NextTrack,
+        "prev-track" => PrevTrack,
+        "next-album" => NextAlbum,
+        "prev-album" => PrevAlbum,
+        "stop" => Stop,
+        "play" => Play,
+        "pause" => Pause,
+        "mute" => MuteToggle,
+        _ => return Err(format!("unknown button `{}`", s)),
+    })
+}
+
+fn parse_category(s: &str) -> Result<DbCategory, String> {
+    use DbCategory::*;
+    Ok(match s {
+        "playlist" => Playlist,
+        "artist" => Artist,
+        "album" => Album,
+        "genre" => Genre,
+        "track" => Track,
+        "composer" => Composer,
+        "audiobook" => Audiobook,
+        "podcast" => Podcast,
+        _ => return Err(format!("unknown category `{}`", s)),
+    })
+}
+
+fn parse_play_control(s: &str) -> Result<PlayControl, String> {
+    use PlayControl::*;
+    Ok(match s {
+        "play-pause" => PlayPause,
+        "stop" => Stop,
+        "next-track" => NextTrack,
+        "prev-track" => PrevTrack,
+        "start-ff" => StartFastForward,
+        "start-rew" => StartRewind,
+        "end-ff-rew" => EndFastForwardRewind,
+        _ => return Err(format!("unknown play control `{}`", s)),
+    })
+}
+
+/// A parsed script command.
+#[derive(Debug)]
+enum Cmd {
+    Wait(Duration),
+    Timeout(Duration),
+    Identify(u8),
+    Send(IapPacket),
+    Expect(u8, u16),
+    Press(Vec<SimpleRemoteButton>),
+    Release,
+    Tap(SimpleRemoteButton, Duration),
+    ExtendedMode,
+    NumRecords(DbCategory),
+    Records(DbCategory, u32, u32),
+    PlayStatus,
+    CurrentTrack,
+    TrackInfo(u32),
+    PlayControl(PlayControl),
+}
+
+fn parse_line(line: &str) -> Result<Option<Cmd>, String> {
+    let line = line.split('#').next().unwrap();
+    let mut args = line.split_whitespace();
+    let cmd = match args.next() {
+        Some(cmd) => cmd,
+        None => return Ok(None),
+    };
+    let args = args.collect::<Vec<_>>();
+
+    let expect_args = |n: usize| {
+        if args.len() != n {
+            Err(format!("`{}` expects {} argument(s)", cmd, n))
+        } else {
+            Ok(())
+        }
+    };
+
+    let cmd = match cmd {
+        "wait" => {
+            expect_args(1)?;
+            Cmd::Wait(Duration::from_millis(parse_num(args[0])?))
+        }
+        "timeout" => {
+            expect_args(1)?;
+            Cmd::Timeout(Duration::from_millis(parse_num(args[0])?))
+        }
+        "identify" => {
+            expect_args(1)?;
+            Cmd::Identify(parse_num(args[0])?)
+        }
+        "send" => {
+            if args.len() < 2 {
+                return Err("`send` expects at least 2 arguments".into());
+            }
+            let data = args[2..]
+                .iter()
+                .map(|b| parse_num(b))
+                .collect::<Result<Vec<u8>, _>>()?;
+            Cmd::Send(IapPacket::new(
+                parse_num(args[0])?,
+                parse_num(args[1])?,
+                data,
+            ))
+        }
+        "expect" => {
+            expect_args(2)?;
+            Cmd::Expect(parse_num(args[0])?, parse_num(args[1])?)
+        }
+        "press" => {
+            if args.is_empty() {
+                return Err("`press` expects at least 1 argument".into());
+            }
+            let buttons = args
+                .iter()
+                .map(|b| parse_button(b))
+                .collect::<Result<Vec<_>, _>>()?;
+            Cmd::Press(buttons)
+        }
+        "release" => {
+            expect_args(0)?;
+            Cmd::Release
+        }
+        "tap" => {
+            let duration = match args.len() {
+                1 => Duration::from_millis(100),
+                2 => Duration::from_millis(parse_num(args[1])?),
+                _ => return Err("`tap` expects 1 or 2 arguments".into()),
+            };
+            Cmd::Tap(parse_button(args[0])?, duration)
+        }
+        "extended-mode" => {
+            expect_args(0)?;
+            Cmd::ExtendedMode
+        }
+        "num-records" => {
+            expect_args(1)?;
+            Cmd::NumRecords(parse_category(args[0])?)
+        }
+        "records" => {
+            expect_args(3)?;
+            Cmd::Records(
+                parse_category(args[0])?,
+                parse_num(args[1])?,
+                parse_num(args[2])?,
+            )
+        }
+        "play-status" => {
+            expect_args(0)?;
+            Cmd::PlayStatus
+        }
+        "current-track" => {
+            expect_args(0)?;
+            Cmd::CurrentTrack
+        }
+        "track-info" => {
+            expect_args(1)?;
+            Cmd::TrackInfo(parse_num(args[0])?)
+        }
+        "play-control" => {
+            expect_args(1)?;
+            Cmd::PlayControl(parse_play_control(args[0])?)
+        }
+        _ => return Err(format!("unknown command `{}`", cmd)),
+    };
+
+    Ok(Some(cmd))
+}
+
+fn run_cmd(handle: &mut IapHandle, cmd: Cmd) -> Result<(), IapError> {
+    match cmd {
+        Cmd::Wait(duration) => std::thread::sleep(duration),
+        Cmd::Timeout(duration) => handle.set_timeout(duration),
+        Cmd::Identify(lingo) => handle.identify(lingo)?,
+        Cmd::Send(packet) => handle.send(packet)?,
+        Cmd::Expect(lingo, cmd) => {
+            let packet = handle.expect(lingo, cmd)?;
+            info!(target: "iAP", "got {:02x?}", packet);
+        }
+        Cmd::Press(buttons) => handle.press(&buttons)?,
+        Cmd::Release => handle.release()?,
+        Cmd::Tap(button, duration) => {
+            handle.press(&[button])?;
+            std::thread::sleep(duration);
+            handle.release()?;
+        }
+        Cmd::ExtendedMode => handle.enter_extended_mode()?,
+        Cmd::NumRecords(category) => {
+            let num = handle.num_db_records(category)?;
+            info!(target: "iAP", "{:?} records: {}", category, num);
+        }
+        Cmd::Records(category, start, count) => {
+            for (idx, name) in handle.db_records(category, start, count)? {
+                info!(target: "iAP", "{:?} {}: {}", category, idx, name);
+            }
+        }
+        Cmd::PlayStatus => {
+            let status = handle.play_status()?;
+            info!(target: "iAP", "{:?}", status);
+        }
+        Cmd::CurrentTrack => {
+            let idx = handle.current_track_index()?;
+            info!(target: "iAP", "current track: {}", idx);
+        }
+        Cmd::TrackInfo(idx) => {
+            let title = handle.track_title(idx)?;
+            let artist = handle.track_artist(idx)?;
+            let album = handle.track_album(idx)?;
+            info!(target: "iAP", "track {}: {} - {} ({})", idx, artist, title, album);
+        }
+        Cmd::PlayControl(control) => handle.play_control(control)?,
+    }
+
+    Ok(())
+}
+
+/// Run a script, stopping at the first error.
+///
+/// The entire script is parsed before any commands are executed.
+pub fn run_script(handle: &mut IapHandle, script: &str) -> Result<(), IapScriptError> {
+    let mut cmds = Vec::new();
+    for (i, line) in script.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(cmd)) => cmds.push((i + 1, cmd)),
+            Ok(None) => {}
+            Err(msg) => return Err(IapScriptError::Parse { line: i + 1, msg }),
+        }
+    }
+
+    for (line, cmd) in cmds {
+        run_cmd(handle, cmd).map_err(|source| IapScriptError::Iap { line, source })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_commands() {
+        assert!(matches!(parse_line("  # just a comment"), Ok(None)));
+        assert!(matches!(
+            parse_line("wait 0x10 # comment"),
+            Ok(Some(Cmd::Wait(d))) if d == Duration::from_millis(16)
+        ));
+        assert!(matches!(
+            parse_line("tap play-pause"),
+            Ok(Some(Cmd::Tap(SimpleRemoteButton::PlayPause, d))) if d == Duration::from_millis(100)
+        ));
+        assert!(matches!(
+            parse_line("records album 0 10"),
+            Ok(Some(Cmd::Records(DbCategory::Album, 0, 10)))
+        ));
+
+        match parse_line("send 4 0x29 1") {
+            Ok(Some(Cmd::Send(packet))) => {
+                assert_eq!(packet, IapPacket::new(4, 0x29, vec![1]))
+            }
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(parse_line("wait").is_err());
+        assert!(parse_line("identify 0x100").is_err());
+        assert!(parse_line("press bogus").is_err());
+        assert!(parse_line("frobnicate").is_err());
+    }
+}
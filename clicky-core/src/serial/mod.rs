@@ -0,0 +1,23 @@
+//! Serial port interface and host backend implementations.
+
+use std::fmt::Debug;
+
+use futures::io::{AsyncRead, AsyncWrite};
+
+pub mod backend;
+pub mod iap;
+
+/// Receiving half of a [SerialBackend] (i.e: data coming from the host).
+pub type SerialRx = Box<dyn AsyncRead + Send + Unpin>;
+
+/// Transmitting half of a [SerialBackend] (i.e: data going to the host).
+pub type SerialTx = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// Abstraction over different host-side serial port backends.
+pub trait SerialBackend: Send + Debug {
+    /// Split the backend into independent receive and transmit halves.
+    ///
+    /// Reads from the host can block indefinitely (e.g: waiting on a user to
+    /// type something), so each half is driven by a separate task.
+    fn split(self: Box<Self>) -> (SerialRx, SerialTx);
+}
@@ -95,6 +95,13 @@ impl Trigger {
     pub fn clear(&self) {
         self.trigger.store(false, Ordering::SeqCst)
     }
+
+    /// Sets the trigger, regardless of the `TriggerKind` (e.g: to notify of a
+    /// change that isn't reflected by a signal level).
+    #[inline]
+    pub fn set(&self) {
+        self.trigger.store(true, Ordering::SeqCst)
+    }
 }
 
 /// The receiving side of a signal line. Able to query the signal level, but not
@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::signal::gpio;
+
+use super::controls::Ipod4gInput;
+use super::devices::i2c::{PcfPower, PowerSource};
+use super::devices::{GpioBlock, InterruptTrigger};
+
+impl Ipod4gInput {
+    /// Every accessory detect input.
+    pub const ALL: [Ipod4gInput; 4] = [
+        Ipod4gInput::Headphones,
+        Ipod4gInput::Dock,
+        Ipod4gInput::Remote,
+        Ipod4gInput::LineIn,
+    ];
+
+    /// The GPIO pin (in the A-D block) the input's detect line is wired to, and
+    /// whether the line is active-high.
+    fn gpio(self) -> (usize, bool) {
+        match self {
+            // rockbox: `headphones_inserted() { return (GPIOA_INPUT_VAL & 0x80) ? true : false; }`
+            Ipod4gInput::Headphones => (7, true),
+            // rockbox: `/* C2 is firewire power */ return (GPIOC_INPUT_VAL & 0x04) ? false : true;`
+            Ipod4gInput::Dock => (16 + 2, false),
+            // XXX: unconfirmed, none of the available references (Rockbox,
+            // ipodloader, ipodloader2) detect the remote via GPIO. The remote
+            // connector sits right next to the headphone jack, and A6 is the
+            // only other free pin on port A.
+            Ipod4gInput::Remote => (6, false),
+            // XXX: unconfirmed, for the same reason. Line-in is only available
+            // through the dock connector, so it's presumably detected on port D.
+            Ipod4gInput::LineIn => (24 + 5, false),
+        }
+    }
+
+    /// How the input's detect line triggers GPIO interrupts.
+    fn trigger(self) -> InterruptTrigger {
+        match self {
+            // plugging in / unplugging the dock interrupts once, instead of for
+            // as long as the dock stays (dis)connected
+            Ipod4gInput::Dock => InterruptTrigger::Edge,
+            _ => InterruptTrigger::Level,
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Ipod4gInput::Headphones => "Headphones",
+            Ipod4gInput::Dock => "Dock",
+            Ipod4gInput::Remote => "Remote",
+            Ipod4gInput::LineIn => "Line-In",
+        }
+    }
+}
+
+/// Handle to the Ipod4g's accessory detect lines.
+///
+/// Each accessory is wired to a GPIO pin (with the appropriate polarity), so
+/// plugging / unplugging it fires the usual GPIO interrupts. Plugging in the
+/// dock also supplies FireWire power to the PMU.
+#[derive(Debug, Clone)]
+pub struct Accessories {
+    lines: Arc<Mutex<HashMap<Ipod4gInput, gpio::Sender>>>,
+    power: PcfPower,
+}
+
+impl Accessories {
+    pub(super) fn new(
+        gpio_changed: gpio::Changed,
+        gpio_abcd: &mut GpioBlock,
+        power: PcfPower,
+    ) -> Accessories {
+        let mut lines = HashMap::new();
+        for &input in Ipod4gInput::ALL.iter() {
+            let (idx, active_high) = input.gpio();
+            let (mut tx, rx) = gpio::new(gpio_changed.clone(), input.label());
+            gpio_abcd
+                .register_in(idx, rx)
+                .set_trigger(idx, input.trigger());
+
+            // everything starts off unplugged
+            if !active_high {
+                tx.set_high();
+            }
+            lines.insert(input, tx);
+        }
+
+        Accessories {
+            lines: Arc::new(Mutex::new(lines)),
+            power,
+        }
+    }
+
+    /// Plug / unplug an accessory.
+    pub fn set_connected(&self, input: Ipod4gInput, connected: bool) {
+        let (_, active_high) = input.gpio();
+        {
+            let mut lines = self.lines.lock().unwrap();
+            let line = lines.get_mut(&input).expect("all inputs are registered");
+            match connected == active_high {
+                true => line.set_high(),
+                false => line.set_low(),
+            }
+        }
+
+        if input == Ipod4gInput::Dock {
+            self.power.set_power(PowerSource::FireWire, connected);
+        }
+    }
+
+    /// Check if an accessory is plugged in.
+    pub fn is_connected(&self, input: Ipod4gInput) -> bool {
+        let (_, active_high) = input.gpio();
+        let lines = self.lines.lock().unwrap();
+        lines[&input].is_set_high() == active_high
+    }
+
+    /// Plug in an accessory if it's unplugged, and vice versa.
+    pub fn toggle(&self, input: Ipod4gInput) {
+        self.set_connected(input, !self.is_connected(input))
+    }
+}
@@ -0,0 +1,48 @@
+use crate::signal::gpio;
+
+use super::devices::PwmChannel;
+
+/// Handle to the Ipod4g's LCD backlight.
+///
+/// The backlight is switched on / off using GPIO B3, and dimmed using PWM
+/// channel 1 (when enabled).
+#[derive(Debug, Clone)]
+pub struct Backlight {
+    enable: gpio::Reciever,
+    pwm: PwmChannel,
+}
+
+impl Backlight {
+    pub(super) fn new(enable: gpio::Reciever, pwm: PwmChannel) -> Backlight {
+        Backlight { enable, pwm }
+    }
+
+    /// Return the backlight's brightness (between 0.0 and 1.0).
+    pub fn brightness(&self) -> f32 {
+        if !self.enable.is_high() {
+            return 0.0;
+        }
+
+        // firmware which doesn't bother with PWM gets full brightness
+        self.pwm.duty_cycle().unwrap_or(1.0)
+    }
+
+    /// Check if the backlight is on.
+    pub fn is_on(&self) -> bool {
+        self.brightness() > 0.0
+    }
+
+    /// Apply the backlight to an ARGB framebuffer.
+    ///
+    /// An unlit LCD is quite a bit dimmer than a lit one.
+    pub(super) fn apply(&self, buf: &mut [u32]) {
+        const UNLIT: f32 = 0.55;
+
+        let scale = UNLIT + (1.0 - UNLIT) * self.brightness();
+        for px in buf.iter_mut() {
+            let [a, r, g, b] = px.to_be_bytes();
+            let dim = |c: u8| (c as f32 * scale) as u8;
+            *px = u32::from_be_bytes([a, dim(r), dim(g), dim(b)]);
+        }
+    }
+}
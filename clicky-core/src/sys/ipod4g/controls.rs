@@ -3,7 +3,7 @@ use super::{Ipod4g, Ipod4gControls};
 use std::collections::HashMap;
 
 use crate::devices::platform::pp::Controls;
-use crate::gui::{ButtonCallback, ScrollCallback, TakeControls};
+use crate::gui::{ButtonCallback, DragCallback, ScrollCallback, TakeControls};
 
 #[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
 pub enum Ipod4gKey {
@@ -15,10 +15,54 @@ pub enum Ipod4gKey {
     Hold,
 }
 
+/// Accessories which can be plugged into the iPod.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum Ipod4gInput {
+    Headphones,
+    /// Anything plugged into the dock connector which supplies FireWire power
+    /// (e.g: a dock, or a FireWire cable).
+    Dock,
+    Remote,
+    LineIn,
+}
+
 #[derive(Default)]
 pub struct Ipod4gBinds {
     pub keys: HashMap<Ipod4gKey, ButtonCallback>,
+    /// Each press plugs the accessory in if it's unplugged, and vice versa.
+    pub inputs: HashMap<Ipod4gInput, ButtonCallback>,
     pub wheel: Option<ScrollCallback>,
+    /// Drag the pointer around the center of the screen to run a finger
+    /// around the clickwheel.
+    pub wheel_drag: Option<DragCallback>,
+}
+
+impl Ipod4g {
+    /// Press / release keys before the frontend has taken the controls (e.g:
+    /// to emulate keys being held down at boot).
+    pub(super) fn set_keys_held(&mut self, keys: &[Ipod4gKey], held: bool) {
+        let controls = match &mut self.controls {
+            Some(controls) => &mut controls.controls,
+            None => return,
+        };
+
+        for key in keys {
+            let signal = match key {
+                Ipod4gKey::Up => &mut controls.up,
+                Ipod4gKey::Down => &mut controls.down,
+                Ipod4gKey::Left => &mut controls.left,
+                Ipod4gKey::Right => &mut controls.right,
+                Ipod4gKey::Action => &mut controls.action,
+                // the hold switch isn't a key
+                Ipod4gKey::Hold => continue,
+            };
+            if held {
+                signal.assert()
+            } else {
+                signal.clear()
+            }
+        }
+    }
 }
 
 impl TakeControls for Ipod4g {
@@ -27,6 +71,7 @@ impl TakeControls for Ipod4g {
     fn take_controls(&mut self) -> Option<Ipod4gBinds> {
         let Ipod4gControls {
             mut hold,
+            accessories,
             controls:
                 Controls {
                     mut action,
@@ -34,7 +79,7 @@ impl TakeControls for Ipod4g {
                     mut down,
                     mut left,
                     mut right,
-                    wheel: (mut wheel_active, wheel_data),
+                    wheel,
                 },
         } = self.controls.take()?;
 
@@ -53,6 +98,18 @@ impl TakeControls for Ipod4g {
             }),
         );
 
+        for &input in Ipod4gInput::ALL.iter() {
+            let accessories = accessories.clone();
+            controls.inputs.insert(
+                input,
+                Box::new(move |pressed| {
+                    if pressed {
+                        accessories.toggle(input)
+                    }
+                }),
+            );
+        }
+
         macro_rules! connect_controls_btn {
             ($key:expr, $signal:expr) => {
                 controls.keys.insert(
@@ -74,21 +131,19 @@ impl TakeControls for Ipod4g {
         connect_controls_btn!(Ipod4gKey::Right, right);
         connect_controls_btn!(Ipod4gKey::Action, action);
 
-        // TODO: make sensitivity adjustable based on user's scroll speed
         controls.wheel = Some({
-            Box::new(move |(_dx, dy)| {
-                // HACK: the signal is edge-triggered
-                // TODO: i really aught to rework how input works...
-                if wheel_active.is_asserting() {
-                    wheel_active.clear();
-                } else {
-                    wheel_active.assert();
-                }
+            let wheel = wheel.clone();
+            Box::new(move |(_dx, dy)| wheel.scroll(dy as f64))
+        });
 
-                let mut wheel_data = wheel_data.lock().unwrap();
-                // from rockbox button-clickwheel.c
-                // #define WHEELCLICKS_PER_ROTATION     96 /* wheelclicks per full rotation */
-                *wheel_data = wheel_data.wrapping_add((-dy * 2.) as i8 as u8) % 96;
+        controls.wheel_drag = Some({
+            Box::new(move |pos| match pos {
+                // ignore the middle of the "wheel", where the angle is ill-defined
+                Some((x, y)) if x.hypot(y) > 0.2 => {
+                    wheel.touch((x as f64).atan2(-y as f64).to_degrees())
+                }
+                Some(_) => {}
+                None => wheel.release(),
             })
         });
 
@@ -22,6 +22,7 @@ pub enum Event {
     Break,
     WatchWrite(u32),
     WatchRead(u32),
+    PoweredOff,
 }
 
 pub struct Ipod4gGdb {
@@ -57,7 +58,7 @@ impl Ipod4gGdb {
         let mut hit_watchpoint = None;
 
         let watchpoint_kinds = &self.watchpoint_kinds;
-        self.sys.step(
+        let running = self.sys.step(
             BlockMode::NonBlocking,
             (&self.watchpoints, |cpuid, access| {
                 if watchpoint_kinds.get(&access.offset) == Some(&access.kind) {
@@ -66,6 +67,10 @@ impl Ipod4gGdb {
             }),
         )?;
 
+        if !running {
+            return Ok(Some((Event::PoweredOff, CpuId::Cpu)));
+        }
+
         if let Some((id, access)) = hit_watchpoint {
             let cpu = match id {
                 CpuId::Cpu => &mut self.sys.cpu,
@@ -201,6 +206,7 @@ fn event_to_stopreason(e: Event, id: CpuId) -> ThreadStopReason<u32> {
             kind: WatchKind::Read,
             addr,
         },
+        Event::PoweredOff => ThreadStopReason::Halted,
     }
 }
 
@@ -1,9 +1,28 @@
-use std::io::{self, Read, Seek, SeekFrom};
+use std::io::{self, Read, Seek, SeekFrom, Write};
 
-use byteorder::{BigEndian, ByteOrder, ReadBytesExt, LE};
+use byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt, LE};
 
 use super::HleBootloaderError;
 
+const STOP_LEN: usize = 256;
+/// The "STOP sign" which prefixes every Apple firmware image (padded out to
+/// fill the entire 256 byte field).
+const APPLE_STOP: &[u8; STOP_LEN] = b"{{~~  /-----\\   {{~~ /       \\  {{~~|         | {{~~| S T O P | {{~~|         | {{~~ \\       /  {{~~  \\-----/   Copyright(C) 2001 Apple Computer, Inc.----------------------------------------------------------------------------------------------------------";
+/// Size of the volume header (including the STOP sign).
+const VOLUME_HEADER_LEN: u64 = STOP_LEN as u64 + 12;
+
+/// Default offset of the image directory (sans the version 3 offset base).
+const DEFAULT_DIR_OFFSET: u32 = 0x4000;
+/// Default location of the extended header.
+const DEFAULT_EXT_HEADER_LOC: u16 = 0x10c;
+/// Images are aligned to sector boundaries.
+const IMAGE_ALIGN: u64 = 0x200;
+
+/// Sum of all bytes in an image, as stored in `ImageInfo::checksum`.
+pub fn checksum(data: &[u8]) -> u32 {
+    data.iter().fold(0u32, |sum, b| sum.wrapping_add(*b as u32))
+}
+
 /// Firmware image metadata.
 ///
 /// See http://www.ipodlinux.org/Firmware.html
@@ -16,19 +35,18 @@ pub struct FirmwareMeta {
 impl FirmwareMeta {
     pub fn parse(fw: &mut (impl Read + Seek)) -> Result<FirmwareMeta, HleBootloaderError> {
         // Volume Header
+        fw.seek(SeekFrom::Start(0))?;
         let header = VolumeHeader::parse(fw)?;
         if header.magic_hi != BigEndian::read_u32(b"[hi]") {
             return Err(HleBootloaderError::BadMagic);
         }
 
-        // TODO: don't assume FW version is 3, as each fw uses slightly-different
-        // offsets between things
-        if header.format_version != 3 {
+        if !matches!(header.format_version, 1..=3) {
             return Err(HleBootloaderError::InvalidVersion(header.format_version));
         }
 
         // Pull directory entries
-        fw.seek(SeekFrom::Start(header.dir_offset as u64 + 0x200))?;
+        fw.seek(SeekFrom::Start(header.dir_offset()))?;
 
         let mut images = Vec::new();
         loop {
@@ -41,9 +59,31 @@ impl FirmwareMeta {
 
         Ok(FirmwareMeta { header, images })
     }
+
+    /// Offset of the `idx`th image's directory entry in the firmware file.
+    pub fn image_info_offset(&self, idx: usize) -> u64 {
+        self.header.dir_offset() + (idx * ImageInfo::LEN) as u64
+    }
+
+    /// Offset of an image's data in the firmware file.
+    pub fn image_data_offset(&self, image: &ImageInfo) -> u64 {
+        self.header.offset_base() + image.dev_offset as u64
+    }
+
+    /// Read an image's data from the firmware file.
+    pub fn read_image(
+        &self,
+        fw: &mut (impl Read + Seek),
+        image: &ImageInfo,
+    ) -> io::Result<Vec<u8>> {
+        fw.seek(SeekFrom::Start(self.image_data_offset(image)))?;
+        let mut data = vec![0; image.len as usize];
+        fw.read_exact(&mut data)?;
+        Ok(data)
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct VolumeHeader {
     pub magic_hi: u32,
     pub dir_offset: u32,
@@ -55,7 +95,7 @@ impl VolumeHeader {
     fn parse(rdr: &mut impl Read) -> io::Result<VolumeHeader> {
         // this is just a static string, which we can skip over. we _should_ make sure
         // that it matches the expected STOP string, but that's overkill...
-        let mut stop = vec![0; 256];
+        let mut stop = vec![0; STOP_LEN];
         rdr.read_exact(&mut stop)?;
 
         #[rustfmt::skip]
@@ -68,9 +108,32 @@ impl VolumeHeader {
 
         Ok(header)
     }
+
+    fn write(&self, wtr: &mut impl Write) -> io::Result<()> {
+        wtr.write_u32::<LE>(self.magic_hi)?;
+        wtr.write_u32::<LE>(self.dir_offset)?;
+        wtr.write_u16::<LE>(self.ext_header_loc)?;
+        wtr.write_u16::<LE>(self.format_version)
+    }
+
+    /// Version 3 firmwares (i.e: 3rd gen iPods and later) store all offsets
+    /// relative to the end of the first sector, whereas earlier versions use
+    /// absolute offsets.
+    pub fn offset_base(&self) -> u64 {
+        if self.format_version >= 3 {
+            0x200
+        } else {
+            0
+        }
+    }
+
+    /// Offset of the image directory in the firmware file.
+    pub fn dir_offset(&self) -> u64 {
+        self.offset_base() + self.dir_offset as u64
+    }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ImageInfo {
     pub dev: [u8; 4],
     pub name: [u8; 4],
@@ -85,6 +148,9 @@ pub struct ImageInfo {
 }
 
 impl ImageInfo {
+    /// Size of each directory entry (in bytes).
+    pub const LEN: usize = 40;
+
     fn parse(rdr: &mut impl Read) -> io::Result<ImageInfo> {
         #[rustfmt::skip]
         let image = ImageInfo {
@@ -102,4 +168,244 @@ impl ImageInfo {
 
         Ok(image)
     }
+
+    fn write(&self, wtr: &mut impl Write) -> io::Result<()> {
+        wtr.write_u32::<LE>(u32::from_be_bytes(self.dev))?;
+        wtr.write_u32::<LE>(u32::from_be_bytes(self.name))?;
+        for val in &[
+            self.id,
+            self.dev_offset,
+            self.len,
+            self.addr,
+            self.entry_offset,
+            self.checksum,
+            self.vers,
+            self.load_addr,
+        ] {
+            wtr.write_u32::<LE>(*val)?;
+        }
+        Ok(())
+    }
+
+    /// Image name as a string (e.g: "osos").
+    pub fn name_str(&self) -> String {
+        String::from_utf8_lossy(&self.name).into_owned()
+    }
+}
+
+/// An in-memory firmware image, which can be modified and re-serialized.
+pub struct Firmware {
+    pub header: VolumeHeader,
+    /// The "STOP sign" at the start of the firmware.
+    pub stop: [u8; STOP_LEN],
+    /// Everything between the volume header and the image directory (i.e: the
+    /// extended header), which is preserved as-is.
+    pub ext_header: Vec<u8>,
+    /// Each image's metadata, along with its data. The `dev_offset`, `len`,
+    /// and `checksum` fields are recomputed when the firmware is written.
+    pub images: Vec<(ImageInfo, Vec<u8>)>,
+}
+
+impl Firmware {
+    /// Create an empty version 3 firmware (as used by the iPod 4g).
+    pub fn new() -> Firmware {
+        Firmware {
+            header: VolumeHeader {
+                magic_hi: BigEndian::read_u32(b"[hi]"),
+                dir_offset: DEFAULT_DIR_OFFSET,
+                ext_header_loc: DEFAULT_EXT_HEADER_LOC,
+                format_version: 3,
+            },
+            stop: *APPLE_STOP,
+            ext_header: Vec::new(),
+            images: Vec::new(),
+        }
+    }
+
+    /// Load a firmware (and all of its images) into memory.
+    pub fn load(fw: &mut (impl Read + Seek)) -> Result<Firmware, HleBootloaderError> {
+        let meta = FirmwareMeta::parse(fw)?;
+
+        let mut stop = [0; STOP_LEN];
+        fw.seek(SeekFrom::Start(0))?;
+        fw.read_exact(&mut stop)?;
+
+        let ext_header_len = meta.header.dir_offset().saturating_sub(VOLUME_HEADER_LEN);
+        let mut ext_header = Vec::new();
+        fw.seek(SeekFrom::Start(VOLUME_HEADER_LEN))?;
+        fw.take(ext_header_len).read_to_end(&mut ext_header)?;
+        if ext_header.len() as u64 != ext_header_len {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let mut images = Vec::new();
+        for image in &meta.images {
+            let data = meta.read_image(fw, image)?;
+            images.push((image.clone(), data));
+        }
+
+        Ok(Firmware {
+            header: meta.header,
+            stop,
+            ext_header,
+            images,
+        })
+    }
+
+    /// Find an image by name.
+    pub fn image(&self, name: &[u8; 4]) -> Option<&(ImageInfo, Vec<u8>)> {
+        self.images.iter().find(|(info, _)| info.name == *name)
+    }
+
+    /// Replace the `osos` image with a custom OS image (e.g: a third-party
+    /// bootloader), in the same way as `ipodpatcher`.
+    ///
+    /// If `keep_original` is set, the original OS image is appended to the
+    /// custom image (at the next sector boundary), so that the custom image
+    /// can chain-load it.
+    pub fn insert_os(&mut self, os: &[u8], keep_original: bool) -> Result<(), HleBootloaderError> {
+        let (info, data) = (self.images.iter_mut())
+            .find(|(info, _)| info.name == *b"osos")
+            .ok_or(HleBootloaderError::MissingOs)?;
+
+        let mut new_data = os.to_vec();
+        if keep_original {
+            let len = (new_data.len() as u64 + IMAGE_ALIGN - 1) / IMAGE_ALIGN * IMAGE_ALIGN;
+            new_data.resize(len as usize, 0);
+            new_data.extend_from_slice(data);
+        }
+
+        // custom images are always executed from their first byte
+        info.entry_offset = 0;
+        *data = new_data;
+        Ok(())
+    }
+
+    /// Serialize the firmware, recomputing each image's offset, length, and
+    /// checksum.
+    pub fn write(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let base = self.header.offset_base();
+        let dir_start = self.header.dir_offset();
+        if VOLUME_HEADER_LEN + self.ext_header.len() as u64 > dir_start {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "extended header overlaps the image directory",
+            ));
+        }
+        let dir_len = ((self.images.len() + 1) * ImageInfo::LEN) as u64;
+
+        // lay out images after the directory
+        let mut offset = (dir_start + dir_len + IMAGE_ALIGN - 1) / IMAGE_ALIGN * IMAGE_ALIGN;
+        for (info, data) in self.images.iter_mut() {
+            info.dev_offset = (offset - base) as u32;
+            info.len = data.len() as u32;
+            info.checksum = checksum(data);
+            offset = (offset + data.len() as u64 + IMAGE_ALIGN - 1) / IMAGE_ALIGN * IMAGE_ALIGN;
+        }
+
+        let mut fw = Vec::with_capacity(offset as usize);
+        fw.extend_from_slice(&self.stop);
+        self.header.write(&mut fw)?;
+        fw.extend_from_slice(&self.ext_header);
+
+        fw.resize(dir_start as usize, 0);
+        for (info, _) in &self.images {
+            info.write(&mut fw)?;
+        }
+
+        for (info, data) in &self.images {
+            fw.resize(base as usize + info.dev_offset as usize, 0);
+            fw.extend_from_slice(data);
+        }
+        fw.resize(offset as usize, 0);
+
+        out.write_all(&fw)
+    }
+}
+
+impl Default for Firmware {
+    fn default() -> Firmware {
+        Firmware::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{checksum, Firmware, ImageInfo, APPLE_STOP, IMAGE_ALIGN, STOP_LEN};
+
+    use std::io::Cursor;
+
+    fn image(name: &[u8; 4], addr: u32) -> ImageInfo {
+        ImageInfo {
+            dev: *b"disk",
+            name: *name,
+            id: 0,
+            dev_offset: 0,
+            len: 0,
+            addr,
+            entry_offset: 0x10,
+            checksum: 0,
+            vers: 0x1234,
+            load_addr: addr,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut fw = Firmware::new();
+        fw.header.dir_offset = 0x2000;
+        fw.ext_header = (0..0x40).collect();
+        fw.images
+            .push((image(b"osos", 0x1000_0000), vec![0xaa; 1000]));
+        fw.images
+            .push((image(b"rsrc", 0x1100_0000), vec![0x55; 0x200]));
+
+        let mut raw = Vec::new();
+        fw.write(&mut raw).unwrap();
+
+        // the STOP sign fills the entire field, and is immediately followed by
+        // the volume header + extended header
+        assert_eq!(&raw[..STOP_LEN], &APPLE_STOP[..]);
+        assert_eq!(&raw[STOP_LEN..STOP_LEN + 4], b"]ih[");
+        assert_eq!(
+            &raw[STOP_LEN + 12..STOP_LEN + 12 + 0x40],
+            &fw.ext_header[..]
+        );
+
+        let parsed = Firmware::load(&mut Cursor::new(&raw)).unwrap();
+        assert_eq!(parsed.stop[..], fw.stop[..]);
+        assert_eq!(parsed.header.dir_offset, 0x2000);
+        assert_eq!(parsed.header.ext_header_loc, fw.header.ext_header_loc);
+        assert_eq!(parsed.header.format_version, 3);
+        // everything up to the directory is preserved
+        assert_eq!(parsed.ext_header.len(), 0x200 + 0x2000 - (STOP_LEN + 12));
+        assert_eq!(parsed.ext_header[..0x40], fw.ext_header[..]);
+
+        assert_eq!(parsed.images.len(), 2);
+        for ((info, data), (orig_info, orig_data)) in parsed.images.iter().zip(&fw.images) {
+            assert_eq!(data, orig_data);
+            assert_eq!(info.name, orig_info.name);
+            assert_eq!(info.addr, orig_info.addr);
+            assert_eq!(info.entry_offset, orig_info.entry_offset);
+            assert_eq!(info.vers, orig_info.vers);
+            assert_eq!(info.load_addr, orig_info.load_addr);
+            assert_eq!(info.len as usize, data.len());
+            assert_eq!(info.checksum, checksum(data));
+            assert_eq!(info.dev_offset as u64 % IMAGE_ALIGN, 0);
+        }
+
+        // re-serializing the parsed firmware produces an identical image
+        let mut parsed = parsed;
+        let mut raw2 = Vec::new();
+        parsed.write(&mut raw2).unwrap();
+        assert_eq!(raw, raw2);
+    }
+
+    #[test]
+    fn rejects_overlapping_ext_header() {
+        let mut fw = Firmware::new();
+        fw.header.dir_offset = 0;
+        fw.ext_header = vec![0; 0x200];
+        assert!(fw.write(&mut Vec::new()).is_err());
+    }
 }
@@ -1,14 +1,18 @@
-use std::io::{Read, Seek, SeekFrom};
+use std::io::{Read, Seek};
+use std::str::FromStr;
 
 use armv4t_emu::{reg, Mode as ArmMode};
 use std::io;
 use thiserror::Error;
 
+use crate::block::BlockDev;
+use crate::devices::generic::AsanRam;
 use crate::memory::Memory;
 
 use super::Ipod4g;
 
-mod firmware;
+pub mod firmware;
+mod partition;
 mod sysinfo;
 
 use sysinfo::sysinfo_t;
@@ -23,13 +27,131 @@ pub enum HleBootloaderError {
     InvalidVersion(u16),
     #[error("Couldn't find valid `osos` image")]
     MissingOs,
+    #[error("Couldn't find `{0}` image")]
+    MissingImage(String),
+    #[error("Couldn't find a firmware partition on the HDD")]
+    NoFirmwarePartition,
+    #[error("`{name}` image is stored on `{dev}` (only images on `disk` can be booted)")]
+    NotOnDisk { name: String, dev: String },
+    #[error("`{name}` image ({len:#x} bytes at {addr:#010x}) doesn't fit in RAM")]
+    InvalidLoadAddr { name: String, addr: u32, len: usize },
+}
+
+/// Firmware image to boot using the HLE bootloader.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum HleImage {
+    /// Pick an image based on which keys are held down, as the stock
+    /// bootloader does: Select+Prev boots diagnostics, Select+Play boots disk
+    /// mode, and anything else boots the OS.
+    Auto,
+    /// `osos`
+    Os,
+    /// `aupd`
+    Update,
+    /// `diag`
+    Diagnostics,
+    /// `disk`
+    DiskMode,
+}
+
+impl HleImage {
+    fn name(self) -> [u8; 4] {
+        match self {
+            HleImage::Auto | HleImage::Os => *b"osos",
+            HleImage::Update => *b"aupd",
+            HleImage::Diagnostics => *b"diag",
+            HleImage::DiskMode => *b"disk",
+        }
+    }
+}
+
+impl FromStr for HleImage {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<HleImage, &'static str> {
+        let image = match s {
+            "auto" => HleImage::Auto,
+            "osos" | "os" => HleImage::Os,
+            "aupd" | "update" => HleImage::Update,
+            "diag" | "diagnostics" => HleImage::Diagnostics,
+            "disk" | "diskmode" => HleImage::DiskMode,
+            _ => return Err("invalid image (expected `auto`, `osos`, `aupd`, `diag`, or `disk`)"),
+        };
+        Ok(image)
+    }
+}
+
+/// Sample the controls, as the stock bootloader does when picking which image
+/// to boot.
+fn image_from_keys(ipod: &Ipod4g) -> HleImage {
+    let controls = match &ipod.controls {
+        Some(controls) => &controls.controls,
+        None => return HleImage::Os,
+    };
+
+    // Select = Action, Prev = Left, Play = Down
+    match (
+        controls.action.is_asserting(),
+        controls.left.is_asserting(),
+        controls.down.is_asserting(),
+    ) {
+        (true, true, _) => HleImage::Diagnostics,
+        (true, _, true) => HleImage::DiskMode,
+        _ => HleImage::Os,
+    }
+}
+
+/// A firmware image loaded by the HLE bootloader.
+///
+/// Kept around after booting, so that the system can be booted again without
+/// re-reading the firmware (e.g: when waking up from standby).
+#[derive(Debug)]
+pub(super) struct HleBootImage {
+    name: [u8; 4],
+    addr: u32,
+    entry_offset: u32,
+    data: Vec<u8>,
+}
+
+const SDRAM_BASE: u32 = 0x1000_0000;
+const FASTRAM_BASE: u32 = 0x4000_0000;
+
+/// Find the RAM (and offset into it) that a `len` byte image loaded at `addr`
+/// should be copied into, or `None` if it doesn't fit entirely within SDRAM /
+/// fast RAM.
+fn image_ram(ipod: &mut Ipod4g, addr: u32, len: usize) -> Option<(&mut AsanRam, u32)> {
+    let (ram, base) = if addr >= FASTRAM_BASE {
+        (&mut ipod.devices.fastram, FASTRAM_BASE)
+    } else if addr >= SDRAM_BASE {
+        (&mut ipod.devices.sdram, SDRAM_BASE)
+    } else {
+        return None;
+    };
+
+    let offset = addr - base;
+    if offset as u64 + len as u64 > ram.len() as u64 {
+        return None;
+    }
+    Some((ram, offset))
+}
+
+/// Same as `run_hle_bootloader`, except the firmware is loaded from the HDD's
+/// firmware partition (as the real bootloader does).
+pub(super) fn run_hle_bootloader_from_hdd(
+    ipod: &mut Ipod4g,
+    hdd: &mut dyn BlockDev,
+    image: HleImage,
+) -> Result<HleBootImage, HleBootloaderError> {
+    let fw_part = partition::Partition::find_firmware(hdd)?;
+    run_hle_bootloader(ipod, fw_part, image)
 }
 
 /// Put the system into a state as though the bootloader in Flash ROM was run.
 pub(super) fn run_hle_bootloader(
     ipod: &mut Ipod4g,
     mut fw_file: impl Read + Seek,
-) -> Result<(), HleBootloaderError> {
+    image: HleImage,
+) -> Result<HleBootImage, HleBootloaderError> {
     if !ipod.devices.flash.is_hle() {
         warn!("Running HLE bootloader even though the system is using a real Flash ROM dump!");
     }
@@ -38,25 +160,78 @@ pub(super) fn run_hle_bootloader(
 
     info!("Parsed firmware meta: {:#x?}", fw_info);
 
-    let os_image = fw_info
-        .images
-        .iter()
-        .find(|img| img.name == *b"osos")
-        .ok_or(HleBootloaderError::MissingOs)?;
+    let find_image = |image: HleImage| fw_info.images.iter().find(|img| img.name == image.name());
+    let os_image = match image {
+        HleImage::Auto => {
+            let selected = image_from_keys(ipod);
+            match find_image(selected) {
+                Some(img) => img,
+                None => {
+                    // fall back to the OS, instead of refusing to boot at all
+                    warn!(
+                        "Firmware doesn't contain a `{}` image, booting `osos` instead",
+                        String::from_utf8_lossy(&selected.name())
+                    );
+                    find_image(HleImage::Os).ok_or(HleBootloaderError::MissingOs)?
+                }
+            }
+        }
+        HleImage::Os => find_image(image).ok_or(HleBootloaderError::MissingOs)?,
+        _ => find_image(image).ok_or_else(|| {
+            HleBootloaderError::MissingImage(String::from_utf8_lossy(&image.name()).into())
+        })?,
+    };
+
+    // images stored elsewhere (e.g: in flash ROM) aren't part of the firmware
+    // file's data
+    if os_image.dev != *b"disk" {
+        return Err(HleBootloaderError::NotOnDisk {
+            name: os_image.name_str(),
+            dev: String::from_utf8_lossy(&os_image.dev).into(),
+        });
+    }
+
+    // the stock bootloader copies the image to `addr` (`load_addr` is unused,
+    // e.g: `make_fw` sets it to 0xffffffff)
+    if image_ram(ipod, os_image.addr, os_image.len as usize).is_none() {
+        return Err(HleBootloaderError::InvalidLoadAddr {
+            name: os_image.name_str(),
+            addr: os_image.addr,
+            len: os_image.len as usize,
+        });
+    }
+
+    // extract image from firmware file
+    let os_image_data = fw_info.read_image(&mut fw_file, os_image)?;
+    if firmware::checksum(&os_image_data) != os_image.checksum {
+        warn!(
+            "`{}` image checksum mismatch (the real bootloader would refuse to boot it)",
+            os_image.name_str()
+        );
+    }
+
+    let boot_image = HleBootImage {
+        name: os_image.name,
+        addr: os_image.addr,
+        entry_offset: os_image.entry_offset,
+        data: os_image_data,
+    };
+    boot_hle_image(ipod, &boot_image);
+    Ok(boot_image)
+}
 
-    // extract image from firmware file, and copy it into RAM
-    fw_file.seek(SeekFrom::Start(os_image.dev_offset as u64 + 0x200))?;
-    let mut os_image_data = vec![0; os_image.len as usize];
-    fw_file.read_exact(&mut os_image_data)?;
+/// Copy a (previously loaded) firmware image into RAM, and set up the system
+/// to start executing it.
+pub(super) fn boot_hle_image(ipod: &mut Ipod4g, image: &HleBootImage) {
+    info!("Booting `{}` image", String::from_utf8_lossy(&image.name));
 
-    ipod.devices.sdram.bulk_write(0, &os_image_data);
+    let (ram, offset) = image_ram(ipod, image.addr, image.data.len())
+        .expect("image load address is validated when the image is loaded");
+    ram.bulk_write(offset, &image.data);
 
     // set the CPU to start execution from the image entry address
-    ipod.cpu.reg_set(
-        ArmMode::User,
-        reg::PC,
-        os_image.addr + os_image.entry_offset,
-    );
+    ipod.cpu
+        .reg_set(ArmMode::User, reg::PC, image.addr + image.entry_offset);
     ipod.cpu.reg_set(ArmMode::User, reg::CPSR, 0xd3); // supervisor mode
     ipod.cop = ipod.cpu;
 
@@ -64,7 +239,11 @@ pub(super) fn run_hle_bootloader(
     ipod.cpu.reg_set(ArmMode::Irq, reg::SP, 0x40017bfc);
 
     // inject fake sysinfo_t into fastram.
-    // TODO: look into how this pointer changes between iPod models
+    //
+    // The pointer lives at a fixed location in fastram, which is the same on
+    // all PP5020 based iPods (including the 4g). PP5022 based iPods keep it at
+    // 0x4001_ff1c instead (see `ipod_set_sysinfo` in ipodloader2's `ipodhw.c`),
+    // but those aren't emulated.
     const SYSINFO_PTR: u32 = 0x4001_7f1c;
     const SYSINFO_LOC: u32 = 0x4000_ff18;
     ipod.devices.w32(SYSINFO_PTR, SYSINFO_LOC).unwrap(); // pointer to sysinfo
@@ -86,6 +265,4 @@ pub(super) fn run_hle_bootloader(
         .unwrap()
         .w32(0x00, 0x20)
         .unwrap();
-
-    Ok(())
 }
@@ -0,0 +1,120 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use futures::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::block::{self, BlockDev};
+
+use super::HleBootloaderError;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// WinPods (i.e: MBR formatted iPods) mark the firmware partition with a
+/// partition type of 0.
+const MBR_FW_PART_TYPE: u8 = 0x00;
+/// MacPods (i.e: APM formatted iPods) mark the firmware partition with this
+/// partition type.
+const APM_FW_PART_TYPE: &[u8] = b"Apple_MDFW";
+
+/// A read-only view of a single partition on a block device.
+pub struct Partition<'a> {
+    dev: &'a mut dyn BlockDev,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+fn read_at(dev: &mut dyn BlockDev, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    futures_executor::block_on(async {
+        dev.seek(SeekFrom::Start(offset)).await?;
+        dev.read_exact(buf).await
+    })
+}
+
+impl<'a> Partition<'a> {
+    /// Locate the firmware partition, using either the DOS MBR or the Apple
+    /// Partition Map (whichever is present).
+    pub fn find_firmware(dev: &'a mut dyn BlockDev) -> Result<Partition<'a>, HleBootloaderError> {
+        let mut block0 = [0; SECTOR_SIZE as usize];
+        read_at(dev, 0, &mut block0)?;
+
+        let (start, len) = if &block0[..2] == b"ER" {
+            find_apm_firmware(dev, &block0)?
+        } else if block0[510..512] == [0x55, 0xaa] {
+            find_mbr_firmware(&block0)?
+        } else {
+            return Err(HleBootloaderError::NoFirmwarePartition);
+        };
+
+        debug!(
+            "Found firmware partition at {:#x} ({:#x} bytes)",
+            start, len
+        );
+
+        Ok(Partition {
+            dev,
+            start,
+            len,
+            pos: 0,
+        })
+    }
+}
+
+fn find_mbr_firmware(mbr: &[u8]) -> Result<(u64, u64), HleBootloaderError> {
+    for entry in mbr[446..510].chunks_exact(16) {
+        let kind = entry[4];
+        let start = LittleEndian::read_u32(&entry[8..12]) as u64;
+        let len = LittleEndian::read_u32(&entry[12..16]) as u64;
+        if kind == MBR_FW_PART_TYPE && len != 0 {
+            return Ok((start * SECTOR_SIZE, len * SECTOR_SIZE));
+        }
+    }
+
+    Err(HleBootloaderError::NoFirmwarePartition)
+}
+
+fn find_apm_firmware(dev: &mut dyn BlockDev, ddr: &[u8]) -> Result<(u64, u64), HleBootloaderError> {
+    let block_size = match BigEndian::read_u16(&ddr[2..4]) as u64 {
+        0 => SECTOR_SIZE,
+        n => n,
+    };
+
+    // the first entry specifies how many entries there are in total
+    let mut num_entries = 1;
+    let mut idx = 1;
+    while idx <= num_entries {
+        let mut entry = [0; SECTOR_SIZE as usize];
+        read_at(dev, idx * block_size, &mut entry)?;
+        if &entry[..2] != b"PM" {
+            break;
+        }
+
+        num_entries = BigEndian::read_u32(&entry[4..8]) as u64;
+        let start = BigEndian::read_u32(&entry[8..12]) as u64;
+        let len = BigEndian::read_u32(&entry[12..16]) as u64;
+        let kind = &entry[48..80];
+        if kind.starts_with(APM_FW_PART_TYPE) && kind[APM_FW_PART_TYPE.len()] == 0 {
+            return Ok((start * block_size, len * block_size));
+        }
+
+        idx += 1;
+    }
+
+    Err(HleBootloaderError::NoFirmwarePartition)
+}
+
+impl Read for Partition<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (buf.len() as u64).min(self.len.saturating_sub(self.pos)) as usize;
+        read_at(self.dev, self.start + self.pos, &mut buf[..n])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for Partition<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = block::seek_pos(self.pos, self.len, pos)?;
+        Ok(self.pos)
+    }
+}
@@ -9,22 +9,32 @@ use crate::error::*;
 use crate::executor::*;
 use crate::gui::RenderCallback;
 use crate::memory::{armv4t_adaptor::MemoryAdapter, MemAccess, MemAccessKind, Memory};
+use crate::serial::SerialBackend;
 use crate::signal::{self, gpio, irq};
 
+mod accessories;
+mod backlight;
 mod controls;
 mod gdb;
 mod hle_bootloader;
+mod outputs;
 
-pub use controls::{Ipod4gBinds, Ipod4gKey};
+pub use accessories::Accessories;
+pub use backlight::Backlight;
+pub use controls::{Ipod4gBinds, Ipod4gInput, Ipod4gKey};
 pub use gdb::Ipod4gGdb;
+pub use hle_bootloader::{firmware, HleBootloaderError, HleImage};
+pub use outputs::{GpioOutputs, Ipod4gOutput, OutputCallback};
 
-use hle_bootloader::run_hle_bootloader;
+use hle_bootloader::{
+    boot_hle_image, run_hle_bootloader, run_hle_bootloader_from_hdd, HleBootImage,
+};
 
 use crate::devices::platform::pp::common::*;
 use crate::devices::util::{ArcMutexDevice, MemSniffer};
 mod devices {
     pub mod i2c {
-        pub use crate::devices::i2c::devices::Pcf5060x;
+        pub use crate::devices::i2c::devices::{Pcf5060x, PcfOnOff, PcfPower, PcfRtc, PowerSource};
     }
 
     pub use crate::devices::{
@@ -41,12 +51,41 @@ enum BlockMode {
 
 pub enum BootKind<F: Read + Seek> {
     ColdBoot,
-    HLEBoot { fw_file: F },
+    HLEBoot {
+        fw_file: F,
+        image: HleImage,
+        /// Keys held down while the iPod powers on (used to emulate the
+        /// bootloader's key combos). They're released as soon as the
+        /// bootloader has sampled them.
+        held_keys: Vec<Ipod4gKey>,
+    },
+    /// Same as `HLEBoot`, except the firmware is loaded from the HDD's
+    /// firmware partition (located via the DOS MBR or Apple Partition Map),
+    /// just like the real bootloader.
+    HLEBootFromHdd {
+        image: HleImage,
+        held_keys: Vec<Ipod4gKey>,
+    },
+}
+
+/// Serial ports exposed by the Ipod4g (via the dock connector and the remote
+/// port).
+#[derive(Debug, Copy, Clone)]
+pub enum SerialIdx {
+    Serial0,
+    Serial1,
+}
+
+/// Snapshot of the system's wake-up sources at the time it entered standby.
+#[derive(Debug)]
+struct Standby {
+    hold_was_high: bool,
 }
 
 #[derive(Debug)]
 struct Ipod4gControls {
     hold: gpio::Sender,
+    accessories: Accessories,
     controls: devices::Controls<signal::Master>,
 }
 
@@ -60,12 +99,22 @@ pub struct Ipod4g {
     cop: Cpu,
     devices: Ipod4gBus,
     controls: Option<Ipod4gControls>,
+    hold: Option<gpio::Reciever>,
+    accessories: Option<Accessories>,
+    standby: Option<Standby>,
+    hle_boot: Option<HleBootImage>,
 
     irq_pending: irq::Pending,
     dma_pending: irq::Pending,
     gpio_changed: gpio::Changed,
     i2c_changed: signal::Trigger,
+    pwm_changed: signal::Trigger,
     reset_requested: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    usb_service_requested: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    firewire_service_requested: std::sync::Arc<std::sync::atomic::AtomicBool>,
+    cpu_active: bool,   // used to track when the battery model should be notified
+    backlight_on: bool, // ditto
+    pmu_on_off: std::sync::Arc<devices::i2c::PcfOnOff>,
 
     executor: Executor,
 }
@@ -88,7 +137,7 @@ pub enum Ipod4gBuildError {
 impl Ipod4g {
     /// Returns a new Ipod4g instance.
     pub fn new<F>(
-        hdd: Box<dyn BlockDev>,
+        mut hdd: Box<dyn BlockDev>,
         flash_rom: Option<Box<[u8]>>,
         boot_kind: BootKind<F>,
     ) -> Result<Ipod4g, Ipod4gBuildError>
@@ -109,25 +158,38 @@ impl Ipod4g {
 
             cpu: Cpu::new(),
             cop: Cpu::new(),
-            devices: Ipod4gBus::new(executor.spawner(), irq_pending.clone(), dma_pending.clone()),
+            devices: Ipod4gBus::new(
+                executor.spawner(),
+                irq_pending.clone(),
+                dma_pending.clone(),
+                gpio_changed.clone(),
+            ),
             controls: None,
+            hold: None,
+            accessories: None,
+            standby: None,
+            hle_boot: None,
 
             irq_pending,
             dma_pending,
             gpio_changed: gpio_changed.clone(),
             i2c_changed: i2c_changed.clone(),
+            pwm_changed: signal::Trigger::new(signal::TriggerKind::Edge),
             reset_requested: Default::default(),
+            usb_service_requested: Default::default(),
+            firewire_service_requested: Default::default(),
+            cpu_active: true,
+            backlight_on: false,
+            pmu_on_off: Default::default(),
 
             executor,
         };
 
         sys.reset_requested = sys.devices.devcon.reset_requested();
-
-        // connect HDD
-        sys.devices
-            .eidecon
-            .as_ide()
-            .attach(devices::ide::IdeIdx::IDE0, hdd);
+        sys.pwm_changed = sys.devices.pwmcon.changed();
+        sys.usb_service_requested = sys.devices.usb.lock().unwrap().service_requested();
+        sys.firewire_service_requested = sys.devices.firewire.lock().unwrap().service_requested();
+        sys.pmu_on_off = sys.devices.pcf_on_off.clone();
 
         // Set up flash_rom (if available)
         if let Some(flash_rom) = flash_rom {
@@ -138,31 +200,65 @@ impl Ipod4g {
         }
 
         // hook-up external controls
-        let (mut hold_tx, hold_rx) = gpio::new(gpio_changed, "Hold");
-        let (controls_tx, controls_rx) = devices::Controls::new_tx_rx(i2c_changed);
+        let (mut hold_tx, hold_rx) = gpio::new(gpio_changed.clone(), "Hold");
+        let (controls_tx, controls_rx) =
+            devices::Controls::new_tx_rx(i2c_changed, sys.executor.spawner());
 
         {
             let mut gpio_abcd = sys.devices.gpio_abcd.lock().unwrap();
             gpio_abcd.register_in(5, hold_rx.clone());
         }
 
+        let accessories = Accessories::new(
+            gpio_changed,
+            &mut sys.devices.gpio_abcd.lock().unwrap(),
+            sys.devices.pcf_power.clone(),
+        );
+
         {
-            sys.devices.opto.register_controls(controls_rx, hold_rx)
+            sys.devices
+                .opto
+                .register_controls(controls_rx, hold_rx.clone())
         }
 
         // HACK: Hold is active-low, so set it to high by default
         hold_tx.set_high();
 
+        sys.hold = Some(hold_rx);
+        sys.accessories = Some(accessories.clone());
         sys.controls = Some(Ipod4gControls {
             hold: hold_tx,
+            accessories,
             controls: controls_tx,
         });
 
         // Run the HLE bootloader if an HLE boot was requested
-        if let BootKind::HLEBoot { fw_file } = boot_kind {
-            run_hle_bootloader(&mut sys, fw_file)?
+        match boot_kind {
+            BootKind::ColdBoot => {}
+            BootKind::HLEBoot {
+                fw_file,
+                image,
+                held_keys,
+            } => {
+                sys.set_keys_held(&held_keys, true);
+                let res = run_hle_bootloader(&mut sys, fw_file, image);
+                sys.set_keys_held(&held_keys, false);
+                sys.hle_boot = Some(res?);
+            }
+            BootKind::HLEBootFromHdd { image, held_keys } => {
+                sys.set_keys_held(&held_keys, true);
+                let res = run_hle_bootloader_from_hdd(&mut sys, &mut *hdd, image);
+                sys.set_keys_held(&held_keys, false);
+                sys.hle_boot = Some(res?);
+            }
         }
 
+        // connect HDD (only after the HLE bootloader is done reading from it)
+        sys.devices
+            .eidecon
+            .as_ide()
+            .attach(devices::ide::IdeIdx::IDE0, hdd);
+
         Ok(sys)
     }
 
@@ -178,6 +274,66 @@ impl Ipod4g {
         self.cop = Cpu::new();
     }
 
+    /// Power the system up from scratch (i.e: as though the PMU had just
+    /// turned it on).
+    fn power_on(&mut self) {
+        self.warm_reset();
+
+        // without a Flash ROM dump, there's no bootloader to run
+        if let Some(image) = self.hle_boot.take() {
+            boot_hle_image(self, &image);
+            self.hle_boot = Some(image);
+        }
+    }
+
+    /// Enter the PMU's standby mode (i.e: OOCC1 GOSTDBY).
+    ///
+    /// NOTE: CPU / COP sleep via the CPU controller isn't a standby state, and
+    /// simply halts the core until an interrupt arrives.
+    fn enter_standby(&mut self) {
+        info!("system entered standby");
+
+        self.devices.hd66753.set_powered(false);
+        self.devices.pcf_power.set_cpu_active(false);
+        self.cpu_active = false;
+        self.pmu_on_off.set_standby(true);
+
+        // only _new_ button presses should wake the system
+        self.i2c_changed.check_and_clear();
+        self.standby = Some(Standby {
+            hold_was_high: self.hold.as_ref().map(|h| h.is_high()).unwrap_or(true),
+        });
+    }
+
+    /// Wait for a wake-up event while in standby.
+    fn step_standby(&mut self) {
+        self.executor.run_until_stalled();
+
+        let hold_was_high = match self.standby {
+            Some(Standby { hold_was_high }) => hold_was_high,
+            None => return,
+        };
+
+        let hold_toggled = match self.hold {
+            Some(ref hold) => hold.is_high() != hold_was_high,
+            None => false,
+        };
+        let button_pressed =
+            self.i2c_changed.check_and_clear() && self.devices.opto.any_button_pressed();
+        let pmu_wake = self.pmu_on_off.take_wake_request();
+
+        if hold_toggled || button_pressed || pmu_wake {
+            info!("system woke from standby");
+
+            self.standby = None;
+            self.pmu_on_off.set_standby(false);
+            self.devices.hd66753.set_powered(true);
+
+            // the PMU powers the SoC back up, which boots from scratch
+            self.power_on();
+        }
+    }
+
     /// Run the system for a single CPU instruction, returning `true` if the
     /// system is still running, or `false` upon reaching some sort of "graceful
     /// exit" condition (e.g: power-off).
@@ -199,6 +355,20 @@ impl Ipod4g {
             return Ok(true);
         }
 
+        if self.pmu_on_off.is_power_off_requested() {
+            info!("system powered off");
+            return Ok(false);
+        }
+
+        if self.pmu_on_off.take_standby_request() {
+            self.enter_standby();
+        }
+
+        if self.standby.is_some() {
+            self.step_standby();
+            return Ok(true);
+        }
+
         // TODO: if neither CPU is running, efficiently block until the next IRQ
 
         let devices = &mut self.devices;
@@ -233,6 +403,13 @@ impl Ipod4g {
             }
         }
 
+        let cpu_active =
+            devices.cpucon.is_cpu_running(CpuId::Cpu) || devices.cpucon.is_cpu_running(CpuId::Cop);
+        if cpu_active != self.cpu_active {
+            self.cpu_active = cpu_active;
+            devices.pcf_power.set_cpu_active(cpu_active);
+        }
+
         if self.skip_irq_check {
             return Ok(true);
         }
@@ -253,16 +430,22 @@ impl Ipod4g {
                 use crate::memory::MemAccessKind;
                 match kind {
                     MemAccessKind::Read => {
-                        let val = (devices.eidecon.as_ide())
+                        // the drive aborts the transfer on error, so there's
+                        // nothing else to do besides logging it
+                        let res = (devices.eidecon.as_ide())
                             .read16(devices::ide::IdeReg::Data)
-                            .unwrap();
-                        devices.w16(addr, val).unwrap();
+                            .and_then(|val| devices.w16(addr, val));
+                        if let Err(e) = res {
+                            warn!("IDE DMA read failed: {:x?}", e);
+                        }
                     }
                     MemAccessKind::Write => {
-                        let val = devices.r16(addr).unwrap();
-                        (devices.eidecon.as_ide())
-                            .write16(devices::ide::IdeReg::Data, val)
-                            .unwrap();
+                        let res = devices.r16(addr).and_then(|val| {
+                            (devices.eidecon.as_ide()).write16(devices::ide::IdeReg::Data, val)
+                        });
+                        if let Err(e) = res {
+                            warn!("IDE DMA write failed: {:x?}", e);
+                        }
                     }
                     MemAccessKind::Execute => {
                         panic!("Unsupported execute DMA");
@@ -271,11 +454,43 @@ impl Ipod4g {
             }
         }
 
+        if self
+            .usb_service_requested
+            .swap(false, std::sync::atomic::Ordering::SeqCst)
+        {
+            let usb = devices.usb.clone();
+            let res = usb.lock().unwrap().service(devices);
+            if let Err(e) = res {
+                warn!("USB controller failed to access memory: {:x?}", e);
+            }
+        }
+
+        if self
+            .firewire_service_requested
+            .swap(false, std::sync::atomic::Ordering::SeqCst)
+        {
+            let firewire = devices.firewire.clone();
+            let res = firewire.lock().unwrap().service(devices);
+            if let Err(e) = res {
+                warn!("FireWire controller failed to access memory: {:x?}", e);
+            }
+        }
+
         // TODO?: explore adding callbacks to the signaling system
-        if self.gpio_changed.check_and_clear() {
+        let gpio_changed = self.gpio_changed.check_and_clear();
+        if gpio_changed {
             devices.gpio_abcd.lock().unwrap().update();
             devices.gpio_efgh.lock().unwrap().update();
             devices.gpio_ijkl.lock().unwrap().update();
+            devices.gpio_outputs.notify();
+        }
+        // the backlight depends on both a GPIO and a PWM channel
+        if gpio_changed | self.pwm_changed.check_and_clear() {
+            let backlight_on = devices.backlight.is_on();
+            if backlight_on != self.backlight_on {
+                self.backlight_on = backlight_on;
+                devices.pcf_power.set_backlight(backlight_on);
+            }
         }
         if self.i2c_changed.check_and_clear() {
             devices.opto.on_change();
@@ -336,7 +551,9 @@ impl Ipod4g {
     pub fn run_cycles(&mut self, cycles: usize) -> FatalMemResult<()> {
         let dummy_sniff_memory = |_, _| {};
         for _ in 0..cycles {
-            self.step(BlockMode::Blocking, (&[], dummy_sniff_memory))?;
+            if !self.step(BlockMode::Blocking, (&[], dummy_sniff_memory))? {
+                break;
+            }
         }
         Ok(())
     }
@@ -349,9 +566,108 @@ impl Ipod4g {
         self.frozen = true;
     }
 
+    /// Connect one of the system's serial ports to a host backend.
+    pub fn attach_serial(&mut self, idx: SerialIdx, backend: Box<dyn SerialBackend>) {
+        match idx {
+            SerialIdx::Serial0 => self.devices.serial0.attach(backend),
+            SerialIdx::Serial1 => self.devices.serial1.attach(backend),
+        }
+    }
+
+    /// Set the identity and capabilities reported by the HDD (e.g: to emulate
+    /// a specific drive model, or a flash storage mod).
+    pub fn set_hdd_profile(&mut self, profile: devices::ide::IdeDriveProfile) {
+        (self.devices.eidecon.as_ide()).set_profile(devices::ide::IdeIdx::IDE0, profile)
+    }
+
+    /// Set the initial state of the HDD's SMART attributes (e.g: power-on
+    /// hours, reallocated sectors).
+    pub fn set_hdd_smart(&mut self, cfg: devices::ide::SmartConfig) {
+        (self.devices.eidecon.as_ide()).set_smart(devices::ide::IdeIdx::IDE0, cfg)
+    }
+
+    /// Inject faults into the HDD (e.g: unreadable sectors), in order to test
+    /// how firmware handles a dying disk.
+    pub fn set_hdd_faults(&mut self, faults: devices::ide::IdeFaults) {
+        (self.devices.eidecon.as_ide()).set_faults(devices::ide::IdeIdx::IDE0, faults)
+    }
+
+    /// Set (or clear) the timing model used to delay the HDD's media accesses
+    /// (i.e: spin-up, seek, and rotational latency).
+    ///
+    /// Enabling the timing model power-cycles the HDD, so this should be called
+    /// prior to running the system.
+    pub fn set_hdd_timing(&mut self, timing: Option<devices::ide::IdeTiming>) {
+        (self.devices.eidecon.as_ide()).set_timing(devices::ide::IdeIdx::IDE0, timing)
+    }
+
+    /// Write any modifications to the Flash ROM (e.g: by an Apple updater)
+    /// back to the provided file.
+    pub fn set_flash_write_back(
+        &mut self,
+        path: impl AsRef<std::path::Path>,
+    ) -> std::io::Result<()> {
+        self.devices.flash.set_write_back(path.as_ref())
+    }
+
+    /// Return a handle to the virtual USB host connected to the system's USB
+    /// port.
+    pub fn usb_host(&self) -> devices::UsbHost {
+        self.devices.usb.lock().unwrap().host()
+    }
+
+    /// Return a handle to the virtual 1394 peer connected to the system's
+    /// FireWire port.
+    pub fn firewire_peer(&self) -> devices::FirewirePeer {
+        self.devices.firewire.lock().unwrap().peer()
+    }
+
+    /// Return a handle to the system's Real Time Clock (i.e: the RTC in the
+    /// PCF5060x PMU).
+    pub fn rtc(&self) -> devices::i2c::PcfRtc {
+        self.devices.pcf_rtc.clone()
+    }
+
+    /// Return a handle to the system's battery and charger (i.e: the PCF5060x
+    /// PMU's Main Battery Charger).
+    pub fn power(&self) -> devices::i2c::PcfPower {
+        self.devices.pcf_power.clone()
+    }
+
+    /// Return a handle to the system's LCD backlight.
+    pub fn backlight(&self) -> Backlight {
+        self.devices.backlight.clone()
+    }
+
+    /// Return a handle to the clickwheel's touch sensor.
+    pub fn clickwheel(&self) -> devices::Clickwheel {
+        let clickwheel = self.devices.opto.clickwheel();
+        clickwheel.expect("controls are registered on init").clone()
+    }
+
+    /// Return a handle to the system's accessory detect lines (headphones,
+    /// dock, etc...).
+    pub fn accessories(&self) -> Accessories {
+        let accessories = self.accessories.as_ref();
+        accessories.expect("registered on init").clone()
+    }
+
+    /// Return a handle to the system's named GPIO outputs (LCD power, HDD
+    /// power, etc...).
+    pub fn gpio_outputs(&self) -> GpioOutputs {
+        self.devices.gpio_outputs.clone()
+    }
+
     /// Return the system's RenderCallback method.
     pub fn render_callback(&self) -> RenderCallback {
-        self.devices.hd66753.render_callback()
+        let mut render_lcd = self.devices.hd66753.render_callback();
+        let backlight = self.backlight();
+
+        Box::new(move |buf: &mut Vec<u32>| -> (usize, usize) {
+            let dims = render_lcd(buf);
+            backlight.apply(buf);
+            dims
+        })
     }
 }
 
@@ -371,8 +687,8 @@ pub struct Ipod4gBus {
     pub timer1: devices::CfgTimer,
     pub timer2: devices::CfgTimer,
     pub usec_timer: devices::UsecTimer,
-    pub firewire: devices::Firewire,
-    pub usb: devices::Usb,
+    pub firewire: ArcMutexDevice<devices::Firewire>,
+    pub usb: ArcMutexDevice<devices::Usb>,
     pub gpio_abcd: ArcMutexDevice<devices::GpioBlock>,
     pub gpio_efgh: ArcMutexDevice<devices::GpioBlock>,
     pub gpio_ijkl: ArcMutexDevice<devices::GpioBlock>,
@@ -380,6 +696,9 @@ pub struct Ipod4gBus {
     pub gpio_mirror_efgh: devices::GpioBlockAtomicMirror,
     pub gpio_mirror_ijkl: devices::GpioBlockAtomicMirror,
     pub i2ccon: devices::I2CCon,
+    pub pcf_rtc: devices::i2c::PcfRtc,
+    pub pcf_power: devices::i2c::PcfPower,
+    pub pcf_on_off: std::sync::Arc<devices::i2c::PcfOnOff>,
     pub opto: devices::OptoWheel,
     pub ppcon: devices::PPCon,
     pub devcon: devices::DevCon,
@@ -401,6 +720,8 @@ pub struct Ipod4gBus {
     pub mystery_flash_stub: devices::Stub,
     pub total_mystery: devices::Stub,
     pub pwmcon: devices::PWMCon,
+    pub backlight: Backlight,
+    pub gpio_outputs: GpioOutputs,
 
     pub pp5002_serial_stub: devices::Stub,
 }
@@ -411,6 +732,7 @@ impl Ipod4gBus {
         task_spawner: Spawner,
         irq_pending: irq::Pending,
         dma_pending: irq::Pending,
+        gpio_changed: gpio::Changed,
     ) -> Ipod4gBus {
         let (ide_irq_tx, ide_irq_rx) = irq::new(irq_pending.clone(), "IDE");
         let (timer1_irq_tx, timer1_irq_rx) = irq::new(irq_pending.clone(), "Timer1");
@@ -419,6 +741,10 @@ impl Ipod4gBus {
         let (gpio1_irq_tx, gpio1_irq_rx) = irq::new(irq_pending.clone(), "GPIO1");
         let (gpio2_irq_tx, gpio2_irq_rx) = irq::new(irq_pending.clone(), "GPIO2");
         let (i2c_irq_tx, i2c_irq_rx) = irq::new(irq_pending.clone(), "I2C");
+        let (ser0_irq_tx, ser0_irq_rx) = irq::new(irq_pending.clone(), "Serial0");
+        let (ser1_irq_tx, ser1_irq_rx) = irq::new(irq_pending.clone(), "Serial1");
+        let (usb_irq_tx, usb_irq_rx) = irq::new(irq_pending.clone(), "USB");
+        let (firewire_irq_tx, firewire_irq_rx) = irq::new(irq_pending.clone(), "FireWire");
 
         let (ide_dmarq_tx, ide_dmarq_rx) = irq::new(dma_pending.clone(), "IDE DMA");
 
@@ -440,36 +766,73 @@ impl Ipod4gBus {
             .register(1, timer2_irq_rx)
             .register_core_specific(4, mbx_cpu_irq_rx, mbx_cop_irq_rx)
             // .register(10, i2s_irq_rx)
-            // .register(20, usb_irq_rx)
+            .register(20, usb_irq_rx)
             .register(23, ide_irq_rx)
-            // .register(25, firewire_irq_rx)
+            .register(25, firewire_irq_rx)
             // .register(26, dma_irq_rx)
             .register(32, gpio0_irq_rx)
             .register(33, gpio1_irq_rx)
             .register(34, gpio2_irq_rx)
-            // .register(36, ser0_irq_rx)
-            // .register(37, ser1_irq_rx)
+            .register(36, ser0_irq_rx)
+            .register(37, ser1_irq_rx)
             .register(40, i2c_irq_rx);
 
         let dmacon0 = DmaCon::new("0", Some(ide_dmarq_rx));
         // the undocumented second engine -- nothing routes DMA requests to it yet
         let dmacon1 = DmaCon::new("1", None);
 
+        let pwmcon = PWMCon::new();
+
+        let gpio_outputs = GpioOutputs::new(
+            gpio_changed.clone(),
+            [
+                &mut gpio_abcd.lock().unwrap(),
+                &mut gpio_efgh.lock().unwrap(),
+                &mut gpio_ijkl.lock().unwrap(),
+            ],
+        );
+        let backlight = Backlight::new(
+            gpio_outputs.line(Ipod4gOutput::Backlight),
+            pwmcon.channel(1),
+        );
+
+        // XXX: unconfirmed. None of the available references (Rockbox's
+        // pp5020.h / ipod4g.h, ipodloader, ipodloader2) say which GPIO the
+        // PCF5060x's INT line is wired to. GPIOB[6] is the only port B pin none
+        // of them touch (ipodloader's opto keypad code drives GPIOB[7]).
+        //
+        // Firmware only enables interrupts on pins it knows about, so if this
+        // turns out to be the wrong pin, alarms just won't interrupt the CPU.
+        //
+        // INT stays asserted until the firmware reads the PCF's INT registers,
+        // so it's edge-triggered to avoid re-interrupting in the meantime.
+        let (pcf_int_tx, pcf_int_rx) = gpio::new(gpio_changed, "PCF5060x INT");
+        gpio_abcd
+            .lock()
+            .unwrap()
+            .register_in(8 + 6, pcf_int_rx)
+            .set_trigger(8 + 6, devices::InterruptTrigger::Edge);
+
+        let pcf = i2c::Pcf5060x::new(task_spawner.clone(), pcf_int_tx);
+        let pcf_rtc = pcf.rtc();
+        let pcf_power = pcf.power();
+        let pcf_on_off = pcf.on_off();
+
         let mut i2ccon = I2CCon::new(i2c_irq_tx.clone());
-        i2ccon.register_device(0x08, Box::new(i2c::Pcf5060x::new()));
+        i2ccon.register_device(0x08, Box::new(pcf));
 
         use devices::*;
         Ipod4gBus {
             sdram: AsanRam::new(32 * 1024 * 1024, true), // 32 MB
             fastram: AsanRam::new(96 * 1024, true),      // 96 KB
             cpuid: CpuIdReg::new(),
-            firewire: Firewire::new(),
-            usb: Usb::new(),
+            firewire: ArcMutexDevice::new(Firewire::new(firewire_irq_tx)),
+            usb: ArcMutexDevice::new(Usb::new(usb_irq_tx)),
             flash: Flash::new(),
             cpucon: CpuCon::new(task_spawner.clone()),
             hd66753: Hd66753::new(),
             timer1: CfgTimer::new("1", timer1_irq_tx, task_spawner.clone()),
-            timer2: CfgTimer::new("2", timer2_irq_tx, task_spawner),
+            timer2: CfgTimer::new("2", timer2_irq_tx, task_spawner.clone()),
             usec_timer: UsecTimer::new(),
             gpio_abcd,
             gpio_efgh,
@@ -478,19 +841,22 @@ impl Ipod4gBus {
             gpio_mirror_efgh: GpioBlockAtomicMirror::new(gpio_mirror_efgh),
             gpio_mirror_ijkl: GpioBlockAtomicMirror::new(gpio_mirror_ijkl),
             i2ccon,
+            pcf_rtc,
+            pcf_power,
+            pcf_on_off,
             opto: OptoWheel::new(i2c_irq_tx),
             ppcon: PPCon::new(),
             devcon: DevCon::new(),
             intcon,
-            eidecon: EIDECon::new(ide_irq_tx, ide_dmarq_tx),
+            eidecon: EIDECon::new(ide_irq_tx, ide_dmarq_tx, task_spawner.clone()),
             memcon: MemCon::new(),
             cachecon: CacheCon::new(),
             i2s: I2SCon::new(),
             mailbox: Mailbox::new(mbx_cpu_irq_tx, mbx_cop_irq_tx),
             dmacon0,
             dmacon1,
-            serial0: Serial::new("0"),
-            serial1: Serial::new("1"),
+            serial0: Serial::new("0", ser0_irq_tx, task_spawner.clone()),
+            serial1: Serial::new("1", ser1_irq_tx, task_spawner),
             evp: Evp::new(),
             rtc: Rtc::new(),
 
@@ -498,7 +864,9 @@ impl Ipod4gBus {
             mystery_lcd_con: Stub::new("Mystery LCD Con?"),
             mystery_flash_stub: Stub::new("Mystery FlashROM Con?"),
             total_mystery: Stub::new("(?) Arbiter Priority"),
-            pwmcon: PWMCon::new(),
+            pwmcon,
+            backlight,
+            gpio_outputs,
 
             pp5002_serial_stub: Stub::new("PP5002 serial stub"),
         }
@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::signal::gpio;
+
+use super::devices::GpioBlock;
+
+/// Called with the new state of a GPIO output whenever it changes.
+pub type OutputCallback = Box<dyn FnMut(bool) + Send>;
+
+/// Named GPIO outputs driven by the firmware.
+#[derive(Debug, Copy, Clone, Hash, Eq, PartialEq)]
+pub enum Ipod4gOutput {
+    LcdPower,
+    Backlight,
+    HddPower,
+    CodecEnable,
+}
+
+impl Ipod4gOutput {
+    /// Every named GPIO output.
+    pub const ALL: [Ipod4gOutput; 4] = [
+        Ipod4gOutput::LcdPower,
+        Ipod4gOutput::Backlight,
+        Ipod4gOutput::HddPower,
+        Ipod4gOutput::CodecEnable,
+    ];
+
+    /// The GPIO pin the output is wired to (numbered from A0 through L7), and
+    /// whether the output is active-high.
+    fn gpio(self) -> (usize, bool) {
+        const B: usize = 8;
+        const C: usize = 8 * 2;
+        const J: usize = 8 * 9;
+
+        match self {
+            // XXX: unconfirmed. None of the available references (Rockbox,
+            // ipodloader, ipodloader2) switch the LCD's supply via GPIO
+            // (ipodloader2 only uses the LCD controller's display control
+            // register), so this sits next to the backlight pin until someone
+            // probes a real board.
+            Ipod4gOutput::LcdPower => (B + 2, true),
+            // ipodloader2: `outl(((0x100 | (on ? 1 : 0)) << 3), 0x6000d824);`
+            Ipod4gOutput::Backlight => (B + 3, true),
+            // rockbox (ide_power_enable): `GPIO_CLEAR_BITWISE(GPIOJ_OUTPUT_VAL, 0x04)`
+            Ipod4gOutput::HddPower => (J + 2, false),
+            // XXX: unconfirmed, for the same reason. C2 is the dock detect
+            // input, and C4 / C5 are handed over to the I2C controller by
+            // ipodloader2's ipod_i2c_init.
+            Ipod4gOutput::CodecEnable => (C + 3, true),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            Ipod4gOutput::LcdPower => "LCD Power",
+            Ipod4gOutput::Backlight => "Backlight",
+            Ipod4gOutput::HddPower => "HDD Power",
+            Ipod4gOutput::CodecEnable => "Codec Enable",
+        }
+    }
+}
+
+struct Output {
+    line: gpio::Reciever,
+    active_high: bool,
+    was_on: bool,
+    observers: Vec<OutputCallback>,
+}
+
+impl Output {
+    fn is_on(&self) -> bool {
+        self.line.is_high() == self.active_high
+    }
+}
+
+impl std::fmt::Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Output")
+            .field("line", &self.line)
+            .field("active_high", &self.active_high)
+            .field("was_on", &self.was_on)
+            .field("observers", &self.observers.len())
+            .finish()
+    }
+}
+
+/// Handle to the Ipod4g's named GPIO outputs.
+///
+/// Observers are notified whenever the firmware switches an output on / off.
+/// Each output's polarity is accounted for, so "on" always means "powered" /
+/// "enabled".
+#[derive(Debug, Clone)]
+pub struct GpioOutputs {
+    outputs: Arc<Mutex<HashMap<Ipod4gOutput, Output>>>,
+}
+
+impl GpioOutputs {
+    /// `blocks` are the A-D, E-H, and I-L GPIO blocks (in that order).
+    pub(super) fn new(gpio_changed: gpio::Changed, blocks: [&mut GpioBlock; 3]) -> GpioOutputs {
+        let mut outputs = HashMap::new();
+        for &output in Ipod4gOutput::ALL.iter() {
+            let (idx, active_high) = output.gpio();
+            let (tx, rx) = gpio::new(gpio_changed.clone(), output.label());
+            blocks[idx / 32].register_out(idx % 32, tx);
+
+            outputs.insert(
+                output,
+                Output {
+                    was_on: rx.is_high() == active_high,
+                    line: rx,
+                    active_high,
+                    observers: Vec::new(),
+                },
+            );
+        }
+
+        GpioOutputs {
+            outputs: Arc::new(Mutex::new(outputs)),
+        }
+    }
+
+    /// Return the raw GPIO line backing an output.
+    pub(super) fn line(&self, output: Ipod4gOutput) -> gpio::Reciever {
+        self.outputs.lock().unwrap()[&output].line.clone()
+    }
+
+    /// Check if an output is currently on.
+    pub fn is_on(&self, output: Ipod4gOutput) -> bool {
+        self.outputs.lock().unwrap()[&output].is_on()
+    }
+
+    /// Register a callback which is invoked whenever `output` is switched on /
+    /// off.
+    ///
+    /// Callbacks are invoked from the thread running the system.
+    pub fn subscribe(&self, output: Ipod4gOutput, callback: OutputCallback) {
+        let mut outputs = self.outputs.lock().unwrap();
+        let output = outputs
+            .get_mut(&output)
+            .expect("all outputs are registered");
+        output.observers.push(callback);
+    }
+
+    /// Notify observers of any outputs which changed since the last call to
+    /// `notify`.
+    pub(super) fn notify(&self) {
+        let mut changed = Vec::new();
+        {
+            let mut outputs = self.outputs.lock().unwrap();
+            for (&name, output) in outputs.iter_mut() {
+                let on = output.is_on();
+                if on != output.was_on {
+                    output.was_on = on;
+                    changed.push((name, on, std::mem::take(&mut output.observers)));
+                }
+            }
+        }
+
+        // callbacks are invoked without holding the lock, so that they're free
+        // to query / subscribe to outputs themselves
+        for (name, on, mut observers) in changed {
+            debug!(
+                "{} switched {}",
+                name.label(),
+                if on { "on" } else { "off" }
+            );
+            for observer in observers.iter_mut() {
+                observer(on)
+            }
+
+            let mut outputs = self.outputs.lock().unwrap();
+            let output = outputs.get_mut(&name).unwrap();
+            observers.append(&mut output.observers);
+            output.observers = observers;
+        }
+    }
+}
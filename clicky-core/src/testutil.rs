@@ -0,0 +1,74 @@
+//! Helpers shared between unit tests.
+
+use std::fs::{self, File, OpenOptions};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A file / directory in the system's temp directory, which is deleted when
+/// dropped.
+///
+/// Tests run in parallel, so `name` must be unique across all tests.
+pub struct TempPath(PathBuf);
+
+impl TempPath {
+    fn new(name: &str) -> TempPath {
+        let path =
+            std::env::temp_dir().join(format!("clicky-test-{}-{}", std::process::id(), name));
+        // clean up after any previous (crashed) run
+        let _ = fs::remove_dir_all(&path);
+        let _ = fs::remove_file(&path);
+        TempPath(path)
+    }
+
+    /// Create a temporary file containing `contents`.
+    pub fn file(name: &str, contents: &[u8]) -> TempPath {
+        let path = TempPath::new(name);
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Create an empty temporary directory.
+    pub fn dir(name: &str) -> TempPath {
+        let path = TempPath::new(name);
+        fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    /// Open the file for reading and writing.
+    pub fn open(&self) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.0)
+            .unwrap()
+    }
+
+    /// Read the entire file.
+    pub fn contents(&self) -> Vec<u8> {
+        fs::read(&self.0).unwrap()
+    }
+}
+
+impl AsRef<Path> for TempPath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Deref for TempPath {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempPath {
+    fn drop(&mut self) {
+        if self.0.is_dir() {
+            let _ = fs::remove_dir_all(&self.0);
+        } else {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
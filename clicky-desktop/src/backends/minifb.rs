@@ -1,13 +1,14 @@
 use std::collections::HashMap;
 use std::sync::mpsc as chan;
 
-use minifb::{Key, Window, WindowOptions};
+use minifb::{Key, MouseButton, MouseMode, Window, WindowOptions};
 
-use clicky_core::gui::{ButtonCallback, RenderCallback, ScrollCallback};
+use clicky_core::gui::{ButtonCallback, DragCallback, RenderCallback, ScrollCallback};
 
 pub struct MinifbControls {
     pub keymap: HashMap<Key, ButtonCallback>,
     pub on_scroll: Option<ScrollCallback>,
+    pub on_drag: Option<DragCallback>,
 }
 
 #[derive(Debug)]
@@ -44,6 +45,7 @@ impl MinifbRenderer {
         window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
 
         let mut key_down: HashMap<Key, bool> = HashMap::new();
+        let mut dragging = false;
         'ui_loop: while window.is_open() && kill_rx.try_recv().is_err() {
             if window.is_key_down(Key::Escape) {
                 break 'ui_loop;
@@ -64,6 +66,28 @@ impl MinifbRenderer {
                 }
             }
 
+            if let Some(ref mut on_drag) = controls.on_drag {
+                let pos = match window.get_mouse_down(MouseButton::Left) {
+                    true => window.get_unscaled_mouse_pos(MouseMode::Discard),
+                    false => None,
+                };
+
+                match pos {
+                    Some((x, y)) => {
+                        // normalize to the center of the window
+                        let (w, h) = window.get_size();
+                        let (w, h) = (w as f32 / 2., h as f32 / 2.);
+                        dragging = true;
+                        on_drag(Some(((x - w) / w, (y - h) / h)))
+                    }
+                    None if dragging => {
+                        dragging = false;
+                        on_drag(None)
+                    }
+                    None => {}
+                }
+            }
+
             // update the framebuffer
             let (w, _h) = update_fb(&mut emu_buffer);
 
@@ -0,0 +1,263 @@
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use structopt::StructOpt;
+
+use clicky_core::sys::ipod4g::firmware::{self, Firmware, ImageInfo};
+
+pub type DynResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const MANIFEST_FILENAME: &str = "manifest.txt";
+const EXT_HEADER_FILENAME: &str = "ext_header.bin";
+
+#[derive(StructOpt)]
+#[structopt(name = "clicky-fw")]
+#[structopt(about = r#"
+Utilities for working with Apple iPod firmware images.
+"#)]
+enum Args {
+    /// List the images contained in a firmware.
+    List {
+        #[structopt(parse(from_os_str))]
+        fw: PathBuf,
+    },
+    /// Check that a firmware's image checksums are valid, and that it contains
+    /// an `osos` image (i.e: that the HLE bootloader will accept it).
+    Verify {
+        #[structopt(parse(from_os_str))]
+        fw: PathBuf,
+    },
+    /// Extract each image to a separate file, along with a manifest which can
+    /// be used to repack the firmware (see `pack`).
+    Extract {
+        #[structopt(parse(from_os_str))]
+        fw: PathBuf,
+        #[structopt(parse(from_os_str))]
+        out_dir: PathBuf,
+    },
+    /// Build a firmware from a directory containing a manifest.
+    ///
+    /// Each line of the manifest describes an image, using a comma-separated
+    /// list of `key=value` pairs: `name`, `dev`, `file` (relative to the
+    /// manifest), and (optionally) `id`, `addr`, `entry`, `vers`, and `load`.
+    /// An optional header line sets the firmware's `version`, `dir_offset`,
+    /// `ext_header_loc`, and `ext_header` (a file containing everything
+    /// between the volume header and the image directory).
+    Pack {
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+    /// Replace a firmware's `osos` image with a custom image (e.g: a
+    /// third-party bootloader), in the same way as `ipodpatcher`.
+    InsertOs {
+        #[structopt(parse(from_os_str))]
+        fw: PathBuf,
+        #[structopt(parse(from_os_str))]
+        os: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+
+        /// Append the original OS image after the custom image, so that it
+        /// can be chain-loaded.
+        #[structopt(long)]
+        keep_original: bool,
+    },
+}
+
+fn load(path: &Path) -> DynResult<Firmware> {
+    let mut file = BufReader::new(fs::File::open(path)?);
+    Ok(Firmware::load(&mut file)?)
+}
+
+fn save(fw: &mut Firmware, path: &Path) -> DynResult<()> {
+    let mut out = Vec::new();
+    fw.write(&mut out)?;
+    fs::write(path, out)?;
+    Ok(())
+}
+
+fn fourcc(s: &str) -> DynResult<[u8; 4]> {
+    let mut cc = [0; 4];
+    if s.len() != 4 {
+        return Err(format!("`{}` must be exactly 4 characters", s).into());
+    }
+    cc.copy_from_slice(s.as_bytes());
+    Ok(cc)
+}
+
+fn parse_u32(s: &str) -> DynResult<u32> {
+    let val = match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+    val.map_err(|_| format!("invalid number `{}`", s).into())
+}
+
+fn manifest_line(info: &ImageInfo, file: &str) -> String {
+    format!(
+        "name={},dev={},id={:#x},addr={:#x},entry={:#x},vers={:#x},load={:#x},file={}",
+        info.name_str(),
+        String::from_utf8_lossy(&info.dev),
+        info.id,
+        info.addr,
+        info.entry_offset,
+        info.vers,
+        info.load_addr,
+        file
+    )
+}
+
+fn parse_manifest(dir: &Path) -> DynResult<Firmware> {
+    let manifest = fs::read_to_string(dir.join(MANIFEST_FILENAME))?;
+    let mut fw = Firmware::new();
+
+    for line in manifest.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut info = ImageInfo {
+            dev: *b"disk",
+            name: [0; 4],
+            id: 0,
+            dev_offset: 0,
+            len: 0,
+            addr: 0,
+            entry_offset: 0,
+            checksum: 0,
+            vers: 0,
+            load_addr: 0,
+        };
+        let mut file = None;
+
+        for arg in line.split(',') {
+            let mut kv = arg.splitn(2, '=');
+            let key = kv.next().unwrap();
+            let val = kv
+                .next()
+                .ok_or_else(|| format!("missing value for `{}`", key))?;
+            match key {
+                "version" => fw.header.format_version = val.parse()?,
+                "dir_offset" => fw.header.dir_offset = parse_u32(val)?,
+                "ext_header_loc" => {
+                    fw.header.ext_header_loc = u16::try_from(parse_u32(val)?)
+                        .map_err(|_| format!("`ext_header_loc` out of range: {}", val))?
+                }
+                "ext_header" => fw.ext_header = fs::read(dir.join(val))?,
+                "name" => info.name = fourcc(val)?,
+                "dev" => info.dev = fourcc(val)?,
+                "id" => info.id = parse_u32(val)?,
+                "addr" => info.addr = parse_u32(val)?,
+                "entry" => info.entry_offset = parse_u32(val)?,
+                "vers" => info.vers = parse_u32(val)?,
+                "load" => info.load_addr = parse_u32(val)?,
+                "file" => file = Some(val),
+                _ => return Err(format!("unknown manifest key `{}`", key).into()),
+            }
+        }
+
+        if let Some(file) = file {
+            if info.name == [0; 4] {
+                return Err("missing `name` for image".into());
+            }
+            fw.images.push((info, fs::read(dir.join(file))?));
+        }
+    }
+
+    Ok(fw)
+}
+
+fn main() -> DynResult<()> {
+    match Args::from_args() {
+        Args::List { fw } => {
+            let fw = load(&fw)?;
+            println!("format version: {}", fw.header.format_version);
+            println!("name dev  id         addr       entry      len        checksum        vers       load");
+            for (info, data) in &fw.images {
+                let ok = firmware::checksum(data) == info.checksum;
+                println!(
+                    "{} {} {:#010x} {:#010x} {:#010x} {:#010x} {:#010x} {} {:#010x} {:#010x}",
+                    info.name_str(),
+                    String::from_utf8_lossy(&info.dev),
+                    info.id,
+                    info.addr,
+                    info.entry_offset,
+                    info.len,
+                    info.checksum,
+                    if ok { "(ok) " } else { "(BAD)" },
+                    info.vers,
+                    info.load_addr,
+                );
+            }
+        }
+        Args::Verify { fw } => {
+            let fw = load(&fw)?;
+            let mut ok = true;
+            for (info, data) in &fw.images {
+                let sum = firmware::checksum(data);
+                if sum != info.checksum {
+                    println!(
+                        "{}: bad checksum (expected {:#010x}, got {:#010x})",
+                        info.name_str(),
+                        info.checksum,
+                        sum
+                    );
+                    ok = false;
+                }
+            }
+            if fw.image(b"osos").is_none() {
+                println!("missing `osos` image");
+                ok = false;
+            }
+
+            if !ok {
+                return Err("firmware failed verification".into());
+            }
+            println!("firmware ok ({} images)", fw.images.len());
+        }
+        Args::Extract { fw, out_dir } => {
+            let fw = load(&fw)?;
+            fs::create_dir_all(&out_dir)?;
+
+            let mut manifest = fs::File::create(out_dir.join(MANIFEST_FILENAME))?;
+            write!(
+                manifest,
+                "version={},dir_offset={:#x},ext_header_loc={:#x}",
+                fw.header.format_version, fw.header.dir_offset, fw.header.ext_header_loc
+            )?;
+            if !fw.ext_header.is_empty() {
+                fs::write(out_dir.join(EXT_HEADER_FILENAME), &fw.ext_header)?;
+                write!(manifest, ",ext_header={}", EXT_HEADER_FILENAME)?;
+            }
+            writeln!(manifest)?;
+            for (i, (info, data)) in fw.images.iter().enumerate() {
+                // image names aren't necessarily unique
+                let file = format!("{}.{}.bin", i, info.name_str());
+                fs::write(out_dir.join(&file), data)?;
+                writeln!(manifest, "{}", manifest_line(info, &file))?;
+                println!("extracted {} ({} bytes)", file, data.len());
+            }
+        }
+        Args::Pack { dir, output } => {
+            let mut fw = parse_manifest(&dir)?;
+            save(&mut fw, &output)?;
+            println!("packed {} images", fw.images.len());
+        }
+        Args::InsertOs {
+            fw,
+            os,
+            output,
+            keep_original,
+        } => {
+            let mut fw = load(&fw)?;
+            fw.insert_os(&fs::read(os)?, keep_original)?;
+            save(&mut fw, &output)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,227 @@
+use std::fs;
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use structopt::StructOpt;
+
+use clicky_core::block::backend::{Compressed, Vvfat};
+use clicky_core::block::BlockDev;
+use clicky_core::sys::ipod4g::firmware::FirmwareMeta;
+
+#[path = "../capacity.rs"]
+mod capacity;
+
+pub type DynResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(StructOpt)]
+#[structopt(name = "clicky-img")]
+#[structopt(about = r#"
+Utilities for working with clicky disk images.
+"#)]
+enum Args {
+    /// Build an iPod HDD image from scratch: a firmware partition, followed
+    /// by a FAT32 data partition.
+    Build {
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+
+        /// Size of the image (e.g: `64MiB`, `8GiB`).
+        #[structopt(long, default_value = "64MiB", parse(try_from_str = parse_capacity))]
+        len: u64,
+        /// Firmware image to place in the firmware partition.
+        #[structopt(long, parse(from_os_str))]
+        firmware: Option<PathBuf>,
+        /// Directory whose contents are copied into the data partition.
+        #[structopt(long, parse(from_os_str))]
+        files: Option<PathBuf>,
+        /// Patch the firmware's `aupd` image entry, which disables the flash
+        /// ROM update that runs on boot (which doesn't work in clicky yet).
+        #[structopt(long)]
+        patch_aupd: bool,
+        /// Use an Apple partition map (i.e: MacPod formatting) instead of an
+        /// MBR (i.e: WinPod formatting). The data partition is FAT32 either
+        /// way.
+        #[structopt(long)]
+        apm: bool,
+    },
+    /// Copy files into the data partition of an existing image (e.g: one
+    /// created with `build`), without having to mount it. Existing files with
+    /// the same name are replaced.
+    Add {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        /// Directory whose contents are copied into the data partition.
+        #[structopt(parse(from_os_str))]
+        files: PathBuf,
+    },
+    /// Convert a raw disk image into a chunk-compressed image (for use with
+    /// `--hdd=compressed:file=...`).
+    Compress {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+
+        /// Size of each compressed chunk (in bytes). Smaller chunks make
+        /// random accesses cheaper, at the cost of a worse compression ratio.
+        #[structopt(long, default_value = "65536")]
+        chunk_size: u32,
+    },
+    /// Convert a chunk-compressed image back into a raw disk image.
+    Decompress {
+        #[structopt(parse(from_os_str))]
+        input: PathBuf,
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+    },
+}
+
+fn parse_capacity(desc: &str) -> Result<u64, &'static str> {
+    capacity::parse_capacity(desc).ok_or("invalid size")
+}
+
+/// Mark the firmware's `aupd` image as already applied, so that the
+/// bootloader doesn't try to run the flash ROM update.
+fn patch_aupd(fw: &mut [u8]) -> DynResult<()> {
+    let meta = FirmwareMeta::parse(&mut Cursor::new(&*fw))?;
+    let idx = (meta.images.iter())
+        .position(|img| img.name == *b"aupd")
+        .ok_or("firmware doesn't contain an `aupd` image")?;
+
+    // low byte of the image's `id` field
+    fw[meta.image_info_offset(idx) as usize + 8] = 1;
+    Ok(())
+}
+
+/// Generate an Apple partition map describing the same partitions as the
+/// image's MBR.
+fn apple_partition_map(mbr: &[u8], len: u64) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 512;
+
+    let mbr_part = |i: usize| {
+        let entry = &mbr[446 + i * 16..];
+        let u32_at =
+            |i: usize| u32::from_le_bytes([entry[i], entry[i + 1], entry[i + 2], entry[i + 3]]);
+        (u32_at(8), u32_at(12))
+    };
+    let (fw_start, fw_len) = mbr_part(0);
+    let (data_start, data_len) = mbr_part(1);
+
+    let partitions = [
+        ("Apple", "Apple_partition_map", 1, fw_start - 1, 0x03),
+        ("firmware", "Apple_MDFW", fw_start, fw_len, 0x33),
+        ("disk", "DOS_FAT_32", data_start, data_len, 0x33),
+    ];
+
+    // block 0 contains the driver descriptor record
+    let mut apm = vec![0; BLOCK_SIZE * (1 + partitions.len())];
+    apm[0..2].copy_from_slice(b"ER");
+    apm[2..4].copy_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+    apm[4..8].copy_from_slice(&((len / BLOCK_SIZE as u64) as u32).to_be_bytes());
+
+    for (i, (name, kind, start, len, status)) in partitions.iter().enumerate() {
+        let entry = &mut apm[(i + 1) * BLOCK_SIZE..][..BLOCK_SIZE];
+        entry[0..2].copy_from_slice(b"PM");
+        entry[4..8].copy_from_slice(&(partitions.len() as u32).to_be_bytes());
+        entry[8..12].copy_from_slice(&start.to_be_bytes());
+        entry[12..16].copy_from_slice(&len.to_be_bytes());
+        entry[16..16 + name.len()].copy_from_slice(name.as_bytes());
+        entry[48..48 + kind.len()].copy_from_slice(kind.as_bytes());
+        entry[84..88].copy_from_slice(&len.to_be_bytes());
+        entry[88..92].copy_from_slice(&(*status as u32).to_be_bytes());
+    }
+
+    apm
+}
+
+fn main() -> DynResult<()> {
+    match Args::from_args() {
+        Args::Build {
+            output,
+            len,
+            firmware,
+            files,
+            patch_aupd: should_patch_aupd,
+            apm,
+        } => {
+            let firmware = match firmware {
+                Some(path) => {
+                    let mut fw = fs::read(path)?;
+                    if should_patch_aupd {
+                        patch_aupd(&mut fw)?;
+                    }
+                    Some(fw.into_boxed_slice())
+                }
+                None if should_patch_aupd => {
+                    return Err("`--patch-aupd` requires a firmware".into())
+                }
+                None => None,
+            };
+
+            let mut img = match files {
+                Some(dir) => Vvfat::new(dir, firmware, len)?,
+                None => Vvfat::empty(firmware, len)?,
+            };
+
+            if apm {
+                let mut mbr = [0; 512];
+                img.seek(SeekFrom::Start(0))?;
+                img.read_exact(&mut mbr)?;
+                let apm = apple_partition_map(&mbr, len);
+                img.seek(SeekFrom::Start(0))?;
+                img.write_all(&apm)?;
+            }
+
+            // only write out non-zero blocks, keeping the output file sparse
+            let mut out = fs::File::create(&output)?;
+            let mut buf = vec![0; 1024 * 1024];
+            let mut pos = 0;
+            img.seek(SeekFrom::Start(0))?;
+            while pos < len {
+                let buf = &mut buf[..(len - pos).min(1024 * 1024) as usize];
+                img.read_exact(buf)?;
+                if buf.iter().any(|b| *b != 0) {
+                    out.seek(SeekFrom::Start(pos))?;
+                    out.write_all(buf)?;
+                }
+                pos += buf.len() as u64;
+            }
+            out.set_len(len)?;
+
+            println!("built {} byte image at {}", len, output.display());
+        }
+        Args::Add { image, files } => {
+            let mut img = fs::OpenOptions::new().read(true).write(true).open(&image)?;
+            Vvfat::add_files(&mut img, &files)?;
+            println!("copied {} into {}", files.display(), image.display());
+        }
+        Args::Compress {
+            input,
+            output,
+            chunk_size,
+        } => {
+            let raw = fs::File::open(input)?;
+            let len = raw.metadata()?.len();
+            let mut out = BufWriter::new(fs::File::create(&output)?);
+            Compressed::compress(&mut BufReader::new(raw), len, &mut out, chunk_size)?;
+            drop(out);
+
+            let compressed_len = fs::metadata(&output)?.len();
+            println!(
+                "compressed {} bytes into {} bytes ({:.1}%)",
+                len,
+                compressed_len,
+                compressed_len as f64 * 100.0 / len.max(1) as f64
+            );
+        }
+        Args::Decompress { input, output } => {
+            let mut img = Compressed::new(fs::File::open(input)?)?;
+            let len = img.len();
+            let mut out = BufWriter::new(fs::File::create(output)?);
+            io::copy(&mut img, &mut out)?;
+            println!("decompressed {} bytes", len);
+        }
+    }
+
+    Ok(())
+}
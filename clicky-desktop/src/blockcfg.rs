@@ -1,27 +1,82 @@
 use std::str::FromStr;
 
+use clicky_core::devices::generic::ide::IdeDriveProfile;
+
+use crate::capacity::parse_capacity;
+
 /// Helper struct to parse Block Device configurations.
+///
+/// Every kind also accepts a `drive=<profile>` option, which selects the
+/// identity reported by the emulated drive (one of `generic`, `mk2004gal`,
+/// `mk4004gah`, `microdrive`, `cf`, or `iflash`).
 pub enum BlockCfg {
     /// `null:len=<len>`
-    Null { len: u64 },
+    Null {
+        len: u64,
+        drive: Option<IdeDriveProfile>,
+    },
     /// `raw:file=/path/`
-    Raw { path: String },
+    Raw {
+        path: String,
+        drive: Option<IdeDriveProfile>,
+    },
     /// `mem:file=/path/[,truncate=<len>]`
-    Mem { path: String, truncate: Option<u64> },
+    Mem {
+        path: String,
+        truncate: Option<u64>,
+        drive: Option<IdeDriveProfile>,
+    },
+    /// `compressed:file=/path/`
+    ///
+    /// A chunk-compressed image (see `clicky-img compress`).
+    Compressed {
+        path: String,
+        drive: Option<IdeDriveProfile>,
+    },
+    /// `cow:base=/path/,delta=/path/[,delta=/path/...][,commit][,discard]`
+    ///
+    /// Multiple deltas are stacked on top of one another, with only the last
+    /// one being written to. `commit` merges the last delta into the layer
+    /// beneath it before starting, whereas `discard` starts off with an empty
+    /// delta.
+    Cow {
+        base: String,
+        deltas: Vec<String>,
+        commit: bool,
+        discard: bool,
+        drive: Option<IdeDriveProfile>,
+    },
+    /// `vvfat:dir=/path/[,firmware=/path/][,len=<len>]`
+    ///
+    /// A disk synthesized from the contents of a host directory, with an
+    /// optional firmware image placed in the firmware partition. `len`
+    /// defaults to 2GiB. Writes are discarded on exit.
+    Vvfat {
+        dir: String,
+        firmware: Option<String>,
+        len: u64,
+        drive: Option<IdeDriveProfile>,
+    },
 }
 
-fn parse_capacity(desc: &str) -> Option<u64> {
-    use human_size::{Byte, ParsingError, Size, SpecificSize};
-    match desc.parse::<Size>() {
-        Ok(s) => {
-            let bytes: SpecificSize<Byte> = s.into();
-            Some(bytes.value() as u64)
+impl BlockCfg {
+    /// The drive profile selected via the `drive` option (if any).
+    pub fn drive(&self) -> Option<&IdeDriveProfile> {
+        match self {
+            BlockCfg::Null { drive, .. } => drive.as_ref(),
+            BlockCfg::Raw { drive, .. } => drive.as_ref(),
+            BlockCfg::Mem { drive, .. } => drive.as_ref(),
+            BlockCfg::Compressed { drive, .. } => drive.as_ref(),
+            BlockCfg::Cow { drive, .. } => drive.as_ref(),
+            BlockCfg::Vvfat { drive, .. } => drive.as_ref(),
         }
-        Err(ParsingError::MissingMultiple) => desc.parse::<u64>().ok(),
-        Err(_) => None,
     }
 }
 
+fn parse_drive(arg: Option<&str>) -> Result<IdeDriveProfile, &'static str> {
+    arg.ok_or("missing argument for `drive`")?.parse()
+}
+
 impl FromStr for BlockCfg {
     type Err = &'static str;
 
@@ -33,6 +88,7 @@ impl FromStr for BlockCfg {
                 let s = s.next().ok_or("missing required options")?.split(',');
 
                 let mut len = None;
+                let mut drive = None;
 
                 for arg in s {
                     let mut s = arg.split('=');
@@ -44,18 +100,21 @@ impl FromStr for BlockCfg {
                                     .ok_or("could not parse `len`")?,
                             );
                         }
+                        "drive" => drive = Some(parse_drive(s.next())?),
                         _ => return Err("unknown `null` option"),
                     }
                 }
 
                 BlockCfg::Null {
                     len: len.ok_or("missing `len` parameter")?,
+                    drive,
                 }
             }
             "raw" => {
                 let s = s.next().ok_or("missing required options")?.split(',');
 
                 let mut file = None;
+                let mut drive = None;
 
                 for arg in s {
                     let mut s = arg.split('=');
@@ -64,12 +123,14 @@ impl FromStr for BlockCfg {
                         "file" => {
                             file = Some(s.next().ok_or("missing argument for `file`")?.into())
                         }
+                        "drive" => drive = Some(parse_drive(s.next())?),
                         _ => return Err("unknown `len` option"),
                     }
                 }
 
                 BlockCfg::Raw {
                     path: file.ok_or("missing `file` parameter")?,
+                    drive,
                 }
             }
             "mem" => {
@@ -77,6 +138,7 @@ impl FromStr for BlockCfg {
 
                 let mut file = None;
                 let mut truncate = None;
+                let mut drive = None;
 
                 for arg in s {
                     let mut s = arg.split('=');
@@ -91,6 +153,7 @@ impl FromStr for BlockCfg {
                                     .ok_or("could not parse `truncate`")?,
                             )
                         }
+                        "drive" => drive = Some(parse_drive(s.next())?),
                         _ => return Err("unknown `len` option"),
                     }
                 }
@@ -98,6 +161,103 @@ impl FromStr for BlockCfg {
                 BlockCfg::Mem {
                     path: file.ok_or("missing `file` parameter")?,
                     truncate,
+                    drive,
+                }
+            }
+            "compressed" => {
+                let s = s.next().ok_or("missing required options")?.split(',');
+
+                let mut file = None;
+                let mut drive = None;
+
+                for arg in s {
+                    let mut s = arg.split('=');
+                    let kind = s.next().unwrap();
+                    match kind {
+                        "file" => {
+                            file = Some(s.next().ok_or("missing argument for `file`")?.into())
+                        }
+                        "drive" => drive = Some(parse_drive(s.next())?),
+                        _ => return Err("unknown `compressed` option"),
+                    }
+                }
+
+                BlockCfg::Compressed {
+                    path: file.ok_or("missing `file` parameter")?,
+                    drive,
+                }
+            }
+            "cow" => {
+                let s = s.next().ok_or("missing required options")?.split(',');
+
+                let mut base = None;
+                let mut deltas = Vec::new();
+                let mut commit = false;
+                let mut discard = false;
+                let mut drive = None;
+
+                for arg in s {
+                    let mut s = arg.split('=');
+                    let kind = s.next().unwrap();
+                    match kind {
+                        "base" => {
+                            base = Some(s.next().ok_or("missing argument for `base`")?.into())
+                        }
+                        "delta" => {
+                            deltas.push(s.next().ok_or("missing argument for `delta`")?.into())
+                        }
+                        "commit" => commit = true,
+                        "discard" => discard = true,
+                        "drive" => drive = Some(parse_drive(s.next())?),
+                        _ => return Err("unknown `cow` option"),
+                    }
+                }
+
+                if deltas.is_empty() {
+                    return Err("missing `delta` parameter");
+                }
+
+                BlockCfg::Cow {
+                    base: base.ok_or("missing `base` parameter")?,
+                    deltas,
+                    commit,
+                    discard,
+                    drive,
+                }
+            }
+            "vvfat" => {
+                let s = s.next().ok_or("missing required options")?.split(',');
+
+                let mut dir = None;
+                let mut firmware = None;
+                let mut len = None;
+                let mut drive = None;
+
+                for arg in s {
+                    let mut s = arg.split('=');
+                    let kind = s.next().unwrap();
+                    match kind {
+                        "dir" => dir = Some(s.next().ok_or("missing argument for `dir`")?.into()),
+                        "firmware" => {
+                            firmware =
+                                Some(s.next().ok_or("missing argument for `firmware`")?.into())
+                        }
+                        "len" => {
+                            len = Some(
+                                parse_capacity(s.next().ok_or("missing argument for `len`")?)
+                                    .ok_or("could not parse `len`")?,
+                            );
+                        }
+                        "drive" => drive = Some(parse_drive(s.next())?),
+                        _ => return Err("unknown `vvfat` option"),
+                    }
+                }
+
+                BlockCfg::Vvfat {
+                    dir: dir.ok_or("missing `dir` parameter")?,
+                    firmware,
+                    len: len.unwrap_or(2 * 1024 * 1024 * 1024),
+                    drive,
                 }
             }
             _ => return Err("invalid block kind"),
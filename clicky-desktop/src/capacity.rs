@@ -0,0 +1,13 @@
+/// Parse a human-readable size (e.g: `64MiB`, `8GiB`), or a plain number of
+/// bytes.
+pub fn parse_capacity(desc: &str) -> Option<u64> {
+    use human_size::{Byte, ParsingError, Size, SpecificSize};
+    match desc.parse::<Size>() {
+        Ok(s) => {
+            let bytes: SpecificSize<Byte> = s.into();
+            Some(bytes.value() as u64)
+        }
+        Err(ParsingError::MissingMultiple) => desc.parse::<u64>().ok(),
+        Err(_) => None,
+    }
+}
@@ -1,4 +1,4 @@
-use clicky_core::sys::ipod4g::{Ipod4gBinds, Ipod4gKey};
+use clicky_core::sys::ipod4g::{Ipod4gBinds, Ipod4gInput, Ipod4gKey};
 use minifb::Key;
 
 use crate::backends::minifb::MinifbControls;
@@ -14,16 +14,36 @@ fn ipod4g_key_to_minifb(key: Ipod4gKey) -> Key {
     }
 }
 
+fn ipod4g_input_to_minifb(input: Ipod4gInput) -> Key {
+    match input {
+        Ipod4gInput::Headphones => Key::J,
+        Ipod4gInput::Dock => Key::D,
+        Ipod4gInput::Remote => Key::R,
+        Ipod4gInput::LineIn => Key::L,
+    }
+}
+
 impl From<Ipod4gBinds> for MinifbControls {
     fn from(binds: Ipod4gBinds) -> MinifbControls {
-        let Ipod4gBinds { keys, wheel } = binds;
+        let Ipod4gBinds {
+            keys,
+            inputs,
+            wheel,
+            wheel_drag,
+        } = binds;
 
         MinifbControls {
             keymap: keys
                 .into_iter()
                 .map(|(k, v)| (ipod4g_key_to_minifb(k), v))
+                .chain(
+                    inputs
+                        .into_iter()
+                        .map(|(i, v)| (ipod4g_input_to_minifb(i), v)),
+                )
                 .collect(),
             on_scroll: wheel,
+            on_drag: wheel_drag,
         }
     }
 }
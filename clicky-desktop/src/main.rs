@@ -10,16 +10,25 @@ pub type DynResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
 use structopt::StructOpt;
 
 use clicky_core::block::{self, BlockDev};
+use clicky_core::devices::generic::ide::{IdeFaults, IdeTiming, SmartConfig};
+use clicky_core::devices::i2c::devices::PowerSource;
 use clicky_core::gui::TakeControls;
-use clicky_core::sys::ipod4g::{BootKind, Ipod4g, Ipod4gGdb};
+use clicky_core::serial::{self, SerialBackend};
+use clicky_core::sys::ipod4g::{
+    BootKind, HleImage, Ipod4g, Ipod4gGdb, Ipod4gInput, Ipod4gKey, SerialIdx,
+};
 
 mod backends;
 mod blockcfg;
+mod capacity;
 mod controls;
 mod gdb;
+mod serialcfg;
+mod usbip;
 
 use crate::blockcfg::BlockCfg;
 use crate::gdb::{make_gdbstub, GdbCfg};
+use crate::serialcfg::SerialCfg;
 
 const SYSDUMP_FILENAME: &str = "sysdump.log";
 
@@ -30,21 +39,73 @@ An emulator for the classic clickwheel iPod 4g.
 "#)]
 struct Args {
     /// Load a firmware file using the HLE bootloader.
-    #[structopt(long, parse(from_os_str))]
+    #[structopt(long, parse(from_os_str), conflicts_with("hle-from-hdd"))]
     hle: Option<PathBuf>,
 
+    /// Use the HLE bootloader, loading the firmware from the HDD's firmware
+    /// partition (just like the real bootloader).
+    #[structopt(long)]
+    hle_from_hdd: bool,
+
+    /// Firmware image to boot when using the HLE bootloader (one of `auto`,
+    /// `osos`, `aupd`, `diag`, or `disk`).
+    ///
+    /// `auto` emulates the stock bootloader's key combos (see `--boot-keys`).
+    #[structopt(long, default_value = "auto")]
+    hle_image: HleImage,
+
+    /// Comma-separated list of keys held down while the iPod boots (`menu`,
+    /// `play`, `prev`, `next`, `select`), e.g: `select,prev` to boot into
+    /// diagnostics, or `select,play` to boot into disk mode.
+    #[structopt(long, use_delimiter = true, parse(try_from_str = parse_boot_key))]
+    boot_keys: Vec<Ipod4gKey>,
+
     /// Path to dumped Flash ROM binary.
-    #[structopt(long, parse(from_os_str), required_unless("hle"))]
+    #[structopt(long, parse(from_os_str), required_unless_one(&["hle", "hle-from-hdd"]))]
     flash_rom: Option<PathBuf>,
 
+    /// Write any modifications made to the Flash ROM back to this file.
+    ///
+    /// When running without a `--flash-rom` dump, the file is only populated
+    /// once the guest erases / programs the flash (e.g: when running an Apple
+    /// updater), producing a new Flash ROM dump.
+    #[structopt(long, parse(from_os_str))]
+    flash_write_back: Option<PathBuf>,
+
     /// HDD image to use.
     ///
     /// At the moment, this should most likely be set to either
     /// `raw:file=/path/to/ipodhd.img` (for persistence) or
-    /// `mem:file=/path/to/ipodhd.img` (for testing).
+    /// `mem:file=/path/to/ipodhd.img` (for testing). Append
+    /// `,drive=<profile>` to emulate a specific drive model (one of
+    /// `generic`, `mk2004gal`, `mk4004gah`, `microdrive`, `cf`, or `iflash`).
     #[structopt(long)]
     hdd: BlockCfg,
 
+    /// Emulate the HDD's mechanical delays (spin-up, seek, and rotational
+    /// latency), instead of completing accesses immediately.
+    #[structopt(long)]
+    hdd_timing: bool,
+
+    /// Initial state of the HDD's SMART attributes, which then evolve as the
+    /// emulator runs.
+    ///
+    /// Format: `key=val[,key=val...]`, where `key` is one of `temp` (ambient
+    /// temperature), `hours` (power-on hours), `starts` (start/stop count),
+    /// `cycles` (power cycle count), or `realloc` (reallocated sectors).
+    #[structopt(long)]
+    hdd_smart: Option<SmartConfig>,
+
+    /// Inject faults into the HDD, emulating a dying disk.
+    ///
+    /// Format: a comma separated list of `unreadable=<start>-<end>`,
+    /// `slow=<start>-<end>:<delay ms>`, `badwrite=<start>-<end>`, and
+    /// `vanish=<num commands>`. Sector ranges are inclusive.
+    ///
+    /// e.g: `--hdd-faults=unreadable=1000-1099,vanish=5000`
+    #[structopt(long)]
+    hdd_faults: Option<IdeFaults>,
+
     /// Spawn a GDB server at system startup.
     ///
     /// Format: `-g <port/path>[,on-fatal-err[,and-on-start]]`
@@ -59,6 +120,118 @@ struct Args {
     /// connection before starting execution.
     #[structopt(short, long)]
     gdb: Option<GdbCfg>,
+
+    /// Host backend for the first serial port.
+    ///
+    /// One of `null`, `stdio`, `file:log=/path/to/log[,input=/path/to/input]`,
+    /// `tcp:port=<port>`, `pty`, or `iap:script=/path/to/script`.
+    ///
+    /// `tcp` waits for a connection on localhost before starting execution,
+    /// while `pty` prints the path of the newly allocated pseudo-terminal.
+    /// `iap` connects an emulated iAP accessory, driven by the provided script
+    /// (see `clicky_core::serial::iap::script` for details).
+    #[structopt(long, default_value = "stdio")]
+    serial0: SerialCfg,
+
+    /// Host backend for the second serial port. Accepts the same options as
+    /// `--serial0`.
+    #[structopt(long, default_value = "null")]
+    serial1: SerialCfg,
+
+    /// Serve the iPod's USB port over USB/IP on `127.0.0.1:<port>`.
+    ///
+    /// The device can then be attached using the standard Linux `usbip`
+    /// tooling, e.g: `usbip --tcp-port <port> attach -r 127.0.0.1 -b 1-1`.
+    #[structopt(long)]
+    usbip: Option<u16>,
+
+    /// Base time for the iPod's RTC, formatted as `YYYY-MM-DDTHH:MM:SS`.
+    ///
+    /// Defaults to the host's local time.
+    #[structopt(long)]
+    rtc_base: Option<String>,
+
+    /// File used to persist the iPod's RTC offset across runs.
+    ///
+    /// If not provided, the RTC offset is discarded on exit.
+    #[structopt(long, parse(from_os_str))]
+    rtc_file: Option<PathBuf>,
+
+    /// Initial battery charge level (in %).
+    #[structopt(long, default_value = "100")]
+    battery: f64,
+
+    /// Start with an external power source plugged in (either `firewire` or
+    /// `usb`).
+    #[structopt(long, possible_values = &["firewire", "usb"])]
+    ext_power: Option<String>,
+
+    /// Number of clickwheel positions (out of 96 per rotation) scrolled per
+    /// notch of the mouse's scroll wheel.
+    #[structopt(long, default_value = "2")]
+    wheel_sensitivity: f64,
+}
+
+fn make_serial_backend(name: &str, cfg: SerialCfg) -> DynResult<Box<dyn SerialBackend>> {
+    Ok(match cfg {
+        SerialCfg::Null => Box::new(serial::backend::Null::new()),
+        SerialCfg::Stdio => Box::new(serial::backend::Stdio::new()),
+        SerialCfg::File { log, input } => {
+            let log = fs::OpenOptions::new().create(true).append(true).open(log)?;
+            let input = match input {
+                Some(path) => Some(fs::File::open(path)?),
+                None => None,
+            };
+            Box::new(serial::backend::File::new(log, input))
+        }
+        SerialCfg::Tcp { port } => {
+            let sockaddr = format!("127.0.0.1:{}", port);
+            eprintln!("Waiting for a {} connection on {:?}...", name, sockaddr);
+
+            let sock = std::net::TcpListener::bind(sockaddr)?;
+            let (stream, addr) = sock.accept()?;
+            eprintln!("{} connected from {}", name, addr);
+
+            Box::new(serial::backend::Tcp::new(stream)?)
+        }
+        SerialCfg::Pty => {
+            #[cfg(not(unix))]
+            {
+                return Err("ptys can only be used on Unix".into());
+            }
+            #[cfg(unix)]
+            {
+                let pty = serial::backend::Pty::new()?;
+                eprintln!("{} is connected to {}", name, pty.path().display());
+                Box::new(pty)
+            }
+        }
+        SerialCfg::Iap { script } => {
+            let script = fs::read_to_string(script)?;
+            let (iap, mut handle) = serial::iap::Iap::new();
+
+            let name = name.to_string();
+            std::thread::spawn(move || {
+                match serial::iap::script::run_script(&mut handle, &script) {
+                    Ok(()) => info!("{} iAP script finished", name),
+                    Err(e) => error!("{} iAP script failed: {}", name, e),
+                }
+            });
+
+            Box::new(iap)
+        }
+    })
+}
+
+fn parse_boot_key(s: &str) -> Result<Ipod4gKey, &'static str> {
+    Ok(match s {
+        "menu" => Ipod4gKey::Up,
+        "play" => Ipod4gKey::Down,
+        "prev" => Ipod4gKey::Left,
+        "next" => Ipod4gKey::Right,
+        "select" => Ipod4gKey::Action,
+        _ => return Err("invalid key"),
+    })
 }
 
 enum System {
@@ -100,13 +273,14 @@ fn main() -> DynResult<()> {
 
     let args = Args::from_args();
 
+    let hdd_profile = args.hdd.drive().cloned();
     let hdd: Box<dyn BlockDev> = match args.hdd {
-        BlockCfg::Null { len } => Box::new(block::backend::Null::new(len)),
-        BlockCfg::Raw { path } => {
+        BlockCfg::Null { len, .. } => Box::new(block::backend::Null::new(len)),
+        BlockCfg::Raw { path, .. } => {
             let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
             Box::new(block::backend::Raw::new(file)?)
         }
-        BlockCfg::Mem { path, truncate } => {
+        BlockCfg::Mem { path, truncate, .. } => {
             let mut file = fs::File::open(path)?;
             let mut data = Vec::new();
             match truncate {
@@ -120,11 +294,66 @@ fn main() -> DynResult<()> {
             }
             Box::new(block::backend::Mem::new(data.into_boxed_slice()))
         }
+        BlockCfg::Compressed { path, .. } => {
+            let file = fs::File::open(path)?;
+            Box::new(block::backend::Compressed::new(file)?)
+        }
+        BlockCfg::Cow {
+            base,
+            deltas,
+            commit,
+            discard,
+            ..
+        } => {
+            let open_delta = |path| {
+                fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(path)
+            };
+
+            // the base image is only written to when committing a single delta
+            let base = fs::OpenOptions::new()
+                .read(true)
+                .write(commit && deltas.len() == 1)
+                .open(base)?;
+            let mut deltas = deltas.iter();
+            // BlockCfg ensures there's at least one delta
+            let mut cow = block::backend::Cow::new(base, open_delta(deltas.next().unwrap())?)?;
+            for delta in deltas {
+                cow = cow.stack(open_delta(delta)?)?;
+            }
+
+            if commit {
+                cow.commit()?;
+            }
+            if discard {
+                cow.discard()?;
+            }
+            Box::new(cow)
+        }
+        BlockCfg::Vvfat {
+            dir, firmware, len, ..
+        } => {
+            let firmware = match firmware {
+                Some(path) => Some(fs::read(path)?.into_boxed_slice()),
+                None => None,
+            };
+            Box::new(block::backend::Vvfat::new(dir, firmware, len)?)
+        }
     };
 
     let boot_kind = match args.hle {
         Some(fw_file) => BootKind::HLEBoot {
             fw_file: fs::File::open(fw_file)?,
+            image: args.hle_image,
+            held_keys: args.boot_keys,
+        },
+        None if args.hle_from_hdd => BootKind::HLEBootFromHdd {
+            image: args.hle_image,
+            held_keys: args.boot_keys,
         },
         None => BootKind::ColdBoot,
     };
@@ -136,6 +365,59 @@ fn main() -> DynResult<()> {
 
     let mut system = Ipod4g::new(hdd, flash_rom, boot_kind)?;
 
+    if let Some(path) = args.flash_write_back {
+        system.set_flash_write_back(path)?;
+    }
+    if let Some(profile) = hdd_profile {
+        system.set_hdd_profile(profile);
+    }
+    if args.hdd_timing {
+        system.set_hdd_timing(Some(IdeTiming::ipod_4g()));
+    }
+    if let Some(cfg) = args.hdd_smart {
+        system.set_hdd_smart(cfg);
+    }
+    if let Some(faults) = args.hdd_faults {
+        system.set_hdd_faults(faults);
+    }
+
+    // configure the RTC
+    let rtc = system.rtc();
+    if let Some(base) = args.rtc_base {
+        rtc.set_base(base.parse()?);
+    }
+    if let Some(path) = args.rtc_file {
+        rtc.set_sidecar(path)?;
+    }
+
+    // configure the battery
+    let power = system.power();
+    power.set_level(args.battery);
+    match args.ext_power.as_deref() {
+        // FireWire power comes in through the dock connector
+        Some("firewire") => system.accessories().set_connected(Ipod4gInput::Dock, true),
+        Some("usb") => power.set_power(PowerSource::Usb, true),
+        _ => {}
+    }
+
+    // configure the clickwheel
+    let clickwheel = system.clickwheel();
+    clickwheel.set_scroll_sensitivity(args.wheel_sensitivity);
+
+    // connect serial ports
+    system.attach_serial(
+        SerialIdx::Serial0,
+        make_serial_backend("serial0", args.serial0)?,
+    );
+    system.attach_serial(
+        SerialIdx::Serial1,
+        make_serial_backend("serial1", args.serial1)?,
+    );
+
+    if let Some(port) = args.usbip {
+        usbip::spawn_usbip_server(port, system.usb_host())?;
+    }
+
     // grab a bunch of UI wiring stuff
     let update_fb = system.render_callback();
     let controls = system.take_controls().unwrap();
@@ -0,0 +1,99 @@
+use std::str::FromStr;
+
+/// Helper struct to parse Serial Port backend configurations.
+pub enum SerialCfg {
+    /// `null`
+    Null,
+    /// `stdio`
+    Stdio,
+    /// `file:log=/path/[,input=/path/]`
+    File { log: String, input: Option<String> },
+    /// `tcp:port=<port>`
+    Tcp { port: u16 },
+    /// `pty`
+    Pty,
+    /// `iap:script=/path/`
+    Iap { script: String },
+}
+
+impl FromStr for SerialCfg {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<SerialCfg, &'static str> {
+        let mut s = s.splitn(2, ':');
+        let kind = s.next().unwrap();
+        Ok(match kind {
+            "null" => SerialCfg::Null,
+            "stdio" => SerialCfg::Stdio,
+            "pty" => SerialCfg::Pty,
+            "file" => {
+                let s = s.next().ok_or("missing required options")?.split(',');
+
+                let mut log = None;
+                let mut input = None;
+
+                for arg in s {
+                    let mut s = arg.split('=');
+                    let kind = s.next().unwrap();
+                    match kind {
+                        "log" => log = Some(s.next().ok_or("missing argument for `log`")?.into()),
+                        "input" => {
+                            input = Some(s.next().ok_or("missing argument for `input`")?.into())
+                        }
+                        _ => return Err("unknown `file` option"),
+                    }
+                }
+
+                SerialCfg::File {
+                    log: log.ok_or("missing `log` parameter")?,
+                    input,
+                }
+            }
+            "tcp" => {
+                let s = s.next().ok_or("missing required options")?.split(',');
+
+                let mut port = None;
+
+                for arg in s {
+                    let mut s = arg.split('=');
+                    let kind = s.next().unwrap();
+                    match kind {
+                        "port" => {
+                            port = Some(
+                                (s.next().ok_or("missing argument for `port`")?)
+                                    .parse::<u16>()
+                                    .map_err(|_| "could not parse `port`")?,
+                            )
+                        }
+                        _ => return Err("unknown `tcp` option"),
+                    }
+                }
+
+                SerialCfg::Tcp {
+                    port: port.ok_or("missing `port` parameter")?,
+                }
+            }
+            "iap" => {
+                let s = s.next().ok_or("missing required options")?.split(',');
+
+                let mut script = None;
+
+                for arg in s {
+                    let mut s = arg.split('=');
+                    let kind = s.next().unwrap();
+                    match kind {
+                        "script" => {
+                            script = Some(s.next().ok_or("missing argument for `script`")?.into())
+                        }
+                        _ => return Err("unknown `iap` option"),
+                    }
+                }
+
+                SerialCfg::Iap {
+                    script: script.ok_or("missing `script` parameter")?,
+                }
+            }
+            _ => return Err("invalid serial backend kind"),
+        })
+    }
+}
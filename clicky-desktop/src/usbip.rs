@@ -0,0 +1,447 @@
+//! A minimal USB/IP server, exposing the iPod's USB port to the host OS.
+//!
+//! Only a single client is served at a time. Isochronous transfers are not
+//! supported.
+//!
+//! Protocol reference: https://www.kernel.org/doc/html/latest/usb/usbip_protocol.html
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use clicky_core::devices::platform::pp::{SetupPacket, UsbDeviceInfo, UsbError, UsbHost};
+
+use crate::DynResult;
+
+const USBIP_VERSION: u16 = 0x0111;
+
+const OP_REQ_DEVLIST: u16 = 0x8005;
+const OP_REP_DEVLIST: u16 = 0x0005;
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+
+const USBIP_CMD_SUBMIT: u32 = 1;
+const USBIP_CMD_UNLINK: u32 = 2;
+const USBIP_RET_SUBMIT: u32 = 3;
+const USBIP_RET_UNLINK: u32 = 4;
+
+const USBIP_DIR_IN: u32 = 1;
+
+const BUSID: &str = "1-1";
+const BUSNUM: u32 = 1;
+const DEVNUM: u32 = 2;
+const SPEED_HIGH: u32 = 3;
+
+mod errno {
+    pub const ENODEV: i32 = 19;
+    pub const EINVAL: i32 = 22;
+    pub const EPIPE: i32 = 32;
+    pub const EPROTO: i32 = 71;
+    pub const ECONNRESET: i32 = 104;
+    pub const ETIMEDOUT: i32 = 110;
+}
+
+/// Spawn a USB/IP server on `127.0.0.1:<port>`.
+pub fn spawn_usbip_server(port: u16, host: UsbHost) -> DynResult<()> {
+    let sockaddr = format!("127.0.0.1:{}", port);
+    let sock = TcpListener::bind(&sockaddr)?;
+    eprintln!("Serving USB/IP on {:?} (busid {})", sockaddr, BUSID);
+
+    std::thread::spawn(move || {
+        // Enumerating the device resets it, so the enumeration results are
+        // reused when listing devices, and only refreshed on import.
+        let mut info = None;
+
+        for stream in sock.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    error!("USB/IP accept failed: {}", e);
+                    continue;
+                }
+            };
+
+            let peer = stream.peer_addr().ok();
+            info!("USB/IP client connected from {:?}", peer);
+            match serve_client(stream, host.clone(), &mut info) {
+                Ok(()) => info!("USB/IP client {:?} disconnected", peer),
+                Err(e) => error!("USB/IP client {:?} errored: {}", peer, e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn read_u16(r: &mut impl Read) -> io::Result<u16> {
+    let mut buf = [0; 2];
+    r.read_exact(&mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn put_u16(buf: &mut Vec<u8>, val: u16) {
+    buf.extend_from_slice(&val.to_be_bytes())
+}
+
+fn put_u32(buf: &mut Vec<u8>, val: u32) {
+    buf.extend_from_slice(&val.to_be_bytes())
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str, len: usize) {
+    let mut field = vec![0; len];
+    field[..s.len()].copy_from_slice(s.as_bytes());
+    buf.extend_from_slice(&field)
+}
+
+/// Interface class / subclass / protocol triples from a config descriptor.
+fn interfaces(info: &UsbDeviceInfo) -> Vec<[u8; 3]> {
+    let mut ifaces = Vec::new();
+    let desc = &info.config_descriptor;
+    let mut i = 0;
+    while i + 1 < desc.len() {
+        let len = desc[i] as usize;
+        if len == 0 {
+            break;
+        }
+        // INTERFACE descriptor (only report the default alternate setting)
+        if desc[i + 1] == 0x04 && len >= 9 && i + 9 <= desc.len() && desc[i + 3] == 0 {
+            ifaces.push([desc[i + 5], desc[i + 6], desc[i + 7]]);
+        }
+        i += len;
+    }
+    ifaces
+}
+
+/// Encode a `usbip_usb_device` struct.
+fn put_device(buf: &mut Vec<u8>, info: &UsbDeviceInfo, num_ifaces: u8) {
+    let dev = &info.device_descriptor;
+    let config = &info.config_descriptor;
+
+    put_str(buf, "/sys/devices/platform/clicky/usb1/1-1", 256);
+    put_str(buf, BUSID, 32);
+    put_u32(buf, BUSNUM);
+    put_u32(buf, DEVNUM);
+    put_u32(buf, SPEED_HIGH);
+    put_u16(buf, info.vendor_id);
+    put_u16(buf, info.product_id);
+    put_u16(buf, u16::from_le_bytes([dev[12], dev[13]]));
+    buf.extend_from_slice(&[
+        dev[4],    // bDeviceClass
+        dev[5],    // bDeviceSubClass
+        dev[6],    // bDeviceProtocol
+        config[5], // bConfigurationValue
+        dev[17],   // bNumConfigurations
+        num_ifaces,
+    ]);
+}
+
+/// Enumerate the device (resetting it in the process), updating the cached
+/// enumeration results.
+fn enumerate(host: &UsbHost, cache: &mut Option<UsbDeviceInfo>) -> Result<UsbDeviceInfo, ()> {
+    match host.enumerate() {
+        Ok(info) => {
+            *cache = Some(info.clone());
+            Ok(info)
+        }
+        Err(e) => {
+            warn!("USB/IP: could not enumerate device: {}", e);
+            *cache = None;
+            Err(())
+        }
+    }
+}
+
+fn serve_client(
+    mut stream: TcpStream,
+    host: UsbHost,
+    cache: &mut Option<UsbDeviceInfo>,
+) -> DynResult<()> {
+    stream.set_nodelay(true)?;
+
+    // connection setup phase
+    let version = read_u16(&mut stream)?;
+    let code = read_u16(&mut stream)?;
+    let _status = read_u32(&mut stream)?;
+    if version != USBIP_VERSION {
+        warn!("USB/IP client is using protocol version {:#06x}", version);
+    }
+
+    match code {
+        OP_REQ_DEVLIST => {
+            let info = match cache {
+                Some(info) => Ok(info.clone()),
+                None => enumerate(&host, cache),
+            };
+
+            let mut buf = Vec::new();
+            put_u16(&mut buf, USBIP_VERSION);
+            put_u16(&mut buf, OP_REP_DEVLIST);
+            put_u32(&mut buf, 0);
+            match info {
+                Err(_) => put_u32(&mut buf, 0),
+                Ok(info) => {
+                    let ifaces = interfaces(&info);
+                    put_u32(&mut buf, 1);
+                    put_device(&mut buf, &info, ifaces.len() as u8);
+                    for iface in ifaces {
+                        buf.extend_from_slice(&iface);
+                        buf.push(0); // padding
+                    }
+                }
+            }
+            stream.write_all(&buf)?;
+            // the client closes the connection after listing devices
+            return Ok(());
+        }
+        OP_REQ_IMPORT => {
+            let mut busid = [0; 32];
+            stream.read_exact(&mut busid)?;
+            let busid = String::from_utf8_lossy(&busid);
+            let busid = busid.trim_end_matches('\0');
+
+            let info = if busid == BUSID {
+                enumerate(&host, cache)
+            } else {
+                warn!("USB/IP: client requested unknown busid {:?}", busid);
+                Err(())
+            };
+
+            let mut buf = Vec::new();
+            put_u16(&mut buf, USBIP_VERSION);
+            put_u16(&mut buf, OP_REP_IMPORT);
+            match info {
+                Err(()) => {
+                    put_u32(&mut buf, 1);
+                    stream.write_all(&buf)?;
+                    return Ok(());
+                }
+                Ok(info) => {
+                    put_u32(&mut buf, 0);
+                    put_device(&mut buf, &info, interfaces(&info).len() as u8);
+                    stream.write_all(&buf)?;
+                }
+            }
+        }
+        _ => return Err(format!("unexpected USB/IP op code {:#06x}", code).into()),
+    }
+
+    // device is imported - start shuffling URBs
+    let mut urb_server = UrbServer {
+        host,
+        stream: Arc::new(Mutex::new(stream.try_clone()?)),
+        pending: Default::default(),
+        workers: HashMap::new(),
+    };
+    let res = urb_server.run(&mut stream);
+
+    // leave the device in a sane state for the next client
+    let _ = urb_server.host.reset();
+    res
+}
+
+#[derive(Debug)]
+struct Urb {
+    seqnum: u32,
+    ep: u8,
+    is_in: bool,
+    len: usize,
+    setup: [u8; 8],
+    data: Vec<u8>,
+}
+
+/// Maps in-flight URB seqnums to whether they've been unlinked.
+type PendingUrbs = Arc<Mutex<HashMap<u32, bool>>>;
+
+struct UrbServer {
+    host: UsbHost,
+    stream: Arc<Mutex<TcpStream>>,
+    pending: PendingUrbs,
+    /// Per-endpoint worker threads, ensuring URBs on an endpoint are completed
+    /// in-order, while still allowing different endpoints to make progress
+    /// independently.
+    workers: HashMap<(u8, bool), mpsc::Sender<Urb>>,
+}
+
+impl UrbServer {
+    fn run(&mut self, stream: &mut TcpStream) -> DynResult<()> {
+        loop {
+            let command = match read_u32(stream) {
+                Ok(command) => command,
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let seqnum = read_u32(stream)?;
+            let _devid = read_u32(stream)?;
+            let direction = read_u32(stream)?;
+            let ep = read_u32(stream)?;
+
+            let mut rest = [0; 28];
+            stream.read_exact(&mut rest)?;
+            let field =
+                |i: usize| u32::from_be_bytes([rest[i], rest[i + 1], rest[i + 2], rest[i + 3]]);
+
+            match command {
+                USBIP_CMD_SUBMIT => {
+                    let len = field(4) as usize;
+                    let number_of_packets = field(12) as i32;
+                    let mut setup = [0; 8];
+                    setup.copy_from_slice(&rest[20..28]);
+
+                    let is_in = direction == USBIP_DIR_IN;
+                    let mut data = vec![0; if is_in { 0 } else { len }];
+                    stream.read_exact(&mut data)?;
+
+                    if number_of_packets > 0 {
+                        // skip the isochronous packet descriptors
+                        let mut iso = vec![0; number_of_packets as usize * 16];
+                        stream.read_exact(&mut iso)?;
+                        warn!("USB/IP: isochronous transfers are not supported");
+                        send_ret_submit(&self.stream, seqnum, Err(errno::EINVAL))?;
+                        continue;
+                    }
+
+                    self.pending.lock().unwrap().insert(seqnum, false);
+                    self.submit(Urb {
+                        seqnum,
+                        ep: ep as u8,
+                        is_in,
+                        len,
+                        setup,
+                        data,
+                    });
+                }
+                USBIP_CMD_UNLINK => {
+                    let unlink_seqnum = field(0);
+
+                    // holding the lock ensures the worker won't send a
+                    // RET_SUBMIT for the unlinked URB after the RET_UNLINK
+                    let mut pending = self.pending.lock().unwrap();
+                    let status = match pending.get_mut(&unlink_seqnum) {
+                        Some(unlinked) => {
+                            *unlinked = true;
+                            -errno::ECONNRESET
+                        }
+                        None => 0,
+                    };
+
+                    let mut buf = Vec::new();
+                    put_u32(&mut buf, USBIP_RET_UNLINK);
+                    put_u32(&mut buf, seqnum);
+                    buf.extend_from_slice(&[0; 12]); // devid, direction, ep
+                    put_u32(&mut buf, status as u32);
+                    buf.extend_from_slice(&[0; 24]);
+                    self.stream.lock().unwrap().write_all(&buf)?;
+                    drop(pending);
+                }
+                _ => return Err(format!("unexpected USB/IP command {:#x}", command).into()),
+            }
+        }
+    }
+
+    fn submit(&mut self, urb: Urb) {
+        let key = (urb.ep, urb.is_in || urb.ep == 0);
+        let (host, stream, pending) = (&self.host, &self.stream, &self.pending);
+        let worker = self.workers.entry(key).or_insert_with(|| {
+            let (tx, rx) = mpsc::channel();
+            let (host, stream, pending) = (host.clone(), stream.clone(), pending.clone());
+            std::thread::spawn(move || urb_worker(rx, host, stream, pending));
+            tx
+        });
+        // the worker only exits once the sender is dropped
+        worker.send(urb).expect("worker thread exited unexpectedly");
+    }
+}
+
+/// `res` contains the number of bytes transferred, alongside any data returned
+/// by the device.
+fn send_ret_submit(
+    stream: &Mutex<TcpStream>,
+    seqnum: u32,
+    res: Result<(usize, Vec<u8>), i32>,
+) -> io::Result<()> {
+    let (status, actual_len, data) = match res {
+        Ok((len, data)) => (0, len, data),
+        Err(errno) => (-errno, 0, Vec::new()),
+    };
+
+    let mut buf = Vec::new();
+    put_u32(&mut buf, USBIP_RET_SUBMIT);
+    put_u32(&mut buf, seqnum);
+    buf.extend_from_slice(&[0; 12]); // devid, direction, ep
+    put_u32(&mut buf, status as u32);
+    put_u32(&mut buf, actual_len as u32);
+    put_u32(&mut buf, 0); // start_frame
+    put_u32(&mut buf, 0); // number_of_packets
+    put_u32(&mut buf, 0); // error_count
+    buf.extend_from_slice(&[0; 8]);
+    buf.extend_from_slice(&data);
+    stream.lock().unwrap().write_all(&buf)
+}
+
+fn usb_errno(e: UsbError) -> i32 {
+    match e {
+        UsbError::Timeout => errno::ETIMEDOUT,
+        UsbError::Stall => errno::EPIPE,
+        UsbError::Aborted => errno::ECONNRESET,
+        UsbError::Disconnected => errno::ENODEV,
+        UsbError::BadDescriptor => errno::EPROTO,
+    }
+}
+
+fn urb_worker(
+    rx: mpsc::Receiver<Urb>,
+    mut host: UsbHost,
+    stream: Arc<Mutex<TcpStream>>,
+    pending: PendingUrbs,
+) {
+    // keep the timeout short, so unlinked IN transfers are noticed promptly
+    host.set_timeout(Duration::from_secs(1));
+    let is_unlinked = |seqnum| pending.lock().unwrap().get(&seqnum) == Some(&true);
+
+    for urb in rx {
+        let res = if urb.ep == 0 {
+            host.control(SetupPacket::from_bytes(urb.setup), &urb.data)
+                .map(|mut data| {
+                    data.truncate(urb.len);
+                    match SetupPacket::from_bytes(urb.setup).is_in() {
+                        true => (data.len(), data),
+                        false => (urb.data.len(), data),
+                    }
+                })
+        } else if urb.is_in {
+            // IN transfers only complete once the device has data to send,
+            // which may take arbitrarily long. The transfer stays queued
+            // across timeouts, so that any data received so far isn't lost.
+            host.submit_in(urb.ep, urb.len).and_then(|xfer| loop {
+                match xfer.wait() {
+                    Err(UsbError::Timeout) if !is_unlinked(urb.seqnum) => continue,
+                    res => break res.map(|data| (data.len(), data)),
+                }
+            })
+        } else {
+            host.bulk_out(urb.ep, &urb.data)
+                .map(|len| (len, Vec::new()))
+        };
+
+        let mut pending = pending.lock().unwrap();
+        if pending.remove(&urb.seqnum) == Some(true) {
+            // already reported via RET_UNLINK
+            continue;
+        }
+
+        let res = send_ret_submit(&stream, urb.seqnum, res.map_err(usb_errno));
+        drop(pending);
+        if let Err(e) = res {
+            error!("USB/IP: failed to send URB response: {}", e);
+            return;
+        }
+    }
+}
@@ -8,7 +8,7 @@ use wasm_bindgen::prelude::*;
 
 use clicky_core::block::{self, BlockDev};
 use clicky_core::gui::{RenderCallback, TakeControls};
-use clicky_core::sys::ipod4g::{BootKind, Ipod4g, Ipod4gBinds, Ipod4gKey};
+use clicky_core::sys::ipod4g::{BootKind, HleImage, Ipod4g, Ipod4gBinds, Ipod4gKey};
 
 #[wasm_bindgen(start)]
 pub fn init() {
@@ -50,17 +50,27 @@ impl Ipod4gContainer {
         let fw =
             gzip_decompress(fw).map_err(|e| format!("could not decompress firmware: {}", e))?;
         debug!("decompressed fw image");
-        let disk =
-            gzip_decompress(disk).map_err(|e| format!("could not decompress disk image: {}", e))?;
-        debug!("decompressed disk image");
-
-        let hdd: Box<dyn BlockDev> = Box::new(block::backend::Mem::new(disk));
+        // chunk-compressed disk images are decompressed on-demand, instead of
+        // all at once
+        let hdd: Box<dyn BlockDev> = if block::backend::Compressed::is_compressed(disk) {
+            let disk = io::Cursor::new(disk.to_vec().into_boxed_slice());
+            let disk = block::backend::Compressed::new(disk)
+                .map_err(|e| format!("could not open compressed disk image: {}", e))?;
+            Box::new(disk)
+        } else {
+            let disk = gzip_decompress(disk)
+                .map_err(|e| format!("could not decompress disk image: {}", e))?;
+            debug!("decompressed disk image");
+            Box::new(block::backend::Mem::new(disk))
+        };
 
         let system = Ipod4g::new(
             hdd,
             None,
             BootKind::HLEBoot {
                 fw_file: io::Cursor::new(fw),
+                image: HleImage::Auto,
+                held_keys: Vec::new(),
             },
         )
         .map_err(|e| e.to_string())?;
@@ -1 +0,0 @@
-sudo udisksctl loop-delete -b /dev/loop0
@@ -1 +0,0 @@
-sudo udisksctl loop-setup -f ipodhd.img